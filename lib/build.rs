@@ -0,0 +1,9 @@
+fn main() {
+    // Scripts can ask for the target wasupdate was built for
+    println!(
+        "cargo:rustc-env=WASUPDATE_TARGET={}",
+        std::env::var("TARGET").unwrap()
+    );
+    // Without this cargo reruns the script whenever any file of the package changes
+    println!("cargo:rerun-if-changed=build.rs");
+}
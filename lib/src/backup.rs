@@ -0,0 +1,201 @@
+use std::{
+    fs,
+    io::{self, Error},
+    path::{Path, PathBuf},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::files::{back_up_entry, move_entry, remove_entry};
+
+pub const STATE_DIR: &str = ".wasupdate";
+const BACKUP_DIR: &str = "backup";
+const BACKUP_FILES_DIR: &str = "files";
+const BACKUP_META: &str = "backup.json";
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BackupMeta {
+    pub version: Option<String>,
+    pub timestamp: u64,
+    pub replaced: Vec<PathBuf>,
+    pub created: Vec<PathBuf>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RollbackReport {
+    pub version: Option<String>,
+    pub timestamp: u64,
+    pub restored: Vec<PathBuf>,
+    pub removed: Vec<PathBuf>,
+}
+
+/// Records the entries an install replaces or creates in `dest`, moving replaced
+/// entries aside so [`rollback`] can put them back.
+pub struct Backup {
+    dest: PathBuf,
+    dir: PathBuf,
+    meta: BackupMeta,
+}
+
+pub fn state_dir(dest: &Path) -> PathBuf {
+    dest.join(STATE_DIR)
+}
+
+fn backup_dir(dest: &Path) -> PathBuf {
+    state_dir(dest).join(BACKUP_DIR)
+}
+
+fn entry_exists(path: &Path) -> bool {
+    fs::symlink_metadata(path).is_ok()
+}
+
+impl Backup {
+    pub fn begin(dest: &Path, version: Option<String>) -> io::Result<Backup> {
+        let dir = backup_dir(dest);
+        if dir.exists() {
+            remove_entry(&dir)?;
+        }
+        fs::create_dir_all(dir.join(BACKUP_FILES_DIR))?;
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_err(Error::other)?
+            .as_secs();
+        Ok(Backup {
+            dest: dest.to_path_buf(),
+            dir,
+            meta: BackupMeta {
+                version,
+                timestamp,
+                replaced: vec![],
+                created: vec![],
+            },
+        })
+    }
+
+    /// Moves the existing entry at `name` aside before it gets replaced.
+    pub fn replace(&mut self, name: &Path) -> io::Result<()> {
+        let backup_path = self.dir.join(BACKUP_FILES_DIR).join(name);
+        if let Some(parent) = backup_path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        back_up_entry(&self.dest.join(name), &backup_path)?;
+        self.meta.replaced.push(name.to_path_buf());
+        Ok(())
+    }
+
+    pub fn create(&mut self, name: &Path) {
+        self.meta.created.push(name.to_path_buf());
+    }
+
+    pub fn save(&self) -> io::Result<()> {
+        let meta = serde_json::to_string_pretty(&self.meta).map_err(Error::other)?;
+        fs::write(self.dir.join(BACKUP_META), meta)
+    }
+}
+
+/// Restores the entries replaced by the last install into `dest` and removes the
+/// entries it created.
+pub fn rollback(dest: &Path) -> io::Result<RollbackReport> {
+    let dir = backup_dir(dest);
+    let meta_path = dir.join(BACKUP_META);
+    if !meta_path.exists() {
+        return Err(Error::new(
+            io::ErrorKind::NotFound,
+            format!("No backup found in '{}'", dir.display()),
+        ));
+    }
+    let meta = fs::read_to_string(&meta_path)?;
+    let meta: BackupMeta = serde_json::from_str(&meta).map_err(|e| {
+        Error::new(
+            io::ErrorKind::InvalidData,
+            format!(
+                "Backup metadata at '{}' is invalid: {e}",
+                meta_path.display()
+            ),
+        )
+    })?;
+
+    let mut removed = vec![];
+    for name in &meta.created {
+        let path = dest.join(name);
+        if entry_exists(&path) {
+            remove_entry(&path)?;
+            removed.push(name.clone());
+        }
+    }
+    let mut restored = vec![];
+    for name in &meta.replaced {
+        let path = dest.join(name);
+        if entry_exists(&path) {
+            remove_entry(&path)?;
+        }
+        move_entry(&dir.join(BACKUP_FILES_DIR).join(name), &path)?;
+        restored.push(name.clone());
+    }
+    fs::remove_dir_all(&dir)?;
+
+    Ok(RollbackReport {
+        version: meta.version,
+        timestamp: meta.timestamp,
+        restored,
+        removed,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn test_dir(name: &str) -> PathBuf {
+        let dir =
+            std::env::temp_dir().join(format!("wasupdate-backup-{}-{name}", std::process::id()));
+        if dir.exists() {
+            fs::remove_dir_all(&dir).unwrap();
+        }
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn test_rollback_without_backup() {
+        let dest = test_dir("none");
+        let err = rollback(&dest).err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::NotFound);
+        fs::remove_dir_all(dest).unwrap();
+    }
+
+    #[test]
+    fn test_rollback_restores_replaced_and_removes_created() {
+        let dest = test_dir("restore");
+        fs::write(dest.join("app"), "old").unwrap();
+        fs::create_dir(dest.join("plugins")).unwrap();
+        fs::write(dest.join("plugins").join("a"), "old plugin").unwrap();
+
+        let mut backup = Backup::begin(&dest, Some("0.9.0".to_string())).unwrap();
+        backup.replace(Path::new("app")).unwrap();
+        fs::write(dest.join("app"), "new").unwrap();
+        backup.replace(Path::new("plugins")).unwrap();
+        fs::create_dir(dest.join("plugins")).unwrap();
+        fs::write(dest.join("plugins").join("b"), "new plugin").unwrap();
+        backup.create(Path::new("added"));
+        fs::write(dest.join("added"), "new file").unwrap();
+        backup.save().unwrap();
+
+        let report = rollback(&dest).unwrap();
+        assert_eq!(report.version.as_deref(), Some("0.9.0"));
+        assert_eq!(report.removed, vec![PathBuf::from("added")]);
+        assert_eq!(
+            report.restored,
+            vec![PathBuf::from("app"), PathBuf::from("plugins")]
+        );
+        assert_eq!(fs::read_to_string(dest.join("app")).unwrap(), "old");
+        assert!(dest.join("plugins").join("a").exists());
+        assert!(!dest.join("plugins").join("b").exists());
+        assert!(!dest.join("added").exists());
+
+        // The backup is consumed by the rollback
+        assert!(rollback(&dest).is_err());
+        fs::remove_dir_all(dest).unwrap();
+    }
+}
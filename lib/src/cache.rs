@@ -0,0 +1,246 @@
+use std::{
+    fs,
+    io::{self, Error},
+    path::{Path, PathBuf},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use serde::{Deserialize, Serialize};
+use sha2::{Digest as _, Sha256};
+
+use crate::files::remove_entry;
+
+const CACHE_DIR: &str = "wasupdate";
+const META_EXTENSION: &str = "json";
+const GIT_DIR: &str = "git";
+
+/// Downloaded artifacts kept with their validators, so a later download of the same
+/// URL can ask the server whether it changed.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DownloadCache {
+    pub dir: PathBuf,
+    /// Entries older than this are removed when pruning.
+    pub max_age: Duration,
+    /// Oldest entries are removed when pruning until the cache fits.
+    pub max_bytes: u64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CacheEntry {
+    pub url: String,
+    pub etag: Option<String>,
+    pub last_modified: Option<String>,
+    pub file_name: String,
+    pub stored: u64,
+}
+
+/// Short digest of `url`, naming the files kept for it.
+pub(crate) fn url_key(url: &str) -> String {
+    let digest = format!("{:x}", Sha256::digest(url.as_bytes()));
+    digest[..16].to_string()
+}
+
+fn now() -> io::Result<u64> {
+    Ok(SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_err(Error::other)?
+        .as_secs())
+}
+
+impl DownloadCache {
+    /// The cache in the platform cache directory, such as `~/.cache/wasupdate`.
+    pub fn in_default_dir() -> Option<DownloadCache> {
+        Some(DownloadCache::new(dirs::cache_dir()?.join(CACHE_DIR)))
+    }
+
+    pub fn new(dir: PathBuf) -> DownloadCache {
+        DownloadCache {
+            dir,
+            max_age: Duration::from_secs(30 * 24 * 60 * 60),
+            max_bytes: 1024 * 1024 * 1024,
+        }
+    }
+
+    fn meta_path(&self, url: &str) -> PathBuf {
+        self.dir.join(format!("{}.{META_EXTENSION}", url_key(url)))
+    }
+
+    fn files_dir(&self, key: &str) -> PathBuf {
+        self.dir.join(key)
+    }
+
+    /// The cached entry for `url` and the path of its file, if both are still there.
+    pub fn lookup(&self, url: &str) -> Option<(CacheEntry, PathBuf)> {
+        let meta = fs::read_to_string(self.meta_path(url)).ok()?;
+        let entry: CacheEntry = serde_json::from_str(&meta).ok()?;
+        let path = self.files_dir(&url_key(url)).join(&entry.file_name);
+        (entry.url == url && path.is_file()).then_some((entry, path))
+    }
+
+    /// Copies the downloaded `file` into the cache. Returns the cached copy.
+    pub fn store(
+        &self,
+        url: &str,
+        etag: Option<String>,
+        last_modified: Option<String>,
+        file: &Path,
+    ) -> io::Result<PathBuf> {
+        let file_name = file
+            .file_name()
+            .ok_or_else(|| Error::new(io::ErrorKind::InvalidInput, "Download has no file name"))?
+            .to_string_lossy()
+            .to_string();
+        let files = self.files_dir(&url_key(url));
+        if files.exists() {
+            remove_entry(&files)?;
+        }
+        fs::create_dir_all(&files)?;
+        let cached = files.join(&file_name);
+        fs::copy(file, &cached)?;
+        let entry = CacheEntry {
+            url: url.to_string(),
+            etag,
+            last_modified,
+            file_name,
+            stored: now()?,
+        };
+        let meta = serde_json::to_string_pretty(&entry).map_err(Error::other)?;
+        fs::write(self.meta_path(url), meta)?;
+        Ok(cached)
+    }
+
+    /// Marks the entry for `url` as fresh after the server confirmed it is unchanged.
+    pub fn touch(&self, url: &str) -> io::Result<()> {
+        let Some((mut entry, _)) = self.lookup(url) else {
+            return Ok(());
+        };
+        entry.stored = now()?;
+        let meta = serde_json::to_string_pretty(&entry).map_err(Error::other)?;
+        fs::write(self.meta_path(url), meta)
+    }
+
+    /// Bare clone of the git repository at `url`, fetched into again by later runs.
+    pub fn repo_dir(&self, url: &str) -> PathBuf {
+        self.dir.join(GIT_DIR).join(url_key(url))
+    }
+
+    /// Removes entries older than `max_age`, then the oldest ones until the cache is
+    /// within `max_bytes`. Git repositories that were not fetched into for `max_age`
+    /// are removed as well. Returns the URLs of the removed entries.
+    pub fn prune(&self) -> io::Result<Vec<String>> {
+        self.prune_repos()?;
+        let Ok(dir) = fs::read_dir(&self.dir) else {
+            return Ok(vec![]);
+        };
+        let mut entries = vec![];
+        for meta in dir {
+            let meta = meta?.path();
+            if meta.extension().is_none_or(|ext| ext != META_EXTENSION) {
+                continue;
+            }
+            let Some(entry) = fs::read_to_string(&meta)
+                .ok()
+                .and_then(|m| serde_json::from_str::<CacheEntry>(&m).ok())
+            else {
+                remove_entry(&meta)?;
+                continue;
+            };
+            let path = self.files_dir(&url_key(&entry.url)).join(&entry.file_name);
+            let size = fs::metadata(&path).map(|m| m.len()).unwrap_or(0);
+            entries.push((entry, size));
+        }
+        entries.sort_by_key(|(entry, _)| std::cmp::Reverse(entry.stored));
+
+        let oldest = now()?.saturating_sub(self.max_age.as_secs());
+        let mut total = 0;
+        let mut removed = vec![];
+        for (entry, size) in entries {
+            total += size;
+            if entry.stored >= oldest && total <= self.max_bytes {
+                continue;
+            }
+            let files = self.files_dir(&url_key(&entry.url));
+            if files.exists() {
+                remove_entry(&files)?;
+            }
+            remove_entry(&self.meta_path(&entry.url))?;
+            removed.push(entry.url);
+        }
+        Ok(removed)
+    }
+
+    /// Removes the repositories of [`DownloadCache::repo_dir`] last fetched into more
+    /// than `max_age` ago. Every fetch rewrites `FETCH_HEAD`.
+    fn prune_repos(&self) -> io::Result<()> {
+        let Ok(repos) = fs::read_dir(self.dir.join(GIT_DIR)) else {
+            return Ok(());
+        };
+        for repo in repos {
+            let repo = repo?.path();
+            let used = [repo.join("FETCH_HEAD"), repo.clone()]
+                .iter()
+                .filter_map(|path| fs::metadata(path).and_then(|m| m.modified()).ok())
+                .max();
+            if used.is_some_and(|used| used.elapsed().is_ok_and(|age| age > self.max_age)) {
+                remove_entry(&repo)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_store_lookup_and_prune() {
+        let root = std::env::temp_dir().join(format!("wasupdate-cache-{}", std::process::id()));
+        let file = root.join("app.tar.gz");
+        fs::create_dir_all(&root).unwrap();
+        fs::write(&file, "archive").unwrap();
+        let mut cache = DownloadCache::new(root.join("cache"));
+        assert!(cache.lookup("http://host/a").is_none());
+
+        for url in ["http://host/a", "http://host/b", "http://host/c"] {
+            cache
+                .store(url, Some("\"v1\"".to_string()), None, &file)
+                .unwrap();
+        }
+        let (entry, path) = cache.lookup("http://host/a").unwrap();
+        assert_eq!(entry.etag.as_deref(), Some("\"v1\""));
+        assert_eq!(path.file_name().unwrap(), "app.tar.gz");
+        assert_eq!(fs::read_to_string(path).unwrap(), "archive");
+
+        let backdate = |cache: &DownloadCache, url: &str| {
+            let meta = cache.meta_path(url);
+            let mut entry: CacheEntry =
+                serde_json::from_str(&fs::read_to_string(&meta).unwrap()).unwrap();
+            entry.stored -= 10;
+            fs::write(&meta, serde_json::to_string(&entry).unwrap()).unwrap();
+        };
+        backdate(&cache, "http://host/a");
+        // Two of the three entries fit, so the oldest one goes
+        cache.max_bytes = 14;
+        assert_eq!(cache.prune().unwrap(), vec!["http://host/a"]);
+        backdate(&cache, "http://host/b");
+        cache.max_age = Duration::from_secs(5);
+        assert_eq!(cache.prune().unwrap(), vec!["http://host/b"]);
+        assert!(cache.lookup("http://host/c").is_some());
+
+        let fresh = cache.repo_dir("https://host/fresh.git");
+        let stale = cache.repo_dir("https://host/stale.git");
+        for repo in [&fresh, &stale] {
+            fs::create_dir_all(repo).unwrap();
+            fs::write(repo.join("FETCH_HEAD"), "").unwrap();
+        }
+        let old = SystemTime::now() - Duration::from_secs(60);
+        for path in [stale.join("FETCH_HEAD"), stale.clone()] {
+            fs::File::open(path).unwrap().set_modified(old).unwrap();
+        }
+        cache.prune().unwrap();
+        assert!(fresh.exists());
+        assert!(!stale.exists());
+        fs::remove_dir_all(root).unwrap();
+    }
+}
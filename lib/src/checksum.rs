@@ -0,0 +1,387 @@
+use std::{
+    error, fmt,
+    fs::File,
+    io::{self, Error},
+    path::{Path, PathBuf},
+};
+
+use md5::Md5;
+use reqwest::StatusCode;
+use sha2::{Digest as _, Sha256, Sha512};
+
+use crate::http::{default_client, request_error, with_headers};
+
+const SIDECAR_EXTENSIONS: [(&str, Algorithm); 4] = [
+    ("sha256", Algorithm::Sha256),
+    ("sha256sum", Algorithm::Sha256),
+    ("sha512", Algorithm::Sha512),
+    ("sha512sum", Algorithm::Sha512),
+];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Algorithm {
+    Sha256,
+    Sha512,
+    Blake3,
+}
+
+impl Algorithm {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Algorithm::Sha256 => "sha256",
+            Algorithm::Sha512 => "sha512",
+            Algorithm::Blake3 => "blake3",
+        }
+    }
+
+    fn hex_len(&self) -> usize {
+        match self {
+            Algorithm::Sha256 | Algorithm::Blake3 => 64,
+            Algorithm::Sha512 => 128,
+        }
+    }
+
+    fn from_name(name: &str) -> Option<Algorithm> {
+        match name.to_lowercase().as_str() {
+            "sha256" => Some(Algorithm::Sha256),
+            "sha512" => Some(Algorithm::Sha512),
+            "blake3" => Some(Algorithm::Blake3),
+            _ => None,
+        }
+    }
+}
+
+/// A digest written as `<algo>:<hex>`, where bare hex means sha256.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Digest {
+    pub algorithm: Algorithm,
+    pub hex: String,
+}
+
+impl fmt::Display for Digest {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}", self.algorithm.name(), self.hex)
+    }
+}
+
+impl Digest {
+    pub fn parse(digest: &str) -> io::Result<Digest> {
+        let digest = digest.trim();
+        let (algorithm, hex) = match digest.split_once(':') {
+            Some((name, hex)) => {
+                let algorithm = Algorithm::from_name(name).ok_or_else(|| {
+                    Error::new(
+                        io::ErrorKind::InvalidInput,
+                        format!("Unsupported checksum algorithm '{name}' in '{digest}'"),
+                    )
+                })?;
+                (algorithm, hex)
+            }
+            None => (Algorithm::Sha256, digest),
+        };
+        let hex = hex.to_lowercase();
+        if hex.len() != algorithm.hex_len() || !hex.chars().all(|c| c.is_ascii_hexdigit()) {
+            return Err(Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("Invalid {} checksum: '{digest}'", algorithm.name()),
+            ));
+        }
+        Ok(Digest { algorithm, hex })
+    }
+
+    /// Hashes the file at `path` with a streaming hasher.
+    pub fn compute_file(algorithm: Algorithm, path: &Path) -> io::Result<Digest> {
+        let mut hasher = Hasher::new(algorithm);
+        io::copy(&mut File::open(path)?, &mut hasher)?;
+        Ok(hasher.finalize())
+    }
+
+    pub fn matches(&self, other: &Digest) -> bool {
+        self == other
+    }
+}
+
+/// Incremental hasher for any supported algorithm, fed through `io::Write`.
+pub enum Hasher {
+    Sha256(Sha256),
+    Sha512(Sha512),
+    Blake3(Box<blake3::Hasher>),
+}
+
+impl Hasher {
+    pub fn new(algorithm: Algorithm) -> Hasher {
+        match algorithm {
+            Algorithm::Sha256 => Hasher::Sha256(Sha256::new()),
+            Algorithm::Sha512 => Hasher::Sha512(Sha512::new()),
+            Algorithm::Blake3 => Hasher::Blake3(Box::default()),
+        }
+    }
+
+    pub fn update(&mut self, data: &[u8]) {
+        match self {
+            Hasher::Sha256(hasher) => hasher.update(data),
+            Hasher::Sha512(hasher) => hasher.update(data),
+            Hasher::Blake3(hasher) => {
+                hasher.update(data);
+            }
+        }
+    }
+
+    pub fn finalize(self) -> Digest {
+        let (algorithm, hex) = match self {
+            Hasher::Sha256(hasher) => (Algorithm::Sha256, format!("{:x}", hasher.finalize())),
+            Hasher::Sha512(hasher) => (Algorithm::Sha512, format!("{:x}", hasher.finalize())),
+            Hasher::Blake3(hasher) => (Algorithm::Blake3, hasher.finalize().to_hex().to_string()),
+        };
+        Digest { algorithm, hex }
+    }
+}
+
+impl io::Write for Hasher {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.update(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Returned (wrapped in an `io::Error`) when an archive does not match the expected digest.
+#[derive(Debug, Clone)]
+pub struct ChecksumMismatch {
+    pub path: PathBuf,
+    pub expected: String,
+    pub actual: String,
+}
+
+impl fmt::Display for ChecksumMismatch {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Checksum mismatch for '{}': expected {}, got {}",
+            self.path.display(),
+            self.expected,
+            self.actual
+        )
+    }
+}
+
+impl error::Error for ChecksumMismatch {}
+
+impl ChecksumMismatch {
+    pub fn from_io(e: &Error) -> Option<&ChecksumMismatch> {
+        e.get_ref()
+            .and_then(|inner| inner.downcast_ref::<ChecksumMismatch>())
+    }
+}
+
+pub fn sha256_file(path: &Path) -> io::Result<String> {
+    Ok(Digest::compute_file(Algorithm::Sha256, path)?.hex)
+}
+
+/// MD5 of the file at `path` in hex, for scripts reading legacy manifests. MD5 is
+/// broken, so it is never accepted for verifying artifacts.
+pub fn md5_file(path: &Path) -> io::Result<String> {
+    let mut hasher = Md5::new();
+    io::copy(&mut File::open(path)?, &mut hasher)?;
+    Ok(format!("{:x}", hasher.finalize()))
+}
+
+/// Verifies `path` against `expected`, given as `<algo>:<hex>` or bare sha256 hex.
+pub fn verify_checksum(path: &Path, expected: &str) -> io::Result<()> {
+    verify_digest(path, &Digest::parse(expected)?)
+}
+
+pub fn verify_digest(path: &Path, expected: &Digest) -> io::Result<()> {
+    let actual = Digest::compute_file(expected.algorithm, path)?;
+    check_digest(path, &actual, expected)
+}
+
+/// Compares a digest computed elsewhere, such as while streaming `path`, with the
+/// expected one.
+pub fn check_digest(path: &Path, actual: &Digest, expected: &Digest) -> io::Result<()> {
+    if !actual.matches(expected) {
+        return Err(Error::new(
+            io::ErrorKind::InvalidData,
+            ChecksumMismatch {
+                path: path.to_path_buf(),
+                expected: expected.to_string(),
+                actual: actual.to_string(),
+            },
+        ));
+    }
+    Ok(())
+}
+
+/// Takes the digest from a sidecar file in the common `<hex>  <filename>` format.
+pub fn parse_sidecar(content: &str) -> Option<String> {
+    content
+        .lines()
+        .find_map(|line| line.split_whitespace().next())
+        .map(|hex| hex.to_string())
+}
+
+/// Fetches the digest published next to `url`, trying the sha256 sidecars before the
+/// sha512 ones. Each request carries the `headers` for its own URL.
+pub fn fetch_sidecar_checksum(
+    url: &str,
+    headers: impl Fn(&str) -> io::Result<Vec<(String, String)>>,
+) -> io::Result<Digest> {
+    for (ext, algorithm) in SIDECAR_EXTENSIONS {
+        let sidecar_url = format!("{url}.{ext}");
+        let response = with_headers(default_client()?.get(&sidecar_url), &headers(&sidecar_url)?)?
+            .send()
+            .map_err(request_error)?;
+        if response.status() == StatusCode::NOT_FOUND {
+            continue;
+        }
+        if !response.status().is_success() {
+            let status = response.status();
+            return Err(Error::other(format!(
+                "Failed to fetch checksum sidecar '{sidecar_url}' with status: {status}"
+            )));
+        }
+        let body = response.text().map_err(request_error)?;
+        let hex = parse_sidecar(&body).ok_or_else(|| {
+            Error::new(
+                io::ErrorKind::InvalidData,
+                format!("Checksum sidecar '{sidecar_url}' is empty"),
+            )
+        })?;
+        return Digest::parse(&format!("{}:{hex}", algorithm.name()));
+    }
+    let tried = SIDECAR_EXTENSIONS
+        .iter()
+        .map(|(ext, _)| format!("'{url}.{ext}'"))
+        .collect::<Vec<_>>()
+        .join(", ");
+    Err(Error::new(
+        io::ErrorKind::NotFound,
+        format!("No checksum sidecar found, tried: {tried}"),
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const HELLO_SHA256: &str = "2cf24dba5fb0a30e26e83b2ac5b9e29e1b161e5c1fa7425e73043362938b9824";
+
+    fn test_file(name: &str, content: &str) -> PathBuf {
+        let path =
+            std::env::temp_dir().join(format!("wasupdate-checksum-{}-{name}", std::process::id()));
+        std::fs::write(&path, content).unwrap();
+        path
+    }
+
+    #[test]
+    fn test_verify_checksum_match() {
+        let path = test_file("match", "hello");
+        assert_eq!(sha256_file(&path).unwrap(), HELLO_SHA256);
+        verify_checksum(&path, HELLO_SHA256).unwrap();
+        verify_checksum(&path, &format!("sha256:{}", HELLO_SHA256.to_uppercase())).unwrap();
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_verify_checksum_mismatch() {
+        let path = test_file("mismatch", "hello!");
+        let err = verify_checksum(&path, HELLO_SHA256).err().unwrap();
+        let mismatch = ChecksumMismatch::from_io(&err).expect("Expected a checksum mismatch");
+        assert_eq!(mismatch.expected, format!("sha256:{HELLO_SHA256}"));
+        assert_ne!(mismatch.actual, mismatch.expected);
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_verify_checksum_invalid() {
+        let path = test_file("invalid", "hello");
+        let err = verify_checksum(&path, "sha256:xyz").err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_parse_sidecar() {
+        let content = format!("{HELLO_SHA256}  myapp-1.2.0.tar.gz\n");
+        assert_eq!(parse_sidecar(&content).as_deref(), Some(HELLO_SHA256));
+        assert_eq!(parse_sidecar(HELLO_SHA256).as_deref(), Some(HELLO_SHA256));
+        assert_eq!(parse_sidecar("\n  \n"), None);
+    }
+
+    const HELLO_SHA512: &str = "9b71d224bd62f3785d96d46ad3ea3d73319bfbc2890caadae2dff72519673ca72323c3d99ba5c11d7c7acc6e14b8c5da0c4663475c2e5c3adef46f73bcdec043";
+    const HELLO_BLAKE3: &str = "ea8f163db38682925e4491c5e58d4bb3506ef8c14eb78a86e908c5624a67200f";
+
+    #[test]
+    fn test_digest_parse() {
+        let digest = Digest::parse(HELLO_SHA256).unwrap();
+        assert_eq!(digest.algorithm, Algorithm::Sha256);
+        let digest = Digest::parse(&format!("SHA512:{}", HELLO_SHA512.to_uppercase())).unwrap();
+        assert_eq!(digest.algorithm, Algorithm::Sha512);
+        assert_eq!(digest.hex, HELLO_SHA512);
+        let digest = Digest::parse(&format!(" blake3:{HELLO_BLAKE3}\n")).unwrap();
+        assert_eq!(digest.to_string(), format!("blake3:{HELLO_BLAKE3}"));
+    }
+
+    #[test]
+    fn test_digest_parse_malformed() {
+        for malformed in [
+            "",
+            "sha256:",
+            "md5:d41d8cd98f00b204e9800998ecf8427e",
+            &format!("sha512:{HELLO_SHA256}"),
+            &format!("sha256:{}", "g".repeat(64)),
+            &HELLO_SHA256[1..],
+        ] {
+            let err = Digest::parse(malformed).err().unwrap();
+            assert_eq!(err.kind(), io::ErrorKind::InvalidInput, "{malformed}");
+        }
+    }
+
+    #[test]
+    fn test_digest_compute_file() {
+        let path = test_file("compute", "hello");
+        for (algorithm, hex) in [
+            (Algorithm::Sha256, HELLO_SHA256),
+            (Algorithm::Sha512, HELLO_SHA512),
+            (Algorithm::Blake3, HELLO_BLAKE3),
+        ] {
+            let digest = Digest::compute_file(algorithm, &path).unwrap();
+            assert_eq!(digest.hex, hex);
+            assert!(
+                digest.matches(&Digest::parse(&format!("{}:{hex}", algorithm.name())).unwrap())
+            );
+            verify_checksum(&path, &digest.to_string()).unwrap();
+        }
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_digest_matches_requires_same_algorithm() {
+        let sha256 = Digest::parse(HELLO_SHA256).unwrap();
+        let blake3 = Digest {
+            algorithm: Algorithm::Blake3,
+            hex: HELLO_SHA256.to_string(),
+        };
+        assert!(!sha256.matches(&blake3));
+    }
+
+    #[test]
+    fn test_md5() {
+        for (input, hex) in [
+            ("", "d41d8cd98f00b204e9800998ecf8427e"),
+            ("abc", "900150983cd24fb0d6963f7d28e17f72"),
+            (
+                "The quick brown fox jumps over the lazy dog",
+                "9e107d9d372bb6826bd81d3542a419d6",
+            ),
+            (&"0123456789".repeat(10), "7a08b07e84641703e5f2c836aa59a170"),
+        ] {
+            let path = test_file("md5", input);
+            assert_eq!(md5_file(&path).unwrap(), hex, "{input:?}");
+            std::fs::remove_file(path).unwrap();
+        }
+    }
+}
@@ -0,0 +1,271 @@
+use std::{
+    fs::File,
+    io::{self, Error, Read, Seek, SeekFrom, Write},
+    path::Path,
+    sync::atomic::{AtomicBool, AtomicU64, Ordering},
+    thread,
+};
+
+use reqwest::{StatusCode, blocking::Client};
+
+use crate::{
+    http,
+    install::{DOWNLOAD_BUFFER_SIZE, InstallOptions},
+    retry::check_response,
+};
+
+/// Splits large downloads into byte ranges fetched over several connections.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ParallelDownload {
+    /// Number of ranges downloaded at the same time.
+    pub connections: usize,
+    /// Size of each range in bytes.
+    pub chunk_size: u64,
+    /// Downloads smaller than this use a single connection.
+    pub min_size: u64,
+}
+
+impl ParallelDownload {
+    pub const DEFAULT: ParallelDownload = ParallelDownload {
+        connections: 4,
+        chunk_size: 8 * 1024 * 1024,
+        min_size: 32 * 1024 * 1024,
+    };
+
+    /// Whether a download of `size` bytes is worth splitting.
+    pub fn applies_to(&self, size: u64) -> bool {
+        self.connections > 1 && size >= self.min_size && size > self.chunk_size
+    }
+}
+
+impl Default for ParallelDownload {
+    fn default() -> Self {
+        ParallelDownload::DEFAULT
+    }
+}
+
+/// Parses sizes such as `8M`, `512K` or `1G` in binary units. Plain numbers are bytes.
+pub fn parse_size(value: &str) -> Result<u64, String> {
+    let value = value.trim();
+    let split = value
+        .find(|c: char| !c.is_ascii_digit())
+        .unwrap_or(value.len());
+    let (amount, unit) = value.split_at(split);
+    let amount: u64 = amount
+        .parse()
+        .map_err(|_| format!("Invalid size '{value}'"))?;
+    let multiplier: u64 = match unit.trim().to_ascii_uppercase().as_str() {
+        "" | "B" => 1,
+        "K" | "KB" | "KIB" => 1024,
+        "M" | "MB" | "MIB" => 1024 * 1024,
+        "G" | "GB" | "GIB" => 1024 * 1024 * 1024,
+        unit => return Err(format!("Unknown unit '{unit}' in size '{value}'")),
+    };
+    match amount.checked_mul(multiplier) {
+        Some(0) | None => Err(format!("Invalid size '{value}'")),
+        Some(size) => Ok(size),
+    }
+}
+
+/// A download of `size` bytes split into ranges, written into `path` as they arrive.
+pub(crate) struct Chunks<'a> {
+    pub client: &'a Client,
+    pub opts: &'a InstallOptions,
+    pub url: &'a str,
+    /// Validator of the first response, so every range comes from the same file.
+    pub validator: Option<&'a str>,
+    pub size: u64,
+    pub pb: &'a indicatif::ProgressBar,
+}
+
+impl Chunks<'_> {
+    /// Preallocates `path` and downloads the ranges on `parallel.connections` worker
+    /// threads. Stops at the first failing range.
+    pub fn download(&self, path: &Path, parallel: &ParallelDownload) -> io::Result<()> {
+        File::create(path)?.set_len(self.size)?;
+        let count = self.size.div_ceil(parallel.chunk_size);
+        let next = AtomicU64::new(0);
+        let failed = AtomicBool::new(false);
+        thread::scope(|scope| {
+            let workers: Vec<_> = (0..parallel.connections.min(count as usize))
+                .map(|_| {
+                    scope.spawn(|| -> io::Result<()> {
+                        let mut file = File::options().write(true).open(path)?;
+                        while !failed.load(Ordering::Relaxed) {
+                            let index = next.fetch_add(1, Ordering::Relaxed);
+                            if index >= count {
+                                break;
+                            }
+                            let start = index * parallel.chunk_size;
+                            let end = (start + parallel.chunk_size).min(self.size);
+                            self.fetch(&mut file, start, end).inspect_err(|_| {
+                                failed.store(true, Ordering::Relaxed);
+                            })?;
+                        }
+                        Ok(())
+                    })
+                })
+                .collect();
+            workers.into_iter().try_for_each(|worker| {
+                worker
+                    .join()
+                    .map_err(|_| Error::other("Download worker panicked"))?
+            })
+        })
+    }
+
+    /// Downloads bytes `start..end` into the same range of `file`.
+    fn fetch(&self, file: &mut File, start: u64, end: u64) -> io::Result<()> {
+        let headers = self.opts.request_headers("GET", self.url)?;
+        let mut request = http::with_headers(self.client.get(self.url), &headers)?
+            .header(reqwest::header::RANGE, format!("bytes={start}-{}", end - 1));
+        if let Some(validator) = self.validator {
+            request = request.header(reqwest::header::IF_RANGE, validator);
+        }
+        let mut response = check_response(request.send()).map_err(|e| e.into_error())?;
+        if response.status() != StatusCode::PARTIAL_CONTENT {
+            return Err(Error::other(format!(
+                "{} answered the range {start}-{} with {} instead of a partial response",
+                self.url,
+                end - 1,
+                response.status()
+            )));
+        }
+        file.seek(SeekFrom::Start(start))?;
+        let mut buffer = vec![0; DOWNLOAD_BUFFER_SIZE];
+        let mut received = 0;
+        while received < end - start {
+            let n = response
+                .read(&mut buffer)
+                .map_err(|e| http::read_error(self.url, e))?;
+            if n == 0 {
+                break;
+            }
+            let n = n.min((end - start - received) as usize);
+            file.write_all(&buffer[..n])?;
+            received += n as u64;
+            self.pb.inc(n as u64);
+        }
+        if received != end - start {
+            return Err(Error::new(
+                io::ErrorKind::UnexpectedEof,
+                format!(
+                    "The range {start}-{} of '{}' was cut short: expected {} bytes, received {received}",
+                    end - 1,
+                    self.url,
+                    end - start
+                ),
+            ));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        fs,
+        sync::{Arc, Mutex},
+    };
+
+    use super::*;
+    use crate::{
+        checksum::{Algorithm, Digest},
+        install::{InstallOptions, download_archive},
+        retry::RetryPolicy,
+        test_server::{Response, TestServer},
+    };
+
+    #[test]
+    fn test_parse_size() {
+        assert_eq!(parse_size("512").unwrap(), 512);
+        assert_eq!(parse_size("64K").unwrap(), 64 * 1024);
+        assert_eq!(parse_size("8MiB").unwrap(), 8 * 1024 * 1024);
+        assert_eq!(parse_size("1g").unwrap(), 1024 * 1024 * 1024);
+        assert!(parse_size("0").is_err());
+        assert!(parse_size("8 parsecs").is_err());
+    }
+
+    fn range_server(
+        body: Arc<Vec<u8>>,
+        honor_range: bool,
+    ) -> (TestServer, Arc<Mutex<Vec<String>>>) {
+        let ranges = Arc::new(Mutex::new(vec![]));
+        let seen = ranges.clone();
+        let server = TestServer::start(move |req| {
+            let range = req.header("range").map(str::to_string);
+            seen.lock().unwrap().push(match req.method.as_str() {
+                "HEAD" => "HEAD".to_string(),
+                _ => range.clone().unwrap_or_default(),
+            });
+            let headers = vec![
+                ("Accept-Ranges".to_string(), "bytes".to_string()),
+                ("ETag".to_string(), "\"fixture\"".to_string()),
+            ];
+            let bounds = range.as_deref().and_then(|r| {
+                let (start, end) = r.strip_prefix("bytes=")?.split_once('-')?;
+                Some((start.parse::<usize>().ok()?, end.parse::<usize>().ok()?))
+            });
+            match bounds {
+                Some((start, end)) if honor_range => Response {
+                    status: 206,
+                    headers,
+                    body: body[start..=end].to_vec(),
+                },
+                _ => Response {
+                    status: 200,
+                    headers,
+                    body: body.to_vec(),
+                },
+            }
+        });
+        (server, ranges)
+    }
+
+    #[test]
+    fn test_parallel_download_matches_fixture() {
+        let fixture: Vec<u8> = (0..300_000u32).map(|i| (i * 7 % 251) as u8).collect();
+        let expected = {
+            let path =
+                std::env::temp_dir().join(format!("wasupdate-fixture-{}", std::process::id()));
+            fs::write(&path, &fixture).unwrap();
+            let digest = Digest::compute_file(Algorithm::Sha256, &path).unwrap();
+            fs::remove_file(path).unwrap();
+            digest
+        };
+        let fixture = Arc::new(fixture);
+        let opts = InstallOptions {
+            parallel: Some(ParallelDownload {
+                connections: 4,
+                chunk_size: 64 * 1024,
+                min_size: 0,
+            }),
+            retry: RetryPolicy {
+                attempts: 1,
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+
+        for honor_range in [true, false] {
+            let (server, ranges) = range_server(fixture.clone(), honor_range);
+            let file = download_archive(&server.url(&format!("/chunked-{honor_range}.bin")), &opts)
+                .unwrap();
+            assert_eq!(
+                Digest::compute_file(Algorithm::Sha256, &file).unwrap(),
+                expected
+            );
+            let ranges = ranges.lock().unwrap();
+            if honor_range {
+                // A HEAD request finds the size, then five ranges cover the file
+                assert_eq!(ranges.len(), 6);
+                assert_eq!(ranges[0], "HEAD");
+                assert!(ranges.contains(&"bytes=262144-299999".to_string()));
+            } else {
+                // The ignored range makes it start over on a single connection
+                assert_eq!(ranges.last().unwrap(), "");
+            }
+            fs::remove_file(file).unwrap();
+        }
+    }
+}
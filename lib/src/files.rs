@@ -0,0 +1,317 @@
+use std::{fs, io, path::Path};
+
+/// Removes the file or directory at `path`.
+///
+/// Entries that are in use, e.g. a running executable on Windows, cannot be
+/// deleted, so they are renamed to `<name>.old-<pid>` instead and left for
+/// [`cleanup_old_files`] on a later run.
+pub fn remove_entry(path: &Path) -> io::Result<()> {
+    let result = if fs::symlink_metadata(path)?.is_dir() {
+        fs::remove_dir_all(path)
+    } else {
+        fs::remove_file(path).or_else(|e| fs::remove_dir(path).map_err(|_| e))
+    };
+    match result {
+        Err(e) if is_in_use(&e) => rename_aside(path, |from, to| fs::rename(from, to)).map(|_| ()),
+        result => result,
+    }
+}
+
+/// Moves the entry at `from` to `to`, like [`fs::rename`].
+///
+/// Renames cannot cross filesystems, e.g. from a tmpfs `/tmp` into the install
+/// directory, so in that case the entry is copied recursively, keeping permissions
+/// and symlinks, and the original removed afterwards.
+pub fn move_entry(from: &Path, to: &Path) -> io::Result<()> {
+    move_entry_with(from, to, |from, to| fs::rename(from, to))
+}
+
+/// Moves the entry at `from` into the backup location `to`.
+///
+/// An entry that is in use cannot be moved out of its directory, so it is copied
+/// to `to` instead and the original renamed to `<name>.old-<pid>` in place, which
+/// frees its name for the replacement and leaves it for [`cleanup_old_files`].
+pub fn back_up_entry(from: &Path, to: &Path) -> io::Result<()> {
+    back_up_entry_with(from, to, |from, to| fs::rename(from, to))
+}
+
+fn back_up_entry_with(
+    from: &Path,
+    to: &Path,
+    rename: impl Fn(&Path, &Path) -> io::Result<()>,
+) -> io::Result<()> {
+    match move_entry_with(from, to, &rename) {
+        Err(e) if is_in_use(&e) => {
+            if let Err(e) = copy_entry(from, to) {
+                if fs::symlink_metadata(to).is_ok() {
+                    let _ = remove_entry(to);
+                }
+                return Err(e);
+            }
+            rename_aside(from, rename).map(|_| ())
+        }
+        result => result,
+    }
+}
+
+fn move_entry_with(
+    from: &Path,
+    to: &Path,
+    rename: impl Fn(&Path, &Path) -> io::Result<()>,
+) -> io::Result<()> {
+    match rename(from, to) {
+        Err(e) if e.kind() == io::ErrorKind::CrossesDevices => {
+            if let Err(e) = copy_entry(from, to) {
+                if fs::symlink_metadata(to).is_ok() {
+                    let _ = remove_entry(to);
+                }
+                return Err(io::Error::new(
+                    e.kind(),
+                    format!(
+                        "Failed to copy '{}' to '{}' across filesystems: {e}",
+                        from.display(),
+                        to.display()
+                    ),
+                ));
+            }
+            remove_entry(from)
+        }
+        result => result,
+    }
+}
+
+fn copy_entry(from: &Path, to: &Path) -> io::Result<()> {
+    let meta = fs::symlink_metadata(from)?;
+    if meta.is_symlink() {
+        copy_symlink(from, to)
+    } else if meta.is_dir() {
+        fs::create_dir(to)?;
+        for entry in fs::read_dir(from)? {
+            let entry = entry?;
+            copy_entry(&entry.path(), &to.join(entry.file_name()))?;
+        }
+        fs::set_permissions(to, meta.permissions())
+    } else {
+        fs::copy(from, to).map(|_| ())
+    }
+}
+
+#[cfg(unix)]
+fn copy_symlink(from: &Path, to: &Path) -> io::Result<()> {
+    std::os::unix::fs::symlink(fs::read_link(from)?, to)
+}
+
+#[cfg(windows)]
+fn copy_symlink(from: &Path, to: &Path) -> io::Result<()> {
+    let target = fs::read_link(from)?;
+    if fs::metadata(from).is_ok_and(|m| m.is_dir()) {
+        std::os::windows::fs::symlink_dir(target, to)
+    } else {
+        std::os::windows::fs::symlink_file(target, to)
+    }
+}
+
+/// Free space available to the current user on the filesystem holding `path`, which
+/// is looked up through its closest existing ancestor.
+pub fn available_space(path: &Path) -> io::Result<u64> {
+    let existing = path
+        .ancestors()
+        .find(|p| p.exists())
+        .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "Path has no existing ancestor"))?;
+    filesystem_available_space(existing)
+}
+
+#[cfg(unix)]
+fn filesystem_available_space(path: &Path) -> io::Result<u64> {
+    use std::{ffi::CString, os::unix::ffi::OsStrExt};
+
+    let c_path = CString::new(path.as_os_str().as_bytes())
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+    let mut stat: libc::statvfs = unsafe { std::mem::zeroed() };
+    if unsafe { libc::statvfs(c_path.as_ptr(), &mut stat) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    #[allow(clippy::unnecessary_cast)]
+    Ok(stat.f_bavail as u64 * stat.f_frsize as u64)
+}
+
+#[cfg(windows)]
+fn filesystem_available_space(path: &Path) -> io::Result<u64> {
+    use std::os::windows::ffi::OsStrExt;
+    use windows_sys::Win32::Storage::FileSystem::GetDiskFreeSpaceExW;
+
+    let wide: Vec<u16> = path.as_os_str().encode_wide().chain(Some(0)).collect();
+    let mut available = 0u64;
+    let ok = unsafe {
+        GetDiskFreeSpaceExW(
+            wide.as_ptr(),
+            &mut available,
+            std::ptr::null_mut(),
+            std::ptr::null_mut(),
+        )
+    };
+    if ok == 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(available)
+}
+
+/// Adds the executable bits matching the readable bits of the file at `path`.
+#[cfg(unix)]
+pub fn set_executable(path: &Path) -> io::Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+
+    let mode = fs::metadata(path)?.permissions().mode();
+    fs::set_permissions(path, fs::Permissions::from_mode(mode | (mode & 0o444) >> 2))
+}
+
+#[cfg(not(unix))]
+pub fn set_executable(_path: &Path) -> io::Result<()> {
+    Ok(())
+}
+
+#[cfg(not(windows))]
+fn is_in_use(e: &io::Error) -> bool {
+    matches!(
+        e.kind(),
+        io::ErrorKind::ResourceBusy | io::ErrorKind::ExecutableFileBusy
+    )
+}
+
+#[cfg(windows)]
+fn is_in_use(e: &io::Error) -> bool {
+    const ERROR_ACCESS_DENIED: i32 = 5;
+    const ERROR_SHARING_VIOLATION: i32 = 32;
+    const ERROR_LOCK_VIOLATION: i32 = 33;
+    matches!(
+        e.raw_os_error(),
+        Some(ERROR_ACCESS_DENIED | ERROR_SHARING_VIOLATION | ERROR_LOCK_VIOLATION)
+    )
+}
+
+fn rename_aside(
+    path: &Path,
+    rename: impl Fn(&Path, &Path) -> io::Result<()>,
+) -> io::Result<std::path::PathBuf> {
+    let mut name = path
+        .file_name()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "Path has no file name"))?
+        .to_owned();
+    name.push(format!(".old-{}", std::process::id()));
+    let aside = path.with_file_name(name);
+    rename(path, &aside)?;
+    Ok(aside)
+}
+
+fn is_old_name(name: &std::ffi::OsStr) -> bool {
+    name.to_str()
+        .and_then(|name| name.rsplit_once(".old-"))
+        .is_some_and(|(_, pid)| !pid.is_empty() && pid.chars().all(|c| c.is_ascii_digit()))
+}
+
+/// Best-effort removal of `.old-<pid>` leftovers from earlier runs under `dir`.
+pub fn cleanup_old_files(dir: &Path) {
+    let Ok(entries) = fs::read_dir(dir) else {
+        return;
+    };
+    for entry in entries.flatten() {
+        let path = entry.path();
+        if is_old_name(&entry.file_name()) {
+            let _ = fs::remove_file(&path).or_else(|_| fs::remove_dir_all(&path));
+        } else if entry.file_type().is_ok_and(|t| t.is_dir()) {
+            cleanup_old_files(&path);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[cfg(unix)]
+    #[test]
+    fn test_move_entry_across_devices() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = std::env::temp_dir().join(format!("wasupdate-files-{}-xdev", std::process::id()));
+        let from = dir.join("from");
+        fs::create_dir_all(from.join("bin")).unwrap();
+        fs::write(from.join("bin").join("app"), "app").unwrap();
+        fs::set_permissions(
+            from.join("bin").join("app"),
+            fs::Permissions::from_mode(0o755),
+        )
+        .unwrap();
+        std::os::unix::fs::symlink("bin/app", from.join("app")).unwrap();
+
+        let to = dir.join("to");
+        let cross_device = |_: &Path, _: &Path| Err(io::Error::from(io::ErrorKind::CrossesDevices));
+        move_entry_with(&from, &to, cross_device).unwrap();
+        assert!(!from.exists());
+        let mode = fs::metadata(to.join("bin").join("app"))
+            .unwrap()
+            .permissions()
+            .mode();
+        assert_eq!(mode & 0o777, 0o755);
+        assert_eq!(fs::read_link(to.join("app")).unwrap(), Path::new("bin/app"));
+        assert_eq!(fs::read_to_string(to.join("app")).unwrap(), "app");
+
+        let other = |_: &Path, _: &Path| Err(io::Error::from(io::ErrorKind::PermissionDenied));
+        let err = move_entry_with(&to, &from, other).err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::PermissionDenied);
+        assert!(to.exists());
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_back_up_entry_in_use() {
+        let dir = std::env::temp_dir().join(format!("wasupdate-files-{}-busy", std::process::id()));
+        fs::create_dir_all(dir.join("backup")).unwrap();
+        let path = dir.join("app.exe");
+        fs::write(&path, "old").unwrap();
+
+        let backup = dir.join("backup").join("app.exe");
+        let busy = |from: &Path, to: &Path| {
+            if to.starts_with(dir.join("backup")) {
+                Err(io::Error::from(io::ErrorKind::ResourceBusy))
+            } else {
+                fs::rename(from, to)
+            }
+        };
+        back_up_entry_with(&path, &backup, busy).unwrap();
+        assert_eq!(fs::read_to_string(&backup).unwrap(), "old");
+        assert!(!path.exists());
+        let aside = dir.join(format!("app.exe.old-{}", std::process::id()));
+        assert_eq!(fs::read_to_string(&aside).unwrap(), "old");
+
+        fs::write(&path, "new").unwrap();
+        cleanup_old_files(&dir);
+        assert!(!aside.exists());
+        assert_eq!(fs::read_to_string(&path).unwrap(), "new");
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[cfg(windows)]
+    #[test]
+    fn test_rename_aside_file_held_open() {
+        let dir = std::env::temp_dir().join(format!("wasupdate-files-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("app.dll");
+        fs::write(&path, "old").unwrap();
+
+        let held = fs::File::open(&path).unwrap();
+        let aside = rename_aside(&path, |from, to| fs::rename(from, to)).unwrap();
+        assert!(!path.exists());
+        assert_eq!(
+            aside.file_name().unwrap().to_str().unwrap(),
+            format!("app.dll.old-{}", std::process::id())
+        );
+        fs::write(&path, "new").unwrap();
+        drop(held);
+
+        cleanup_old_files(&dir);
+        assert!(!aside.exists());
+        assert_eq!(fs::read_to_string(&path).unwrap(), "new");
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
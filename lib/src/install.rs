@@ -1,184 +1,427 @@
 use std::{
-    env::{self, temp_dir},
+    env,
     fs::{self, File},
     io::{self, Error, Read, Write, copy},
-    path::PathBuf,
+    path::{Component, Path, PathBuf},
+    str::FromStr,
+    sync::Arc,
+    time::{Duration, UNIX_EPOCH},
 };
 
 use flate2::bufread::GzDecoder;
-use reqwest::blocking::get;
+use globset::{Glob, GlobSet, GlobSetBuilder};
+use reqwest::StatusCode;
+use serde::{Deserialize, Serialize};
 
 use crate::{
     STDOUT_WRITE,
-    print::{p_error, p_good},
+    backup::{Backup, state_dir},
+    cache::{DownloadCache, url_key},
+    checksum::{
+        Digest as ChecksumDigest, Hasher, check_digest, fetch_sidecar_checksum, sha256_file,
+        verify_checksum, verify_digest,
+    },
+    chunked::{Chunks, ParallelDownload},
+    files::{available_space, cleanup_old_files, move_entry, remove_entry, set_executable},
+    git::{self, GitCheckout, GitSource},
+    http::{self, HttpSettings},
+    installer::{InstallerRun, is_system_package, run_system_installer},
+    layout::{self, version_dir},
+    manifest::{Manifest, ManifestEntry},
+    patch::bspatch,
+    print::{p_good, p_warn},
+    retry::{Failure, RetryPolicy, check_response, retry},
+    signature::{fetch_signature, read_signature, verify_signature},
+    temp::{RunDir, temp_root},
 };
 
-pub fn install(loc: &str) -> io::Result<()> {
-    let path = PathBuf::from(loc);
-    if path.exists() && path.is_file() {
-        install_archive(&path)
-    } else if reqwest::Url::parse(loc).is_ok() {
-        download_install_archive(loc)
-    } else {
-        Err(Error::new(
-            io::ErrorKind::NotFound,
-            format!(
-                "Location at '{}' appears to be an invalid URL and not exist locally.",
-                loc
-            ),
-        ))
-    }
+const STAGING_DIR: &str = "staging";
+const REPLACED_DIR: &str = "replaced";
+const EXTRACTED_FILE: &str = "extracted";
+
+#[derive(Debug, Clone, Default)]
+pub struct InstallOptions {
+    /// Directory to install into, defaults to the directory of the current executable.
+    pub dest: Option<PathBuf>,
+    /// Version being replaced, recorded in the backup for rollbacks.
+    pub current_version: Option<String>,
+    /// Version being installed, recorded in the install manifest.
+    pub version: Option<String>,
+    /// Expected digest of the archive, verified before anything is extracted.
+    pub checksum: Option<String>,
+    /// Verify downloads against the `.sha256` sidecar published next to them.
+    pub verify_sidecar: bool,
+    /// Armored OpenPGP public key, inline or as a path, checked against the `.asc`
+    /// signature published next to the archive.
+    pub signing_key: Option<String>,
+    /// Executable of another process that may be running from the destination, in
+    /// addition to the current executable.
+    pub running_exe: Option<PathBuf>,
+    /// Mark a file installed without an archive, or the one picked by `extract`, as
+    /// executable on unix.
+    pub make_executable: bool,
+    /// Replace existing directories wholesale instead of merging the new release into
+    /// them, which drops files that only exist locally.
+    pub replace_directories: bool,
+    /// Number of leading path components removed from every archive entry, like tar's
+    /// `--strip-components`. Replaces the single top-level folder heuristic when set.
+    pub strip_components: Option<usize>,
+    /// Globs of the entries to install, relative to the install directory. Everything
+    /// is installed when empty.
+    pub include: Vec<String>,
+    /// Globs of the entries to leave out, taking precedence over `include`.
+    pub exclude: Vec<String>,
+    /// Path or glob of the one archive entry to install, placed in the install
+    /// directory under its file name.
+    pub extract: Option<String>,
+    /// Stage the update in temp space and report the planned file operations instead
+    /// of applying them.
+    pub dry_run: bool,
+    /// Skip checking for enough free space before downloading and extracting, for
+    /// filesystems that misreport it.
+    pub skip_space_check: bool,
+    /// Leave existing files alone when the update brings an identical copy, as decided
+    /// by the given check.
+    pub skip_unchanged: Option<UnchangedCheck>,
+    /// How installed versions are laid out in the destination.
+    pub layout: Layout,
+    /// Retries of failed downloads.
+    pub retry: RetryPolicy,
+    /// Timeouts, proxy and default headers of the HTTP client used for downloads.
+    pub http: HttpSettings,
+    /// Headers sent with downloads, such as credentials for private artifact stores.
+    pub headers: Vec<(String, String)>,
+    /// Download large artifacts as several byte ranges at once, when the server
+    /// supports ranges. A single connection is used when unset.
+    pub parallel: Option<ParallelDownload>,
+    /// Extract tar archives while downloading them instead of writing them to temp
+    /// space first. Checksums are computed on the stream as it passes through.
+    pub stream: bool,
+    /// Private key used for `sftp://` locations instead of ssh-agent.
+    pub ssh_key: Option<PathBuf>,
+    /// Connect to SSH servers whose host key is unknown or changed, with a warning.
+    pub insecure_host_key: bool,
+    /// Directory for the downloads and dry-run staging of this run. Installs create a
+    /// unique one under the temp directory when unset and remove it afterwards.
+    pub work_dir: Option<PathBuf>,
+    /// Directory downloads, partial downloads and run directories are created in,
+    /// instead of [`temp_root`]. Archives are also staged there when it is on the same
+    /// filesystem as the destination. Created when missing.
+    pub download_dir: Option<PathBuf>,
+    /// Hand `.msi`, `.deb` and `.rpm` artifacts to `msiexec`, `dpkg` or `rpm` instead of
+    /// copying them into the destination. Usually needs elevated privileges.
+    pub allow_system_installers: bool,
+    /// Locations tried in order when downloading from the given one fails.
+    pub mirrors: Vec<String>,
+    /// Cache of downloaded artifacts, revalidated with the server before reuse. Every
+    /// download goes to the network when unset.
+    pub cache: Option<DownloadCache>,
+    /// Directory the downloaded archive is moved into after a successful install
+    /// instead of being removed with the run directory. Turns off streaming.
+    pub keep_download: Option<PathBuf>,
+    /// Signs each request to an `s3://` location for its own URL right before it is
+    /// sent. Set while installing from one.
+    #[cfg(feature = "s3")]
+    pub s3_signer: Option<crate::s3::S3Signer>,
 }
 
-pub fn install_archive(path: &PathBuf) -> io::Result<()> {
-    match path.extension() {
-        Some(ext) if ext == "zip" => install_from_zip(path),
-        Some(ext) if ext == "tar" => install_from_tar(path),
-        Some(ext) if ext == "gz" || ext == "tgz" => install_from_tar_gz(path),
-        _ => install_simple_file(path),
+/// Where an update is installed within the destination.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Layout {
+    /// Files are replaced in the destination itself.
+    #[default]
+    InPlace,
+    /// Each version gets its own `versions/<version>` directory and the `current` link
+    /// is switched to it, keeping the `keep` newest versions around.
+    Versioned { keep: usize },
+}
+
+/// Versions the versioned layout keeps unless told otherwise.
+pub const DEFAULT_KEEP_VERSIONS: usize = 3;
+
+/// A [`Layout`] as named by `--layout` and the `layout` key of `install_version`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum LayoutKind {
+    #[default]
+    InPlace,
+    Versioned,
+}
+
+impl LayoutKind {
+    pub const NAMES: [&str; 2] = ["in-place", "versioned"];
+
+    /// The layout, keeping `keep` versions when versioned.
+    pub fn with_keep(self, keep: usize) -> Layout {
+        match self {
+            LayoutKind::InPlace => Layout::InPlace,
+            LayoutKind::Versioned => Layout::Versioned { keep },
+        }
     }
 }
 
-pub fn install_from_zip(path: &PathBuf) -> io::Result<()> {
-    // Placeholder for actual zip extraction logic
-    eprintln!("Installing from ZIP archive: {:?}", path);
-    let mut archive = zip::ZipArchive::new(File::open(path)?)?;
-    let archive_len = archive.len();
-    let pb = if unsafe { STDOUT_WRITE } {
-        indicatif::ProgressBar::new(archive_len as u64).with_style(
-            indicatif::ProgressStyle::default_spinner()
-                .template("{spinner:.green} {msg} ({elapsed_precise})")
-                .unwrap(),
-        )
-    } else {
-        indicatif::ProgressBar::hidden()
-    };
-    for i in 0..archive.len() {
-        let mut file = archive.by_index(i).unwrap();
-        let outpath = match file.enclosed_name() {
-            Some(path) => path,
-            None => continue,
-        };
-        pb.set_message(format!(
-            "Extracting {} ({}/{})",
-            outpath.display(),
-            i + 1,
-            archive_len
-        ));
+impl FromStr for LayoutKind {
+    type Err = String;
 
-        if file.is_dir() {
-            fs::create_dir_all(&outpath).unwrap();
-        } else {
-            if let Some(p) = outpath.parent() {
-                if !p.exists() {
-                    fs::create_dir_all(p).unwrap();
-                }
-            }
-            let mut outfile = fs::File::create(&outpath).unwrap();
-            copy(&mut file, &mut outfile).unwrap();
+    fn from_str(name: &str) -> Result<Self, Self::Err> {
+        match name.trim().to_ascii_lowercase().as_str() {
+            "in-place" => Ok(LayoutKind::InPlace),
+            "versioned" => Ok(LayoutKind::Versioned),
+            _ => Err(format!(
+                "Unknown layout '{name}', expected one of: {}",
+                LayoutKind::NAMES.join(", ")
+            )),
         }
+    }
+}
 
-        // Get and Set permissions
-        #[cfg(unix)]
-        {
-            use std::os::unix::fs::PermissionsExt;
+/// How an existing file is compared with the incoming one for `skip_unchanged`.
+///
+/// Archive entries are compared while they are extracted, and unchanged ones are
+/// staged as hard links to the installed file instead of being written again.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UnchangedCheck {
+    /// Same size and content, compared while the entry is streamed out of the archive.
+    Hash,
+    /// Same size and modification time, which avoids reading the files. Only tar
+    /// archives carry the modification times this compares, so zip entries and plain
+    /// files are always written.
+    SizeAndMtime,
+}
 
-            if let Some(mode) = file.unix_mode() {
-                fs::set_permissions(&outpath, fs::Permissions::from_mode(mode)).unwrap();
-            }
+impl UnchangedCheck {
+    pub const NAMES: [&str; 2] = ["hash", "mtime"];
+}
+
+impl FromStr for UnchangedCheck {
+    type Err = String;
+
+    fn from_str(name: &str) -> Result<Self, Self::Err> {
+        match name.trim().to_ascii_lowercase().as_str() {
+            "hash" => Ok(UnchangedCheck::Hash),
+            "mtime" => Ok(UnchangedCheck::SizeAndMtime),
+            _ => Err(format!(
+                "Unknown unchanged check '{name}', expected one of: {}",
+                UnchangedCheck::NAMES.join(", ")
+            )),
         }
     }
-    let fname = path
-        .file_name()
-        .and_then(|name| name.to_str())
-        .ok_or_else(|| {
-            Error::new(
-                io::ErrorKind::InvalidInput,
-                "Provided path has no valid file name",
-            )
-        })?
-        .strip_suffix(".zip")
-        .ok_or_else(|| {
-            Error::new(
-                io::ErrorKind::InvalidInput,
-                "File name does not end with .zip",
-            )
-        })?
-        .to_string();
-    // trim end matching .tar.gz or .tgz
-    unroll_folder(&PathBuf::from(fname))
 }
 
-pub fn install_from_tar(path: &PathBuf) -> io::Result<()> {
-    eprintln!("Installing from TAR archive: {:?}", path);
-    let file = File::open(path)?;
-    let mut archive = tar::Archive::new(file);
-    let current_exe_path = env::current_exe().map_err(Error::other)?;
-    let parent_dir = current_exe_path.parent().ok_or_else(|| {
-        Error::new(
-            io::ErrorKind::NotFound,
-            "Current executable path has no parent",
-        )
-    })?;
-    archive.unpack(parent_dir)?;
-    let basename = path
-        .file_stem()
-        .and_then(|name| name.to_str())
-        .ok_or_else(|| Error::new(io::ErrorKind::InvalidInput, "Invalid file name"))?;
-    let unrolled_path = parent_dir.join(basename);
-    unroll_folder(&unrolled_path)
+impl InstallOptions {
+    pub fn dest_dir(&self) -> io::Result<PathBuf> {
+        match &self.dest {
+            Some(dest) => Ok(dest.clone()),
+            None => exe_dir(),
+        }
+    }
+
+    pub fn run_dir(&self) -> PathBuf {
+        self.work_dir
+            .clone()
+            .unwrap_or_else(|| self.download_root())
+    }
+
+    /// Directory the run directories and partial downloads are created in.
+    pub fn download_root(&self) -> PathBuf {
+        self.download_dir.clone().unwrap_or_else(temp_root)
+    }
+
+    /// Headers to send with a `method` request for `url`: the configured `headers`,
+    /// plus the S3 signature of exactly that request.
+    pub(crate) fn request_headers(
+        &self,
+        method: &str,
+        url: &str,
+    ) -> io::Result<Vec<(String, String)>> {
+        #[cfg(feature = "s3")]
+        if let Some(signer) = &self.s3_signer {
+            return Ok([self.headers.clone(), signer.headers(method, url)?].concat());
+        }
+        let _ = (method, url);
+        Ok(self.headers.clone())
+    }
 }
 
-pub fn install_simple_file(path: &PathBuf) -> io::Result<()> {
-    let current_exe_path = env::current_exe().map_err(Error::other)?;
-    let parent_dir = current_exe_path.parent().ok_or_else(|| {
-        Error::new(
-            io::ErrorKind::NotFound,
-            "Current executable path has no parent",
-        )
-    })?;
-    let dest_path = parent_dir.join(path.file_name().ok_or_else(|| {
-        Error::new(
-            io::ErrorKind::InvalidInput,
-            "Provided path has no file name",
-        )
-    })?);
-    if dest_path.exists() {
-        fs::remove_file(&dest_path).or_else(|_| fs::remove_dir_all(&dest_path))?;
+/// Runs `f` with `work_dir` set to a new run directory unless the caller gave one.
+/// The directory is removed when `f` returns or panics.
+fn with_run_dir<T>(
+    opts: &InstallOptions,
+    f: impl FnOnce(&InstallOptions) -> io::Result<T>,
+) -> io::Result<T> {
+    if opts.work_dir.is_some() {
+        return f(opts);
     }
-    fs::copy(path, dest_path)?;
-    Ok(())
+    let run_dir = RunDir::create(&opts.download_root())?;
+    f(&InstallOptions {
+        work_dir: Some(run_dir.path().to_path_buf()),
+        ..opts.clone()
+    })
 }
 
-pub fn unroll_folder(path: &PathBuf) -> io::Result<()> {
-    if !path.is_dir() {
-        return Ok(());
+/// The update resolved by the script's `install_version`, either given as a plain
+/// location or as a map that also carries install settings.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Artifact {
+    pub location: String,
+    /// Fallback locations of the same artifact, tried in order after `location`.
+    pub mirrors: Vec<String>,
+    pub strip_components: Option<usize>,
+    pub include: Option<Vec<String>>,
+    pub exclude: Option<Vec<String>>,
+    pub extract: Option<String>,
+    pub layout: Option<LayoutKind>,
+    pub keep_versions: Option<usize>,
+    pub patch: Option<Patch>,
+    pub headers: Option<Vec<(String, String)>>,
+    /// Expected digest such as `sha256:<hex>`, instead of the one of the script's
+    /// `checksum` function.
+    pub checksum: Option<String>,
+}
+
+/// A bsdiff patch that turns one installed file of `base_version` into the file of
+/// the new version, tried before downloading the full artifact.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Patch {
+    pub location: String,
+    pub base_version: String,
+    /// File the patch applies to, relative to the install directory.
+    pub file: String,
+    /// Expected digest of the patched file, always verified.
+    pub checksum: String,
+}
+
+impl Artifact {
+    /// The layout to install with. A `requested` one wins over the script's, and
+    /// `keep_versions` is kept unless the script says how many.
+    pub fn layout(&self, requested: Option<LayoutKind>, keep_versions: usize) -> Layout {
+        requested
+            .or(self.layout)
+            .unwrap_or_default()
+            .with_keep(self.keep_versions.unwrap_or(keep_versions))
     }
-    let parent_dir = path.parent().ok_or_else(|| {
-        Error::new(
-            io::ErrorKind::NotFound,
-            "Provided path has no parent directory",
-        )
-    })?;
-    for entry in fs::read_dir(path)? {
-        let entry = entry?;
-        let dest_path = parent_dir.join(entry.file_name());
-        if dest_path.exists() {
-            fs::remove_file(&dest_path).or_else(|_| fs::remove_dir_all(&dest_path))?;
+
+    /// Applies the install settings given by the script on top of `opts`, all but the
+    /// layout, which [`Artifact::layout`] weighs against the command line.
+    pub fn apply(&self, opts: &mut InstallOptions) {
+        if let Some(strip_components) = self.strip_components {
+            opts.strip_components = Some(strip_components);
+        }
+        if let Some(include) = &self.include {
+            opts.include = include.clone();
+        }
+        if let Some(exclude) = &self.exclude {
+            opts.exclude = exclude.clone();
+        }
+        if let Some(extract) = &self.extract {
+            opts.extract = Some(extract.clone());
+        }
+        if let Some(checksum) = &self.checksum {
+            opts.checksum = Some(checksum.clone());
+        }
+        if !self.mirrors.is_empty() {
+            opts.mirrors = self.mirrors.clone();
+        }
+        if let Some(headers) = &self.headers {
+            opts.headers
+                .retain(|(name, _)| !headers.iter().any(|(n, _)| n.eq_ignore_ascii_case(name)));
+            opts.headers.extend(headers.iter().cloned());
         }
-        fs::rename(entry.path(), dest_path)?;
     }
-    fs::remove_dir_all(path)?;
-    Ok(())
 }
 
-pub fn install_from_tar_gz(path: &PathBuf) -> io::Result<()> {
-    eprintln!("Installing from TAR.GZ archive: {:?}", path);
-    let file = File::open(path)?;
-    let file = io::BufReader::new(file);
-    let decompresed = GzDecoder::new(file);
-    let mut archive = tar::Archive::new(decompresed);
-    // get exectuable path
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum FileAction {
+    Create,
+    Overwrite,
+    Delete,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FileOperation {
+    pub action: FileAction,
+    pub path: PathBuf,
+}
+
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct InstallSummary {
+    /// Entries left out by the include and exclude globs.
+    pub excluded: Vec<PathBuf>,
+    /// Files a dry run would create, overwrite or delete, relative to the install
+    /// directory.
+    pub planned: Vec<FileOperation>,
+    /// Files the install created, overwrote or deleted, also written to the install
+    /// directory.
+    pub manifest: Option<Manifest>,
+    /// Files left in place because the update brought an identical copy.
+    pub unchanged: Vec<PathBuf>,
+    /// Version directory the `current` link was switched to, for the versioned layout.
+    pub activated: Option<PathBuf>,
+    /// Where the installed artifact came from.
+    pub source: Option<ArtifactSource>,
+    /// Location the artifact was downloaded from, which is a mirror when the given
+    /// location failed.
+    pub served_by: Option<String>,
+    /// The downloaded archive, when kept with `keep_download`.
+    pub kept_download: Option<KeptDownload>,
+    /// The system installer run for a native package.
+    pub installer: Option<InstallerRun>,
+    /// The commit a `git+` location was installed from.
+    pub git: Option<GitCheckout>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct KeptDownload {
+    pub path: PathBuf,
+    pub sha256: String,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ArtifactSource {
+    /// A file given by path.
+    Local,
+    /// Downloaded from the server.
+    Network,
+    /// Reused from the download cache after the server reported it unchanged.
+    Cache,
+}
+
+/// A downloaded artifact in temp space.
+#[derive(Debug, Clone)]
+pub struct Download {
+    pub path: PathBuf,
+    pub source: ArtifactSource,
+    /// Run directory created for the download when the caller gave no `work_dir`,
+    /// removed once the last copy of the download is dropped.
+    _run_dir: Option<Arc<RunDir>>,
+}
+
+impl Download {
+    pub(crate) fn new(path: PathBuf, source: ArtifactSource) -> Download {
+        Download {
+            path,
+            source,
+            _run_dir: None,
+        }
+    }
+}
+
+impl AsRef<Path> for Download {
+    fn as_ref(&self) -> &Path {
+        &self.path
+    }
+}
+
+impl std::ops::Deref for Download {
+    type Target = Path;
+
+    fn deref(&self) -> &Path {
+        &self.path
+    }
+}
+
+pub fn exe_dir() -> io::Result<PathBuf> {
     let current_exe_path = env::current_exe().map_err(Error::other)?;
     let parent_dir = current_exe_path.parent().ok_or_else(|| {
         Error::new(
@@ -186,100 +429,3548 @@ pub fn install_from_tar_gz(path: &PathBuf) -> io::Result<()> {
             "Current executable path has no parent",
         )
     })?;
+    Ok(parent_dir.to_path_buf())
+}
 
-    archive.unpack(parent_dir)?;
-    let fname = path
-        .file_name()
-        .ok_or_else(|| {
-            Error::new(
-                io::ErrorKind::InvalidInput,
-                "Provided path has no file name",
-            )
-        })?
-        .to_str()
-        .ok_or_else(|| Error::new(io::ErrorKind::InvalidInput, "File name is not valid UTF-8"))?
-        .strip_suffix(".tar.gz")
-        .ok_or_else(|| {
-            Error::new(
-                io::ErrorKind::InvalidInput,
-                "File name does not end with .tar.gz",
-            )
-        })?
-        .to_string();
-    // trim end matching .tar.gz or .tgz
-    unroll_folder(&PathBuf::from(fname))
+/// Check of an archive on disk, run after it was downloaded and its signature checked
+/// but before anything is extracted. An error aborts the install.
+pub type ArchiveCheck<'a> = &'a dyn Fn(&Path) -> io::Result<()>;
+
+/// Installs the archive or file at `loc`, which is a local path or a URL. Downloads
+/// go into a run directory that is removed afterwards.
+pub fn install(loc: &str, opts: &InstallOptions) -> io::Result<InstallSummary> {
+    with_run_dir(opts, |opts| install_location(loc, opts, None))
 }
 
-pub fn download_archive(url: &str) -> io::Result<PathBuf> {
-    reqwest::Url::parse(url)
-        .map_err(|e| Error::new(io::ErrorKind::InvalidInput, format!("Invalid URL: {e}")))?;
-    let response = get(url).map_err(Error::other)?;
-    // Get filename from last part of the URL
-    // Try get header from Content-Disposition, if not available, use last part of the URL
-    let file_name_from_content_disposition = response
-        .headers()
-        .get(reqwest::header::CONTENT_DISPOSITION)
-        .and_then(|cd| cd.to_str().ok())
-        .and_then(|cd| {
-            cd.split(';').find_map(|part| {
-                if part.trim_start().starts_with("filename=") {
-                    part.split('=')
-                        .nth(1)
-                        .map(|s| s.trim_matches('"').to_string())
-                } else {
-                    None
-                }
-            })
-        });
-    let filename_from_url = url
-        .rsplit('/')
-        .next()
-        .ok_or_else(|| Error::new(io::ErrorKind::InvalidInput, "Invalid URL"))?
-        .to_string();
-    let filename = file_name_from_content_disposition.unwrap_or_else(|| filename_from_url.clone());
+/// Like [`install`], handing the archive to `check` before extracting it. Archives are
+/// always downloaded whole for this, and `git+` locations, which have none, skip it.
+pub fn install_checked(
+    loc: &str,
+    opts: &InstallOptions,
+    check: ArchiveCheck,
+) -> io::Result<InstallSummary> {
+    with_run_dir(opts, |opts| install_location(loc, opts, Some(check)))
+}
 
-    let total_size = response
-        .headers()
-        .get(reqwest::header::CONTENT_LENGTH)
-        .and_then(|len| len.to_str().ok())
-        .and_then(|len| len.parse::<u64>().ok());
-    let pb = if unsafe { STDOUT_WRITE } {
-        indicatif::ProgressBar::new(total_size.unwrap_or(0))
-            .with_style(
-                indicatif::ProgressStyle::default_bar()
-                    .template("{spinner:.green} {msg} [{elapsed_precise}] {wide_bar} {bytes}/{total_bytes} ({eta})")
-                    .unwrap(),
-            )
-            .with_message(format!("Downloading {filename}"))
+fn install_location(
+    loc: &str,
+    opts: &InstallOptions,
+    check: Option<ArchiveCheck>,
+) -> io::Result<InstallSummary> {
+    let path = PathBuf::from(loc);
+    if path.exists() && path.is_file() {
+        if let Some(key) = &opts.signing_key {
+            let signature = read_signature(&path)?;
+            verify_signature(&path, &signature, key)?;
+        }
+        if let Some(check) = check {
+            check(&path)?;
+        }
+        let summary = install_archive(&path, opts)?;
+        Ok(InstallSummary {
+            source: Some(ArtifactSource::Local),
+            ..summary
+        })
+    } else if loc.starts_with("git+") {
+        install_git(loc, opts)
+    } else if loc.starts_with("s3://") {
+        install_s3(loc, opts, check)
+    } else if reqwest::Url::parse(loc).is_ok() {
+        download_install(loc, opts, check)
     } else {
-        indicatif::ProgressBar::hidden()
+        Err(Error::new(
+            io::ErrorKind::NotFound,
+            format!(
+                "Location at '{}' appears to be an invalid URL and not exist locally.",
+                loc
+            ),
+        ))
+    }
+}
+
+/// Checks out the tag, branch or commit of a `git+<url>#<ref>` location into staging
+/// with the system `git` and installs it like an extracted archive.
+fn install_git(loc: &str, opts: &InstallOptions) -> io::Result<InstallSummary> {
+    let source = GitSource::parse(loc)?;
+    let repo = match &opts.cache {
+        Some(cache) => cache.repo_dir(&source.url),
+        None => opts.run_dir().join("git"),
     };
-    let mut source = response;
-    let mut buffer = [0; 8192];
-    let temp_dir = temp_dir();
-    let temp_file = temp_dir.join(&filename);
-    let mut dest = File::create(&temp_file).map_err(Error::other)?;
-    loop {
-        let n = source.read(&mut buffer).map_err(Error::other)?;
-        if n == 0 {
-            break; // EOF
+    let dest = opts.dest_dir()?;
+    let target = install_target(&dest, opts)?;
+    let (state, staging) = prepare_staging(&dest, opts)?;
+    p_good(&format!(
+        "Fetching {} from {}",
+        source.reference,
+        source.display_url()
+    ));
+    let result = git::export(&source, &repo, |reader| {
+        unpack_tar(tar::Archive::new(reader), None, &staging, opts)
+    })
+    .and_then(|checkout| {
+        let summary = install_staged(&staging, &dest, &target, opts)?;
+        Ok(InstallSummary {
+            source: Some(ArtifactSource::Network),
+            git: Some(checkout),
+            ..summary
+        })
+    });
+    let _ = fs::remove_dir_all(if opts.dry_run { &state } else { &staging });
+    result
+}
+
+/// Downloads the object at `s3://bucket/key` over HTTPS, signed with the credentials
+/// from the AWS environment, and installs it.
+#[cfg(feature = "s3")]
+fn install_s3(
+    loc: &str,
+    opts: &InstallOptions,
+    check: Option<ArchiveCheck>,
+) -> io::Result<InstallSummary> {
+    use crate::s3::{S3Config, resolve};
+
+    let request = resolve(loc, &S3Config::from_env())?;
+    let opts = InstallOptions {
+        s3_signer: request.signer,
+        ..opts.clone()
+    };
+    download_install(&request.url, &opts, check)
+}
+
+#[cfg(not(feature = "s3"))]
+fn install_s3(
+    _loc: &str,
+    _opts: &InstallOptions,
+    _check: Option<ArchiveCheck>,
+) -> io::Result<InstallSummary> {
+    Err(Error::new(
+        io::ErrorKind::Unsupported,
+        "s3:// locations require wasupdate to be built with the 's3' feature",
+    ))
+}
+
+/// Connection problems may pass and are retried, anything else such as a rejected
+/// login or a missing file fails right away.
+fn remote_failure(e: Error) -> Failure {
+    match e.kind() {
+        io::ErrorKind::ConnectionRefused
+        | io::ErrorKind::ConnectionReset
+        | io::ErrorKind::ConnectionAborted
+        | io::ErrorKind::NotConnected
+        | io::ErrorKind::BrokenPipe
+        | io::ErrorKind::TimedOut
+        | io::ErrorKind::WouldBlock
+        | io::ErrorKind::UnexpectedEof
+        | io::ErrorKind::Interrupted => Failure::Transient(e),
+        _ => Failure::Permanent(e),
+    }
+}
+
+/// Whether `loc` is an `sftp://` or `ftp://` location, downloaded by [`download_remote`]
+/// instead of over HTTP.
+fn is_remote(loc: &str) -> bool {
+    loc.starts_with("sftp://") || loc.starts_with("ftp://")
+}
+
+/// Downloads an `sftp://` or `ftp://` location into temp space, when built with the
+/// matching feature.
+fn download_remote(loc: &str, opts: &InstallOptions) -> io::Result<Download> {
+    let (scheme, _) = loc.split_once("://").unwrap_or_default();
+    match scheme {
+        #[cfg(feature = "sftp")]
+        "sftp" => crate::sftp::download(loc, opts),
+        #[cfg(feature = "ftp")]
+        "ftp" => crate::ftp::download(loc, opts),
+        _ => {
+            let _ = opts;
+            Err(Error::new(
+                io::ErrorKind::Unsupported,
+                format!(
+                    "{scheme}:// locations require wasupdate to be built with the '{scheme}' feature"
+                ),
+            ))
         }
-        dest.write_all(&buffer[..n]).map_err(Error::other)?;
-        pb.inc(n as u64);
     }
-    pb.finish_with_message("Download complete");
+}
+
+/// Extracts the archive into a staging directory in the run directory, or next to the
+/// destination when that is on another filesystem, and then promotes the staged entries
+/// into place, backing up whatever they replace.
+pub fn install_archive(path: &PathBuf, opts: &InstallOptions) -> io::Result<InstallSummary> {
+    if let Some(checksum) = &opts.checksum {
+        verify_checksum(path, checksum)?;
+    }
+    if opts.allow_system_installers && is_system_package(path) {
+        return Ok(InstallSummary {
+            installer: Some(run_system_installer(path, opts.dry_run)?),
+            ..Default::default()
+        });
+    }
+    let dest = opts.dest_dir()?;
+    let target = install_target(&dest, opts)?;
+    let (state, staging) = prepare_staging(&dest, opts)?;
+    if !opts.skip_space_check {
+        ensure_space(&staging, uncompressed_size(path)?)?;
+    }
+    let result = match path.extension() {
+        Some(ext) if ext == "zip" => install_from_zip(path, &staging, opts),
+        Some(ext) if ext == "tar" => install_from_tar(path, &staging, opts),
+        Some(ext) if ext == "gz" || ext == "tgz" => install_from_tar_gz(path, &staging, opts),
+        Some(ext) if ext == "zst" || ext == "tzst" => install_from_tar_zst(path, &staging, opts),
+        Some(ext) if ext.eq_ignore_ascii_case("appimage") => {
+            install_appimage(path, &staging, &dest, opts)
+        }
+        _ => install_simple_file(path, &staging).and_then(|()| match path.file_name() {
+            Some(name) if opts.make_executable => set_executable(&staging.join(name)),
+            _ => Ok(()),
+        }),
+    }
+    .and_then(|()| install_staged(&staging, &dest, &target, opts));
+    let _ = fs::remove_dir_all(if opts.dry_run { &state } else { &staging });
+    result
+}
 
-    Ok(temp_file)
+/// Directory the update ends up in, which is a version directory for the versioned
+/// layout.
+fn install_target(dest: &Path, opts: &InstallOptions) -> io::Result<PathBuf> {
+    match opts.layout {
+        Layout::InPlace => Ok(dest.to_path_buf()),
+        Layout::Versioned { .. } => {
+            let version = opts.version.as_deref().ok_or_else(|| {
+                Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "The versioned layout needs the version being installed",
+                )
+            })?;
+            Ok(version_dir(dest, version))
+        }
+    }
 }
 
-pub fn download_install_archive(url: &str) -> io::Result<()> {
-    let download_result = download_archive(url)?;
-    p_good(
-        format!(
-            "Download complete, proceding to install: {}",
-            download_result.display()
-        )
-        .as_str(),
-    );
-    install_archive(&download_result)
+/// Whether `a` and `b`, or their closest existing ancestors, are on the same
+/// filesystem, so entries can be renamed from one to the other.
+#[cfg(unix)]
+fn same_filesystem(a: &Path, b: &Path) -> bool {
+    use std::os::unix::fs::MetadataExt;
+
+    let dev = |path: &Path| {
+        path.ancestors()
+            .find_map(|p| fs::metadata(p).ok())
+            .map(|m| m.dev())
+    };
+    matches!((dev(a), dev(b)), (Some(a), Some(b)) if a == b)
+}
+
+#[cfg(not(unix))]
+fn same_filesystem(_: &Path, _: &Path) -> bool {
+    false
+}
+
+/// Directory the staging directory is created in: the run directory under the download
+/// root, or the state directory of `dest` when the run directory is on another
+/// filesystem, so promoting staged entries stays a rename.
+fn staging_parent(dest: &Path, opts: &InstallOptions) -> PathBuf {
+    match &opts.work_dir {
+        Some(work_dir) if same_filesystem(work_dir, dest) => work_dir.clone(),
+        _ => state_dir(dest),
+    }
+}
+
+/// Creates an empty staging directory, under [`staging_parent`] or in temp space for a
+/// dry run. Returns the state and staging directories.
+fn prepare_staging(dest: &Path, opts: &InstallOptions) -> io::Result<(PathBuf, PathBuf)> {
+    let state = if opts.dry_run {
+        opts.run_dir().join("dry-run")
+    } else {
+        staging_parent(dest, opts)
+    };
+    let staging = state.join(STAGING_DIR);
+    if staging.exists() {
+        fs::remove_dir_all(&staging)?;
+    }
+    fs::create_dir_all(&staging)?;
+    Ok((state, staging))
+}
+
+/// Filters the staged entries, then plans and applies moving them into `target`.
+fn install_staged(
+    staging: &Path,
+    dest: &Path,
+    target: &Path,
+    opts: &InstallOptions,
+) -> io::Result<InstallSummary> {
+    filter_staged(staging, opts).and_then(|excluded| {
+        if let Some(pattern) = &opts.extract {
+            let extracted = extract_single(staging, pattern)?;
+            if opts.make_executable {
+                set_executable(&extracted)?;
+            }
+        }
+        let mut planned = vec![];
+        plan_dir(staging, target, Path::new(""), opts, &mut planned)?;
+        planned.sort_by(|a, b| a.path.cmp(&b.path));
+        if opts.dry_run {
+            return Ok(InstallSummary {
+                excluded,
+                planned,
+                ..Default::default()
+            });
+        }
+        if let Layout::Versioned { keep } = opts.layout {
+            if fs::symlink_metadata(target).is_ok() {
+                remove_entry(target)?;
+            }
+            if let Some(parent) = target.parent() {
+                fs::create_dir_all(parent)?;
+            }
+            move_entry(staging, target)?;
+            let files = planned
+                .iter()
+                .map(|op| ManifestEntry::describe(target, op))
+                .collect::<io::Result<Vec<_>>>()?;
+            let manifest = Manifest::new(opts.version.clone(), files)?;
+            manifest.save(target)?;
+            let version = opts.version.as_deref().unwrap_or_default();
+            let activated = layout::activate(dest, version)?;
+            layout::prune(dest, keep)?;
+            return Ok(InstallSummary {
+                excluded,
+                manifest: Some(manifest),
+                activated: Some(activated),
+                ..Default::default()
+            });
+        }
+        let (deleted, written): (Vec<_>, Vec<_>) = planned
+            .iter()
+            .partition(|op| op.action == FileAction::Delete);
+        let mut files = deleted
+            .into_iter()
+            .map(|op| ManifestEntry::describe(dest, op))
+            .collect::<io::Result<Vec<_>>>()?;
+        let unchanged = promote(staging, dest, opts)?;
+        for op in written {
+            files.push(ManifestEntry::describe(dest, op)?);
+        }
+        let manifest = Manifest::new(opts.version.clone(), files)?;
+        manifest.save(dest)?;
+        Ok(InstallSummary {
+            excluded,
+            manifest: Some(manifest),
+            unchanged,
+            ..Default::default()
+        })
+    })
+}
+
+/// Updates the installed `patch.file` by applying the bsdiff patch to it, then installs
+/// the result like a single file update. Fails without touching the install directory
+/// when the installed version is not the patch's base version or the patched file does
+/// not match `patch.checksum`, so the caller can fall back to the full artifact.
+pub fn apply_patch(patch: &Patch, opts: &InstallOptions) -> io::Result<InstallSummary> {
+    with_run_dir(opts, |opts| apply_patch_in(patch, opts, None))
+}
+
+/// Like [`apply_patch`], handing the patched file to `check` before it is installed.
+pub fn apply_patch_checked(
+    patch: &Patch,
+    opts: &InstallOptions,
+    check: ArchiveCheck,
+) -> io::Result<InstallSummary> {
+    with_run_dir(opts, |opts| apply_patch_in(patch, opts, Some(check)))
+}
+
+fn apply_patch_in(
+    patch: &Patch,
+    opts: &InstallOptions,
+    check: Option<ArchiveCheck>,
+) -> io::Result<InstallSummary> {
+    if opts.current_version.as_deref() != Some(patch.base_version.as_str()) {
+        return Err(Error::new(
+            io::ErrorKind::InvalidInput,
+            format!(
+                "Patch applies to version {} but {} is installed",
+                patch.base_version,
+                opts.current_version.as_deref().unwrap_or("no version")
+            ),
+        ));
+    }
+    if opts.layout != Layout::InPlace {
+        return Err(Error::new(
+            io::ErrorKind::Unsupported,
+            "Patches can only be applied to in-place installs",
+        ));
+    }
+    let dest = opts.dest_dir()?;
+    let file = sanitize_entry_path(Path::new(&patch.file))?;
+    let base = dest.join(&file);
+    let old = fs::read(&base).map_err(|e| {
+        Error::new(
+            e.kind(),
+            format!("Failed to read '{}' to patch: {e}", base.display()),
+        )
+    })?;
+    let local = PathBuf::from(&patch.location);
+    let patch_bytes = if local.is_file() {
+        fs::read(&local)?
+    } else {
+        fs::read(download_archive(&patch.location, opts)?)?
+    };
+    let new = bspatch(&old, &patch_bytes)?;
+
+    let opts = InstallOptions {
+        strip_components: None,
+        include: vec![],
+        exclude: vec![],
+        extract: None,
+        ..opts.clone()
+    };
+    let (state, staging) = prepare_staging(&dest, &opts)?;
+    let staged = staging.join(&file);
+    let result = (|| {
+        if !opts.skip_space_check {
+            ensure_space(&staging, new.len() as u64)?;
+        }
+        if let Some(parent) = staged.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        fs::write(&staged, &new)?;
+        fs::set_permissions(&staged, fs::metadata(&base)?.permissions())?;
+        verify_checksum(&staged, &patch.checksum)?;
+        if let Some(check) = check {
+            check(&staged)?;
+        }
+        install_staged(&staging, &dest, &dest, &opts)
+    })();
+    let _ = fs::remove_dir_all(if opts.dry_run { &state } else { &staging });
+    result
+}
+
+/// Decides which entries get installed from the include and exclude globs. A path is
+/// matched along with its parent directories, so `docs` covers everything below it.
+struct EntryFilter {
+    include: Option<GlobSet>,
+    exclude: GlobSet,
+}
+
+impl EntryFilter {
+    fn new(opts: &InstallOptions) -> io::Result<Option<EntryFilter>> {
+        if opts.include.is_empty() && opts.exclude.is_empty() {
+            return Ok(None);
+        }
+        let include = if opts.include.is_empty() {
+            None
+        } else {
+            Some(build_globset(&opts.include)?)
+        };
+        Ok(Some(EntryFilter {
+            include,
+            exclude: build_globset(&opts.exclude)?,
+        }))
+    }
+
+    fn is_installed(&self, path: &Path) -> bool {
+        let matches = |set: &GlobSet| {
+            path.ancestors()
+                .take_while(|p| !p.as_os_str().is_empty())
+                .any(|p| set.is_match(p))
+        };
+        !matches(&self.exclude) && self.include.as_ref().is_none_or(matches)
+    }
+}
+
+pub(crate) fn build_globset(patterns: &[String]) -> io::Result<GlobSet> {
+    let mut builder = GlobSetBuilder::new();
+    for pattern in patterns {
+        let glob = Glob::new(pattern).map_err(|e| {
+            Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("Invalid glob '{pattern}': {e}"),
+            )
+        })?;
+        builder.add(glob);
+    }
+    builder
+        .build()
+        .map_err(|e| Error::new(io::ErrorKind::InvalidInput, e))
+}
+
+/// Removes the staged entries filtered out by the include and exclude globs and
+/// returns their paths relative to the install directory.
+fn filter_staged(staging: &Path, opts: &InstallOptions) -> io::Result<Vec<PathBuf>> {
+    let mut excluded = vec![];
+    if let Some(filter) = EntryFilter::new(opts)? {
+        filter_dir(staging, Path::new(""), &filter, &mut excluded)?;
+    }
+    Ok(excluded)
+}
+
+/// Reduces the staging directory to the one file matching `pattern`, moved to the top
+/// under its file name. Fails unless exactly one file matches.
+fn extract_single(staging: &Path, pattern: &str) -> io::Result<PathBuf> {
+    let matcher = build_globset(&[pattern.to_string()])?;
+    let mut matches = vec![];
+    find_matches(staging, Path::new(""), &matcher, &mut matches)?;
+    let rel = match matches.as_slice() {
+        [rel] => rel.clone(),
+        [] => {
+            return Err(Error::new(
+                io::ErrorKind::NotFound,
+                format!("No archive entry matches '{pattern}'"),
+            ));
+        }
+        _ => {
+            let names: Vec<_> = matches
+                .iter()
+                .map(|m| format!("'{}'", m.display()))
+                .collect();
+            return Err(Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "Archive entries {} all match '{pattern}', expected exactly one",
+                    names.join(", ")
+                ),
+            ));
+        }
+    };
+    let file_name = rel.file_name().unwrap_or(rel.as_os_str()).to_owned();
+    // Park the file next to the staging directory while clearing out everything else
+    let held = staging.with_file_name(EXTRACTED_FILE);
+    move_entry(&staging.join(&rel), &held)?;
+    fs::remove_dir_all(staging)?;
+    fs::create_dir(staging)?;
+    let extracted = staging.join(file_name);
+    move_entry(&held, &extracted)?;
+    Ok(extracted)
+}
+
+fn find_matches(
+    staging: &Path,
+    rel: &Path,
+    matcher: &GlobSet,
+    matches: &mut Vec<PathBuf>,
+) -> io::Result<()> {
+    for entry in fs::read_dir(staging.join(rel))? {
+        let entry = entry?;
+        let name = rel.join(entry.file_name());
+        if entry.file_type()?.is_dir() {
+            find_matches(staging, &name, matcher, matches)?;
+        } else if matcher.is_match(&name) {
+            matches.push(name);
+        }
+    }
+    Ok(())
+}
+
+fn filter_dir(
+    staging: &Path,
+    rel: &Path,
+    filter: &EntryFilter,
+    excluded: &mut Vec<PathBuf>,
+) -> io::Result<()> {
+    for entry in fs::read_dir(staging.join(rel))? {
+        let entry = entry?;
+        let name = rel.join(entry.file_name());
+        if entry.file_type()?.is_dir() {
+            let before = excluded.len();
+            filter_dir(staging, &name, filter, excluded)?;
+            // Drop directories that only held excluded entries
+            if excluded.len() > before && fs::read_dir(entry.path())?.next().is_none() {
+                fs::remove_dir(entry.path())?;
+            }
+        } else if !filter.is_installed(&name) {
+            fs::remove_file(entry.path())?;
+            excluded.push(name);
+        }
+    }
+    Ok(())
+}
+
+/// Removes executables renamed aside by earlier installs, which may still have been
+/// running (and locked) back then.
+pub fn cleanup_replaced(dest: &Path) {
+    let _ = fs::remove_dir_all(state_dir(dest).join(REPLACED_DIR));
+}
+
+/// Renames a running executable that is about to be replaced out of the way and
+/// leaves an unlocked copy in its place, so the backup still captures the old file.
+fn move_running_exe_aside(staging: &Path, dest: &Path, exe: &Path) -> io::Result<()> {
+    let (Ok(dest), Ok(exe)) = (dest.canonicalize(), exe.canonicalize()) else {
+        return Ok(());
+    };
+    let Some(top) = exe
+        .strip_prefix(&dest)
+        .ok()
+        .and_then(|rel| rel.components().next())
+    else {
+        return Ok(());
+    };
+    if fs::symlink_metadata(staging.join(top)).is_err() {
+        return Ok(());
+    }
+    let file_name = exe.file_name().ok_or_else(|| {
+        Error::new(
+            io::ErrorKind::InvalidInput,
+            "Running executable has no file name",
+        )
+    })?;
+    let replaced = state_dir(&dest).join(REPLACED_DIR);
+    fs::create_dir_all(&replaced)?;
+    let mut aside_name = file_name.to_owned();
+    aside_name.push(format!(".{}.old", std::process::id()));
+    let aside = replaced.join(aside_name);
+    fs::rename(&exe, &aside)?;
+    fs::copy(&aside, &exe)?;
+    Ok(())
+}
+
+/// Moves the staged entries into `dest`, returning the files skipped as unchanged.
+pub fn promote(staging: &Path, dest: &Path, opts: &InstallOptions) -> io::Result<Vec<PathBuf>> {
+    check_staged_links(staging, Path::new(""))?;
+    cleanup_replaced(dest);
+    cleanup_old_files(dest);
+    let running_exes = env::current_exe()
+        .into_iter()
+        .chain(opts.running_exe.clone())
+        .chain(running_appimage());
+    for exe in running_exes {
+        move_running_exe_aside(staging, dest, &exe)?;
+    }
+    let mut backup = Backup::begin(dest, opts.current_version.clone())?;
+    let mut unchanged = vec![];
+    let result = promote_dir(
+        staging,
+        dest,
+        Path::new(""),
+        &mut backup,
+        opts,
+        &mut unchanged,
+    );
+    backup.save()?;
+    result.map(|()| unchanged)
+}
+
+/// Checks the symlinks under `rel` against their final location in the staging
+/// directory. Links are checked while extracting too, but stripping and unrolling
+/// the top-level folder afterwards moves them up, so a target that stayed inside of
+/// the archive can end up pointing outside of `dest`.
+fn check_staged_links(staging: &Path, rel: &Path) -> io::Result<()> {
+    for entry in fs::read_dir(staging.join(rel))? {
+        let entry = entry?;
+        let name = rel.join(entry.file_name());
+        let file_type = entry.file_type()?;
+        if file_type.is_symlink() {
+            check_link_target(&name, &fs::read_link(entry.path())?)?;
+        } else if file_type.is_dir() {
+            check_staged_links(staging, &name)?;
+        }
+    }
+    Ok(())
+}
+
+/// Whether the existing file at `dest_path` matches the staged one, per `skip_unchanged`.
+fn is_unchanged(staged: &Path, dest_path: &Path, opts: &InstallOptions) -> io::Result<bool> {
+    let Some(check) = opts.skip_unchanged else {
+        return Ok(false);
+    };
+    let (Ok(staged_meta), Ok(dest_meta)) = (
+        fs::symlink_metadata(staged),
+        fs::symlink_metadata(dest_path),
+    ) else {
+        return Ok(false);
+    };
+    if !staged_meta.is_file() || !dest_meta.is_file() || staged_meta.len() != dest_meta.len() {
+        return Ok(false);
+    }
+    // Entries found unchanged while extracting are staged as links to the installed file
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::MetadataExt;
+
+        if (staged_meta.dev(), staged_meta.ino()) == (dest_meta.dev(), dest_meta.ino()) {
+            return Ok(true);
+        }
+    }
+    Ok(match check {
+        UnchangedCheck::Hash => sha256_file(staged)? == sha256_file(dest_path)?,
+        UnchangedCheck::SizeAndMtime => staged_meta.modified()? == dest_meta.modified()?,
+    })
+}
+
+/// Moves the staged entries under `rel` into place. Directories that exist on both
+/// sides are merged, unless `replace_directories` is set, so files that only exist
+/// locally survive the update.
+fn promote_dir(
+    staging: &Path,
+    dest: &Path,
+    rel: &Path,
+    backup: &mut Backup,
+    opts: &InstallOptions,
+    unchanged: &mut Vec<PathBuf>,
+) -> io::Result<()> {
+    for entry in fs::read_dir(staging.join(rel))? {
+        let entry = entry?;
+        let name = rel.join(entry.file_name());
+        let dest_path = dest.join(&name);
+        match fs::symlink_metadata(&dest_path) {
+            Ok(meta)
+                if meta.is_dir() && entry.file_type()?.is_dir() && !opts.replace_directories =>
+            {
+                promote_dir(staging, dest, &name, backup, opts, unchanged)?;
+                continue;
+            }
+            Ok(_) if is_unchanged(&entry.path(), &dest_path, opts)? => {
+                unchanged.push(name);
+                continue;
+            }
+            Ok(_) => backup.replace(&name)?,
+            Err(_) => backup.create(&name),
+        }
+        move_entry(&entry.path(), &dest_path)?;
+    }
+    Ok(())
+}
+
+/// Ensures `required` bytes fit on the filesystem holding `path`.
+pub fn ensure_space(path: &Path, required: u64) -> io::Result<()> {
+    ensure_space_with(path, required, available_space)
+}
+
+fn ensure_space_with(
+    path: &Path,
+    required: u64,
+    available_space: impl Fn(&Path) -> io::Result<u64>,
+) -> io::Result<()> {
+    let available = available_space(path)?;
+    if required > available {
+        return Err(Error::new(
+            io::ErrorKind::StorageFull,
+            format!(
+                "Not enough free space for '{}': {required} bytes required, {available} bytes available",
+                path.display()
+            ),
+        ));
+    }
+    Ok(())
+}
+
+/// Sums the sizes of the entries in the archive at `path`, which is what extracting
+/// it takes up.
+fn uncompressed_size(path: &Path) -> io::Result<u64> {
+    let tar_size = |archive: &mut tar::Archive<Box<dyn Read>>| -> io::Result<u64> {
+        let mut size = 0;
+        for entry in archive.entries()? {
+            size += entry?.header().size()?;
+        }
+        Ok(size)
+    };
+    match path.extension() {
+        Some(ext) if ext == "zip" => {
+            // Entries written with data descriptors have no known size up front, in
+            // which case the check is skipped
+            let archive = zip::ZipArchive::new(File::open(path)?)?;
+            Ok(archive
+                .decompressed_size()
+                .map_or(0, |size| size.try_into().unwrap_or(u64::MAX)))
+        }
+        Some(ext) if ext == "tar" => tar_size(&mut tar::Archive::new(Box::new(File::open(path)?))),
+        Some(ext) if ext == "gz" || ext == "tgz" => {
+            let file = io::BufReader::new(File::open(path)?);
+            tar_size(&mut tar::Archive::new(Box::new(GzDecoder::new(file))))
+        }
+        Some(ext) if ext == "zst" || ext == "tzst" => {
+            let decoder = TarFormat::Zstd.decoder(File::open(path)?)?;
+            tar_size(&mut tar::Archive::new(decoder))
+        }
+        _ => Ok(fs::metadata(path)?.len()),
+    }
+}
+
+/// Lists the files and symlinks under `rel`, relative to `root`.
+fn list_files(root: &Path, rel: &Path) -> io::Result<Vec<PathBuf>> {
+    let mut files = vec![];
+    if !fs::symlink_metadata(root.join(rel))?.is_dir() {
+        files.push(rel.to_path_buf());
+        return Ok(files);
+    }
+    for entry in fs::read_dir(root.join(rel))? {
+        files.extend(list_files(root, &rel.join(entry?.file_name()))?);
+    }
+    Ok(files)
+}
+
+/// Works out what [`promote`] would do with the staged entries under `rel` without
+/// touching the destination.
+fn plan_dir(
+    staging: &Path,
+    dest: &Path,
+    rel: &Path,
+    opts: &InstallOptions,
+    planned: &mut Vec<FileOperation>,
+) -> io::Result<()> {
+    let is_file = |path: &Path| fs::symlink_metadata(path).is_ok_and(|m| !m.is_dir());
+    for entry in fs::read_dir(staging.join(rel))? {
+        let entry = entry?;
+        let name = rel.join(entry.file_name());
+        let dest_meta = fs::symlink_metadata(dest.join(&name));
+        if entry.file_type()?.is_dir()
+            && dest_meta.as_ref().is_ok_and(|m| m.is_dir())
+            && !opts.replace_directories
+        {
+            plan_dir(staging, dest, &name, opts, planned)?;
+            continue;
+        }
+        if is_unchanged(&entry.path(), &dest.join(&name), opts)? {
+            continue;
+        }
+        // The existing entry is replaced as a whole, so whatever the update does not
+        // bring back is deleted
+        if dest_meta.is_ok() {
+            for path in list_files(dest, &name)? {
+                if !is_file(&staging.join(&path)) {
+                    planned.push(FileOperation {
+                        action: FileAction::Delete,
+                        path,
+                    });
+                }
+            }
+        }
+        for path in list_files(staging, &name)? {
+            let action = if is_file(&dest.join(&path)) {
+                FileAction::Overwrite
+            } else {
+                FileAction::Create
+            };
+            planned.push(FileOperation { action, path });
+        }
+    }
+    Ok(())
+}
+
+/// Validates an archive entry path, rejecting absolute paths, drive prefixes and `..`
+/// components that would place the entry outside of the extraction directory.
+pub fn sanitize_entry_path(path: &Path) -> io::Result<PathBuf> {
+    let mut sanitized = PathBuf::new();
+    for component in path.components() {
+        match component {
+            Component::Normal(part) => sanitized.push(part),
+            Component::CurDir => {}
+            Component::ParentDir | Component::RootDir | Component::Prefix(_) => {
+                return Err(Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!(
+                        "Archive entry '{}' would be extracted outside of the install directory",
+                        path.display()
+                    ),
+                ));
+            }
+        }
+    }
+    Ok(sanitized)
+}
+
+/// Validates the target of a symlink archive entry, which must be relative and
+/// resolve to a location inside of the extraction directory.
+pub fn check_link_target(entry: &Path, target: &Path) -> io::Result<()> {
+    let escapes = || {
+        Error::new(
+            io::ErrorKind::InvalidData,
+            format!(
+                "Symlink '{}' -> '{}' points outside of the install directory",
+                entry.display(),
+                target.display()
+            ),
+        )
+    };
+    let mut depth = sanitize_entry_path(entry)?.components().count();
+    // The link resolves relative to the directory containing it
+    depth = depth.saturating_sub(1);
+    for component in target.components() {
+        match component {
+            Component::Normal(_) => depth += 1,
+            Component::CurDir => {}
+            Component::ParentDir => depth = depth.checked_sub(1).ok_or_else(escapes)?,
+            Component::RootDir | Component::Prefix(_) => return Err(escapes()),
+        }
+    }
+    Ok(())
+}
+
+/// The installed file that the archive entry extracted to `path` replaces, once the
+/// top-level folder named `stem` is unrolled, when `skip_unchanged` is set and it has
+/// the same size and mode as the entry.
+fn installed_file(
+    path: &Path,
+    stem: Option<&str>,
+    size: u64,
+    mode: Option<u32>,
+    opts: &InstallOptions,
+) -> Option<PathBuf> {
+    opts.skip_unchanged?;
+    if opts.layout != Layout::InPlace {
+        return None;
+    }
+    let rel = match stem.map(|stem| path.strip_prefix(stem)) {
+        Some(Ok(rest)) if opts.strip_components.is_none() && !rest.as_os_str().is_empty() => rest,
+        _ => path,
+    };
+    let installed = opts.dest_dir().ok()?.join(rel);
+    let meta = fs::symlink_metadata(&installed).ok()?;
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+
+        if mode.is_some_and(|mode| mode & 0o777 != meta.permissions().mode() & 0o777) {
+            return None;
+        }
+    }
+    #[cfg(not(unix))]
+    let _ = mode;
+    (meta.is_file() && meta.len() == size).then_some(installed)
+}
+
+/// Stages the `installed` file at `target` as a hard link, or as a copy when the
+/// staging directory is on another filesystem, like for a dry run.
+fn link_installed(installed: &Path, target: &Path) -> io::Result<()> {
+    fs::hard_link(installed, target).or_else(|_| fs::copy(installed, target).map(|_| ()))
+}
+
+/// Compares the incoming file read from `reader` with the `installed` one chunk by
+/// chunk, and links the installed file at `target` when both are identical. Once they
+/// differ, the part read so far, which matches the installed file, and the rest of
+/// `reader` are written to `target` instead. Returns whether the file was linked.
+fn stage_if_identical(reader: &mut impl Read, installed: &Path, target: &Path) -> io::Result<bool> {
+    let mut existing = io::BufReader::new(File::open(installed)?);
+    let mut incoming = vec![0; 64 * 1024];
+    let mut current = vec![0; incoming.len()];
+    let mut matched = 0;
+    // The length of the incoming chunk that differs, if any
+    let pending = loop {
+        let n = reader.read(&mut incoming)?;
+        if n == 0 {
+            break (existing.read(&mut current[..1])? != 0).then_some(0);
+        }
+        match existing.read_exact(&mut current[..n]) {
+            Ok(()) if current[..n] == incoming[..n] => matched += n as u64,
+            Ok(()) => break Some(n),
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => break Some(n),
+            Err(e) => return Err(e),
+        }
+    };
+    let Some(pending) = pending else {
+        link_installed(installed, target)?;
+        return Ok(true);
+    };
+    let mut file = File::create(target)?;
+    copy(&mut File::open(installed)?.take(matched), &mut file)?;
+    file.write_all(&incoming[..pending])?;
+    copy(reader, &mut file)?;
+    Ok(false)
+}
+
+/// Applies the mode and modification time from the tar `header` to the file written
+/// to `target`, like unpacking the entry does.
+fn apply_tar_metadata(header: &tar::Header, target: &Path) -> io::Result<()> {
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+
+        fs::set_permissions(target, fs::Permissions::from_mode(header.mode()? & 0o777))?;
+    }
+    File::options()
+        .write(true)
+        .open(target)?
+        .set_modified(UNIX_EPOCH + Duration::from_secs(header.mtime()?))
+}
+
+/// Maps an archive entry to its path inside the extraction directory, removing the
+/// leading `strip_components`. Entries that are stripped away entirely yield `None`.
+fn entry_dest_path(path: &Path, opts: &InstallOptions) -> io::Result<Option<PathBuf>> {
+    let stripped: PathBuf = sanitize_entry_path(path)?
+        .components()
+        .skip(opts.strip_components.unwrap_or(0))
+        .collect();
+    Ok((!stripped.as_os_str().is_empty()).then_some(stripped))
+}
+
+/// Creates the parent directories of `target`, making sure they do not resolve
+/// through a symlink to somewhere outside of `dest`.
+fn create_parent_dirs(target: &Path, dest: &Path) -> io::Result<()> {
+    let Some(parent) = target.parent() else {
+        return Ok(());
+    };
+    fs::create_dir_all(parent)?;
+    if !parent.canonicalize()?.starts_with(dest.canonicalize()?) {
+        return Err(Error::new(
+            io::ErrorKind::InvalidData,
+            format!(
+                "Archive entry '{}' would be extracted outside of the install directory",
+                target.display()
+            ),
+        ));
+    }
+    Ok(())
+}
+
+/// Spinner showing the entry being extracted, hidden when stdout output is off.
+fn extract_progress() -> indicatif::ProgressBar {
+    if unsafe { STDOUT_WRITE } {
+        indicatif::ProgressBar::new_spinner().with_style(
+            indicatif::ProgressStyle::default_spinner()
+                .template("{spinner:.green} {msg} ({elapsed_precise})")
+                .unwrap(),
+        )
+    } else {
+        indicatif::ProgressBar::hidden()
+    }
+}
+
+/// Unpacks `archive` into `dest`. With `skip_unchanged`, files are compared with the
+/// installed ones they replace, after unrolling the top-level folder named `stem`.
+fn unpack_tar<R: Read>(
+    mut archive: tar::Archive<R>,
+    stem: Option<&str>,
+    dest: &Path,
+    opts: &InstallOptions,
+) -> io::Result<()> {
+    // The entry count is unknown until the end of the archive
+    let pb = extract_progress();
+    for (i, entry) in archive.entries()?.enumerate() {
+        let mut entry = entry?;
+        let Some(path) = entry_dest_path(&entry.path()?, opts)? else {
+            continue;
+        };
+        let target = dest.join(&path);
+        if !pb.is_hidden() {
+            pb.set_message(format!("Extracting {} ({})", target.display(), i + 1));
+        }
+        create_parent_dirs(&target, dest)?;
+        let entry_type = entry.header().entry_type();
+        if let Some(link) = entry.link_name()? {
+            if entry_type.is_hard_link() {
+                let Some(source) = entry_dest_path(&link, opts)? else {
+                    continue;
+                };
+                fs::hard_link(dest.join(source), &target)?;
+                continue;
+            } else if entry_type.is_symlink() {
+                check_link_target(&path, &link)?;
+            }
+        }
+        if entry_type.is_file()
+            && let Some(installed) =
+                installed_file(&path, stem, entry.size(), entry.header().mode().ok(), opts)
+        {
+            match opts.skip_unchanged {
+                Some(UnchangedCheck::Hash) => {
+                    if !stage_if_identical(&mut entry, &installed, &target)? {
+                        apply_tar_metadata(entry.header(), &target)?;
+                    }
+                    continue;
+                }
+                _ if fs::metadata(&installed)?.modified()?
+                    == UNIX_EPOCH + Duration::from_secs(entry.header().mtime()?) =>
+                {
+                    link_installed(&installed, &target)?;
+                    continue;
+                }
+                _ => {}
+            }
+        }
+        // Mode bits from the header are applied, so executables stay executable, and
+        // symlinks are recreated as links rather than copies of their targets
+        entry.unpack(&target)?;
+    }
+    pb.finish_and_clear();
+    Ok(())
+}
+
+pub fn install_from_zip(path: &PathBuf, dest: &Path, opts: &InstallOptions) -> io::Result<()> {
+    // Placeholder for actual zip extraction logic
+    eprintln!("Installing from ZIP archive: {:?}", path);
+    let mut archive = zip::ZipArchive::new(File::open(path)?)?;
+    let archive_len = archive.len();
+    let stem = path
+        .file_name()
+        .and_then(|name| name.to_str())
+        .and_then(|name| name.strip_suffix(".zip"));
+    let pb = extract_progress();
+    for i in 0..archive.len() {
+        let mut file = archive.by_index(i)?;
+        let Some(entry_path) = entry_dest_path(Path::new(file.name()), opts)? else {
+            continue;
+        };
+        let outpath = dest.join(&entry_path);
+        pb.set_message(format!(
+            "Extracting {} ({}/{})",
+            outpath.display(),
+            i + 1,
+            archive_len
+        ));
+
+        // Symlinks are stored with their target as content and the link type in the
+        // unix mode bits
+        #[cfg(unix)]
+        {
+            if file.is_symlink() {
+                let mut target = String::new();
+                file.read_to_string(&mut target)?;
+                check_link_target(&entry_path, Path::new(&target))?;
+                create_parent_dirs(&outpath, dest)?;
+                std::os::unix::fs::symlink(&target, &outpath)?;
+                continue;
+            }
+        }
+
+        if file.is_dir() {
+            fs::create_dir_all(&outpath)?;
+        } else {
+            create_parent_dirs(&outpath, dest)?;
+            let installed = installed_file(&entry_path, stem, file.size(), file.unix_mode(), opts)
+                .filter(|_| opts.skip_unchanged == Some(UnchangedCheck::Hash));
+            match installed {
+                Some(installed) if stage_if_identical(&mut file, &installed, &outpath)? => continue,
+                Some(_) => {}
+                None => {
+                    let mut outfile = fs::File::create(&outpath)?;
+                    copy(&mut file, &mut outfile)?;
+                }
+            }
+        }
+
+        // Get and Set permissions
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+
+            if let Some(mode) = file.unix_mode() {
+                fs::set_permissions(&outpath, fs::Permissions::from_mode(mode))?;
+            }
+        }
+    }
+    if opts.strip_components.is_some() {
+        return Ok(());
+    }
+    let fname = path
+        .file_name()
+        .and_then(|name| name.to_str())
+        .ok_or_else(|| {
+            Error::new(
+                io::ErrorKind::InvalidInput,
+                "Provided path has no valid file name",
+            )
+        })?
+        .strip_suffix(".zip")
+        .ok_or_else(|| {
+            Error::new(
+                io::ErrorKind::InvalidInput,
+                "File name does not end with .zip",
+            )
+        })?
+        .to_string();
+    unroll_folder(&dest.join(fname))
+}
+
+pub fn install_from_tar(path: &PathBuf, dest: &Path, opts: &InstallOptions) -> io::Result<()> {
+    eprintln!("Installing from TAR archive: {:?}", path);
+    let file = File::open(path)?;
+    let basename = path
+        .file_stem()
+        .and_then(|name| name.to_str())
+        .ok_or_else(|| Error::new(io::ErrorKind::InvalidInput, "Invalid file name"))?;
+    unpack_tar(tar::Archive::new(file), Some(basename), dest, opts)?;
+    if opts.strip_components.is_some() {
+        return Ok(());
+    }
+    let unrolled_path = dest.join(basename);
+    unroll_folder(&unrolled_path)
+}
+
+pub fn install_simple_file(path: &PathBuf, dest: &Path) -> io::Result<()> {
+    let dest_path = dest.join(path.file_name().ok_or_else(|| {
+        Error::new(
+            io::ErrorKind::InvalidInput,
+            "Provided path has no file name",
+        )
+    })?);
+    if fs::symlink_metadata(&dest_path).is_ok() {
+        remove_entry(&dest_path)?;
+    }
+    // Copying carries over the permissions of the source file
+    fs::copy(path, dest_path)?;
+    Ok(())
+}
+
+/// The AppImage this process runs from, which the AppImage runtime sets in `APPIMAGE`.
+/// The running executable itself lives inside the mounted image.
+fn running_appimage() -> Option<PathBuf> {
+    env::var_os("APPIMAGE")
+        .filter(|path| !path.is_empty())
+        .map(PathBuf::from)
+}
+
+/// Stages the AppImage at `path` as an executable, under the name of the AppImage that
+/// is running from `dest` when there is one, so it replaces that one.
+fn install_appimage(
+    path: &Path,
+    staging: &Path,
+    dest: &Path,
+    opts: &InstallOptions,
+) -> io::Result<()> {
+    let installed = opts
+        .running_exe
+        .clone()
+        .into_iter()
+        .chain(running_appimage())
+        .find(|exe| {
+            exe.extension()
+                .is_some_and(|ext| ext.eq_ignore_ascii_case("appimage"))
+                && exe.parent().and_then(|p| p.canonicalize().ok()) == dest.canonicalize().ok()
+        });
+    let name = match installed.as_deref().and_then(Path::file_name) {
+        Some(name) => name,
+        None => path.file_name().ok_or_else(|| {
+            Error::new(
+                io::ErrorKind::InvalidInput,
+                "Provided path has no file name",
+            )
+        })?,
+    };
+    let staged = staging.join(name);
+    fs::copy(path, &staged)?;
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+
+        fs::set_permissions(&staged, fs::Permissions::from_mode(0o755))?;
+    }
+    Ok(())
+}
+
+/// Moves the contents of `path` into its parent directory. Entries are renamed, so
+/// symlinks are moved as links without following them.
+pub fn unroll_folder(path: &PathBuf) -> io::Result<()> {
+    if !fs::symlink_metadata(path).is_ok_and(|m| m.is_dir()) {
+        return Ok(());
+    }
+    let parent_dir = path.parent().ok_or_else(|| {
+        Error::new(
+            io::ErrorKind::NotFound,
+            "Provided path has no parent directory",
+        )
+    })?;
+    for entry in fs::read_dir(path)? {
+        let entry = entry?;
+        let dest_path = parent_dir.join(entry.file_name());
+        if fs::symlink_metadata(&dest_path).is_ok() {
+            remove_entry(&dest_path)?;
+        }
+        move_entry(&entry.path(), &dest_path)?;
+    }
+    fs::remove_dir_all(path)?;
+    Ok(())
+}
+
+pub fn install_from_tar_gz(path: &PathBuf, dest: &Path, opts: &InstallOptions) -> io::Result<()> {
+    eprintln!("Installing from TAR.GZ archive: {:?}", path);
+    let file = File::open(path)?;
+    let file = io::BufReader::new(file);
+    let decompresed = GzDecoder::new(file);
+    let stem = path
+        .file_name()
+        .and_then(|name| name.to_str())
+        .and_then(|name| name.strip_suffix(".tar.gz"));
+    unpack_tar(tar::Archive::new(decompresed), stem, dest, opts)?;
+    if opts.strip_components.is_some() {
+        return Ok(());
+    }
+    let fname = path
+        .file_name()
+        .ok_or_else(|| {
+            Error::new(
+                io::ErrorKind::InvalidInput,
+                "Provided path has no file name",
+            )
+        })?
+        .to_str()
+        .ok_or_else(|| Error::new(io::ErrorKind::InvalidInput, "File name is not valid UTF-8"))?
+        .strip_suffix(".tar.gz")
+        .ok_or_else(|| {
+            Error::new(
+                io::ErrorKind::InvalidInput,
+                "File name does not end with .tar.gz",
+            )
+        })?
+        .to_string();
+    unroll_folder(&dest.join(fname))
+}
+
+pub fn install_from_tar_zst(path: &PathBuf, dest: &Path, opts: &InstallOptions) -> io::Result<()> {
+    let name = path
+        .file_name()
+        .and_then(|name| name.to_str())
+        .ok_or_else(|| Error::new(io::ErrorKind::InvalidInput, "Invalid file name"))?;
+    unpack_tar_stream(File::open(path)?, name, TarFormat::Zstd, dest, opts)
+}
+
+/// Tar archives, which are read front to back and so can be extracted while they are
+/// still being downloaded.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum TarFormat {
+    Plain,
+    Gzip,
+    Zstd,
+}
+
+impl TarFormat {
+    /// The format and the name without its extension, which is the top-level folder
+    /// archives commonly wrap their contents in.
+    fn from_name(name: &str) -> Option<(TarFormat, &str)> {
+        [
+            (".tar", TarFormat::Plain),
+            (".tar.gz", TarFormat::Gzip),
+            (".tgz", TarFormat::Gzip),
+            (".tar.zst", TarFormat::Zstd),
+            (".tzst", TarFormat::Zstd),
+        ]
+        .into_iter()
+        .find_map(|(suffix, format)| Some((format, name.strip_suffix(suffix)?)))
+    }
+
+    fn decoder<'a>(self, reader: impl Read + 'a) -> io::Result<Box<dyn Read + 'a>> {
+        Ok(match self {
+            TarFormat::Plain => Box::new(reader),
+            TarFormat::Gzip => Box::new(GzDecoder::new(io::BufReader::new(reader))),
+            TarFormat::Zstd => Box::new(zstd::Decoder::new(reader)?),
+        })
+    }
+}
+
+/// Unpacks the tar archive named `name` read from `reader` into `dest`, then unrolls
+/// the top-level folder named after the archive unless `strip_components` is set.
+fn unpack_tar_stream(
+    reader: impl Read,
+    name: &str,
+    format: TarFormat,
+    dest: &Path,
+    opts: &InstallOptions,
+) -> io::Result<()> {
+    let (_, stem) = TarFormat::from_name(name).ok_or_else(|| {
+        Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("'{name}' is not a tar archive"),
+        )
+    })?;
+    unpack_tar(
+        tar::Archive::new(format.decoder(reader)?),
+        Some(stem),
+        dest,
+        opts,
+    )?;
+    if opts.strip_components.is_some() {
+        return Ok(());
+    }
+    unroll_folder(&dest.join(stem))
+}
+
+/// Reduces a server or URL provided file name to its final path component. Names that
+/// are empty, only dots or contain control characters are rejected.
+pub(crate) fn sanitize_file_name(name: &str) -> Option<String> {
+    let name = name.rsplit(['/', '\\']).next()?.trim();
+    if name.is_empty() || name == "." || name == ".." || name.chars().any(char::is_control) {
+        return None;
+    }
+    Some(name.to_string())
+}
+
+/// Validator and URL of a partial download, kept next to it so a later attempt can
+/// resume where it stopped.
+#[derive(Debug, Serialize, Deserialize)]
+struct PartialDownload {
+    url: String,
+    validator: String,
+}
+
+/// Where the download of `url` is written until it completes.
+fn partial_download_path(url: &str, opts: &InstallOptions) -> PathBuf {
+    opts.download_root()
+        .join(format!("wasupdate-{}.part", url_key(url)))
+}
+
+fn partial_meta_path(partial: &Path) -> PathBuf {
+    partial.with_extension("part.json")
+}
+
+/// Bytes already downloaded from `url` and the validator they were fetched with, if
+/// the server allowed resuming.
+fn resumable_download(url: &str, partial: &Path) -> Option<(u64, String)> {
+    let meta = fs::read_to_string(partial_meta_path(partial)).ok()?;
+    let meta: PartialDownload = serde_json::from_str(&meta).ok()?;
+    let offset = fs::metadata(partial).ok()?.len();
+    (meta.url == url && offset > 0).then_some((offset, meta.validator))
+}
+
+/// The strong validator of a response, usable in `If-Range`.
+fn response_validator(response: &reqwest::blocking::Response) -> Option<String> {
+    let header = |name| {
+        response
+            .headers()
+            .get(name)
+            .and_then(|value| value.to_str().ok())
+            .map(str::to_string)
+    };
+    header(reqwest::header::ETAG)
+        .filter(|etag| !etag.starts_with("W/"))
+        .or_else(|| header(reqwest::header::LAST_MODIFIED))
+}
+
+/// Writes the body of `response` into `partial`, appending when resuming at `offset`.
+fn download_body(
+    mut source: reqwest::blocking::Response,
+    url: &str,
+    partial: &Path,
+    meta_path: &Path,
+    offset: u64,
+    total_size: Option<u64>,
+    pb: &indicatif::ProgressBar,
+) -> Result<(), Failure> {
+    let mut buffer = vec![0; DOWNLOAD_BUFFER_SIZE];
+    let mut dest = if offset > 0 {
+        File::options().append(true).open(partial)?
+    } else {
+        File::create(partial)?
+    };
+    let mut received = offset;
+    loop {
+        let n = match source.read(&mut buffer) {
+            Ok(n) => n,
+            Err(e) => {
+                let e = match total_size {
+                    Some(expected)
+                        if received < expected && e.kind() != io::ErrorKind::TimedOut =>
+                    {
+                        truncated_download(url, expected, received)
+                    }
+                    _ => http::read_error(url, e),
+                };
+                discard_unresumable(partial, meta_path);
+                return Err(Failure::Transient(e));
+            }
+        };
+        if n == 0 {
+            break; // EOF
+        }
+        dest.write_all(&buffer[..n]).map_err(Error::other)?;
+        received += n as u64;
+        pb.inc(n as u64);
+    }
+    if let Some(expected) = total_size
+        && received != expected
+    {
+        pb.abandon();
+        discard_unresumable(partial, meta_path);
+        return Err(Failure::Transient(truncated_download(
+            url, expected, received,
+        )));
+    }
+    Ok(())
+}
+
+/// Whether the server serves byte ranges of the response's resource.
+fn accepts_ranges(response: &reqwest::blocking::Response) -> bool {
+    response.status() == StatusCode::PARTIAL_CONTENT
+        || response
+            .headers()
+            .get(reqwest::header::ACCEPT_RANGES)
+            .is_some_and(|value| value.as_bytes() == b"bytes")
+}
+
+fn content_length(response: &reqwest::blocking::Response) -> Option<u64> {
+    response
+        .headers()
+        .get(reqwest::header::CONTENT_LENGTH)
+        .and_then(|len| len.to_str().ok())
+        .and_then(|len| len.parse::<u64>().ok())
+}
+
+/// Size of the buffer response bodies are copied through. Larger reads mean fewer
+/// system calls and progress updates.
+pub(crate) const DOWNLOAD_BUFFER_SIZE: usize = 64 * 1024;
+
+/// Progress of a download, a bar with speed and ETA when the size is known and a
+/// spinner counting bytes otherwise. The speed is an exponentially weighted average,
+/// so it stays steady between reads. Hidden when stdout output is off.
+pub(crate) fn download_progress(total_size: Option<u64>, filename: &str) -> indicatif::ProgressBar {
+    if !unsafe { STDOUT_WRITE } {
+        return indicatif::ProgressBar::hidden();
+    }
+    let pb = match total_size {
+        Some(size) => indicatif::ProgressBar::new(size).with_style(
+            indicatif::ProgressStyle::default_bar()
+                .template("{spinner:.green} {msg} [{elapsed_precise}] {wide_bar} {bytes}/{total_bytes} ({bytes_per_sec}, {eta})")
+                .unwrap(),
+        ),
+        None => indicatif::ProgressBar::new_spinner().with_style(
+            indicatif::ProgressStyle::default_spinner()
+                .template("{spinner:.green} {msg} [{elapsed_precise}] {bytes} ({bytes_per_sec})")
+                .unwrap(),
+        ),
+    };
+    // Keeps the spinner and elapsed time moving while the connection stalls
+    pb.enable_steady_tick(Duration::from_millis(200));
+    pb.with_message(format!("Downloading {filename}"))
+}
+
+pub(crate) fn truncated_download(url: &str, expected: u64, received: u64) -> Error {
+    Error::new(
+        io::ErrorKind::UnexpectedEof,
+        format!(
+            "The download of '{url}' was cut short: expected {expected} bytes, received {received}"
+        ),
+    )
+}
+
+/// Removes a failed partial download unless the server allowed resuming it.
+fn discard_unresumable(partial: &Path, meta_path: &Path) {
+    if !meta_path.exists() {
+        let _ = fs::remove_file(partial);
+    }
+}
+
+/// Downloads `url` into temp space, retrying network errors and server failures as
+/// set by `opts.retry`. An interrupted download is kept, and when the server accepts
+/// byte ranges the next attempt asks only for the rest, as long as the file is
+/// unchanged; otherwise it starts over.
+///
+/// Without a `work_dir` the file is downloaded into a new run directory, which is
+/// removed when the returned download is dropped.
+pub fn download_archive(url: &str, opts: &InstallOptions) -> io::Result<Download> {
+    download(url, opts)
+}
+
+/// Like [`download_archive`], also telling whether the artifact came from the cache.
+/// A cached copy is reused when the server answers its `ETag` or `Last-Modified`
+/// with 304 Not Modified.
+pub fn download(url: &str, opts: &InstallOptions) -> io::Result<Download> {
+    if opts.work_dir.is_none() {
+        // The download is handed to the caller, so its directory lives as long as it
+        let run_dir = RunDir::create(&opts.download_root())?;
+        let download = download(
+            url,
+            &InstallOptions {
+                work_dir: Some(run_dir.path().to_path_buf()),
+                ..opts.clone()
+            },
+        )?;
+        return Ok(Download {
+            _run_dir: Some(Arc::new(run_dir)),
+            ..download
+        });
+    }
+    reqwest::Url::parse(url)
+        .map_err(|e| Error::new(io::ErrorKind::InvalidInput, format!("Invalid URL: {e}")))?;
+    let download = retry(&opts.retry, &format!("Downloading {url}"), || {
+        if is_remote(url) {
+            return download_remote(url, opts).map_err(remote_failure);
+        }
+        download_attempt(url, opts, opts.parallel.as_ref())
+    })?;
+    if let Some(cache) = &opts.cache
+        && let Err(e) = cache.prune()
+    {
+        p_warn(&format!("Failed to prune the download cache: {e}"));
+    }
+    Ok(download)
+}
+
+fn download_attempt(
+    url: &str,
+    opts: &InstallOptions,
+    parallel: Option<&ParallelDownload>,
+) -> Result<Download, Failure> {
+    let partial = partial_download_path(url, opts);
+    let meta_path = partial_meta_path(&partial);
+    let resume = resumable_download(url, &partial);
+    let cached = match &resume {
+        None => opts.cache.as_ref().and_then(|cache| cache.lookup(url)),
+        Some(_) => None,
+    };
+    let client = http::client(&opts.http)?;
+    // A download that can be split is checked with `HEAD` first, so the ranges are
+    // requested without starting a transfer of the whole body
+    let probed = match parallel {
+        Some(parallel) if resume.is_none() && cached.is_none() => {
+            head(&client, url, opts).ok().filter(|response| {
+                accepts_ranges(response)
+                    && full_size(response).is_some_and(|size| parallel.applies_to(size))
+            })
+        }
+        _ => None,
+    };
+    let (response, offset, remaining) = if let Some(response) = probed {
+        let size = full_size(&response);
+        (response, 0, size)
+    } else {
+        let mut request = http::with_headers(client.get(url), &opts.request_headers("GET", url)?)?;
+        if let Some((offset, validator)) = &resume {
+            request = request
+                .header(reqwest::header::RANGE, format!("bytes={offset}-"))
+                .header(reqwest::header::IF_RANGE, validator);
+        }
+        if let Some((entry, _)) = &cached {
+            if let Some(etag) = &entry.etag {
+                request = request.header(reqwest::header::IF_NONE_MATCH, etag);
+            }
+            if let Some(last_modified) = &entry.last_modified {
+                request = request.header(reqwest::header::IF_MODIFIED_SINCE, last_modified);
+            }
+        }
+        let response = request.send();
+        if let Some((_, cached)) = &cached
+            && response
+                .as_ref()
+                .is_ok_and(|r| r.status() == StatusCode::NOT_MODIFIED)
+        {
+            // Installing may move the archive, so it gets a copy of the cached file
+            let temp_file = opts.run_dir().join(cached.file_name().unwrap_or_default());
+            fs::copy(cached, &temp_file)?;
+            if let Some(cache) = &opts.cache {
+                cache.touch(url)?;
+            }
+            return Ok(Download::new(temp_file, ArtifactSource::Cache));
+        }
+        if resume.is_some()
+            && response
+                .as_ref()
+                .is_ok_and(|r| r.status() == StatusCode::RANGE_NOT_SATISFIABLE)
+        {
+            let _ = fs::remove_file(&partial);
+            let _ = fs::remove_file(&meta_path);
+            return Err(Failure::Transient(Error::other(format!(
+                "The partial download of '{url}' cannot be resumed"
+            ))));
+        }
+        let response = check_response(response)?;
+        let offset = match resume {
+            Some((offset, expected)) if response.status() == StatusCode::PARTIAL_CONTENT => {
+                if response_validator(&response)
+                    .as_ref()
+                    .is_some_and(|validator| *validator != expected)
+                {
+                    let _ = fs::remove_file(&partial);
+                    let _ = fs::remove_file(&meta_path);
+                    return Err(Failure::Transient(Error::other(format!(
+                        "'{url}' changed while resuming its download"
+                    ))));
+                }
+                offset
+            }
+            _ => 0,
+        };
+        let remaining = content_length(&response);
+        (response, offset, remaining)
+    };
+    let validator = response_validator(&response);
+    let header = |name| {
+        response
+            .headers()
+            .get(name)
+            .and_then(|value| value.to_str().ok())
+            .map(str::to_string)
+    };
+    let etag = header(reqwest::header::ETAG);
+    let last_modified = header(reqwest::header::LAST_MODIFIED);
+    let accepts_ranges = accepts_ranges(&response);
+    match &validator {
+        Some(validator) if accepts_ranges => {
+            let meta = PartialDownload {
+                url: url.to_string(),
+                validator: validator.clone(),
+            };
+            fs::write(
+                &meta_path,
+                serde_json::to_string(&meta).map_err(Error::other)?,
+            )?;
+        }
+        _ => {
+            let _ = fs::remove_file(&meta_path);
+        }
+    }
+    // Try get the filename from Content-Disposition, if not available, use last part of the URL
+    let file_name_from_content_disposition = response
+        .headers()
+        .get(reqwest::header::CONTENT_DISPOSITION)
+        .and_then(|cd| cd.to_str().ok())
+        .and_then(http::content_disposition_file_name);
+    let filename_from_url = url
+        .rsplit('/')
+        .next()
+        .and_then(sanitize_file_name)
+        .ok_or_else(|| Error::new(io::ErrorKind::InvalidInput, "Invalid URL"))?;
+    let filename = file_name_from_content_disposition
+        .as_deref()
+        .and_then(sanitize_file_name)
+        .unwrap_or_else(|| filename_from_url.clone());
+
+    if let Some(remaining) = remaining
+        && !opts.skip_space_check
+    {
+        ensure_space(&opts.download_root(), remaining)?;
+    }
+    let total_size = remaining.map(|remaining| remaining + offset);
+    let pb = download_progress(total_size, &filename);
+    pb.set_position(offset);
+    // The resumed bytes are not part of the transfer rate
+    pb.reset_eta();
+    let chunked = parallel.filter(|parallel| {
+        offset == 0 && accepts_ranges && total_size.is_some_and(|size| parallel.applies_to(size))
+    });
+    if let Some(parallel) = chunked {
+        // The ranges are requested separately, the body of this response is not needed
+        drop(response);
+        let _ = fs::remove_file(&meta_path);
+        let chunks = Chunks {
+            client: &client,
+            opts,
+            url,
+            validator: validator.as_deref(),
+            size: total_size.unwrap_or_default(),
+            pb: &pb,
+        };
+        if let Err(e) = chunks.download(&partial, parallel) {
+            pb.abandon();
+            let _ = fs::remove_file(&partial);
+            p_warn(&format!(
+                "Parallel download of {url} failed: {e}. Downloading it on a single connection"
+            ));
+            return download_attempt(url, opts, None);
+        }
+    } else {
+        download_body(response, url, &partial, &meta_path, offset, total_size, &pb)?;
+    }
+    pb.finish_with_message("Download complete");
+
+    let _ = fs::remove_file(&meta_path);
+    let temp_file = opts.run_dir().join(&filename);
+    fs::rename(&partial, &temp_file)?;
+    if let Some(cache) = &opts.cache
+        && (etag.is_some() || last_modified.is_some())
+        && let Err(e) = cache.store(url, etag, last_modified, &temp_file)
+    {
+        p_warn(&format!("Failed to cache the download of {url}: {e}"));
+    }
+    Ok(Download::new(temp_file, ArtifactSource::Network))
+}
+
+/// What a pre-flight request found out about an artifact without downloading it.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Probe {
+    pub status: u16,
+    /// Size of the artifact, when the server tells.
+    pub size: Option<u64>,
+    /// URL the artifact is served from after following redirects.
+    pub url: String,
+}
+
+/// Checks that `url` can be downloaded with a `HEAD` request, falling back to a `GET`
+/// of the first byte for servers that reject `HEAD`, such as presigned links.
+pub fn probe(url: &str, opts: &InstallOptions) -> io::Result<Probe> {
+    let client = http::client(&opts.http)?;
+    retry(&opts.retry, &format!("Checking {url}"), || {
+        let response = head(&client, url, opts)?;
+        Ok(Probe {
+            status: response.status().as_u16(),
+            size: full_size(&response),
+            url: response.url().to_string(),
+        })
+    })
+}
+
+/// Requests the headers of `url` with `HEAD`, falling back to a `GET` of the first
+/// byte for servers that reject `HEAD`.
+fn head(
+    client: &reqwest::blocking::Client,
+    url: &str,
+    opts: &InstallOptions,
+) -> Result<reqwest::blocking::Response, Failure> {
+    let response =
+        http::with_headers(client.head(url), &opts.request_headers("HEAD", url)?)?.send();
+    let head_rejected = response.as_ref().is_ok_and(|r| {
+        matches!(
+            r.status(),
+            StatusCode::METHOD_NOT_ALLOWED | StatusCode::NOT_IMPLEMENTED | StatusCode::FORBIDDEN
+        )
+    });
+    if head_rejected {
+        let request = http::with_headers(client.get(url), &opts.request_headers("GET", url)?)?
+            .header(reqwest::header::RANGE, "bytes=0-0");
+        check_response(request.send())
+    } else {
+        check_response(response)
+    }
+}
+
+/// Size of the resource behind a [`head`] response. A ranged response carries it
+/// after the slash of Content-Range.
+fn full_size(response: &reqwest::blocking::Response) -> Option<u64> {
+    match response.status() {
+        StatusCode::PARTIAL_CONTENT => response
+            .headers()
+            .get(reqwest::header::CONTENT_RANGE)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.rsplit_once('/')?.1.parse().ok()),
+        _ => content_length(response),
+    }
+}
+
+pub fn download_install_archive(url: &str, opts: &InstallOptions) -> io::Result<InstallSummary> {
+    download_install(url, opts, None)
+}
+
+fn download_install(
+    url: &str,
+    opts: &InstallOptions,
+    check: Option<ArchiveCheck>,
+) -> io::Result<InstallSummary> {
+    // A signature or check can only look at the whole archive, before extracting it
+    let streamable = |url: &str| {
+        url.rsplit('/')
+            .next()
+            .and_then(sanitize_file_name)
+            .filter(|name| !is_remote(url) && TarFormat::from_name(name).is_some())
+    };
+    if opts.stream
+        && opts.signing_key.is_none()
+        && check.is_none()
+        && opts.keep_download.is_none()
+        && streamable(url).is_some()
+    {
+        let (served_by, summary) = try_mirrors(url, opts, |url| match streamable(url) {
+            Some(name) => stream_install_archive(url, &name, opts),
+            None => download_install_file(url, opts),
+        })?;
+        return Ok(InstallSummary {
+            served_by: Some(served_by),
+            ..summary
+        });
+    }
+    let (served_by, download) = try_mirrors(url, opts, |url| download(url, opts))?;
+    let summary = install_download(&served_by, download, opts, check)?;
+    Ok(InstallSummary {
+        served_by: Some(served_by),
+        ..summary
+    })
+}
+
+fn download_install_file(url: &str, opts: &InstallOptions) -> io::Result<InstallSummary> {
+    install_download(url, download(url, opts)?, opts, None)
+}
+
+/// Tries `attempt` with `url` and then with every mirror until one succeeds. Invalid
+/// data, such as a checksum mismatch, fails right away since mirrors serve the same
+/// artifact. Returns the location that worked.
+fn try_mirrors<T>(
+    url: &str,
+    opts: &InstallOptions,
+    mut attempt: impl FnMut(&str) -> io::Result<T>,
+) -> io::Result<(String, T)> {
+    let mut failures = vec![];
+    let locations: Vec<&str> = std::iter::once(url)
+        .chain(opts.mirrors.iter().map(String::as_str))
+        .collect();
+    for (i, location) in locations.iter().enumerate() {
+        match attempt(location) {
+            Ok(value) => return Ok((location.to_string(), value)),
+            Err(e) if e.kind() == io::ErrorKind::InvalidData || locations.len() == 1 => {
+                return Err(e);
+            }
+            Err(e) => {
+                if let Some(next) = locations.get(i + 1) {
+                    p_warn(&format!(
+                        "Downloading {location} failed: {e}. Trying {next}"
+                    ));
+                }
+                failures.push(format!("{location}: {e}"));
+            }
+        }
+    }
+    Err(Error::other(format!(
+        "All {} locations failed. {}",
+        locations.len(),
+        failures.join("; ")
+    )))
+}
+
+fn install_download(
+    url: &str,
+    download: Download,
+    opts: &InstallOptions,
+    check: Option<ArchiveCheck>,
+) -> io::Result<InstallSummary> {
+    let Download {
+        path: download_result,
+        source,
+        ..
+    } = download;
+    if opts.verify_sidecar {
+        let digest = fetch_sidecar_checksum(url, |url| opts.request_headers("GET", url))?;
+        verify_digest(&download_result, &digest)?;
+    }
+    if let Some(key) = &opts.signing_key {
+        let signature = fetch_signature(url, |url| opts.request_headers("GET", url))?;
+        verify_signature(&download_result, &signature, key)?;
+    }
+    if let Some(check) = check {
+        check(&download_result)?;
+    }
+    p_good(
+        format!(
+            "Download complete, proceding to install: {}",
+            download_result.display()
+        )
+        .as_str(),
+    );
+    let summary = install_archive(&download_result, opts)?;
+    let kept_download = match &opts.keep_download {
+        Some(dir) => Some(keep_download(&download_result, dir)?),
+        None => None,
+    };
+    Ok(InstallSummary {
+        source: Some(source),
+        kept_download,
+        ..summary
+    })
+}
+
+/// Moves the installed archive at `path` into `dir` under the same file name. A
+/// download taken from the cache is already a copy, so the cache keeps its own.
+fn keep_download(path: &Path, dir: &Path) -> io::Result<KeptDownload> {
+    let name = path.file_name().ok_or_else(|| {
+        Error::new(
+            io::ErrorKind::InvalidInput,
+            "Provided path has no file name",
+        )
+    })?;
+    fs::create_dir_all(dir)?;
+    let kept = dir.join(name);
+    if fs::symlink_metadata(&kept).is_ok() {
+        remove_entry(&kept)?;
+    }
+    move_entry(path, &kept).map_err(|e| {
+        Error::new(
+            e.kind(),
+            format!("Failed to keep the download in '{}': {e}", dir.display()),
+        )
+    })?;
+    Ok(KeptDownload {
+        sha256: sha256_file(&kept)?,
+        path: kept,
+    })
+}
+
+/// Response body that hashes and counts the bytes passing through it.
+struct StreamReader {
+    response: reqwest::blocking::Response,
+    hashers: Vec<Hasher>,
+    received: u64,
+    read_failed: bool,
+    pb: indicatif::ProgressBar,
+}
+
+impl Read for StreamReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self
+            .response
+            .read(buf)
+            .inspect_err(|_| self.read_failed = true)?;
+        for hasher in &mut self.hashers {
+            hasher.update(&buf[..n]);
+        }
+        self.received += n as u64;
+        self.pb.inc(n as u64);
+        Ok(n)
+    }
+}
+
+/// Downloads the tar archive at `url` and extracts it into the staging directory as it
+/// arrives, then installs it like [`install_archive`]. The expected checksums are
+/// compared with digests of the stream once it ends, before anything is installed.
+fn stream_install_archive(
+    url: &str,
+    name: &str,
+    opts: &InstallOptions,
+) -> io::Result<InstallSummary> {
+    let mut expected = vec![];
+    if let Some(checksum) = &opts.checksum {
+        expected.push(ChecksumDigest::parse(checksum)?);
+    }
+    if opts.verify_sidecar {
+        expected.push(fetch_sidecar_checksum(url, |url| {
+            opts.request_headers("GET", url)
+        })?);
+    }
+    p_good(&format!("Extracting {name} while downloading it"));
+    let summary = retry(&opts.retry, &format!("Downloading {url}"), || {
+        stream_attempt(url, name, &expected, opts)
+    })?;
+    Ok(InstallSummary {
+        source: Some(ArtifactSource::Network),
+        ..summary
+    })
+}
+
+fn stream_attempt(
+    url: &str,
+    name: &str,
+    expected: &[ChecksumDigest],
+    opts: &InstallOptions,
+) -> Result<InstallSummary, Failure> {
+    let (format, _) = TarFormat::from_name(name)
+        .ok_or_else(|| Error::new(io::ErrorKind::InvalidInput, "Not a tar archive"))?;
+    let request = http::with_headers(
+        http::client(&opts.http)?.get(url),
+        &opts.request_headers("GET", url)?,
+    )?;
+    let response = check_response(request.send())?;
+    let total_size = content_length(&response);
+    let dest = opts.dest_dir()?;
+    let target = install_target(&dest, opts)?;
+    let (state, staging) = prepare_staging(&dest, opts)?;
+    let cleanup = || {
+        let _ = fs::remove_dir_all(if opts.dry_run { &state } else { &staging });
+    };
+    if let Some(size) = total_size
+        && !opts.skip_space_check
+    {
+        ensure_space(&staging, size).inspect_err(|_| cleanup())?;
+    }
+    let mut reader = StreamReader {
+        response,
+        hashers: expected.iter().map(|d| Hasher::new(d.algorithm)).collect(),
+        received: 0,
+        read_failed: false,
+        pb: download_progress(total_size, name),
+    };
+    // The tar reader stops at the end-of-archive marker, the rest is still hashed
+    let unpacked = unpack_tar_stream(&mut reader, name, format, &staging, opts)
+        .and_then(|()| io::copy(&mut reader, &mut io::sink()).map(|_| ()));
+    if let Err(e) = unpacked {
+        cleanup();
+        return Err(match total_size {
+            Some(size) if reader.read_failed && reader.received < size => {
+                Failure::Transient(truncated_download(url, size, reader.received))
+            }
+            _ if reader.read_failed => Failure::Transient(http::read_error(url, e)),
+            _ => Failure::Permanent(e),
+        });
+    }
+    if let Some(size) = total_size
+        && reader.received != size
+    {
+        cleanup();
+        return Err(Failure::Transient(truncated_download(
+            url,
+            size,
+            reader.received,
+        )));
+    }
+    reader.pb.finish_with_message("Download complete");
+    for (hasher, expected) in reader.hashers.into_iter().zip(expected) {
+        if let Err(e) = check_digest(Path::new(name), &hasher.finalize(), expected) {
+            cleanup();
+            return Err(Failure::Permanent(e));
+        }
+    }
+    let result = install_staged(&staging, &dest, &target, opts);
+    cleanup();
+    Ok(result?)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        env::temp_dir,
+        sync::{
+            Arc, Mutex,
+            atomic::{AtomicU32, Ordering},
+        },
+        time::Duration,
+    };
+
+    use super::*;
+    use crate::{
+        backup::rollback,
+        checksum::ChecksumMismatch,
+        test_server::{Response, TestServer},
+    };
+
+    fn test_dir(name: &str) -> PathBuf {
+        let dir = temp_dir().join(format!("wasupdate-install-{}-{name}", std::process::id()));
+        if dir.exists() {
+            fs::remove_dir_all(&dir).unwrap();
+        }
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn write_tar(path: &Path, files: &[(&str, &str)]) {
+        let mut builder = tar::Builder::new(File::create(path).unwrap());
+        for (name, content) in files {
+            let mut header = tar::Header::new_gnu();
+            header.set_size(content.len() as u64);
+            header.set_mode(0o644);
+            header.set_cksum();
+            builder
+                .append_data(&mut header, name, content.as_bytes())
+                .unwrap();
+        }
+        builder.finish().unwrap();
+    }
+
+    #[test]
+    fn test_install_tar_unrolls_and_rolls_back() {
+        let root = test_dir("tar");
+        let dest = root.join("dest");
+        fs::create_dir_all(&dest).unwrap();
+        fs::write(dest.join("app"), "old").unwrap();
+        let archive = root.join("app-1.0.0.tar");
+        write_tar(
+            &archive,
+            &[("app-1.0.0/app", "new"), ("app-1.0.0/README", "readme")],
+        );
+
+        let opts = InstallOptions {
+            dest: Some(dest.clone()),
+            current_version: Some("0.9.0".to_string()),
+            ..Default::default()
+        };
+        let summary = install_archive(&archive, &opts).expect("Failed to install archive");
+        assert_eq!(fs::read_to_string(dest.join("app")).unwrap(), "new");
+        assert!(dest.join("README").exists());
+        let manifest = summary.manifest.unwrap();
+        assert_eq!(manifest.file_count(), 2);
+        assert_eq!(manifest.total_bytes(), 9);
+        assert_eq!(manifest.files[1].action, FileAction::Overwrite);
+        assert_eq!(
+            crate::manifest::read_manifest(&dest).unwrap().files,
+            manifest.files
+        );
+        assert!(!state_dir(&dest).join(STAGING_DIR).exists());
+
+        let report = rollback(&dest).expect("Failed to roll back");
+        assert_eq!(report.version.as_deref(), Some("0.9.0"));
+        assert_eq!(fs::read_to_string(dest.join("app")).unwrap(), "old");
+        assert!(!dest.join("README").exists());
+        fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn test_install_checksum_mismatch_leaves_dest_untouched() {
+        let root = test_dir("checksum");
+        let dest = root.join("dest");
+        fs::create_dir_all(&dest).unwrap();
+        fs::write(dest.join("app"), "old").unwrap();
+        let archive = root.join("app");
+        fs::write(&archive, "new").unwrap();
+
+        let opts = InstallOptions {
+            dest: Some(dest.clone()),
+            checksum: Some(format!("sha256:{}", "0".repeat(64))),
+            ..Default::default()
+        };
+        let err = install_archive(&archive, &opts).err().unwrap();
+        assert!(ChecksumMismatch::from_io(&err).is_some());
+        assert_eq!(fs::read_to_string(dest.join("app")).unwrap(), "old");
+        fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn test_install_checked_rejection_leaves_dest_untouched() {
+        let root = test_dir("checked");
+        let dest = root.join("dest");
+        fs::create_dir_all(&dest).unwrap();
+        fs::write(dest.join("app"), "old").unwrap();
+        let archive = root.join("app-1.1.0.tar");
+        write_tar(&archive, &[("app", "new")]);
+        let opts = InstallOptions {
+            dest: Some(dest.clone()),
+            ..Default::default()
+        };
+
+        let checked = std::cell::RefCell::new(vec![]);
+        let reject = |path: &Path| {
+            checked.borrow_mut().push(path.to_path_buf());
+            Err(Error::new(io::ErrorKind::InvalidData, "not attested"))
+        };
+        let err = install_checked(archive.to_str().unwrap(), &opts, &reject).unwrap_err();
+        assert_eq!(err.to_string(), "not attested");
+        assert_eq!(*checked.borrow(), vec![archive.clone()]);
+        assert_eq!(fs::read_to_string(dest.join("app")).unwrap(), "old");
+
+        install_checked(archive.to_str().unwrap(), &opts, &|_| Ok(())).unwrap();
+        assert_eq!(fs::read_to_string(dest.join("app")).unwrap(), "new");
+        fs::remove_dir_all(root).unwrap();
+    }
+
+    fn sidecar_server(artifact: &'static str, sidecar: Option<String>) -> TestServer {
+        TestServer::start(move |req| match req.path.as_str() {
+            "/sidecar/app" => Response::ok(artifact),
+            "/sidecar/app.sha256sum" => match &sidecar {
+                Some(digest) => Response::ok(format!("{digest}  app\n")),
+                None => Response::status(404),
+            },
+            _ => Response::status(404),
+        })
+    }
+
+    #[test]
+    fn test_download_verify_sidecar() {
+        let root = test_dir("sidecar");
+        let artifact = root.join("artifact");
+        fs::write(&artifact, "new").unwrap();
+        let digest = sha256_file(&artifact).unwrap();
+        let opts = InstallOptions {
+            dest: Some(root.clone()),
+            verify_sidecar: true,
+            ..Default::default()
+        };
+
+        let server = sidecar_server("new", Some(digest));
+        download_install_archive(&server.url("/sidecar/app"), &opts)
+            .expect("Failed to install with matching sidecar");
+        assert_eq!(fs::read_to_string(root.join("app")).unwrap(), "new");
+
+        let server = sidecar_server("tampered", Some(sha256_file(&artifact).unwrap()));
+        let err = download_install_archive(&server.url("/sidecar/app"), &opts)
+            .err()
+            .unwrap();
+        assert!(ChecksumMismatch::from_io(&err).is_some());
+        assert_eq!(fs::read_to_string(root.join("app")).unwrap(), "new");
+
+        let server = sidecar_server("new", None);
+        let err = download_install_archive(&server.url("/sidecar/app"), &opts)
+            .err()
+            .unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::NotFound);
+        fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn test_download_sidecar_sends_headers() {
+        let root = test_dir("sidecar-auth");
+        let artifact = root.join("artifact");
+        fs::write(&artifact, "new").unwrap();
+        let digest = sha256_file(&artifact).unwrap();
+        let server = TestServer::start(move |request| {
+            if request.header("authorization") != Some("Bearer s3cret") {
+                return Response::status(401);
+            }
+            match request.path.as_str() {
+                "/private/app" => Response::ok("new"),
+                "/private/app.sha256" => Response::ok(format!("{digest}  app\n")),
+                _ => Response::status(404),
+            }
+        });
+        let opts = InstallOptions {
+            dest: Some(root.clone()),
+            verify_sidecar: true,
+            headers: vec![("Authorization".to_string(), "Bearer s3cret".to_string())],
+            ..Default::default()
+        };
+        download_install_archive(&server.url("/private/app"), &opts).unwrap();
+        assert_eq!(fs::read_to_string(root.join("app")).unwrap(), "new");
+        fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn test_install_replaces_running_exe() {
+        let root = test_dir("running");
+        let dest = root.join("dest");
+        fs::create_dir_all(dest.join("bin")).unwrap();
+        fs::write(dest.join("bin").join("app"), "old").unwrap();
+        let archive = root.join("app-1.0.0.tar");
+        write_tar(&archive, &[("app-1.0.0/bin/app", "new")]);
+
+        let opts = InstallOptions {
+            dest: Some(dest.clone()),
+            running_exe: Some(dest.join("bin").join("app")),
+            ..Default::default()
+        };
+        install_archive(&archive, &opts).expect("Failed to install archive");
+        assert_eq!(
+            fs::read_to_string(dest.join("bin").join("app")).unwrap(),
+            "new"
+        );
+        let replaced = state_dir(&dest).join(REPLACED_DIR);
+        let aside = replaced.join(format!("app.{}.old", std::process::id()));
+        assert_eq!(fs::read_to_string(aside).unwrap(), "old");
+
+        rollback(&dest).expect("Failed to roll back");
+        assert_eq!(
+            fs::read_to_string(dest.join("bin").join("app")).unwrap(),
+            "old"
+        );
+        cleanup_replaced(&dest);
+        assert!(!replaced.exists());
+        fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn test_sanitize_entry_path() {
+        assert_eq!(
+            sanitize_entry_path(Path::new("./app-1.0.0/bin/app")).unwrap(),
+            PathBuf::from("app-1.0.0/bin/app")
+        );
+        assert_eq!(
+            sanitize_entry_path(Path::new("./")).unwrap(),
+            PathBuf::new()
+        );
+        for malicious in ["../evil", "app/../../evil", "/etc/cron.d/evil"] {
+            let err = sanitize_entry_path(Path::new(malicious)).err().unwrap();
+            assert_eq!(err.kind(), io::ErrorKind::InvalidData, "{malicious}");
+        }
+    }
+
+    #[test]
+    fn test_sanitize_file_name() {
+        assert_eq!(
+            sanitize_file_name("../../home/user/.bashrc").as_deref(),
+            Some(".bashrc")
+        );
+        assert_eq!(
+            sanitize_file_name("..\\..\\evil.exe").as_deref(),
+            Some("evil.exe")
+        );
+        assert_eq!(
+            sanitize_file_name("app.tar.gz").as_deref(),
+            Some("app.tar.gz")
+        );
+        assert_eq!(sanitize_file_name("/etc/cron.d/x").as_deref(), Some("x"));
+        assert_eq!(sanitize_file_name("releases/.."), None);
+        assert_eq!(sanitize_file_name("app\n.zip"), None);
+        assert_eq!(sanitize_file_name(""), None);
+    }
+
+    #[test]
+    fn test_install_tar_rejects_traversal() {
+        let root = test_dir("tar-traversal");
+        let dest = root.join("dest");
+        fs::create_dir_all(&dest).unwrap();
+        let archive = root.join("evil.tar");
+        let mut builder = tar::Builder::new(File::create(&archive).unwrap());
+        for name in ["app", "../evil"] {
+            let mut header = tar::Header::new_gnu();
+            header.as_gnu_mut().unwrap().name[..name.len()].copy_from_slice(name.as_bytes());
+            header.set_size(4);
+            header.set_mode(0o644);
+            header.set_cksum();
+            builder.append(&header, "evil".as_bytes()).unwrap();
+        }
+        builder.finish().unwrap();
+
+        let opts = InstallOptions {
+            dest: Some(dest.clone()),
+            ..Default::default()
+        };
+        let err = install_archive(&archive, &opts).err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        assert!(!root.join("evil").exists());
+        assert!(!dest.join("app").exists());
+        fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn test_install_zip_rejects_traversal() {
+        let root = test_dir("zip-traversal");
+        let dest = root.join("dest");
+        fs::create_dir_all(&dest).unwrap();
+        for (i, name) in ["../evil", "/tmp/evil"].iter().enumerate() {
+            let archive = root.join(format!("evil-{i}.zip"));
+            let mut writer = zip::ZipWriter::new(File::create(&archive).unwrap());
+            let options = zip::write::SimpleFileOptions::default();
+            writer.start_file("app", options).unwrap();
+            writer.write_all(b"evil").unwrap();
+            writer.start_file(*name, options).unwrap();
+            writer.write_all(b"evil").unwrap();
+            writer.finish().unwrap();
+
+            let opts = InstallOptions {
+                dest: Some(dest.clone()),
+                ..Default::default()
+            };
+            let err = install_archive(&archive, &opts).err().unwrap();
+            assert_eq!(err.kind(), io::ErrorKind::InvalidData, "{name}");
+            assert!(!root.join("evil").exists());
+            assert!(!dest.join("app").exists());
+        }
+        fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn test_check_link_target() {
+        let entry = Path::new("app-1.0.0/lib/libfoo.so");
+        assert!(check_link_target(entry, Path::new("libfoo.so.3.1")).is_ok());
+        assert!(check_link_target(entry, Path::new("../bin/app")).is_ok());
+        assert!(check_link_target(entry, Path::new("../../app")).is_ok());
+        for target in ["../../../etc/passwd", "/usr/lib/libfoo.so"] {
+            let err = check_link_target(entry, Path::new(target)).err().unwrap();
+            assert_eq!(err.kind(), io::ErrorKind::InvalidData, "{target}");
+        }
+    }
+
+    fn write_tar_with_link(path: &Path, target: &str) {
+        let mut builder = tar::Builder::new(File::create(path).unwrap());
+        let mut header = tar::Header::new_gnu();
+        header.set_size(7);
+        header.set_mode(0o644);
+        builder
+            .append_data(&mut header, "app/lib/libfoo.so.3.1", "libfoo!".as_bytes())
+            .unwrap();
+        let mut header = tar::Header::new_gnu();
+        header.set_entry_type(tar::EntryType::Symlink);
+        header.set_size(0);
+        header.set_path("app/lib/libfoo.so").unwrap();
+        header.set_link_name_literal(target).unwrap();
+        header.set_cksum();
+        builder.append(&header, io::empty()).unwrap();
+        builder.finish().unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_install_tar_preserves_symlinks() {
+        let root = test_dir("tar-symlink");
+        let dest = root.join("dest");
+        fs::create_dir_all(dest.join("lib")).unwrap();
+        fs::write(dest.join("lib").join("libfoo.so"), "old").unwrap();
+        let archive = root.join("app.tar");
+        write_tar_with_link(&archive, "libfoo.so.3.1");
+
+        let opts = InstallOptions {
+            dest: Some(dest.clone()),
+            ..Default::default()
+        };
+        install_archive(&archive, &opts).unwrap();
+        let link = dest.join("lib").join("libfoo.so");
+        assert!(fs::symlink_metadata(&link).unwrap().is_symlink());
+        assert_eq!(
+            fs::read_link(&link).unwrap(),
+            PathBuf::from("libfoo.so.3.1")
+        );
+        assert_eq!(fs::read_to_string(&link).unwrap(), "libfoo!");
+        fs::remove_dir_all(root).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_install_tar_rejects_symlink_escaping_after_unroll() {
+        let root = test_dir("tar-unroll-symlink");
+        let dest = root.join("dest");
+        fs::create_dir_all(&dest).unwrap();
+        let archive = root.join("app.tar");
+        // Inside of the archive this resolves to `app`, but the `app` folder is
+        // unrolled into `dest`, which moves the link up a level
+        write_tar_with_link(&archive, "../../app");
+
+        let opts = InstallOptions {
+            dest: Some(dest.clone()),
+            ..Default::default()
+        };
+        let err = install_archive(&archive, &opts).err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        assert!(!dest.join("lib").exists());
+        fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn test_install_tar_rejects_absolute_symlink() {
+        let root = test_dir("tar-abs-symlink");
+        let dest = root.join("dest");
+        fs::create_dir_all(&dest).unwrap();
+        let archive = root.join("app.tar");
+        write_tar_with_link(&archive, "/usr/lib/libfoo.so.3.1");
+
+        let opts = InstallOptions {
+            dest: Some(dest.clone()),
+            ..Default::default()
+        };
+        let err = install_archive(&archive, &opts).err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        assert!(!dest.join("lib").exists());
+        fs::remove_dir_all(root).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_install_zip_preserves_symlinks() {
+        let root = test_dir("zip-symlink");
+        let dest = root.join("dest");
+        fs::create_dir_all(&dest).unwrap();
+        let archive = root.join("app.zip");
+        let mut writer = zip::ZipWriter::new(File::create(&archive).unwrap());
+        let options = zip::write::SimpleFileOptions::default();
+        writer.start_file("app/lib/libfoo.so.3.1", options).unwrap();
+        writer.write_all(b"libfoo!").unwrap();
+        writer
+            .add_symlink("app/lib/libfoo.so", "libfoo.so.3.1", options)
+            .unwrap();
+        writer.finish().unwrap();
+
+        let opts = InstallOptions {
+            dest: Some(dest.clone()),
+            ..Default::default()
+        };
+        install_archive(&archive, &opts).unwrap();
+        let link = dest.join("lib").join("libfoo.so");
+        assert!(fs::symlink_metadata(&link).unwrap().is_symlink());
+        assert_eq!(fs::read_to_string(&link).unwrap(), "libfoo!");
+        fs::remove_dir_all(root).unwrap();
+    }
+
+    #[cfg(unix)]
+    fn mode(path: &Path) -> u32 {
+        use std::os::unix::fs::PermissionsExt;
+        fs::metadata(path).unwrap().permissions().mode() & 0o777
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_install_simple_file_mode() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let root = test_dir("simple-mode");
+        let dest = root.join("dest");
+        fs::create_dir_all(&dest).unwrap();
+        let binary = root.join("app");
+        fs::write(&binary, "#!/bin/sh").unwrap();
+        fs::set_permissions(&binary, fs::Permissions::from_mode(0o640)).unwrap();
+
+        let mut opts = InstallOptions {
+            dest: Some(dest.clone()),
+            ..Default::default()
+        };
+        install_archive(&binary, &opts).unwrap();
+        assert_eq!(mode(&dest.join("app")), 0o640);
+
+        opts.make_executable = true;
+        install_archive(&binary, &opts).unwrap();
+        assert_eq!(mode(&dest.join("app")), 0o750);
+        fs::remove_dir_all(root).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_install_tar_mode() {
+        let root = test_dir("tar-mode");
+        let dest = root.join("dest");
+        fs::create_dir_all(&dest).unwrap();
+        let archive = root.join("app.tar");
+        let mut builder = tar::Builder::new(File::create(&archive).unwrap());
+        for (name, mode) in [("app/bin/app", 0o755), ("app/README", 0o644)] {
+            let mut header = tar::Header::new_gnu();
+            header.set_size(4);
+            header.set_mode(mode);
+            builder
+                .append_data(&mut header, name, "data".as_bytes())
+                .unwrap();
+        }
+        builder.finish().unwrap();
+
+        let opts = InstallOptions {
+            dest: Some(dest.clone()),
+            ..Default::default()
+        };
+        install_archive(&archive, &opts).unwrap();
+        assert_eq!(mode(&dest.join("bin").join("app")), 0o755);
+        assert_eq!(mode(&dest.join("README")), 0o644);
+        fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn test_install_merges_directories() {
+        let root = test_dir("merge");
+        let dest = root.join("dest");
+        fs::create_dir_all(dest.join("plugins")).unwrap();
+        fs::write(dest.join("plugins").join("shipped.lua"), "old").unwrap();
+        fs::write(dest.join("plugins").join("user.lua"), "mine").unwrap();
+        let archive = root.join("app.tar");
+        write_tar(
+            &archive,
+            &[
+                ("app/plugins/shipped.lua", "new"),
+                ("app/plugins/extra/added.lua", "added"),
+            ],
+        );
+
+        let mut opts = InstallOptions {
+            dest: Some(dest.clone()),
+            ..Default::default()
+        };
+        install_archive(&archive, &opts).unwrap();
+        let plugins = dest.join("plugins");
+        assert_eq!(
+            fs::read_to_string(plugins.join("shipped.lua")).unwrap(),
+            "new"
+        );
+        assert_eq!(
+            fs::read_to_string(plugins.join("user.lua")).unwrap(),
+            "mine"
+        );
+        assert!(plugins.join("extra").join("added.lua").exists());
+
+        rollback(&dest).unwrap();
+        assert_eq!(
+            fs::read_to_string(plugins.join("shipped.lua")).unwrap(),
+            "old"
+        );
+        assert_eq!(
+            fs::read_to_string(plugins.join("user.lua")).unwrap(),
+            "mine"
+        );
+        assert!(!plugins.join("extra").exists());
+
+        opts.replace_directories = true;
+        install_archive(&archive, &opts).unwrap();
+        assert_eq!(
+            fs::read_to_string(plugins.join("shipped.lua")).unwrap(),
+            "new"
+        );
+        assert!(!plugins.join("user.lua").exists());
+        fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn test_install_strip_components() {
+        let root = test_dir("strip");
+        let dest = root.join("dest");
+        fs::create_dir_all(&dest).unwrap();
+        let archive = root.join("v1.2.0.tar");
+        write_tar(
+            &archive,
+            &[
+                ("myrepo-1.2.0/bin/app", "app"),
+                ("myrepo-1.2.0/README", "readme"),
+                ("LICENSE", "shallow"),
+            ],
+        );
+
+        let mut opts = InstallOptions {
+            dest: Some(dest.clone()),
+            strip_components: Some(1),
+            ..Default::default()
+        };
+        install_archive(&archive, &opts).unwrap();
+        assert_eq!(
+            fs::read_to_string(dest.join("bin").join("app")).unwrap(),
+            "app"
+        );
+        assert!(dest.join("README").exists());
+        assert!(!dest.join("LICENSE").exists());
+        assert!(!dest.join("myrepo-1.2.0").exists());
+
+        // Stripping a flat archive drops everything without failing
+        let flat = root.join("flat.tar");
+        write_tar(&flat, &[("app", "app")]);
+        install_archive(&flat, &opts).unwrap();
+        assert!(!dest.join("app").exists());
+
+        opts.strip_components = Some(2);
+        let zip = root.join("v1.2.0.zip");
+        let mut writer = zip::ZipWriter::new(File::create(&zip).unwrap());
+        writer
+            .start_file(
+                "myrepo-1.2.0/bin/tool",
+                zip::write::SimpleFileOptions::default(),
+            )
+            .unwrap();
+        writer.write_all(b"tool").unwrap();
+        writer.finish().unwrap();
+        install_archive(&zip, &opts).unwrap();
+        assert_eq!(fs::read_to_string(dest.join("tool")).unwrap(), "tool");
+        fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn test_install_include_exclude() {
+        let root = test_dir("filter");
+        let dest = root.join("dest");
+        fs::create_dir_all(&dest).unwrap();
+        fs::write(dest.join("config.toml"), "edited").unwrap();
+        let archive = root.join("app.tar");
+        write_tar(
+            &archive,
+            &[
+                ("app/bin/app", "app"),
+                ("app/config.toml", "default"),
+                ("app/docs/guide.md", "guide"),
+                ("app/docs/api/index.md", "api"),
+                ("app/share/app.toml", "share"),
+            ],
+        );
+
+        let mut opts = InstallOptions {
+            dest: Some(dest.clone()),
+            exclude: vec!["docs/**".to_string(), "*.toml".to_string()],
+            ..Default::default()
+        };
+        let summary = install_archive(&archive, &opts).unwrap();
+        assert_eq!(summary.excluded.len(), 4);
+        assert!(dest.join("bin").join("app").exists());
+        assert_eq!(
+            fs::read_to_string(dest.join("config.toml")).unwrap(),
+            "edited"
+        );
+        assert!(!dest.join("docs").exists());
+        assert!(!dest.join("share").exists());
+        rollback(&dest).unwrap();
+
+        // Excludes win over includes, and directories match everything below them
+        opts.include = vec!["docs".to_string(), "bin/*".to_string()];
+        opts.exclude = vec!["docs/api/**".to_string()];
+        let summary = install_archive(&archive, &opts).unwrap();
+        assert_eq!(summary.excluded.len(), 3);
+        assert!(dest.join("bin").join("app").exists());
+        assert!(dest.join("docs").join("guide.md").exists());
+        assert!(!dest.join("docs").join("api").exists());
+        assert_eq!(
+            fs::read_to_string(dest.join("config.toml")).unwrap(),
+            "edited"
+        );
+        fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn test_install_extract_single() {
+        let root = test_dir("extract");
+        let dest = root.join("dest");
+        fs::create_dir_all(&dest).unwrap();
+        let archive = root.join("mytool-linux.tar");
+        write_tar(
+            &archive,
+            &[
+                ("mytool-1.0.0/mytool", "binary"),
+                ("mytool-1.0.0/LICENSE", "license"),
+                ("mytool-1.0.0/completions/mytool.bash", "complete"),
+            ],
+        );
+
+        let mut opts = InstallOptions {
+            dest: Some(dest.clone()),
+            extract: Some("**/mytool".to_string()),
+            make_executable: true,
+            ..Default::default()
+        };
+        install_archive(&archive, &opts).unwrap();
+        assert_eq!(fs::read_to_string(dest.join("mytool")).unwrap(), "binary");
+        assert!(!dest.join("LICENSE").exists());
+        assert!(!dest.join("mytool-1.0.0").exists());
+        #[cfg(unix)]
+        assert_eq!(mode(&dest.join("mytool")) & 0o111, 0o111);
+
+        opts.extract = Some("**/mytool*".to_string());
+        let err = install_archive(&archive, &opts).err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        opts.extract = Some("bin/mytool".to_string());
+        let err = install_archive(&archive, &opts).err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::NotFound);
+        assert_eq!(fs::read_to_string(dest.join("mytool")).unwrap(), "binary");
+        fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn test_install_dry_run() {
+        let root = test_dir("dry-run");
+        let dest = root.join("dest");
+        fs::create_dir_all(dest.join("plugins")).unwrap();
+        fs::write(dest.join("app"), "old").unwrap();
+        fs::write(dest.join("plugins").join("user.lua"), "mine").unwrap();
+        let archive = root.join("app-1.0.0.tar");
+        write_tar(
+            &archive,
+            &[
+                ("app-1.0.0/app", "new"),
+                ("app-1.0.0/plugins/shipped.lua", "shipped"),
+            ],
+        );
+
+        let mut opts = InstallOptions {
+            dest: Some(dest.clone()),
+            dry_run: true,
+            ..Default::default()
+        };
+        let op = |action, path: &str| FileOperation {
+            action,
+            path: PathBuf::from(path),
+        };
+        let summary = install_archive(&archive, &opts).unwrap();
+        assert_eq!(
+            summary.planned,
+            vec![
+                op(FileAction::Overwrite, "app"),
+                op(FileAction::Create, "plugins/shipped.lua"),
+            ]
+        );
+
+        opts.replace_directories = true;
+        let summary = install_archive(&archive, &opts).unwrap();
+        assert_eq!(
+            summary.planned,
+            vec![
+                op(FileAction::Overwrite, "app"),
+                op(FileAction::Create, "plugins/shipped.lua"),
+                op(FileAction::Delete, "plugins/user.lua"),
+            ]
+        );
+        assert_eq!(fs::read_to_string(dest.join("app")).unwrap(), "old");
+        assert!(!dest.join("plugins").join("shipped.lua").exists());
+        assert!(!state_dir(&dest).exists());
+        fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn test_ensure_space() {
+        let available = |_: &Path| Ok(1024);
+        assert!(ensure_space_with(Path::new("dest"), 1024, available).is_ok());
+        let err = ensure_space_with(Path::new("dest"), 4096, available)
+            .err()
+            .unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::StorageFull);
+        assert!(
+            err.to_string()
+                .contains("4096 bytes required, 1024 bytes available")
+        );
+    }
+
+    #[test]
+    fn test_uncompressed_size() {
+        let root = test_dir("size");
+        let archive = root.join("app.tar");
+        write_tar(&archive, &[("app/a", "12345"), ("app/b", "678")]);
+        assert_eq!(uncompressed_size(&archive).unwrap(), 8);
+        fs::remove_dir_all(root).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_install_skip_unchanged() {
+        use std::os::unix::fs::MetadataExt;
+
+        let root = test_dir("unchanged");
+        let dest = root.join("dest");
+        fs::create_dir_all(&dest).unwrap();
+        let archive = root.join("app.tar");
+        write_tar(&archive, &[("app/lib/data", "same"), ("app/bin", "v1")]);
+        let mut opts = InstallOptions {
+            dest: Some(dest.clone()),
+            ..Default::default()
+        };
+        install_archive(&archive, &opts).unwrap();
+        let data = dest.join("lib").join("data");
+        let before = (
+            fs::metadata(&data).unwrap(),
+            fs::metadata(dest.join("bin")).unwrap(),
+        );
+
+        // as named by --skip-unchanged
+        for (check, version) in [("hash", "v2"), ("mtime", "v3.0")] {
+            write_tar(&archive, &[("app/lib/data", "same"), ("app/bin", version)]);
+            opts.skip_unchanged = Some(check.parse().unwrap());
+            let summary = install_archive(&archive, &opts).unwrap();
+            assert_eq!(
+                summary.unchanged,
+                vec![PathBuf::from("lib/data")],
+                "{check:?}"
+            );
+            assert_eq!(summary.manifest.unwrap().file_count(), 1);
+            let after = fs::metadata(&data).unwrap();
+            assert_eq!(after.ino(), before.0.ino());
+            assert_eq!(after.modified().unwrap(), before.0.modified().unwrap());
+            assert_ne!(
+                fs::metadata(dest.join("bin")).unwrap().ino(),
+                before.1.ino()
+            );
+            assert_eq!(fs::read_to_string(dest.join("bin")).unwrap(), version);
+        }
+        fs::remove_dir_all(root).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_artifact_layout() {
+        let artifact = Artifact {
+            layout: Some(LayoutKind::Versioned),
+            ..Default::default()
+        };
+        assert_eq!(artifact.layout(None, 5), Layout::Versioned { keep: 5 });
+        assert_eq!(
+            artifact.layout(Some(LayoutKind::InPlace), 5),
+            Layout::InPlace
+        );
+        let artifact = Artifact {
+            keep_versions: Some(2),
+            ..artifact
+        };
+        assert_eq!(artifact.layout(None, 5), Layout::Versioned { keep: 2 });
+        assert_eq!(Artifact::default().layout(None, 5), Layout::InPlace);
+        assert_eq!("versioned".parse(), Ok(LayoutKind::Versioned));
+        assert!("sideways".parse::<LayoutKind>().is_err());
+    }
+
+    #[test]
+    fn test_install_versioned_layout() {
+        let root = test_dir("versioned");
+        let dest = root.join("dest");
+        fs::create_dir_all(&dest).unwrap();
+        let mut opts = InstallOptions {
+            dest: Some(dest.clone()),
+            layout: Layout::Versioned { keep: 2 },
+            ..Default::default()
+        };
+        for version in ["1.0.0", "1.1.0", "1.2.0"] {
+            let archive = root.join("app.tar");
+            write_tar(&archive, &[("app/bin/app", version)]);
+            opts.version = Some(version.to_string());
+            let summary = install_archive(&archive, &opts).unwrap();
+            assert_eq!(summary.activated, Some(version_dir(&dest, version)));
+            let app = dest.join(layout::CURRENT_LINK).join("bin").join("app");
+            assert_eq!(fs::read_to_string(app).unwrap(), version);
+        }
+        assert_eq!(
+            layout::installed_versions(&dest).unwrap(),
+            vec!["1.2.0", "1.1.0"]
+        );
+
+        layout::rollback(&dest).unwrap();
+        assert_eq!(layout::active_version(&dest).as_deref(), Some("1.1.0"));
+        fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn test_apply_patch() {
+        let root = test_dir("patch");
+        let dest = root.join("dest");
+        fs::create_dir_all(dest.join("bin")).unwrap();
+        let old = b"app version 1.0.0\n";
+        let new = b"app version 1.1.0\nwith a new line\n";
+        fs::write(dest.join("bin").join("app"), old).unwrap();
+        let patch_file = root.join("app.patch");
+        fs::write(&patch_file, crate::patch::tests::make_patch(old, new)).unwrap();
+        let expected = root.join("expected");
+        fs::write(&expected, new).unwrap();
+        let mut patch = Patch {
+            location: patch_file.to_string_lossy().to_string(),
+            base_version: "1.0.0".to_string(),
+            file: "bin/app".to_string(),
+            checksum: format!("sha256:{}", "0".repeat(64)),
+        };
+        let mut opts = InstallOptions {
+            dest: Some(dest.clone()),
+            current_version: Some("0.9.0".to_string()),
+            version: Some("1.1.0".to_string()),
+            ..Default::default()
+        };
+
+        // Wrong base version and a mismatching result both leave the install alone
+        assert!(apply_patch(&patch, &opts).is_err());
+        opts.current_version = Some("1.0.0".to_string());
+        let err = apply_patch(&patch, &opts).unwrap_err();
+        assert!(ChecksumMismatch::from_io(&err).is_some());
+        assert_eq!(fs::read(dest.join("bin").join("app")).unwrap(), old);
+
+        patch.checksum = sha256_file(&expected).unwrap();
+        let reject = |path: &Path| {
+            assert_eq!(fs::read(path).unwrap(), new);
+            Err(Error::new(io::ErrorKind::InvalidData, "rejected"))
+        };
+        let err = apply_patch_checked(&patch, &opts, &reject).unwrap_err();
+        assert_eq!(err.to_string(), "rejected");
+        assert_eq!(fs::read(dest.join("bin").join("app")).unwrap(), old);
+
+        let summary = apply_patch(&patch, &opts).unwrap();
+        assert_eq!(fs::read(dest.join("bin").join("app")).unwrap(), new);
+        let manifest = summary.manifest.unwrap();
+        assert_eq!(manifest.files.len(), 1);
+        assert_eq!(manifest.files[0].action, FileAction::Overwrite);
+        assert_eq!(manifest.files[0].path, Path::new("bin/app"));
+        fs::remove_dir_all(root).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_install_zip_skip_unchanged() {
+        use std::os::unix::fs::MetadataExt;
+
+        let root = test_dir("zip-unchanged");
+        let dest = root.join("dest");
+        fs::create_dir_all(&dest).unwrap();
+        let archive = root.join("app.zip");
+        let write_zip = |version: &str| {
+            let mut writer = zip::ZipWriter::new(File::create(&archive).unwrap());
+            let options = zip::write::SimpleFileOptions::default();
+            writer.start_file("app/lib/data", options).unwrap();
+            writer.write_all(b"same").unwrap();
+            writer.start_file("app/bin", options).unwrap();
+            writer.write_all(version.as_bytes()).unwrap();
+            writer.finish().unwrap();
+        };
+        write_zip("v1");
+        let mut opts = InstallOptions {
+            dest: Some(dest.clone()),
+            ..Default::default()
+        };
+        install_archive(&archive, &opts).unwrap();
+        let data = dest.join("lib").join("data");
+        let before = fs::metadata(&data).unwrap();
+
+        write_zip("v2");
+        opts.skip_unchanged = Some(UnchangedCheck::Hash);
+        let summary = install_archive(&archive, &opts).unwrap();
+        assert_eq!(summary.unchanged, vec![PathBuf::from("lib/data")]);
+        assert_eq!(fs::metadata(&data).unwrap().ino(), before.ino());
+        assert_eq!(fs::read_to_string(dest.join("bin")).unwrap(), "v2");
+        fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn test_stage_if_identical() {
+        let root = test_dir("identical");
+        let installed = root.join("installed");
+        fs::write(&installed, "hello world").unwrap();
+        let target = root.join("staged");
+        assert!(stage_if_identical(&mut "hello world".as_bytes(), &installed, &target).unwrap());
+        assert_eq!(fs::read_to_string(&target).unwrap(), "hello world");
+        for incoming in ["hello there", "hello", "hello world!", ""] {
+            let _ = fs::remove_file(&target);
+            assert!(!stage_if_identical(&mut incoming.as_bytes(), &installed, &target).unwrap());
+            assert_eq!(fs::read_to_string(&target).unwrap(), incoming);
+        }
+        assert_eq!(fs::read_to_string(&installed).unwrap(), "hello world");
+        fs::remove_dir_all(root).unwrap();
+    }
+
+    /// Serves `body` but drops the connection halfway through the first response.
+    fn flaky_server(
+        body: &'static str,
+        honor_range: bool,
+    ) -> (TestServer, Arc<Mutex<Vec<String>>>) {
+        let ranges = Arc::new(Mutex::new(vec![]));
+        let seen = ranges.clone();
+        let server = TestServer::start(move |req| {
+            let headers = |status, body: &str, len: usize| Response {
+                status,
+                headers: vec![
+                    ("Accept-Ranges".to_string(), "bytes".to_string()),
+                    ("ETag".to_string(), "\"v1\"".to_string()),
+                    ("Content-Length".to_string(), len.to_string()),
+                ],
+                body: body.as_bytes().to_vec(),
+            };
+            let range = req.header("range").map(str::to_string);
+            let mut seen = seen.lock().unwrap();
+            seen.push(range.clone().unwrap_or_default());
+            match range.and_then(|r| r.strip_prefix("bytes=")?.strip_suffix('-')?.parse().ok()) {
+                _ if seen.len() == 1 => headers(200, &body[..body.len() / 2], body.len()),
+                Some(offset) if honor_range && req.header("if-range") == Some("\"v1\"") => {
+                    let rest: &str = &body[offset..];
+                    headers(206, rest, rest.len())
+                }
+                _ => headers(200, body, body.len()),
+            }
+        });
+        (server, ranges)
+    }
+
+    #[test]
+    fn test_download_resumes_with_range() {
+        let body = "0123456789abcdefghijklmnopqrstuvwxyz";
+        for honor_range in [true, false] {
+            let (server, ranges) = flaky_server(body, honor_range);
+            let url = server.url("/resume/app.bin");
+            let opts = InstallOptions {
+                retry: RetryPolicy {
+                    attempts: 1,
+                    ..Default::default()
+                },
+                ..Default::default()
+            };
+            assert!(download_archive(&url, &opts).is_err());
+            assert!(partial_download_path(&url, &opts).exists());
+
+            let file = download_archive(&url, &opts).unwrap();
+            assert_eq!(fs::read_to_string(&file).unwrap(), body);
+            let ranges = ranges.lock().unwrap();
+            assert_eq!(ranges[0], "");
+            assert!(ranges[1].starts_with("bytes="));
+            assert!(!partial_download_path(&url, &opts).exists());
+            fs::remove_file(file).unwrap();
+        }
+    }
+
+    #[test]
+    fn test_download_retries_transient_failures() {
+        let requests = Arc::new(AtomicU32::new(0));
+        let count = requests.clone();
+        let server = TestServer::start(move |req| {
+            let n = count.fetch_add(1, Ordering::SeqCst);
+            match req.path.as_str() {
+                "/retry/app.bin" if n < 2 => Response::status(if n == 0 { 503 } else { 429 }),
+                "/retry/app.bin" => Response::ok("app"),
+                _ => Response::status(404),
+            }
+        });
+        let opts = InstallOptions {
+            retry: RetryPolicy {
+                attempts: 3,
+                base_delay: Duration::from_millis(1),
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let file = download_archive(&server.url("/retry/app.bin"), &opts).unwrap();
+        assert_eq!(fs::read_to_string(&file).unwrap(), "app");
+        assert_eq!(requests.load(Ordering::SeqCst), 3);
+        fs::remove_file(file).unwrap();
+
+        // Client errors are not retried
+        requests.store(10, Ordering::SeqCst);
+        assert!(download_archive(&server.url("/retry/missing.bin"), &opts).is_err());
+        assert_eq!(requests.load(Ordering::SeqCst), 11);
+    }
+
+    #[test]
+    fn test_download_sends_headers() {
+        let server = TestServer::start(|req| match req.header("authorization") {
+            Some("Bearer s3cret") => Response::ok("private"),
+            _ => Response::status(401),
+        });
+        let url = server.url("/private/app.bin");
+        let mut opts = InstallOptions::default();
+        let err = download_archive(&url, &opts).unwrap_err();
+        assert!(err.to_string().contains("401"));
+
+        let artifact = Artifact {
+            location: url.clone(),
+            headers: Some(vec![(
+                "Authorization".to_string(),
+                "Bearer s3cret".to_string(),
+            )]),
+            ..Default::default()
+        };
+        opts.headers = vec![("authorization".to_string(), "Bearer stale".to_string())];
+        artifact.apply(&mut opts);
+        assert_eq!(opts.headers.len(), 1);
+        let file = download_archive(&url, &opts).unwrap();
+        assert_eq!(fs::read_to_string(&file).unwrap(), "private");
+        fs::remove_file(file).unwrap();
+
+        opts.headers = vec![("Authorization".to_string(), "Bearer s3cret\n".to_string())];
+        let err = download_archive(&url, &opts).unwrap_err();
+        assert!(!err.to_string().contains("s3cret"));
+    }
+
+    #[test]
+    fn test_download_revalidates_cached_artifact() {
+        let requests = Arc::new(Mutex::new(vec![]));
+        let seen = requests.clone();
+        let server = TestServer::start(move |req| {
+            let condition = req.header("if-none-match").map(str::to_string);
+            seen.lock()
+                .unwrap()
+                .push(condition.clone().unwrap_or_default());
+            match condition.as_deref() {
+                Some("\"v1\"") => Response::status(304),
+                _ => Response {
+                    status: 200,
+                    headers: vec![("ETag".to_string(), "\"v1\"".to_string())],
+                    body: b"cached app".to_vec(),
+                },
+            }
+        });
+        let url = server.url("/cached/app.bin");
+        let cache_dir = temp_dir().join(format!("wasupdate-download-cache-{}", std::process::id()));
+        let opts = InstallOptions {
+            cache: Some(DownloadCache::new(cache_dir.clone())),
+            ..Default::default()
+        };
+
+        let first = download(&url, &opts).unwrap();
+        assert_eq!(first.source, ArtifactSource::Network);
+        fs::remove_file(&first.path).unwrap();
+        let second = download(&url, &opts).unwrap();
+        assert_eq!(second.source, ArtifactSource::Cache);
+        assert_eq!(fs::read_to_string(&second.path).unwrap(), "cached app");
+        assert_eq!(*requests.lock().unwrap(), vec!["", "\"v1\""]);
+
+        // Without a cache the conditional headers are never sent
+        let third = download(&url, &InstallOptions::default()).unwrap();
+        assert_eq!(third.source, ArtifactSource::Network);
+        assert_eq!(requests.lock().unwrap()[2], "");
+        fs::remove_file(third.path).unwrap();
+        fs::remove_dir_all(cache_dir).unwrap();
+    }
+
+    #[test]
+    fn test_download_detects_truncated_body() {
+        let server = TestServer::start(|_| Response {
+            status: 200,
+            headers: vec![("Content-Length".to_string(), "100".to_string())],
+            body: b"0123456789".to_vec(),
+        });
+        let url = server.url("/truncated/app.tar.gz");
+        let opts = InstallOptions {
+            retry: RetryPolicy {
+                attempts: 1,
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let e = download_archive(&url, &opts).unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::UnexpectedEof);
+        assert!(
+            e.to_string().contains("expected 100 bytes, received 10"),
+            "{e}"
+        );
+        // The server does not support ranges, so nothing is kept to resume
+        assert!(!partial_download_path(&url, &opts).exists());
+    }
+
+    #[test]
+    fn test_stream_install_tar_gz_and_zst() {
+        let root = test_dir("stream");
+        let dest = root.join("dest");
+        fs::create_dir_all(&dest).unwrap();
+        let tar = root.join("app-2.0.0.tar");
+        write_tar(&tar, &[("app-2.0.0/app", "streamed")]);
+        let tar = fs::read(&tar).unwrap();
+        let mut gz = flate2::write::GzEncoder::new(vec![], flate2::Compression::default());
+        gz.write_all(&tar).unwrap();
+        let gz = gz.finish().unwrap();
+        let zst = zstd::encode_all(tar.as_slice(), 0).unwrap();
+        let mut hasher = Hasher::new(crate::checksum::Algorithm::Sha256);
+        hasher.update(&gz);
+        let digest = hasher.finalize().to_string();
+        let server = TestServer::start(move |req| match req.path.as_str() {
+            "/app-2.0.0.tar.gz" => Response::ok(gz.clone()),
+            "/app-2.0.0.tar.zst" => Response::ok(zst.clone()),
+            _ => Response::status(404),
+        });
+
+        let mut opts = InstallOptions {
+            dest: Some(dest.clone()),
+            stream: true,
+            checksum: Some(format!("sha256:{}", "0".repeat(64))),
+            ..Default::default()
+        };
+        let url = server.url("/app-2.0.0.tar.gz");
+        let err = download_install_archive(&url, &opts).unwrap_err();
+        assert!(ChecksumMismatch::from_io(&err).is_some());
+        assert!(!dest.join("app").exists());
+        assert!(!temp_dir().join("app-2.0.0.tar.gz").exists());
+
+        opts.checksum = Some(digest);
+        let summary = download_install_archive(&url, &opts).unwrap();
+        assert_eq!(summary.source, Some(ArtifactSource::Network));
+        assert_eq!(fs::read_to_string(dest.join("app")).unwrap(), "streamed");
+
+        fs::remove_file(dest.join("app")).unwrap();
+        opts.checksum = None;
+        download_install_archive(&server.url("/app-2.0.0.tar.zst"), &opts).unwrap();
+        assert_eq!(fs::read_to_string(dest.join("app")).unwrap(), "streamed");
+        fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn test_download_falls_back_to_mirrors() {
+        let root = test_dir("mirrors");
+        let dest = root.join("dest");
+        fs::create_dir_all(&dest).unwrap();
+        let server = TestServer::start(|req| match req.path.as_str() {
+            "/mirror-b/app" => Response::ok("from b"),
+            "/mirror-c/app" => Response::ok("from c"),
+            _ => Response::status(404),
+        });
+        let mut opts = InstallOptions {
+            dest: Some(dest.clone()),
+            mirrors: vec![server.url("/mirror-b/app"), server.url("/mirror-c/app")],
+            retry: RetryPolicy {
+                attempts: 1,
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let summary = install(&server.url("/mirror-a/app"), &opts).unwrap();
+        assert_eq!(summary.served_by, Some(server.url("/mirror-b/app")));
+        assert_eq!(fs::read_to_string(dest.join("app")).unwrap(), "from b");
+
+        opts.mirrors = vec![server.url("/mirror-d/app")];
+        let e = install(&server.url("/mirror-a/app"), &opts).unwrap_err();
+        assert!(e.to_string().starts_with("All 2 locations failed."), "{e}");
+        assert!(e.to_string().contains("/mirror-d/app"), "{e}");
+        fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn test_concurrent_downloads_of_same_file_name() {
+        let server = TestServer::start(|req| match req.path.as_str() {
+            "/first/release.tar.gz" => Response::ok("first"),
+            "/second/release.tar.gz" => Response::ok("second"),
+            _ => Response::status(404),
+        });
+        let downloads: Vec<_> = ["first", "second"]
+            .map(|name| {
+                let url = server.url(&format!("/{name}/release.tar.gz"));
+                std::thread::spawn(move || download_archive(&url, &InstallOptions::default()))
+            })
+            .into_iter()
+            .map(|handle| handle.join().unwrap().unwrap())
+            .collect();
+        assert_ne!(downloads[0].path, downloads[1].path);
+        assert_eq!(fs::read_to_string(&downloads[0]).unwrap(), "first");
+        assert_eq!(fs::read_to_string(&downloads[1]).unwrap(), "second");
+        for download in downloads {
+            assert_eq!(download.file_name().unwrap(), "release.tar.gz");
+            // The run directory goes away with the download
+            let run_dir = download.parent().unwrap().to_path_buf();
+            drop(download);
+            assert!(!run_dir.exists());
+        }
+    }
+
+    #[test]
+    fn test_keep_download_after_install() {
+        let dir = test_dir("keep_download");
+        let archive = dir.join("release.tar");
+        write_tar(&archive, &[("app.txt", "new")]);
+        let body = fs::read(&archive).unwrap();
+        let server = TestServer::start(move |req| match req.header("if-none-match") {
+            Some(_) => Response::status(304),
+            None => Response {
+                status: 200,
+                headers: vec![("ETag".to_string(), "\"v2\"".to_string())],
+                body: body.clone(),
+            },
+        });
+        let kept_dir = dir.join("kept");
+        let opts = InstallOptions {
+            dest: Some(dir.join("dest")),
+            stream: true,
+            cache: Some(DownloadCache::new(dir.join("cache"))),
+            keep_download: Some(kept_dir.clone()),
+            ..Default::default()
+        };
+        let url = server.url("/release.tar");
+        for source in [ArtifactSource::Network, ArtifactSource::Cache] {
+            let summary = install(&url, &opts).unwrap();
+            assert_eq!(summary.source, Some(source));
+            let kept = summary.kept_download.unwrap();
+            assert_eq!(kept.path, kept_dir.join("release.tar"));
+            assert_eq!(kept.sha256, sha256_file(&archive).unwrap());
+            assert_eq!(fs::read_to_string(dir.join("dest/app.txt")).unwrap(), "new");
+        }
+        // The cache still holds its copy
+        assert!(opts.cache.as_ref().unwrap().lookup(&url).is_some());
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_install_downloads_into_download_dir() {
+        let dir = test_dir("download_dir");
+        let archive = dir.join("release.tar");
+        write_tar(&archive, &[("app.txt", "new")]);
+        let body = fs::read(&archive).unwrap();
+        let server = TestServer::start(move |_| Response {
+            status: 200,
+            headers: vec![("Accept-Ranges".to_string(), "bytes".to_string())],
+            body: body.clone(),
+        });
+        let download_dir = dir.join("downloads/nested");
+        let opts = InstallOptions {
+            dest: Some(dir.join("dest")),
+            download_dir: Some(download_dir.clone()),
+            ..Default::default()
+        };
+        let file = download_archive(&server.url("/release.tar"), &opts).unwrap();
+        assert!(file.starts_with(&download_dir));
+        install(&server.url("/release.tar"), &opts).unwrap();
+        assert_eq!(fs::read_to_string(dir.join("dest/app.txt")).unwrap(), "new");
+        // Only the run directory handed out by download_archive remains
+        assert_eq!(fs::read_dir(&download_dir).unwrap().count(), 1);
+        let staged = InstallOptions {
+            work_dir: Some(download_dir.join("run")),
+            ..opts.clone()
+        };
+        let parent = staging_parent(&dir.join("dest"), &staged);
+        if cfg!(unix) {
+            assert_eq!(parent, download_dir.join("run"));
+        }
+        drop(file);
+
+        let opts = InstallOptions {
+            download_dir: Some(archive.join("downloads")),
+            ..opts
+        };
+        let e = install(&server.url("/release.tar"), &opts).unwrap_err();
+        assert!(
+            e.to_string()
+                .contains("Cannot create a temporary directory")
+        );
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_download_ignores_path_in_content_disposition() {
+        let server = TestServer::start(|req| {
+            let disposition = match req.path.as_str() {
+                "/traversal/app.zip" => r#"attachment; filename="../../.profile""#,
+                "/absolute/app.zip" => "attachment; filename=/etc/cron.d/x",
+                "/encoded/app.zip" => "attachment; filename*=UTF-8''..%2F..%2Fevil.zip",
+                _ => "attachment; filename=\"..\"",
+            };
+            Response {
+                status: 200,
+                headers: vec![("Content-Disposition".to_string(), disposition.to_string())],
+                body: b"artifact".to_vec(),
+            }
+        });
+        for (path, expected) in [
+            ("/traversal/app.zip", ".profile"),
+            ("/absolute/app.zip", "x"),
+            ("/encoded/app.zip", "evil.zip"),
+            ("/dots/app.zip", "app.zip"),
+        ] {
+            let file = download_archive(&server.url(path), &InstallOptions::default()).unwrap();
+            assert_eq!(file.file_name().unwrap(), expected);
+            assert!(file.parent().unwrap().starts_with(temp_root()));
+            fs::remove_dir_all(file.parent().unwrap()).unwrap();
+        }
+    }
+
+    #[test]
+    fn test_probe_reports_size_and_final_url() {
+        let server = TestServer::start(|req| match (req.method.as_str(), req.path.as_str()) {
+            (_, "/latest.tar.gz") => Response {
+                status: 302,
+                headers: vec![("Location".to_string(), "/v2/app.tar.gz".to_string())],
+                body: vec![],
+            },
+            ("HEAD", "/v2/app.tar.gz") => Response {
+                status: 200,
+                headers: vec![("Content-Length".to_string(), "1234".to_string())],
+                body: vec![],
+            },
+            ("HEAD", "/presigned.zip") => Response::status(403),
+            ("GET", "/presigned.zip") => Response {
+                status: 206,
+                headers: vec![("Content-Range".to_string(), "bytes 0-0/5678".to_string())],
+                body: b"P".to_vec(),
+            },
+            _ => Response::status(404),
+        });
+        let opts = InstallOptions {
+            retry: RetryPolicy {
+                attempts: 1,
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let probe_result = probe(&server.url("/latest.tar.gz"), &opts).unwrap();
+        assert_eq!(
+            probe_result,
+            Probe {
+                status: 200,
+                size: Some(1234),
+                url: server.url("/v2/app.tar.gz"),
+            }
+        );
+        let probe_result = probe(&server.url("/presigned.zip"), &opts).unwrap();
+        assert_eq!((probe_result.status, probe_result.size), (206, Some(5678)));
+        let e = probe(&server.url("/typo.zip"), &opts).unwrap_err();
+        assert!(e.to_string().contains("404"), "{e}");
+    }
+
+    #[test]
+    fn test_install_appimage_replaces_running_one() {
+        let root = test_dir("appimage");
+        let dest = root.join("dest");
+        fs::create_dir_all(&dest).unwrap();
+        fs::write(dest.join("Editor.AppImage"), "old").unwrap();
+        let download = root.join("Editor-2.0.0-x86_64.AppImage");
+        fs::write(&download, "new").unwrap();
+
+        let opts = InstallOptions {
+            dest: Some(dest.clone()),
+            running_exe: Some(dest.join("Editor.AppImage")),
+            ..Default::default()
+        };
+        install_archive(&download, &opts).unwrap();
+        assert_eq!(
+            fs::read_to_string(dest.join("Editor.AppImage")).unwrap(),
+            "new"
+        );
+        #[cfg(unix)]
+        assert_eq!(mode(&dest.join("Editor.AppImage")) & 0o777, 0o755);
+        let aside = state_dir(&dest)
+            .join(REPLACED_DIR)
+            .join(format!("Editor.AppImage.{}.old", std::process::id()));
+        assert_eq!(fs::read_to_string(aside).unwrap(), "old");
+
+        // Without a running AppImage it is installed under its own name
+        let opts = InstallOptions {
+            running_exe: None,
+            ..opts
+        };
+        install_archive(&download, &opts).unwrap();
+        assert_eq!(
+            fs::read_to_string(dest.join("Editor-2.0.0-x86_64.AppImage")).unwrap(),
+            "new"
+        );
+        fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn test_install_git_tag() {
+        let root = test_dir("git");
+        let repo = root.join("repo");
+        fs::create_dir_all(repo.join("docs")).unwrap();
+        let git = |args: &[&str]| {
+            let status = std::process::Command::new("git")
+                .args(["-c", "user.name=test", "-c", "user.email=test@example.com"])
+                .arg("-C")
+                .arg(&repo)
+                .args(args)
+                .output()
+                .unwrap();
+            assert!(status.status.success(), "{status:?}");
+            String::from_utf8(status.stdout).unwrap().trim().to_string()
+        };
+        git(&["init", "--quiet"]);
+        fs::write(repo.join("tool.sh"), "v1").unwrap();
+        fs::write(repo.join("docs/readme.md"), "docs").unwrap();
+        git(&["add", "."]);
+        git(&["commit", "--quiet", "-m", "v1"]);
+        git(&["tag", "v1.0.0"]);
+        let v1 = git(&["rev-parse", "HEAD"]);
+        fs::write(repo.join("tool.sh"), "v2").unwrap();
+        git(&["commit", "--quiet", "-am", "v2"]);
+
+        let opts = InstallOptions {
+            dest: Some(root.join("dest")),
+            exclude: vec!["docs/**".to_string()],
+            cache: Some(DownloadCache::new(root.join("cache"))),
+            ..Default::default()
+        };
+        let loc = format!("git+file://{}#v1.0.0", repo.display());
+        let summary = install(&loc, &opts).unwrap();
+        assert_eq!(summary.git.unwrap().commit, v1);
+        assert_eq!(fs::read_to_string(root.join("dest/tool.sh")).unwrap(), "v1");
+        assert!(!root.join("dest/docs").exists());
+
+        // The cached clone is fetched into again for the next ref
+        let loc = format!("git+file://{}", repo.display());
+        install(&loc, &opts).unwrap();
+        assert_eq!(fs::read_to_string(root.join("dest/tool.sh")).unwrap(), "v2");
+
+        let e = install(&format!("{loc}#no-such-tag"), &opts).unwrap_err();
+        assert!(e.to_string().contains("git fetch failed"), "{e}");
+        fs::remove_dir_all(root).unwrap();
+    }
 }
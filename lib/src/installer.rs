@@ -0,0 +1,171 @@
+use std::{
+    io::{self, Error},
+    path::Path,
+    process::Command,
+};
+
+use serde::{Deserialize, Serialize};
+
+/// A native installer run for an `.msi`, `.deb` or `.rpm` artifact.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct InstallerRun {
+    /// The command line, for logs and audits.
+    pub command: String,
+    /// Exit code of the installer, unset for a dry run.
+    pub exit_code: Option<i32>,
+    /// Combined stdout and stderr of the installer.
+    pub output: String,
+}
+
+/// Whether `path` is a package handed to the system installer.
+pub fn is_system_package(path: &Path) -> bool {
+    path.extension()
+        .and_then(|ext| ext.to_str())
+        .is_some_and(|ext| matches!(ext.to_ascii_lowercase().as_str(), "msi" | "deb" | "rpm"))
+}
+
+/// Candidate command lines for installing `path`, tried in order until one of the
+/// programs exists.
+fn candidates(path: &Path) -> Vec<(&'static str, Vec<String>)> {
+    let file = path.display().to_string();
+    let ext = path
+        .extension()
+        .and_then(|ext| ext.to_str())
+        .unwrap_or_default()
+        .to_ascii_lowercase();
+    match ext.as_str() {
+        "msi" => vec![(
+            "msiexec",
+            vec![
+                "/i".to_string(),
+                file,
+                "/qn".to_string(),
+                "/norestart".to_string(),
+            ],
+        )],
+        "deb" => vec![
+            ("dpkg", vec!["-i".to_string(), file.clone()]),
+            // apt-get only treats arguments as files when they look like paths
+            (
+                "apt-get",
+                vec!["install".to_string(), "-y".to_string(), path_arg(path)],
+            ),
+        ],
+        "rpm" => vec![("rpm", vec!["-U".to_string(), file])],
+        _ => vec![],
+    }
+}
+
+fn path_arg(path: &Path) -> String {
+    if path.is_absolute() {
+        path.display().to_string()
+    } else {
+        format!("./{}", path.display())
+    }
+}
+
+fn command_line(program: &str, args: &[String]) -> String {
+    std::iter::once(program)
+        .chain(args.iter().map(String::as_str))
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// Installs the package at `path` with the system installer. Fails with the output of
+/// the installer when it exits unsuccessfully.
+pub fn run_system_installer(path: &Path, dry_run: bool) -> io::Result<InstallerRun> {
+    let candidates = candidates(path);
+    let Some((first, _)) = candidates.first() else {
+        return Err(Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("'{}' is not a system installer package", path.display()),
+        ));
+    };
+    if dry_run {
+        let (program, args) = &candidates[0];
+        return Ok(InstallerRun {
+            command: command_line(program, args),
+            exit_code: None,
+            output: String::new(),
+        });
+    }
+    for (program, args) in &candidates {
+        match run(program, args) {
+            Err(e) if e.kind() == io::ErrorKind::NotFound => continue,
+            result => return result,
+        }
+    }
+    Err(Error::new(
+        io::ErrorKind::NotFound,
+        format!(
+            "{first} not found, it is needed to install '{}'",
+            path.display()
+        ),
+    ))
+}
+
+fn run(program: &str, args: &[String]) -> io::Result<InstallerRun> {
+    let command = command_line(program, args);
+    let output = Command::new(program).args(args).output()?;
+    let mut text = String::from_utf8_lossy(&output.stdout).to_string();
+    text.push_str(&String::from_utf8_lossy(&output.stderr));
+    if !output.status.success() {
+        return Err(Error::other(format!(
+            "'{command}' failed with {}: {}",
+            output.status,
+            text.trim()
+        )));
+    }
+    Ok(InstallerRun {
+        command,
+        exit_code: output.status.code(),
+        output: text,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use super::*;
+
+    #[test]
+    fn test_candidates() {
+        assert!(is_system_package(Path::new("app-1.2.deb")));
+        assert!(is_system_package(Path::new("Setup.MSI")));
+        assert!(!is_system_package(Path::new("app.tar.gz")));
+        let deb = candidates(Path::new("app.deb"));
+        assert_eq!(command_line(deb[0].0, &deb[0].1), "dpkg -i app.deb");
+        assert_eq!(
+            command_line(deb[1].0, &deb[1].1),
+            "apt-get install -y ./app.deb"
+        );
+        let rpm = PathBuf::from("/tmp/app.rpm");
+        let rpm = candidates(&rpm);
+        assert_eq!(command_line(rpm[0].0, &rpm[0].1), "rpm -U /tmp/app.rpm");
+    }
+
+    #[test]
+    fn test_dry_run_reports_command() {
+        let run = run_system_installer(Path::new("setup.msi"), true).unwrap();
+        assert_eq!(run.command, "msiexec /i setup.msi /qn /norestart");
+        assert_eq!(run.exit_code, None);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_run_maps_exit_status() {
+        let args = ["-c".to_string(), "echo done".to_string()];
+        let ok = run("sh", &args).unwrap();
+        assert_eq!(ok.exit_code, Some(0));
+        assert_eq!(ok.output.trim(), "done");
+
+        let args = ["-c".to_string(), "echo broken >&2; exit 3".to_string()];
+        let e = run("sh", &args).unwrap_err();
+        assert!(e.to_string().contains("exit status: 3"), "{e}");
+        assert!(e.to_string().contains("broken"), "{e}");
+
+        let e = run("wasupdate-no-such-installer", &[]).unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::NotFound);
+    }
+}
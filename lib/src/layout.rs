@@ -0,0 +1,182 @@
+use std::{
+    fs,
+    io::{self, Error},
+    path::{Path, PathBuf},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use semver::Version;
+
+use crate::{backup::RollbackReport, files::remove_entry};
+
+pub const VERSIONS_DIR: &str = "versions";
+pub const CURRENT_LINK: &str = "current";
+
+pub fn version_dir(dest: &Path, version: &str) -> PathBuf {
+    dest.join(VERSIONS_DIR).join(version)
+}
+
+/// Whether `dest` uses the side-by-side layout, with a `current` link into `versions/`.
+pub fn is_versioned(dest: &Path) -> bool {
+    fs::symlink_metadata(dest.join(CURRENT_LINK)).is_ok() && dest.join(VERSIONS_DIR).is_dir()
+}
+
+/// The version the `current` link points to.
+pub fn active_version(dest: &Path) -> Option<String> {
+    let target = fs::read_link(dest.join(CURRENT_LINK)).ok()?;
+    Some(target.file_name()?.to_string_lossy().to_string())
+}
+
+/// Installed versions, newest first. Versions that are not semver sort after the ones
+/// that are.
+pub fn installed_versions(dest: &Path) -> io::Result<Vec<String>> {
+    let versions_dir = dest.join(VERSIONS_DIR);
+    if !versions_dir.is_dir() {
+        return Ok(vec![]);
+    }
+    let mut versions = vec![];
+    for entry in fs::read_dir(versions_dir)? {
+        let entry = entry?;
+        if entry.file_type()?.is_dir() {
+            versions.push(entry.file_name().to_string_lossy().to_string());
+        }
+    }
+    versions.sort_by_cached_key(|v| {
+        let parsed = Version::parse(v.trim_start_matches(['v', 'V'])).ok();
+        (parsed.is_none(), std::cmp::Reverse(parsed), v.clone())
+    });
+    Ok(versions)
+}
+
+/// Points the `current` link at `versions/<version>`. On unix the new link is renamed
+/// over the old one, so the switch is atomic.
+pub fn activate(dest: &Path, version: &str) -> io::Result<PathBuf> {
+    let target = version_dir(dest, version);
+    if !target.is_dir() {
+        return Err(Error::new(
+            io::ErrorKind::NotFound,
+            format!("Version directory '{}' does not exist", target.display()),
+        ));
+    }
+    link_current(dest, version)?;
+    Ok(target)
+}
+
+#[cfg(unix)]
+fn link_current(dest: &Path, version: &str) -> io::Result<()> {
+    let link = dest.join(CURRENT_LINK);
+    let new_link = dest.join(format!("{CURRENT_LINK}.new-{}", std::process::id()));
+    if fs::symlink_metadata(&new_link).is_ok() {
+        fs::remove_file(&new_link)?;
+    }
+    std::os::unix::fs::symlink(Path::new(VERSIONS_DIR).join(version), &new_link)?;
+    fs::rename(new_link, link)
+}
+
+/// Junctions cannot be replaced atomically, so the old one is removed first.
+#[cfg(windows)]
+fn link_current(dest: &Path, version: &str) -> io::Result<()> {
+    let link = dest.join(CURRENT_LINK);
+    if fs::symlink_metadata(&link).is_ok() {
+        fs::remove_dir(&link)?;
+    }
+    let target = version_dir(dest, version).canonicalize()?;
+    let status = std::process::Command::new("cmd")
+        .args(["/C", "mklink", "/J"])
+        .arg(&link)
+        .arg(target)
+        .stdout(std::process::Stdio::null())
+        .status()?;
+    if !status.success() {
+        return Err(Error::other(format!(
+            "Failed to create junction '{}'",
+            link.display()
+        )));
+    }
+    Ok(())
+}
+
+/// Removes installed versions beyond the `keep` newest ones, never removing the active
+/// version. Returns the removed versions.
+pub fn prune(dest: &Path, keep: usize) -> io::Result<Vec<String>> {
+    let active = active_version(dest);
+    let mut removed = vec![];
+    for version in installed_versions(dest)?.into_iter().skip(keep.max(1)) {
+        if Some(&version) == active.as_ref() {
+            continue;
+        }
+        remove_entry(&version_dir(dest, &version))?;
+        removed.push(version);
+    }
+    Ok(removed)
+}
+
+/// Points the `current` link back at the newest retained version older than the
+/// active one.
+pub fn rollback(dest: &Path) -> io::Result<RollbackReport> {
+    let active = active_version(dest).ok_or_else(|| {
+        Error::new(
+            io::ErrorKind::NotFound,
+            format!("No active version found in '{}'", dest.display()),
+        )
+    })?;
+    let versions = installed_versions(dest)?;
+    let previous = versions
+        .iter()
+        .skip_while(|v| **v != active)
+        .nth(1)
+        .ok_or_else(|| {
+            Error::new(
+                io::ErrorKind::NotFound,
+                format!(
+                    "No version older than {active} is retained in '{}'",
+                    dest.display()
+                ),
+            )
+        })?;
+    activate(dest, previous)?;
+    let timestamp = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_err(Error::other)?
+        .as_secs();
+    Ok(RollbackReport {
+        version: Some(previous.clone()),
+        timestamp,
+        restored: vec![Path::new(VERSIONS_DIR).join(previous)],
+        removed: vec![],
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[cfg(unix)]
+    #[test]
+    fn test_activate_prune_and_rollback() {
+        let dest = std::env::temp_dir().join(format!("wasupdate-layout-{}", std::process::id()));
+        for version in ["0.9.0", "1.0.0", "1.1.0", "1.10.0"] {
+            fs::create_dir_all(version_dir(&dest, version)).unwrap();
+        }
+        assert_eq!(
+            installed_versions(&dest).unwrap(),
+            vec!["1.10.0", "1.1.0", "1.0.0", "0.9.0"]
+        );
+
+        activate(&dest, "1.1.0").unwrap();
+        assert!(is_versioned(&dest));
+        assert_eq!(active_version(&dest).as_deref(), Some("1.1.0"));
+        assert_eq!(
+            fs::read_link(dest.join(CURRENT_LINK)).unwrap(),
+            Path::new("versions/1.1.0")
+        );
+
+        assert_eq!(prune(&dest, 2).unwrap(), vec!["1.0.0", "0.9.0"]);
+        activate(&dest, "1.10.0").unwrap();
+        let report = rollback(&dest).unwrap();
+        assert_eq!(report.version.as_deref(), Some("1.1.0"));
+        assert_eq!(active_version(&dest).as_deref(), Some("1.1.0"));
+        assert!(rollback(&dest).is_err());
+        fs::remove_dir_all(dest).unwrap();
+    }
+}
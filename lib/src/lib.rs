@@ -1,6 +1,34 @@
+pub mod backup;
+pub mod cache;
+pub mod checksum;
+pub mod chunked;
+pub mod files;
+#[cfg(feature = "ftp")]
+pub mod ftp;
+pub mod git;
+pub mod http;
 pub mod install;
+pub mod installer;
+pub mod layout;
+pub mod manifest;
+pub mod patch;
 pub mod print;
+#[cfg(any(feature = "sftp", feature = "ftp"))]
+mod remote;
+pub mod retry;
 pub mod rhai;
+#[cfg(feature = "s3")]
+pub mod s3;
+#[cfg(feature = "sftp")]
+pub mod sftp;
+pub mod signature;
+pub mod temp;
+#[cfg(test)]
+mod test_server;
 pub mod utilities;
+pub mod version;
+
+#[cfg(not(any(feature = "native-tls", feature = "rustls")))]
+compile_error!("Enable the 'native-tls' or the 'rustls' feature for HTTPS support");
 
 pub static mut STDOUT_WRITE: bool = false;
@@ -0,0 +1,259 @@
+use std::{
+    fs,
+    io::{self, Error},
+    path::{Path, PathBuf},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    backup::{STATE_DIR, state_dir},
+    checksum::sha256_file,
+    install::{FileAction, FileOperation, build_globset},
+};
+
+pub const MANIFEST_FILE: &str = ".wasupdate-manifest.json";
+const PREVIOUS_MANIFEST: &str = "manifest.prev.json";
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ManifestEntry {
+    pub action: FileAction,
+    pub path: PathBuf,
+    pub size: u64,
+    /// Digest of the file contents, absent for symlinks.
+    pub sha256: Option<String>,
+}
+
+/// Record of the files an install created, overwrote or deleted in the install
+/// directory, with their sizes and digests.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Manifest {
+    pub version: Option<String>,
+    pub timestamp: u64,
+    pub files: Vec<ManifestEntry>,
+}
+
+impl ManifestEntry {
+    /// Describes the file at `op.path` in `dest` as it currently is.
+    pub fn describe(dest: &Path, op: &FileOperation) -> io::Result<ManifestEntry> {
+        let path = dest.join(&op.path);
+        let meta = fs::symlink_metadata(&path)?;
+        let sha256 = if meta.is_symlink() {
+            None
+        } else {
+            Some(sha256_file(&path)?)
+        };
+        Ok(ManifestEntry {
+            action: op.action,
+            path: op.path.clone(),
+            size: meta.len(),
+            sha256,
+        })
+    }
+}
+
+impl Manifest {
+    pub fn new(version: Option<String>, mut files: Vec<ManifestEntry>) -> io::Result<Manifest> {
+        files.sort_by(|a, b| a.path.cmp(&b.path));
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_err(Error::other)?
+            .as_secs();
+        Ok(Manifest {
+            version,
+            timestamp,
+            files,
+        })
+    }
+
+    /// Number of files written by the install.
+    pub fn file_count(&self) -> usize {
+        self.written().count()
+    }
+
+    /// Total size of the files written by the install.
+    pub fn total_bytes(&self) -> u64 {
+        self.written().map(|entry| entry.size).sum()
+    }
+
+    fn written(&self) -> impl Iterator<Item = &ManifestEntry> {
+        self.files
+            .iter()
+            .filter(|entry| entry.action != FileAction::Delete)
+    }
+
+    /// Writes the manifest into `dest`, keeping the previous one in the state directory.
+    pub fn save(&self, dest: &Path) -> io::Result<()> {
+        let path = dest.join(MANIFEST_FILE);
+        if path.exists() {
+            let state = state_dir(dest);
+            fs::create_dir_all(&state)?;
+            fs::rename(&path, state.join(PREVIOUS_MANIFEST))?;
+        }
+        let manifest = serde_json::to_string_pretty(self).map_err(Error::other)?;
+        fs::write(path, manifest)
+    }
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct VerifyReport {
+    /// Files written by the last install that no longer exist.
+    pub missing: Vec<PathBuf>,
+    /// Files whose size or contents differ from what the last install wrote.
+    pub modified: Vec<PathBuf>,
+    /// Files in the directories written by the last install that it did not write.
+    pub extraneous: Vec<PathBuf>,
+}
+
+impl VerifyReport {
+    pub fn is_clean(&self) -> bool {
+        self.missing.is_empty() && self.modified.is_empty() && self.extraneous.is_empty()
+    }
+}
+
+/// Reads the manifest of the last install into `dest`.
+pub fn read_manifest(dest: &Path) -> io::Result<Manifest> {
+    read_manifest_file(&dest.join(MANIFEST_FILE))
+}
+
+/// Checks the install directory containing `manifest_path` against the files the
+/// manifest records, leaving out paths matched by the `ignore` globs.
+pub fn verify(manifest_path: &Path, ignore: &[String]) -> io::Result<VerifyReport> {
+    let manifest = read_manifest_file(manifest_path)?;
+    let dest = manifest_path.parent().unwrap_or(Path::new("."));
+    let ignore = build_globset(ignore)?;
+    let mut report = VerifyReport::default();
+    let mut dirs = vec![];
+    let mut written = vec![];
+    for entry in manifest.files {
+        if entry.action == FileAction::Delete || ignore.is_match(&entry.path) {
+            continue;
+        }
+        let parent = entry.path.parent().unwrap_or(Path::new("")).to_path_buf();
+        if !dirs.contains(&parent) {
+            dirs.push(parent);
+        }
+        written.push(entry.path.clone());
+        let path = dest.join(&entry.path);
+        let Ok(meta) = fs::symlink_metadata(&path) else {
+            report.missing.push(entry.path);
+            continue;
+        };
+        let modified = match &entry.sha256 {
+            _ if meta.len() != entry.size => true,
+            Some(sha256) => !meta.is_file() || sha256_file(&path)? != *sha256,
+            None => !meta.is_symlink(),
+        };
+        if modified {
+            report.modified.push(entry.path);
+        }
+    }
+    for dir in dirs {
+        let Ok(entries) = fs::read_dir(dest.join(&dir)) else {
+            continue;
+        };
+        for entry in entries {
+            let entry = entry?;
+            let path = dir.join(entry.file_name());
+            let bookkeeping = dir.as_os_str().is_empty()
+                && (entry.file_name() == STATE_DIR || entry.file_name() == MANIFEST_FILE);
+            if entry.file_type()?.is_dir()
+                || bookkeeping
+                || written.contains(&path)
+                || ignore.is_match(&path)
+            {
+                continue;
+            }
+            report.extraneous.push(path);
+        }
+    }
+    report.extraneous.sort();
+    Ok(report)
+}
+
+fn read_manifest_file(path: &Path) -> io::Result<Manifest> {
+    let manifest = fs::read_to_string(path).map_err(|e| match e.kind() {
+        io::ErrorKind::NotFound => Error::new(
+            io::ErrorKind::NotFound,
+            format!("No install manifest found at '{}'", path.display()),
+        ),
+        _ => e,
+    })?;
+    serde_json::from_str(&manifest).map_err(|e| {
+        Error::new(
+            io::ErrorKind::InvalidData,
+            format!("Install manifest at '{}' is invalid: {e}", path.display()),
+        )
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_save_rotates_previous_manifest() {
+        let dest = std::env::temp_dir().join(format!("wasupdate-manifest-{}", std::process::id()));
+        fs::create_dir_all(&dest).unwrap();
+        fs::write(dest.join("app"), "app").unwrap();
+        let op = FileOperation {
+            action: FileAction::Create,
+            path: PathBuf::from("app"),
+        };
+        let entry = ManifestEntry::describe(&dest, &op).unwrap();
+        assert_eq!(entry.size, 3);
+        assert_eq!(entry.sha256, Some(sha256_file(&dest.join("app")).unwrap()));
+
+        Manifest::new(Some("1.0.0".to_string()), vec![entry.clone()])
+            .unwrap()
+            .save(&dest)
+            .unwrap();
+        Manifest::new(Some("1.1.0".to_string()), vec![entry])
+            .unwrap()
+            .save(&dest)
+            .unwrap();
+        let manifest = read_manifest(&dest).unwrap();
+        assert_eq!(manifest.version.as_deref(), Some("1.1.0"));
+        assert_eq!(manifest.file_count(), 1);
+        assert_eq!(manifest.total_bytes(), 3);
+        let previous = fs::read_to_string(state_dir(&dest).join(PREVIOUS_MANIFEST)).unwrap();
+        assert!(previous.contains("1.0.0"));
+        fs::remove_dir_all(dest).unwrap();
+    }
+
+    #[test]
+    fn test_verify_reports_drift() {
+        let dest =
+            std::env::temp_dir().join(format!("wasupdate-manifest-{}-verify", std::process::id()));
+        fs::create_dir_all(dest.join("bin")).unwrap();
+        let mut files = vec![];
+        for (name, content) in [
+            ("bin/app", "app"),
+            ("bin/tool", "tool"),
+            ("config.toml", "defaults"),
+            ("README", "readme"),
+        ] {
+            fs::write(dest.join(name), content).unwrap();
+            let op = FileOperation {
+                action: FileAction::Create,
+                path: PathBuf::from(name),
+            };
+            files.push(ManifestEntry::describe(&dest, &op).unwrap());
+        }
+        Manifest::new(None, files).unwrap().save(&dest).unwrap();
+        let manifest_path = dest.join(MANIFEST_FILE);
+        assert!(verify(&manifest_path, &[]).unwrap().is_clean());
+
+        fs::write(dest.join("bin").join("app"), "ap!").unwrap();
+        fs::remove_file(dest.join("README")).unwrap();
+        fs::write(dest.join("config.toml"), "edited").unwrap();
+        fs::write(dest.join("bin").join("stray"), "stray").unwrap();
+        let report = verify(&manifest_path, &["*.toml".to_string()]).unwrap();
+        assert_eq!(report.missing, vec![PathBuf::from("README")]);
+        assert_eq!(report.modified, vec![PathBuf::from("bin/app")]);
+        assert_eq!(report.extraneous, vec![PathBuf::from("bin/stray")]);
+        assert!(!report.is_clean());
+        fs::remove_dir_all(dest).unwrap();
+    }
+}
@@ -0,0 +1,159 @@
+use std::io::{self, Error, Read};
+
+use bzip2::read::BzDecoder;
+
+const BSDIFF_MAGIC: &[u8; 8] = b"BSDIFF40";
+const HEADER_LEN: usize = 32;
+/// Largest output a patch may produce. The length comes from the patch header and the
+/// output is allocated up front, so it is capped before trusting it.
+pub const MAX_NEW_LEN: usize = 1 << 30;
+
+/// Reads an 8 byte sign-magnitude integer as written by bsdiff.
+fn offtin(buf: [u8; 8]) -> i64 {
+    let magnitude = i64::from_le_bytes(buf) & i64::MAX;
+    if buf[7] & 0x80 != 0 {
+        -magnitude
+    } else {
+        magnitude
+    }
+}
+
+fn read_offtin(reader: &mut impl Read) -> io::Result<i64> {
+    let mut buf = [0; 8];
+    reader.read_exact(&mut buf)?;
+    Ok(offtin(buf))
+}
+
+fn corrupt(msg: &str) -> Error {
+    Error::new(io::ErrorKind::InvalidData, format!("Corrupt patch: {msg}"))
+}
+
+/// Applies a patch in the `BSDIFF40` format produced by bsdiff to `old`.
+pub fn bspatch(old: &[u8], patch: &[u8]) -> io::Result<Vec<u8>> {
+    if patch.len() < HEADER_LEN || &patch[..8] != BSDIFF_MAGIC {
+        return Err(corrupt("missing BSDIFF40 header"));
+    }
+    let header = |i: usize| offtin(patch[i..i + 8].try_into().unwrap());
+    let (ctrl_len, diff_len, new_len) = (header(8), header(16), header(24));
+    let (Ok(ctrl_len), Ok(diff_len), Ok(new_len)) = (
+        usize::try_from(ctrl_len),
+        usize::try_from(diff_len),
+        usize::try_from(new_len),
+    ) else {
+        return Err(corrupt("negative block length"));
+    };
+    if new_len > MAX_NEW_LEN {
+        return Err(corrupt(&format!(
+            "output of {new_len} bytes exceeds the limit of {MAX_NEW_LEN} bytes"
+        )));
+    }
+    let diff_start = HEADER_LEN
+        .checked_add(ctrl_len)
+        .filter(|start| *start <= patch.len())
+        .ok_or_else(|| corrupt("control block out of bounds"))?;
+    let extra_start = diff_start
+        .checked_add(diff_len)
+        .filter(|start| *start <= patch.len())
+        .ok_or_else(|| corrupt("diff block out of bounds"))?;
+    let mut ctrl = BzDecoder::new(&patch[HEADER_LEN..diff_start]);
+    let mut diff = BzDecoder::new(&patch[diff_start..extra_start]);
+    let mut extra = BzDecoder::new(&patch[extra_start..]);
+
+    let mut new = vec![0; new_len];
+    let mut new_pos = 0usize;
+    let mut old_pos = 0i64;
+    while new_pos < new_len {
+        let add_len = read_offtin(&mut ctrl)?;
+        let copy_len = read_offtin(&mut ctrl)?;
+        let seek = read_offtin(&mut ctrl)?;
+        let add_end = usize::try_from(add_len)
+            .ok()
+            .and_then(|len| new_pos.checked_add(len))
+            .filter(|end| *end <= new_len)
+            .ok_or_else(|| corrupt("diff runs past the output"))?;
+        diff.read_exact(&mut new[new_pos..add_end])?;
+        for (i, byte) in new[new_pos..add_end].iter_mut().enumerate() {
+            if let Some(old_byte) = usize::try_from(old_pos + i as i64)
+                .ok()
+                .and_then(|pos| old.get(pos))
+            {
+                *byte = byte.wrapping_add(*old_byte);
+            }
+        }
+        old_pos += add_len;
+        new_pos = add_end;
+
+        let copy_end = usize::try_from(copy_len)
+            .ok()
+            .and_then(|len| new_pos.checked_add(len))
+            .filter(|end| *end <= new_len)
+            .ok_or_else(|| corrupt("extra data runs past the output"))?;
+        extra.read_exact(&mut new[new_pos..copy_end])?;
+        new_pos = copy_end;
+        old_pos += seek;
+    }
+    Ok(new)
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use std::io::Write;
+
+    use bzip2::{Compression, write::BzEncoder};
+
+    use super::*;
+
+    fn offtout(n: i64) -> [u8; 8] {
+        let mut buf = n.unsigned_abs().to_le_bytes();
+        if n < 0 {
+            buf[7] |= 0x80;
+        }
+        buf
+    }
+
+    fn bz(data: &[u8]) -> Vec<u8> {
+        let mut encoder = BzEncoder::new(vec![], Compression::best());
+        encoder.write_all(data).unwrap();
+        encoder.finish().unwrap()
+    }
+
+    /// Builds a valid, if naive, bsdiff patch: the common length is added as a diff
+    /// against `old` and the rest of `new` is carried as extra data.
+    pub(crate) fn make_patch(old: &[u8], new: &[u8]) -> Vec<u8> {
+        let common = old.len().min(new.len());
+        let diff: Vec<u8> = (0..common).map(|i| new[i].wrapping_sub(old[i])).collect();
+        let mut ctrl = vec![];
+        ctrl.extend(offtout(common as i64));
+        ctrl.extend(offtout((new.len() - common) as i64));
+        ctrl.extend(offtout(0));
+        let (ctrl, diff, extra) = (bz(&ctrl), bz(&diff), bz(&new[common..]));
+
+        let mut patch = BSDIFF_MAGIC.to_vec();
+        patch.extend(offtout(ctrl.len() as i64));
+        patch.extend(offtout(diff.len() as i64));
+        patch.extend(offtout(new.len() as i64));
+        patch.extend(ctrl);
+        patch.extend(diff);
+        patch.extend(extra);
+        patch
+    }
+
+    #[test]
+    fn test_bspatch() {
+        let old = b"app version 1.0.0\n";
+        let new = b"app version 1.1.0\nwith a new line\n";
+        assert_eq!(bspatch(old, &make_patch(old, new)).unwrap(), new);
+        assert_eq!(bspatch(new, &make_patch(new, old)).unwrap(), old);
+        assert_eq!(offtin(offtout(-42)), -42);
+
+        let mut truncated = make_patch(old, new);
+        truncated.truncate(40);
+        assert!(bspatch(old, &truncated).is_err());
+        assert!(bspatch(old, b"not a patch").is_err());
+
+        let mut oversized = make_patch(old, new);
+        oversized[24..32].copy_from_slice(&offtout(i64::MAX));
+        let err = bspatch(old, &oversized).err().unwrap();
+        assert!(err.to_string().contains("exceeds the limit"), "{err}");
+    }
+}
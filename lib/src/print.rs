@@ -1,7 +1,90 @@
+use std::sync::atomic::{AtomicU8, Ordering};
+
 use console::{Emoji, style};
 
 use crate::STDOUT_WRITE;
 
+/// How messages logged by update scripts are shown.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LogFormat {
+    /// Styled lines on stdout.
+    Pretty,
+    /// One `script_log` JSON record per line on stderr, keeping stdout machine-readable.
+    Json,
+    /// Dropped, the default for library users.
+    Quiet,
+}
+
+static LOG_FORMAT: AtomicU8 = AtomicU8::new(LogFormat::Quiet as u8);
+
+pub fn set_log_format(format: LogFormat) {
+    LOG_FORMAT.store(format as u8, Ordering::Relaxed);
+}
+
+pub fn log_format() -> LogFormat {
+    match LOG_FORMAT.load(Ordering::Relaxed) {
+        0 => LogFormat::Pretty,
+        1 => LogFormat::Json,
+        _ => LogFormat::Quiet,
+    }
+}
+
+/// The line a script message at `level` is shown as, `None` when it is dropped.
+pub fn script_log_line(format: LogFormat, level: &str, message: &str) -> Option<String> {
+    match format {
+        LogFormat::Pretty => Some(match level {
+            "warn" => format!("{} {}", Emoji("⚠️", "!"), style(message).yellow()),
+            "info" => format!("{} {}", Emoji("💬", ">"), message),
+            _ => format!("  {}", style(message).dim()),
+        }),
+        LogFormat::Json => Some(
+            serde_json::json!({
+                "event": "script_log",
+                "level": level,
+                "message": message,
+            })
+            .to_string(),
+        ),
+        LogFormat::Quiet => None,
+    }
+}
+
+/// `text` wrapped at `width` columns and cut to `max_lines`, ending with a note on how
+/// many lines were left out.
+pub fn wrap_text(text: &str, width: usize, max_lines: usize) -> Vec<String> {
+    let mut lines = vec![];
+    for line in text.trim_end().lines() {
+        let indent = line.len() - line.trim_start().len();
+        let mut current = line[..indent].to_string();
+        for word in line.split_whitespace() {
+            let taken = current.chars().count();
+            if taken > indent && taken + 1 + word.chars().count() > width {
+                lines.push(std::mem::replace(&mut current, " ".repeat(indent)));
+            } else if current.chars().count() > indent {
+                current.push(' ');
+            }
+            current.push_str(word);
+        }
+        lines.push(current.trim_end().to_string());
+    }
+    if lines.len() > max_lines {
+        let hidden = lines.len() - max_lines;
+        lines.truncate(max_lines);
+        lines.push(format!("... {hidden} more lines"));
+    }
+    lines
+}
+
+/// Shows a message logged by an update script in the current [`LogFormat`].
+pub fn p_script_log(level: &str, message: &str) {
+    let format = log_format();
+    match script_log_line(format, level, message) {
+        Some(line) if format == LogFormat::Json => eprintln!("{line}"),
+        Some(line) => println!("{line}"),
+        None => {}
+    }
+}
+
 pub fn print_error(message: &str) {
     if unsafe { STDOUT_WRITE } {
         eprintln!("Error: {}", message);
@@ -34,3 +117,66 @@ pub fn p_success(msg: &str) {
     }
     println!("{} {}", Emoji("✅", "✔️"), style(msg).bold().underlined(),);
 }
+
+/// Shows a warning on stderr, so it stays out of output that is piped on.
+pub fn p_warn(msg: &str) {
+    if unsafe { !STDOUT_WRITE } {
+        return;
+    }
+    eprintln!("{} {}", Emoji("⚠️", "!"), style(msg).yellow());
+}
+
+/// Shows that a failed step is tried again, on stderr like [`p_warn`].
+pub fn p_retry(msg: &str) {
+    if unsafe { !STDOUT_WRITE } {
+        return;
+    }
+    eprintln!("{} {}", Emoji("🔁", "~"), style(msg).yellow());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_wrap_text() {
+        let text = "## 2.0.0\n\n- Faster startup on large projects\n  and lower memory use\n";
+        assert_eq!(
+            wrap_text(text, 20, 10),
+            vec![
+                "## 2.0.0",
+                "",
+                "- Faster startup on",
+                "large projects",
+                "  and lower memory",
+                "  use",
+            ]
+        );
+        assert_eq!(
+            wrap_text("a\nb\nc\nd", 80, 2),
+            vec!["a", "b", "... 2 more lines"]
+        );
+        assert_eq!(
+            wrap_text("unbreakable-long-word", 5, 2),
+            vec!["unbreakable-long-word"]
+        );
+    }
+
+    #[test]
+    fn test_script_log_line() {
+        let line = script_log_line(LogFormat::Json, "info", "checking \"beta\"").unwrap();
+        let record: serde_json::Value = serde_json::from_str(&line).unwrap();
+        assert_eq!(
+            record,
+            serde_json::json!({
+                "event": "script_log",
+                "level": "info",
+                "message": "checking \"beta\"",
+            })
+        );
+        let line = script_log_line(LogFormat::Pretty, "warn", "mirror is slow").unwrap();
+        assert!(line.contains("mirror is slow"), "{line}");
+        assert!(!line.contains('{'), "{line}");
+        assert_eq!(script_log_line(LogFormat::Quiet, "warn", "dropped"), None);
+    }
+}
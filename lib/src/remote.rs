@@ -0,0 +1,166 @@
+//! Pieces shared by the SFTP and FTP download backends.
+
+use std::{
+    fs::{self, File},
+    io::{self, Error, Read, Write},
+    path::{Path, PathBuf},
+};
+
+use crate::{
+    http,
+    install::{
+        ArtifactSource, DOWNLOAD_BUFFER_SIZE, Download, InstallOptions, download_progress,
+        sanitize_file_name, truncated_download,
+    },
+    print::p_warn,
+};
+
+/// A file on a server reached over SFTP or FTP, parsed from its URL.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RemoteFile {
+    pub host: String,
+    pub port: u16,
+    pub user: Option<String>,
+    pub password: Option<String>,
+    /// Path on the server, percent-decoded.
+    pub path: String,
+    pub file_name: String,
+}
+
+fn percent_decode(value: &str) -> io::Result<String> {
+    String::from_utf8(http::percent_decode(value)?)
+        .map_err(|e| Error::new(io::ErrorKind::InvalidInput, e))
+}
+
+impl RemoteFile {
+    pub fn parse(location: &str, default_port: u16) -> io::Result<RemoteFile> {
+        let invalid = |reason: &str| {
+            Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("Invalid location '{location}': {reason}"),
+            )
+        };
+        let url = reqwest::Url::parse(location).map_err(|e| invalid(&e.to_string()))?;
+        let host = url.host_str().ok_or_else(|| invalid("missing host"))?;
+        let path = percent_decode(url.path())?;
+        let file_name = path
+            .rsplit('/')
+            .next()
+            .and_then(sanitize_file_name)
+            .ok_or_else(|| invalid("missing file name"))?;
+        Ok(RemoteFile {
+            host: host
+                .trim_start_matches('[')
+                .trim_end_matches(']')
+                .to_string(),
+            port: url.port().unwrap_or(default_port),
+            user: Some(percent_decode(url.username())?).filter(|u| !u.is_empty()),
+            password: url.password().map(percent_decode).transpose()?,
+            path,
+            file_name,
+        })
+    }
+
+    /// Writes `source` into `dir` under the file name, with a progress bar
+    /// against `size` when the server told it. Fails when fewer bytes arrive.
+    pub fn save(
+        &self,
+        mut source: impl Read,
+        size: Option<u64>,
+        dir: &Path,
+    ) -> io::Result<PathBuf> {
+        let location = format!("{}:{}", self.host, self.path);
+        let pb = download_progress(size, &self.file_name);
+        let partial = dir.join(format!("{}.part", self.file_name));
+        let mut dest = File::create(&partial)?;
+        let mut buffer = vec![0; DOWNLOAD_BUFFER_SIZE];
+        let mut received = 0;
+        loop {
+            let n = source.read(&mut buffer).inspect_err(|_| {
+                let _ = fs::remove_file(&partial);
+            })?;
+            if n == 0 {
+                break;
+            }
+            dest.write_all(&buffer[..n])?;
+            received += n as u64;
+            pb.inc(n as u64);
+        }
+        if let Some(expected) = size
+            && received != expected
+        {
+            pb.abandon();
+            let _ = fs::remove_file(&partial);
+            return Err(truncated_download(&location, expected, received));
+        }
+        pb.finish_with_message("Download complete");
+        let temp_file = dir.join(&self.file_name);
+        fs::rename(&partial, &temp_file)?;
+        Ok(temp_file)
+    }
+}
+
+/// Copy of the cached download of `location` in the run directory, when the cache
+/// holds one with the same `validator`. The servers have no conditional requests, so
+/// the validator is made from the size and modification time they report.
+pub fn cached_copy(
+    location: &str,
+    validator: Option<&str>,
+    opts: &InstallOptions,
+) -> io::Result<Option<Download>> {
+    let (Some(cache), Some(validator)) = (&opts.cache, validator) else {
+        return Ok(None);
+    };
+    let Some((entry, cached)) = cache.lookup(location) else {
+        return Ok(None);
+    };
+    if entry.etag.as_deref() != Some(validator) {
+        return Ok(None);
+    }
+    let temp_file = opts.run_dir().join(cached.file_name().unwrap_or_default());
+    fs::copy(cached, &temp_file)?;
+    cache.touch(location)?;
+    Ok(Some(Download::new(temp_file, ArtifactSource::Cache)))
+}
+
+/// Keeps the download of `location` in the cache under its `validator`.
+pub fn cache_download(
+    location: &str,
+    validator: Option<String>,
+    path: PathBuf,
+    opts: &InstallOptions,
+) -> Download {
+    if let (Some(cache), Some(validator)) = (&opts.cache, validator)
+        && let Err(e) = cache.store(location, Some(validator), None, &path)
+    {
+        p_warn(&format!("Failed to cache the download of {location}: {e}"));
+    }
+    Download::new(path, ArtifactSource::Network)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_remote_file() {
+        let file = RemoteFile::parse("sftp://deploy@vendor.example:2222/fw/bundle%201.tar.gz", 22)
+            .unwrap();
+        assert_eq!(
+            file,
+            RemoteFile {
+                host: "vendor.example".to_string(),
+                port: 2222,
+                user: Some("deploy".to_string()),
+                password: None,
+                path: "/fw/bundle 1.tar.gz".to_string(),
+                file_name: "bundle 1.tar.gz".to_string(),
+            }
+        );
+        let file = RemoteFile::parse("ftp://user:p%40ss@[::1]/app.zip", 21).unwrap();
+        assert_eq!(file.host, "::1");
+        assert_eq!(file.port, 21);
+        assert_eq!(file.password.as_deref(), Some("p@ss"));
+        assert!(RemoteFile::parse("sftp://vendor.example/", 22).is_err());
+    }
+}
@@ -0,0 +1,208 @@
+use std::{
+    collections::hash_map::RandomState,
+    fmt,
+    hash::{BuildHasher, Hasher},
+    io::{self, Error},
+    sync::{
+        RwLock,
+        atomic::{AtomicU32, Ordering},
+    },
+    thread,
+    time::Duration,
+};
+
+use reqwest::{StatusCode, blocking::Response};
+
+use crate::{http::request_error, print::p_retry};
+
+/// How often and how patiently failed requests are retried.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RetryPolicy {
+    /// Total number of attempts, including the first one.
+    pub attempts: u32,
+    /// Delay before the first retry, doubled for every retry after it.
+    pub base_delay: Duration,
+    /// Upper bound of the delay between two attempts.
+    pub max_delay: Duration,
+}
+
+impl RetryPolicy {
+    pub const DEFAULT: RetryPolicy = RetryPolicy {
+        attempts: 3,
+        base_delay: Duration::from_millis(500),
+        max_delay: Duration::from_secs(30),
+    };
+
+    /// Delay before retry number `retry`, counting from zero. The exponential delay is
+    /// jittered down by up to half so clients failing together do not retry together.
+    pub fn delay(&self, retry: u32) -> Duration {
+        let delay = self
+            .base_delay
+            .saturating_mul(2u32.saturating_pow(retry))
+            .min(self.max_delay);
+        let jitter = RandomState::new().build_hasher().finish() % 1000;
+        delay / 2 + delay / 2 * jitter as u32 / 1000
+    }
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy::DEFAULT
+    }
+}
+
+/// Policy used by requests made from the update script.
+static POLICY: RwLock<RetryPolicy> = RwLock::new(RetryPolicy::DEFAULT);
+static RETRIES: AtomicU32 = AtomicU32::new(0);
+
+pub fn set_policy(policy: RetryPolicy) {
+    *POLICY.write().unwrap() = policy;
+}
+
+pub fn policy() -> RetryPolicy {
+    *POLICY.read().unwrap()
+}
+
+/// Number of retries made by this process so far.
+pub fn retry_count() -> u32 {
+    RETRIES.load(Ordering::Relaxed)
+}
+
+/// Why an attempt failed, deciding whether it is worth another try.
+#[derive(Debug)]
+pub enum Failure {
+    /// Network errors and server side failures that may pass.
+    Transient(Error),
+    /// Everything else, such as client errors and local I/O errors.
+    Permanent(Error),
+}
+
+impl From<io::Error> for Failure {
+    fn from(e: io::Error) -> Self {
+        Failure::Permanent(e)
+    }
+}
+
+impl Failure {
+    pub fn into_error(self) -> Error {
+        match self {
+            Failure::Transient(e) | Failure::Permanent(e) => e,
+        }
+    }
+}
+
+impl fmt::Display for Failure {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Failure::Transient(e) | Failure::Permanent(e) => e.fmt(f),
+        }
+    }
+}
+
+pub(crate) fn is_transient_status(status: StatusCode) -> bool {
+    status.is_server_error() || status == StatusCode::TOO_MANY_REQUESTS
+}
+
+/// Turns the result of sending a request into a response with a successful status,
+/// treating network errors, 5xx and 429 responses as transient.
+pub fn check_response(result: reqwest::Result<Response>) -> Result<Response, Failure> {
+    match result {
+        Err(e) if e.is_builder() => Err(Failure::Permanent(Error::other(e))),
+        Err(e) if e.is_redirect() => Err(Failure::Permanent(request_error(e))),
+        Err(e) => Err(Failure::Transient(request_error(e))),
+        Ok(response) if response.status() == StatusCode::PROXY_AUTHENTICATION_REQUIRED => {
+            Err(Failure::Permanent(Error::new(
+                io::ErrorKind::PermissionDenied,
+                format!(
+                    "The proxy rejected the credentials while requesting {}",
+                    response.url()
+                ),
+            )))
+        }
+        Ok(response) if is_transient_status(response.status()) => {
+            Err(Failure::Transient(Error::other(format!(
+                "{} responded with {}",
+                response.url(),
+                response.status()
+            ))))
+        }
+        Ok(response) if !response.status().is_success() => Err(Failure::Permanent(Error::other(
+            format!("{} responded with {}", response.url(), response.status()),
+        ))),
+        Ok(response) => Ok(response),
+    }
+}
+
+/// Runs `attempt` until it succeeds, fails permanently or runs out of attempts,
+/// sleeping with exponential backoff in between.
+pub fn retry<T>(
+    policy: &RetryPolicy,
+    what: &str,
+    mut attempt: impl FnMut() -> Result<T, Failure>,
+) -> io::Result<T> {
+    let mut retries = 0;
+    loop {
+        match attempt() {
+            Ok(value) => return Ok(value),
+            Err(Failure::Permanent(e)) => return Err(e),
+            Err(Failure::Transient(e)) if retries + 1 >= policy.attempts => return Err(e),
+            Err(Failure::Transient(e)) => {
+                let delay = policy.delay(retries);
+                retries += 1;
+                RETRIES.fetch_add(1, Ordering::Relaxed);
+                p_retry(&format!(
+                    "{what} failed: {e}. Retrying in {} ms ({}/{})",
+                    delay.as_millis(),
+                    retries + 1,
+                    policy.attempts
+                ));
+                thread::sleep(delay);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_delay_backs_off_with_jitter() {
+        let policy = RetryPolicy {
+            attempts: 5,
+            base_delay: Duration::from_millis(100),
+            max_delay: Duration::from_millis(300),
+        };
+        for _ in 0..20 {
+            let first = policy.delay(0);
+            assert!(first >= Duration::from_millis(50) && first <= Duration::from_millis(100));
+            let second = policy.delay(1);
+            assert!(second >= Duration::from_millis(100) && second <= Duration::from_millis(200));
+            assert!(policy.delay(10) <= Duration::from_millis(300));
+        }
+    }
+
+    #[test]
+    fn test_retry_stops_on_permanent_failures() {
+        let policy = RetryPolicy {
+            attempts: 3,
+            base_delay: Duration::from_millis(1),
+            ..Default::default()
+        };
+        let mut calls = 0;
+        let result: io::Result<()> = retry(&policy, "Test", || {
+            calls += 1;
+            Err(Failure::Transient(Error::other("reset")))
+        });
+        assert!(result.is_err());
+        assert_eq!(calls, 3);
+
+        let mut calls = 0;
+        let result: io::Result<()> = retry(&policy, "Test", || {
+            calls += 1;
+            Err(Failure::Permanent(Error::other("not found")))
+        });
+        assert!(result.is_err());
+        assert_eq!(calls, 1);
+    }
+}
@@ -1,212 +1,1441 @@
-use std::path::PathBuf;
+use std::{
+    any::TypeId,
+    collections::HashMap,
+    error, fmt,
+    path::{Path, PathBuf},
+    str::FromStr,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
 
-use regex::Regex;
-use rhai::{AST, Engine, EvalAltResult, Scope};
-use semver::Version;
+use rhai::{
+    AST, Array, Dynamic, Engine, EvalAltResult, FuncArgs, ImmutableString, Map, Module,
+    ModuleResolver, Position, RhaiNativeFunc, Scope, ScriptFnMetadata, Shared,
+};
+use semver::{Version, VersionReq};
+use serde::Serialize;
 
-use crate::utilities;
+use crate::{
+    checksum::Digest,
+    install::{Artifact, Patch},
+    print,
+    utilities::{self, FetchCache},
+    version::{ScriptVersion, VersionScheme},
+};
 
 pub type RhaiResult<T> = std::result::Result<T, Box<EvalAltResult>>;
 
+#[derive(Debug, Clone)]
 pub enum Script {
     File(PathBuf),
     Inline(String),
 }
 
+/// How the engine treats what the script returns.
+#[derive(Debug, Clone, Default)]
+pub struct EngineOptions {
+    /// Only accept semver versions with all three components, and versions of other
+    /// schemes as they are, without dropping a leading `v`.
+    pub strict_versions: bool,
+    /// Make the helpers that write files fail, for runs that promise no side effects
+    /// such as `--check` and `--dry-run`.
+    pub read_only: bool,
+    /// Scheme to read versions with, instead of the one of the script's `version_scheme`
+    /// function.
+    pub scheme: Option<VersionScheme>,
+    /// Let prereleases be the latest version, whatever the script's `allow_prerelease`
+    /// function returns.
+    pub allow_prerelease: bool,
+    /// Version of the running wasupdate, checked against the script's `requires_wasupdate`
+    /// function. Scripts are not checked without it.
+    pub wasupdate_version: Option<Version>,
+    /// Directories searched for modules the script imports, after the directory of the
+    /// script file.
+    pub module_paths: Vec<PathBuf>,
+    /// Values the script reads with `get_var(key)`, such as a tenant or an install root.
+    pub vars: HashMap<String, String>,
+    pub limits: ScriptLimits,
+    pub sandbox: SandboxLevel,
+}
+
+/// What a script may do besides computing. Functions a level denies stay registered and
+/// fail when called, so scripts report what they were not allowed to do.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum SandboxLevel {
+    /// Everything, including running commands and writing files.
+    #[default]
+    Full,
+    /// HTTP requests and downloads, but no commands or file writes.
+    Network,
+    /// Neither commands, file writes, network access, environment variables nor imported
+    /// modules.
+    Pure,
+}
+
+impl SandboxLevel {
+    pub const NAMES: [&str; 3] = ["full", "network", "pure"];
+
+    /// Whether scripts may do what `capability` stands for, nothing unless listed here.
+    fn allows(self, capability: Capability) -> bool {
+        matches!(
+            (self, capability),
+            (SandboxLevel::Full, _)
+                | (
+                    SandboxLevel::Network,
+                    Capability::Network | Capability::Env | Capability::Import
+                )
+        )
+    }
+}
+
+impl FromStr for SandboxLevel {
+    type Err = String;
+
+    fn from_str(name: &str) -> Result<Self, Self::Err> {
+        match name.trim().to_ascii_lowercase().as_str() {
+            "full" => Ok(SandboxLevel::Full),
+            "network" => Ok(SandboxLevel::Network),
+            "pure" => Ok(SandboxLevel::Pure),
+            _ => Err(format!(
+                "Unknown sandbox level '{name}', expected one of: {}",
+                SandboxLevel::NAMES.join(", ")
+            )),
+        }
+    }
+}
+
+impl fmt::Display for SandboxLevel {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            SandboxLevel::Full => "full",
+            SandboxLevel::Network => "network",
+            SandboxLevel::Pure => "pure",
+        };
+        f.write_str(name)
+    }
+}
+
+/// What a helper or an import needs the [`SandboxLevel`] to allow.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Capability {
+    Run,
+    Write,
+    Network,
+    Env,
+    Import,
+}
+
+fn sandbox_denied(func: &str, level: SandboxLevel) -> RhaiResult<Dynamic> {
+    Err(format!("Function '{func}' is disabled by sandbox policy '{level}'").into())
+}
+
+/// Registers `func` as the helper `name` when `level` allows `capability`, and otherwise
+/// one taking the same parameters that fails with [`sandbox_denied`].
+fn register_sandboxed<Func, A, const N: usize, const X: bool, R, const F: bool>(
+    engine: &mut Engine,
+    level: SandboxLevel,
+    capability: Capability,
+    name: &'static str,
+    func: Func,
+) where
+    Func: RhaiNativeFunc<A, N, X, R, F> + 'static,
+    A: 'static,
+    R: Clone + 'static,
+{
+    if level.allows(capability) {
+        engine.register_fn(name, func);
+    } else {
+        // register_fn maps string parameters to rhai's own string type itself, raw ones are
+        // taken as given
+        let params = Func::param_types().map(|param| {
+            if param == TypeId::of::<&str>() || param == TypeId::of::<String>() {
+                TypeId::of::<ImmutableString>()
+            } else {
+                param
+            }
+        });
+        engine.register_raw_fn(name, params, move |_, _| sandbox_denied(name, level));
+    }
+}
+
+/// Bounds on every call into the script, so a runaway script fails instead of hanging.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ScriptLimits {
+    /// Operations a call may run, 0 for no limit.
+    pub max_operations: u64,
+    /// Depth of nested function calls.
+    pub max_call_levels: usize,
+    /// Length of strings in bytes, 0 for no limit.
+    pub max_string_size: usize,
+    /// Length of arrays, 0 for no limit.
+    pub max_array_size: usize,
+    /// Entries of maps, 0 for no limit.
+    pub max_map_size: usize,
+    /// Wall-clock time a call may take. Only checked between operations of the script, so
+    /// a slow `fetch` or `run` finishes first.
+    pub timeout: Option<Duration>,
+    /// Time commands started by the script may run before they are killed with everything
+    /// they started, `None` letting them run forever.
+    pub run_timeout: Option<Duration>,
+}
+
+impl ScriptLimits {
+    pub const DEFAULT: ScriptLimits = ScriptLimits {
+        max_operations: 50_000_000,
+        // rhai's own default, lower in debug builds where stack frames are larger
+        max_call_levels: if cfg!(debug_assertions) { 8 } else { 64 },
+        max_string_size: 64 * 1024 * 1024,
+        max_array_size: 1_000_000,
+        max_map_size: 1_000_000,
+        timeout: Some(Duration::from_secs(60)),
+        run_timeout: Some(utilities::DEFAULT_RUN_TIMEOUT),
+    };
+
+    /// Which limit `e` ran into, following it through nested calls and modules.
+    fn hit_by(&self, e: &EvalAltResult) -> Option<String> {
+        match e {
+            EvalAltResult::ErrorInFunctionCall(_, _, inner, _)
+            | EvalAltResult::ErrorInModule(_, inner, _) => self.hit_by(inner),
+            EvalAltResult::ErrorTooManyOperations(_) => {
+                Some(format!("the limit of {} operations", self.max_operations))
+            }
+            EvalAltResult::ErrorStackOverflow(_) => Some(format!(
+                "the limit of {} nested calls",
+                self.max_call_levels
+            )),
+            EvalAltResult::ErrorDataTooLarge(..) => Some("a size limit".to_string()),
+            EvalAltResult::ErrorTerminated(..) => self
+                .timeout
+                .map(|timeout| format!("the timeout of {timeout:?}")),
+            _ => None,
+        }
+    }
+}
+
+impl Default for ScriptLimits {
+    fn default() -> Self {
+        ScriptLimits::DEFAULT
+    }
+}
+
+/// Parses a `key=value` variable for the script.
+pub fn parse_var(var: &str) -> Result<(String, String), String> {
+    let (key, value) = var
+        .split_once('=')
+        .ok_or_else(|| "Variables should be given as 'key=value'".to_string())?;
+    let key = key.trim();
+    if key.is_empty() {
+        return Err(format!("Variable '{var}' has no key"));
+    }
+    Ok((key.to_string(), value.to_string()))
+}
+
+/// Resolves `import "common"` to `common.rhai` in the first of `dirs` that has it. Modules
+/// are named by their file path, so errors inside them point at the file.
+struct ScriptModules {
+    dirs: Vec<PathBuf>,
+    cache: Mutex<HashMap<PathBuf, Shared<Module>>>,
+    level: SandboxLevel,
+}
+
+impl ModuleResolver for ScriptModules {
+    fn resolve(
+        &self,
+        engine: &Engine,
+        _source: Option<&str>,
+        path: &str,
+        pos: Position,
+    ) -> RhaiResult<Shared<Module>> {
+        if !self.level.allows(Capability::Import) {
+            let message = format!(
+                "Importing '{path}' is disabled by sandbox policy '{}'",
+                self.level
+            );
+            return Err(EvalAltResult::ErrorRuntime(message.into(), pos).into());
+        }
+        let file = self
+            .dirs
+            .iter()
+            .map(|dir| dir.join(path).with_extension("rhai"))
+            .find(|file| file.is_file())
+            .ok_or_else(|| EvalAltResult::ErrorModuleNotFound(path.to_string(), pos))?;
+        if let Some(module) = self.cache.lock().unwrap().get(&file) {
+            return Ok(module.clone());
+        }
+        let name = file.display().to_string();
+        let in_module = |e| Box::new(EvalAltResult::ErrorInModule(name.clone(), e, pos));
+        let mut ast = engine.compile_file(file.clone()).map_err(in_module)?;
+        ast.set_source(name.as_str());
+        let module: Shared<Module> = Module::eval_ast_as_new(Scope::new(), &ast, engine)
+            .map_err(in_module)?
+            .into();
+        self.cache.lock().unwrap().insert(file, module.clone());
+        Ok(module)
+    }
+}
+
+/// The script needs a newer wasupdate than the running one.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UnsupportedWasupdate {
+    pub required: String,
+    pub running: String,
+}
+
+impl fmt::Display for UnsupportedWasupdate {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "this script requires wasupdate {}, you are running {}",
+            self.required, self.running
+        )
+    }
+}
+
+impl error::Error for UnsupportedWasupdate {}
+
+impl UnsupportedWasupdate {
+    pub fn from_rhai(e: &EvalAltResult) -> Option<&UnsupportedWasupdate> {
+        match e {
+            EvalAltResult::ErrorSystem(_, inner) => inner.downcast_ref::<UnsupportedWasupdate>(),
+            _ => None,
+        }
+    }
+}
+
+/// Where in a script or module file an error happened.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct ScriptPosition {
+    /// Path of the file, `None` for an inline script.
+    pub file: Option<String>,
+    pub line: usize,
+    pub column: usize,
+}
+
+/// An error of the script, located in its source when rhai knows where it happened.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ScriptError {
+    pub message: String,
+    pub position: Option<ScriptPosition>,
+    /// The source line at `position`.
+    pub snippet: Option<String>,
+}
+
+impl ScriptError {
+    /// Locates `e`, raised by `script`, at the innermost position of the error, inside
+    /// called functions and imported modules.
+    pub fn from_rhai(e: &EvalAltResult, script: &Script) -> ScriptError {
+        let main = match script {
+            Script::File(path) => Some(path.display().to_string()),
+            Script::Inline(_) => None,
+        };
+        let (file, pos) = innermost(e, main.clone());
+        let position = pos.line().map(|line| ScriptPosition {
+            file,
+            line,
+            column: pos.position().unwrap_or(1),
+        });
+        let snippet = position.as_ref().and_then(|position| {
+            let source = match (&position.file, script) {
+                (None, Script::Inline(code)) => code.clone(),
+                (Some(file), _) => std::fs::read_to_string(file).ok()?,
+                (None, Script::File(_)) => return None,
+            };
+            let line = source.lines().nth(position.line - 1)?;
+            Some(line.trim_end().to_string())
+        });
+        ScriptError {
+            message: e.to_string(),
+            position,
+            snippet,
+        }
+    }
+}
+
+/// The file and position of the innermost error in `e`, starting out in `file`.
+fn innermost(e: &EvalAltResult, file: Option<String>) -> (Option<String>, Position) {
+    let (inner, inner_file) = match e {
+        EvalAltResult::ErrorInFunctionCall(_, source, inner, _) if !source.is_empty() => {
+            (inner, Some(source.to_string()))
+        }
+        EvalAltResult::ErrorInFunctionCall(_, _, inner, _) => (inner, file.clone()),
+        EvalAltResult::ErrorInModule(name, inner, _) => (inner, Some(name.to_string())),
+        _ => return (file, e.position()),
+    };
+    match innermost(inner, inner_file) {
+        (_, pos) if pos.is_none() => (file, e.position()),
+        found => found,
+    }
+}
+
+impl fmt::Display for ScriptError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.message)?;
+        let Some(position) = &self.position else {
+            return Ok(());
+        };
+        let file = position.file.as_deref().unwrap_or("<inline script>");
+        write!(f, "\n  --> {file}:{}:{}", position.line, position.column)?;
+        if let Some(snippet) = &self.snippet {
+            let number = position.line.to_string();
+            let gutter = " ".repeat(number.len());
+            // tabs are kept so the caret lines up with the source
+            let indent: String = snippet
+                .chars()
+                .take(position.column.saturating_sub(1))
+                .map(|c| if c == '\t' { '\t' } else { ' ' })
+                .collect();
+            write!(
+                f,
+                "\n{gutter} |\n{number} | {snippet}\n{gutter} | {indent}^"
+            )?;
+        }
+        Ok(())
+    }
+}
+
+impl error::Error for ScriptError {}
+
+/// Calls `func` of the script, stopping it once `limits.timeout` has passed.
+fn call_fn<T: Clone + 'static>(
+    engine: &Engine,
+    ast: &AST,
+    deadline: &Mutex<Option<Instant>>,
+    limits: &ScriptLimits,
+    func: &str,
+    args: impl FuncArgs,
+) -> RhaiResult<T> {
+    *deadline.lock().unwrap() = limits.timeout.map(|timeout| Instant::now() + timeout);
+    let result = engine.call_fn::<T>(&mut Scope::new(), ast, func, args);
+    *deadline.lock().unwrap() = None;
+    result.map_err(|e| match limits.hit_by(&e) {
+        Some(limit) => {
+            let message = format!("Function '{func}' exceeded {limit}: {e}");
+            EvalAltResult::ErrorRuntime(message.into(), e.position()).into()
+        }
+        None => e,
+    })
+}
+
 pub struct WasaupEngine {
     engine: Engine,
     ast: AST,
+    options: EngineOptions,
+    script: Script,
+    /// When the running call exceeds the timeout, `None` between calls.
+    deadline: Arc<Mutex<Option<Instant>>>,
+    /// File name of the script without extension, the name when it has no `name` function.
+    script_stem: Option<String>,
+    /// Whether the script has the `latest_version(channel)` form, besides or instead of
+    /// the one without parameters.
+    has_latest_version_channel: bool,
+    has_latest_version: bool,
+    has_pre_install: bool,
     has_post_install: bool,
+    has_checksum: bool,
+    has_verify: bool,
+    has_changelog: bool,
+    has_should_update: bool,
+    has_all_versions: bool,
+    has_version_scheme: bool,
+    has_allow_prerelease: bool,
+    has_name: bool,
+    has_description: bool,
+    /// Resolved once by [`WasaupEngine::with_options`].
+    scheme: VersionScheme,
 }
 
 const CURRENT_VERSION_FN: &str = "current_version";
 const LATEST_VERSION_FN: &str = "latest_version";
+/// Channel given to `latest_version(channel)` when none was asked for.
+pub const DEFAULT_CHANNEL: &str = "stable";
 const INSTALL_VERSION_FN: &str = "install_version";
+const PRE_INSTALL_FN: &str = "pre_install";
 const POST_INSTALL_FN: &str = "post_install";
+const CHECKSUM_FN: &str = "checksum";
+const VERIFY_FN: &str = "verify";
+const CHANGELOG_FN: &str = "changelog";
+const SHOULD_UPDATE_FN: &str = "should_update";
+const ALL_VERSIONS_FN: &str = "all_versions";
+const VERSION_SCHEME_FN: &str = "version_scheme";
+const ALLOW_PRERELEASE_FN: &str = "allow_prerelease";
+const NAME_FN: &str = "name";
+const REQUIRES_WASUPDATE_FN: &str = "requires_wasupdate";
+const DESCRIPTION_FN: &str = "description";
+/// Functions of the script wasupdate calls, with the numbers of parameters each may take.
+const HOOKS: &[(&str, &[usize])] = &[
+    (LATEST_VERSION_FN, &[0, 1]),
+    (CURRENT_VERSION_FN, &[0]),
+    (INSTALL_VERSION_FN, &[1]),
+    (PRE_INSTALL_FN, &[1]),
+    (POST_INSTALL_FN, &[1]),
+    (CHECKSUM_FN, &[1]),
+    (VERIFY_FN, &[2]),
+    (CHANGELOG_FN, &[1]),
+    (SHOULD_UPDATE_FN, &[2]),
+    (ALL_VERSIONS_FN, &[0]),
+    (VERSION_SCHEME_FN, &[0]),
+    (ALLOW_PRERELEASE_FN, &[0]),
+    (REQUIRES_WASUPDATE_FN, &[0]),
+    (NAME_FN, &[0]),
+    (DESCRIPTION_FN, &[0]),
+];
+
+/// The name of the hook `func` is, failing when it is one of the [`HOOKS`] but private
+/// or with another number of parameters.
+fn check_hook(func: &ScriptFnMetadata) -> RhaiResult<Option<&'static str>> {
+    let Some((name, counts)) = HOOKS.iter().find(|(name, _)| *name == func.name) else {
+        return Ok(None);
+    };
+    let found = func.params.len();
+    if !counts.contains(&found) {
+        let expected = match counts {
+            [0] => "not have any parameters".to_string(),
+            [1] => "have exactly one parameter".to_string(),
+            [2] => "have exactly two parameters".to_string(),
+            counts => {
+                let counts: Vec<_> = counts.iter().map(usize::to_string).collect();
+                format!("have {} parameters", counts.join(" or "))
+            }
+        };
+        return Err(format!("Function '{name}' should {expected}, found: {found}").into());
+    }
+    if func.access.is_private() {
+        return Err(format!("Function '{name}' should not be private").into());
+    }
+    Ok(Some(name))
+}
+
+/// Keys of the map `install_version` may return, others are ignored with a warning.
+const ARTIFACT_KEYS: [&str; 14] = [
+    "url",
+    "urls",
+    "strip_components",
+    "include",
+    "exclude",
+    "extract",
+    "layout",
+    "keep_versions",
+    "headers",
+    "patch",
+    "checksum",
+    "sha256",
+    "sha512",
+    "blake3",
+];
+
+fn artifact_from_map(map: Map) -> RhaiResult<Artifact> {
+    for key in map.keys() {
+        if !ARTIFACT_KEYS.contains(&key.as_str()) {
+            print::p_warn(&format!(
+                "Ignoring unknown key '{key}' returned by '{INSTALL_VERSION_FN}'"
+            ));
+        }
+    }
+    let url = match map.get("url") {
+        Some(url) => Some(url.clone().into_string().map_err(|t| {
+            format!("Key 'url' returned by '{INSTALL_VERSION_FN}' should be a string, found: {t}")
+        })?),
+        None => None,
+    };
+    // `urls` lists mirrors, tried after `url` when both are given
+    let mut locations: Vec<String> = url
+        .into_iter()
+        .chain(string_list(&map, "urls")?.unwrap_or_default())
+        .collect();
+    if locations.is_empty() {
+        return Err(
+            format!("Map returned by '{INSTALL_VERSION_FN}' is missing 'url' or 'urls'").into(),
+        );
+    }
+    let location = locations.remove(0);
+    let strip_components = match map.get("strip_components") {
+        Some(n) => {
+            let n = n
+                .as_int()
+                .ok()
+                .and_then(|n| usize::try_from(n).ok())
+                .ok_or_else(|| {
+                    format!(
+                        "Key 'strip_components' returned by '{INSTALL_VERSION_FN}' should be a non-negative integer"
+                    )
+                })?;
+            Some(n)
+        }
+        None => None,
+    };
+    Ok(Artifact {
+        location,
+        mirrors: locations,
+        strip_components,
+        include: string_list(&map, "include")?,
+        exclude: string_list(&map, "exclude")?,
+        extract: match map.get("extract") {
+            Some(extract) => Some(extract.clone().into_string().map_err(|t| {
+                format!(
+                    "Key 'extract' returned by '{INSTALL_VERSION_FN}' should be a string, found: {t}"
+                )
+            })?),
+            None => None,
+        },
+        layout: match map.get("layout") {
+            Some(layout) => Some(
+                layout
+                    .clone()
+                    .into_string()
+                    .ok()
+                    .and_then(|layout| layout.parse().ok())
+                    .ok_or_else(|| {
+                        format!(
+                            "Key 'layout' returned by '{INSTALL_VERSION_FN}' should be \"in-place\" or \"versioned\""
+                        )
+                    })?,
+            ),
+            None => None,
+        },
+        keep_versions: match map.get("keep_versions") {
+            Some(keep) => Some(
+                keep.as_int()
+                    .ok()
+                    .and_then(|n| usize::try_from(n).ok())
+                    .filter(|n| *n > 0)
+                    .ok_or_else(|| {
+                        format!(
+                            "Key 'keep_versions' returned by '{INSTALL_VERSION_FN}' should be a positive integer"
+                        )
+                    })?,
+            ),
+            None => None,
+        },
+        headers: match map.get("headers") {
+            Some(headers) => Some(headers_from_map(headers)?),
+            None => None,
+        },
+        patch: match map.get("patch") {
+            Some(patch) => Some(patch_from_map(patch)?),
+            None => None,
+        },
+        checksum: checksum_from_map(&map)?,
+    })
+}
+
+/// The digest given as `checksum: "sha256:<hex>"`, or with the algorithm as the key such
+/// as `sha256: "<hex>"`.
+fn checksum_from_map(map: &Map) -> RhaiResult<Option<String>> {
+    let mut given = ["checksum", "sha256", "sha512", "blake3"]
+        .into_iter()
+        .filter_map(|key| Some((key, map.get(key)?)));
+    let Some((key, value)) = given.next() else {
+        return Ok(None);
+    };
+    if let Some((other, _)) = given.next() {
+        return Err(format!(
+            "Map returned by '{INSTALL_VERSION_FN}' should have one of '{key}' and '{other}', not both"
+        )
+        .into());
+    }
+    let value = value.clone().into_string().map_err(|t| {
+        format!("Key '{key}' returned by '{INSTALL_VERSION_FN}' should be a string, found: {t}")
+    })?;
+    let checksum = match key {
+        "checksum" => value.trim().to_string(),
+        algorithm => format!("{algorithm}:{}", value.trim()),
+    };
+    Digest::parse(&checksum).map_err(|e| {
+        format!("Key '{key}' returned by '{INSTALL_VERSION_FN}' is not a valid checksum: {e}")
+    })?;
+    Ok(Some(checksum))
+}
+
+fn headers_from_map(headers: &Dynamic) -> RhaiResult<Vec<(String, String)>> {
+    let error =
+        || format!("Key 'headers' returned by '{INSTALL_VERSION_FN}' should be a map of strings");
+    let map = headers.read_lock::<Map>().ok_or_else(error)?;
+    map.iter()
+        .map(|(name, value)| {
+            let value = value.clone().into_string().map_err(|_| error())?;
+            Ok((name.to_string(), value))
+        })
+        .collect()
+}
+
+fn patch_from_map(patch: &Dynamic) -> RhaiResult<Patch> {
+    let map = patch
+        .read_lock::<Map>()
+        .ok_or_else(|| format!("Key 'patch' returned by '{INSTALL_VERSION_FN}' should be a map"))?;
+    let field = |key: &str| -> RhaiResult<String> {
+        let value = map.get(key).ok_or_else(|| {
+            format!("Patch returned by '{INSTALL_VERSION_FN}' is missing '{key}'")
+        })?;
+        Ok(value.clone().into_string().map_err(|t| {
+            format!(
+                "Key '{key}' of the patch returned by '{INSTALL_VERSION_FN}' should be a string, found: {t}"
+            )
+        })?)
+    };
+    Ok(Patch {
+        location: field("url")?,
+        base_version: field("base_version")?,
+        file: field("file")?,
+        checksum: field("checksum")?,
+    })
+}
 
-fn extract_version(input: &str) -> Option<String> {
-    let re = Regex::new(r"\b[vV]?(\d+)\.(\d+)\.(\d+)\b").unwrap();
-    re.captures(input)
-        .map(|caps| format!("{}.{}.{}", &caps[1], &caps[2], &caps[3]))
+fn string_list(map: &Map, key: &str) -> RhaiResult<Option<Vec<String>>> {
+    let Some(value) = map.get(key) else {
+        return Ok(None);
+    };
+    let error =
+        || format!("Key '{key}' returned by '{INSTALL_VERSION_FN}' should be an array of strings");
+    let array = value.clone().into_array().map_err(|_| error())?;
+    let list = array
+        .into_iter()
+        .map(|item| item.into_string().map_err(|_| error()))
+        .collect::<Result<_, _>>()?;
+    Ok(Some(list))
 }
 
 impl WasaupEngine {
-    pub fn current_version(&self) -> RhaiResult<Version> {
-        let semver_str =
-            self.engine
-                .call_fn::<String>(&mut Scope::new(), &self.ast, CURRENT_VERSION_FN, ())?;
-        let semver_str = semver_str.trim();
-        let semver_extracted = extract_version(semver_str)
-            .ok_or_else(|| format!("Failed to extract version from: '{semver_str}'"))?;
-        let semver = match semver::Version::parse(&semver_extracted) {
-            Ok(version) => version,
-            Err(e) => {
-                let error_msg = format!("Failed to parse '{semver_str}' as current version: {}", e);
-                return Err(error_msg.into());
+    /// `e`, raised by this script, located in its source.
+    pub fn error(&self, e: &EvalAltResult) -> ScriptError {
+        ScriptError::from_rhai(e, &self.script)
+    }
+
+    /// Calls `func` of the script within the limits of the engine.
+    fn call<T: Clone + 'static>(&self, func: &str, args: impl FuncArgs) -> RhaiResult<T> {
+        call_fn(
+            &self.engine,
+            &self.ast,
+            &self.deadline,
+            &self.options.limits,
+            func,
+            args,
+        )
+    }
+
+    /// The scheme versions are read with: the engine option, else what the optional
+    /// `version_scheme` function of the script returns, else semver.
+    pub fn version_scheme(&self) -> VersionScheme {
+        self.scheme
+    }
+
+    fn resolve_version_scheme(&self) -> RhaiResult<VersionScheme> {
+        if let Some(scheme) = self.options.scheme {
+            return Ok(scheme);
+        }
+        if !self.has_version_scheme {
+            return Ok(VersionScheme::default());
+        }
+        let scheme = self
+            .call::<Dynamic>(VERSION_SCHEME_FN, ())?
+            .into_string()
+            .map_err(|t| {
+                format!("Function '{VERSION_SCHEME_FN}' should return a string, found: {t}")
+            })?;
+        Ok(scheme.parse::<VersionScheme>()?)
+    }
+
+    /// Parses `text` with the version scheme, `kind` names the version in errors.
+    pub fn parse_version(&self, text: &str, kind: &str) -> RhaiResult<ScriptVersion> {
+        ScriptVersion::parse(text, self.scheme, self.options.strict_versions)
+            .map_err(|e| format!("Failed to parse '{}' as {kind} version: {e}", text.trim()).into())
+    }
+
+    pub fn current_version(&self) -> RhaiResult<ScriptVersion> {
+        let version = self.call::<String>(CURRENT_VERSION_FN, ())?;
+        self.parse_version(&version, "current")
+    }
+
+    pub fn latest_version(&self) -> RhaiResult<ScriptVersion> {
+        self.latest_version_in(None)
+    }
+
+    /// Latest version on `channel`, asked from `latest_version(channel)`. Without a channel
+    /// the form without parameters is preferred and the other one gets [`DEFAULT_CHANNEL`].
+    pub fn latest_version_in(&self, channel: Option<&str>) -> RhaiResult<ScriptVersion> {
+        let version = match channel {
+            Some(channel) if self.has_latest_version_channel => {
+                self.call::<String>(LATEST_VERSION_FN, (channel.to_string(),))?
             }
+            Some(channel) => {
+                return Err(format!(
+                    "Function '{LATEST_VERSION_FN}' has no 'channel' parameter, cannot check channel '{channel}'"
+                )
+                .into());
+            }
+            None if self.has_latest_version => self.call::<String>(LATEST_VERSION_FN, ())?,
+            None => self.call::<String>(LATEST_VERSION_FN, (DEFAULT_CHANNEL.to_string(),))?,
         };
+        self.parse_version(&version, "latest")
+    }
 
-        Ok(semver)
-    }
-
-    pub fn latest_version(&self) -> RhaiResult<Version> {
-        let semver_str =
-            self.engine
-                .call_fn::<String>(&mut Scope::new(), &self.ast, LATEST_VERSION_FN, ())?;
-        let semver_str = semver_str.trim();
-        let semver_extracted = extract_version(semver_str)
-            .ok_or_else(|| format!("Failed to extract version from: '{semver_str}'"))?;
-        let semver = match semver::Version::parse(&semver_extracted) {
-            Ok(version) => version,
-            Err(e) => {
-                let error_msg = format!("Failed to parse '{semver_str}' as latest version: {}", e);
-                return Err(error_msg.into());
+    pub fn install_version(&self, version: &str) -> RhaiResult<Artifact> {
+        let archive_loc = self.call::<Dynamic>(INSTALL_VERSION_FN, (version.to_string(),))?;
+        if archive_loc.is_map() {
+            return artifact_from_map(archive_loc.cast::<Map>());
+        }
+        if archive_loc.is_array() {
+            let mut map = Map::new();
+            map.insert("urls".into(), archive_loc);
+            return artifact_from_map(map);
+        }
+        let location = archive_loc.into_string().map_err(|t| {
+            format!(
+                "Function '{INSTALL_VERSION_FN}' should return a string, an array of strings or a map, found: {t}"
+            )
+        })?;
+        Ok(Artifact {
+            location,
+            ..Default::default()
+        })
+    }
+
+    pub fn checksum(&self, version: &str) -> RhaiResult<Option<String>> {
+        if !self.has_checksum {
+            return Ok(None);
+        }
+        let checksum = self.call::<String>(CHECKSUM_FN, (version.to_string(),))?;
+        Ok(Some(checksum.trim().to_string()))
+    }
+
+    /// Whether to update from `current` to `latest` according to the optional
+    /// `should_update` function of the script, `None` when it has none.
+    pub fn should_update(
+        &self,
+        current: &ScriptVersion,
+        latest: &ScriptVersion,
+    ) -> RhaiResult<Option<bool>> {
+        if !self.has_should_update {
+            return Ok(None);
+        }
+        let decision =
+            self.call::<Dynamic>(SHOULD_UPDATE_FN, (current.to_string(), latest.to_string()))?;
+        let decision = decision.as_bool().map_err(|t| {
+            format!("Function '{SHOULD_UPDATE_FN}' should return a bool, found: {t}")
+        })?;
+        Ok(Some(decision))
+    }
+
+    /// Name of the application the script updates from its optional `name` function, else
+    /// the file name of the script without extension. `None` for an inline script without
+    /// the function.
+    pub fn name(&self) -> RhaiResult<Option<String>> {
+        if !self.has_name {
+            return Ok(self.script_stem.clone());
+        }
+        self.metadata(NAME_FN).map(Some)
+    }
+
+    /// Description of the application from the optional `description` function of the
+    /// script.
+    pub fn description(&self) -> RhaiResult<Option<String>> {
+        if !self.has_description {
+            return Ok(None);
+        }
+        self.metadata(DESCRIPTION_FN).map(Some)
+    }
+
+    fn metadata(&self, func: &str) -> RhaiResult<String> {
+        let text = self
+            .call::<Dynamic>(func, ())?
+            .into_string()
+            .map_err(|t| format!("Function '{func}' should return a string, found: {t}"))?;
+        Ok(text.trim().to_string())
+    }
+
+    /// Whether prereleases may be the latest version: with the engine option, or when the
+    /// optional `allow_prerelease` function of the script returns `true`.
+    pub fn allow_prerelease(&self) -> RhaiResult<bool> {
+        if self.options.allow_prerelease || !self.has_allow_prerelease {
+            return Ok(self.options.allow_prerelease);
+        }
+        self.call::<Dynamic>(ALLOW_PRERELEASE_FN, ())?
+            .as_bool()
+            .map_err(|t| {
+                format!("Function '{ALLOW_PRERELEASE_FN}' should return a bool, found: {t}").into()
+            })
+    }
+
+    /// The newest version the prerelease policy lets `current` update to. A prerelease
+    /// `latest` is only allowed when prereleases are, or `current` is one too. Otherwise
+    /// the newest release of `all_versions` takes its place, or `current` when there is
+    /// no newer release.
+    pub fn latest_allowed(
+        &self,
+        current: &ScriptVersion,
+        latest: &ScriptVersion,
+    ) -> RhaiResult<ScriptVersion> {
+        if !latest.is_prerelease() || current.is_prerelease() || self.allow_prerelease()? {
+            return Ok(latest.clone());
+        }
+        let newest_release = self
+            .all_versions()?
+            .unwrap_or_default()
+            .into_iter()
+            .filter(|version| !version.is_prerelease())
+            .max();
+        Ok(newest_release
+            .filter(|version| version > current)
+            .unwrap_or_else(|| current.clone()))
+    }
+
+    /// Every version known to the optional `all_versions` function of the script, oldest
+    /// first, `None` when it has none. Fails naming each entry that is not a version.
+    pub fn all_versions(&self) -> RhaiResult<Option<Vec<ScriptVersion>>> {
+        if !self.has_all_versions {
+            return Ok(None);
+        }
+        let entries = self
+            .call::<Dynamic>(ALL_VERSIONS_FN, ())?
+            .into_array()
+            .map_err(|t| {
+                format!(
+                    "Function '{ALL_VERSIONS_FN}' should return an array of strings, found: {t}"
+                )
+            })?;
+        let mut versions = vec![];
+        let mut invalid = vec![];
+        for entry in entries {
+            let version = entry.clone().into_string().ok().and_then(|entry| {
+                ScriptVersion::parse(&entry, self.scheme, self.options.strict_versions).ok()
+            });
+            match version {
+                Some(version) => versions.push(version),
+                None => invalid.push(format!("'{entry}'")),
             }
-        };
+        }
+        if !invalid.is_empty() {
+            return Err(format!(
+                "Failed to parse entries returned by '{ALL_VERSIONS_FN}' as versions: {}",
+                invalid.join(", ")
+            )
+            .into());
+        }
+        versions.sort();
+        versions.dedup();
+        Ok(Some(versions))
+    }
+
+    /// Release notes of `version` from the optional `changelog` function of the script,
+    /// `None` when it has none.
+    pub fn changelog(&self, version: &str) -> RhaiResult<Option<String>> {
+        if !self.has_changelog {
+            return Ok(None);
+        }
+        let changelog = self
+            .call::<Dynamic>(CHANGELOG_FN, (version.to_string(),))?
+            .into_string()
+            .map_err(|t| format!("Function '{CHANGELOG_FN}' should return a string, found: {t}"))?;
+        Ok(Some(changelog))
+    }
 
-        Ok(semver)
+    /// Whether the script has a `verify` function to check downloaded archives with.
+    pub fn has_verify(&self) -> bool {
+        self.has_verify
     }
 
-    pub fn install_version(&self, version: &str) -> RhaiResult<String> {
-        let archive_loc = self.engine.call_fn::<String>(
-            &mut Scope::new(),
-            &self.ast,
-            INSTALL_VERSION_FN,
-            (version.to_string(),),
-        )?;
-        Ok(archive_loc)
+    /// Runs the optional `verify` function of the script on the archive of `version` at
+    /// `path`. Fails when the function throws or returns `false`.
+    pub fn verify(&self, version: &str, path: &Path) -> RhaiResult<()> {
+        if !self.has_verify {
+            return Ok(());
+        }
+        let verdict =
+            self.call::<Dynamic>(VERIFY_FN, (version.to_string(), path.display().to_string()))?;
+        if verdict.as_bool() == Ok(false) {
+            return Err(format!(
+                "Function '{VERIFY_FN}' rejected the archive '{}'",
+                path.display()
+            )
+            .into());
+        }
+        Ok(())
+    }
+
+    /// Runs the optional `pre_install` function of the script, once an update to `version`
+    /// is decided and before anything is downloaded.
+    pub fn pre_install(&self, version: &str) -> RhaiResult<()> {
+        if !self.has_pre_install {
+            return Ok(());
+        }
+        // whatever the hook evaluates to last is ignored
+        self.call::<Dynamic>(PRE_INSTALL_FN, (version.to_string(),))
+            .map(|_| ())
     }
 
+    /// Runs the optional `post_install` function of the script, after `version` was
+    /// installed successfully.
     pub fn post_install(&self, version: &str) -> RhaiResult<()> {
         if !self.has_post_install {
             return Ok(());
         }
-        self.engine.call_fn::<()>(
-            &mut Scope::new(),
-            &self.ast,
-            POST_INSTALL_FN,
-            (version.to_string(),),
-        )
+        self.call::<Dynamic>(POST_INSTALL_FN, (version.to_string(),))
+            .map(|_| ())
     }
 
     pub fn new(script: Script) -> RhaiResult<WasaupEngine> {
+        Self::with_options(script, EngineOptions::default())
+    }
+
+    pub fn with_options(script: Script, options: EngineOptions) -> RhaiResult<WasaupEngine> {
         let mut engine = Engine::new();
-        engine.register_fn("fetch", utilities::fetch);
-        engine.register_fn("run", utilities::run);
+        let level = options.sandbox;
+        engine.on_print(|text| print::p_script_log("log", text));
+        engine.on_debug(|text, _, _| print::p_script_log("debug", text));
+        let limits = options.limits;
+        engine
+            .set_max_operations(limits.max_operations)
+            .set_max_call_levels(limits.max_call_levels)
+            .set_max_string_size(limits.max_string_size)
+            .set_max_array_size(limits.max_array_size)
+            .set_max_map_size(limits.max_map_size);
+        let deadline: Arc<Mutex<Option<Instant>>> = Arc::default();
+        let running = deadline.clone();
+        engine.on_progress(move |operations| {
+            // the clock is read every 1024 operations, not on every one
+            if operations % 1024 != 0 {
+                return None;
+            }
+            match *running.lock().unwrap() {
+                Some(deadline) if Instant::now() >= deadline => Some("timeout".into()),
+                _ => None,
+            }
+        });
+        engine.register_fn("log", |message: &str| print::p_script_log("log", message));
+        engine.register_fn("info", |message: &str| print::p_script_log("info", message));
+        engine.register_fn("warn", |message: &str| print::p_script_log("warn", message));
+        // GETs are answered once per engine, fetch_nocache always asks the server
+        let cache = FetchCache::default();
+        let responses = cache.clone();
+        register_sandboxed(
+            &mut engine,
+            level,
+            Capability::Network,
+            "fetch",
+            move |url: &str| responses.fetch(url),
+        );
+        let responses = cache.clone();
+        register_sandboxed(
+            &mut engine,
+            level,
+            Capability::Network,
+            "fetch_with_headers",
+            move |url: &str, headers: Map| responses.fetch_with_headers(url, headers),
+        );
+        let responses = cache;
+        register_sandboxed(
+            &mut engine,
+            level,
+            Capability::Network,
+            "fetch_json",
+            move |url: &str| responses.fetch_json(url),
+        );
+        register_sandboxed(
+            &mut engine,
+            level,
+            Capability::Network,
+            "fetch_nocache",
+            utilities::fetch,
+        );
+        register_sandboxed(
+            &mut engine,
+            level,
+            Capability::Network,
+            "http_post",
+            utilities::http_post,
+        );
+        register_sandboxed(
+            &mut engine,
+            level,
+            Capability::Network,
+            "http_request",
+            utilities::http_request,
+        );
+        register_sandboxed(
+            &mut engine,
+            level,
+            Capability::Network,
+            "download",
+            utilities::download,
+        );
+        engine.register_fn("parse_json", utilities::parse_json);
+        engine.register_fn("parse_yaml", utilities::parse_yaml);
+        engine.register_fn("parse_toml", utilities::parse_toml);
+        engine.register_fn("html_select", utilities::html_select);
+        engine.register_fn("html_select_attr", utilities::html_select_attr);
+        engine.register_fn("url_join", utilities::url_join);
+        engine.register_fn("reg_read", utilities::reg_read);
+        engine.register_fn("sleep_ms", utilities::sleep_ms);
+        engine.register_fn("retry", utilities::retry_call);
+        let timeout = options.limits.run_timeout;
+        register_sandboxed(
+            &mut engine,
+            level,
+            Capability::Run,
+            "run",
+            move |cmd: &str| utilities::run(timeout, cmd),
+        );
+        register_sandboxed(
+            &mut engine,
+            level,
+            Capability::Run,
+            "run_args",
+            move |program: &str, args: Array| utilities::run_args(timeout, program, args),
+        );
+        register_sandboxed(
+            &mut engine,
+            level,
+            Capability::Run,
+            "run_timeout",
+            utilities::run_timeout,
+        );
+        register_sandboxed(
+            &mut engine,
+            level,
+            Capability::Run,
+            "run_full",
+            move |cmd: &str| utilities::run_full(timeout, cmd),
+        );
+        register_sandboxed(
+            &mut engine,
+            level,
+            Capability::Run,
+            "run_with_env",
+            move |cmd: &str, env: Map| utilities::run_with_env(timeout, cmd, env),
+        );
+        register_sandboxed(
+            &mut engine,
+            level,
+            Capability::Run,
+            "run_with_env",
+            move |cmd: &str, env: Map, clean: bool| {
+                utilities::run_with_env_clean(timeout, cmd, env, clean)
+            },
+        );
+        register_sandboxed(
+            &mut engine,
+            level,
+            Capability::Run,
+            "run_with_env",
+            move |command: Array, env: Map| utilities::run_args_with_env(timeout, command, env),
+        );
+        register_sandboxed(
+            &mut engine,
+            level,
+            Capability::Run,
+            "run_with_env",
+            move |command: Array, env: Map, clean: bool| {
+                utilities::run_args_with_env_clean(timeout, command, env, clean)
+            },
+        );
         engine.register_fn("jq", utilities::jq);
+        engine.register_fn("jq", utilities::jq_map);
         engine.register_fn("exists", utilities::exists);
-        engine.register_fn("env", utilities::env);
+        engine.register_fn("which", utilities::which);
+        register_sandboxed(&mut engine, level, Capability::Env, "env", utilities::env);
+        register_sandboxed(
+            &mut engine,
+            level,
+            Capability::Env,
+            "env_or",
+            utilities::env_or,
+        );
+        let vars = options.vars.clone();
+        engine.register_fn("get_var", move |key: &str| -> RhaiResult<String> {
+            vars.get(key)
+                .cloned()
+                .ok_or_else(|| format!("Variable '{key}' is not set").into())
+        });
+        let vars = options.vars.clone();
+        engine.register_fn("get_var", move |key: &str, default: &str| {
+            vars.get(key)
+                .cloned()
+                .unwrap_or_else(|| default.to_string())
+        });
         engine.register_fn("read", utilities::read);
-        engine.register_fn("write", utilities::write);
-        let ast = match script {
-            Script::File(path) => engine.compile_file(path)?,
+        let read_only = options.read_only;
+        register_sandboxed(
+            &mut engine,
+            level,
+            Capability::Write,
+            "write",
+            move |path: &str, content: &str| utilities::write(read_only, path, content),
+        );
+        engine.register_fn("regex_find", utilities::regex_find);
+        engine.register_fn("regex_capture", utilities::regex_capture);
+        engine.register_fn("regex_capture", utilities::regex_capture_named);
+        engine.register_fn("regex_matches", utilities::regex_matches);
+        engine.register_fn("extract_semver", utilities::extract_semver);
+        engine.register_fn("semver_gt", utilities::semver_gt);
+        engine.register_fn("semver_max", utilities::semver_max);
+        engine.register_fn("semver_satisfies", utilities::semver_satisfies);
+        engine.register_fn("semver_parse", utilities::semver_parse);
+        engine.register_fn("os", utilities::os);
+        engine.register_fn("os_family", utilities::os_family);
+        engine.register_fn("arch", utilities::arch);
+        engine.register_fn("target_triple", utilities::target_triple);
+        engine.register_fn("platform", utilities::platform);
+        // Relative paths are taken from the directory of the script file
+        let script_dir = match &script {
+            Script::File(path) => path
+                .parent()
+                .filter(|dir| !dir.as_os_str().is_empty())
+                .map(Path::to_path_buf),
+            Script::Inline(_) => None,
+        };
+        // Imports too, never from the working directory
+        let module_dirs = match &script {
+            Script::File(_) => Some(script_dir.clone().unwrap_or_else(|| PathBuf::from("."))),
+            Script::Inline(_) => None,
+        };
+        engine.set_module_resolver(ScriptModules {
+            dirs: module_dirs
+                .into_iter()
+                .chain(options.module_paths.iter().cloned())
+                .collect(),
+            cache: Mutex::default(),
+            level,
+        });
+        let base = script_dir.clone();
+        engine.register_fn("read_file", move |path: &str| {
+            utilities::read_file(base.as_deref(), path)
+        });
+        let base = script_dir.clone();
+        engine.register_fn("read_file_raw", move |path: &str| {
+            utilities::read_file_raw(base.as_deref(), path)
+        });
+        let base = script_dir.clone();
+        engine.register_fn("file_exists", move |path: &str| {
+            utilities::file_exists(base.as_deref(), path)
+        });
+        let base = script_dir.clone();
+        engine.register_fn("dir_exists", move |path: &str| {
+            utilities::dir_exists(base.as_deref(), path)
+        });
+        let base = script_dir.clone();
+        engine.register_fn("file_size", move |path: &str| {
+            utilities::file_size(base.as_deref(), path)
+        });
+        let base = script_dir.clone();
+        engine.register_fn("file_mtime_secs", move |path: &str| {
+            utilities::file_mtime_secs(base.as_deref(), path)
+        });
+        let base = script_dir.clone();
+        engine.register_fn("list_dir", move |path: &str| {
+            utilities::list_dir(base.as_deref(), path)
+        });
+        let base = script_dir.clone();
+        engine.register_fn("glob", move |pattern: &str| {
+            utilities::glob(base.as_deref(), pattern)
+        });
+        let base = script_dir.clone();
+        engine.register_fn("sha256_file", move |path: &str| {
+            utilities::sha256_file(base.as_deref(), path)
+        });
+        let base = script_dir.clone();
+        engine.register_fn("md5_file", move |path: &str| {
+            utilities::md5_file(base.as_deref(), path)
+        });
+        let base = script_dir;
+        register_sandboxed(
+            &mut engine,
+            level,
+            Capability::Write,
+            "write_file",
+            move |path: &str, content: &str| {
+                utilities::write_file(base.as_deref(), read_only, path, content)
+            },
+        );
+        engine.register_fn("base64_encode", utilities::base64_encode);
+        engine.register_fn("base64_decode", utilities::base64_decode);
+        engine.register_fn("base64url_encode", utilities::base64url_encode);
+        engine.register_fn("base64url_decode", utilities::base64url_decode);
+        engine.register_fn("sha256", utilities::sha256);
+        register_sandboxed(
+            &mut engine,
+            level,
+            Capability::Network,
+            "crates_latest",
+            utilities::crates_latest,
+        );
+        engine.register_fn("crates_download_url", utilities::crates_download_url);
+        register_sandboxed(
+            &mut engine,
+            level,
+            Capability::Network,
+            "github_latest_release",
+            utilities::github_latest_release,
+        );
+        register_sandboxed(
+            &mut engine,
+            level,
+            Capability::Network,
+            "github_latest_release",
+            utilities::github_latest_release_with,
+        );
+        register_sandboxed(
+            &mut engine,
+            level,
+            Capability::Network,
+            "github_release_asset",
+            utilities::github_release_asset,
+        );
+        register_sandboxed(
+            &mut engine,
+            level,
+            Capability::Network,
+            "gitlab_latest_release",
+            utilities::gitlab_latest_release,
+        );
+        register_sandboxed(
+            &mut engine,
+            level,
+            Capability::Network,
+            "gitlab_release_asset",
+            utilities::gitlab_release_asset,
+        );
+        register_sandboxed(
+            &mut engine,
+            level,
+            Capability::Network,
+            "gitlab_release_asset",
+            utilities::gitlab_release_asset_with_token,
+        );
+        register_sandboxed(
+            &mut engine,
+            level,
+            Capability::Network,
+            "gitea_latest_release",
+            utilities::gitea_latest_release,
+        );
+        register_sandboxed(
+            &mut engine,
+            level,
+            Capability::Network,
+            "gitea_latest_release",
+            utilities::gitea_latest_release_with_token,
+        );
+        register_sandboxed(
+            &mut engine,
+            level,
+            Capability::Network,
+            "gitea_release_asset",
+            utilities::gitea_release_asset,
+        );
+        register_sandboxed(
+            &mut engine,
+            level,
+            Capability::Network,
+            "gitea_release_asset",
+            utilities::gitea_release_asset_with_token,
+        );
+        let script_stem = match &script {
+            Script::File(path) => path
+                .file_stem()
+                .map(|stem| stem.to_string_lossy().to_string()),
+            Script::Inline(_) => None,
+        };
+        let ast = match &script {
+            Script::File(path) => engine.compile_file(path.clone())?,
             Script::Inline(code) => engine.compile(code.as_str())?,
         };
 
-        let mut has_latest_version = false;
-        let mut has_current_version = false;
-        let mut has_install_version = false;
-        let mut has_post_install = false;
+        // checked before anything else so scripts written for a newer wasupdate are
+        // reported as such rather than failing on a hook this version does not know
+        if let Some(func) = ast
+            .iter_functions()
+            .find(|func| func.name == REQUIRES_WASUPDATE_FN)
+        {
+            check_hook(&func)?;
+        }
+        if let Some(running) = options.wasupdate_version.as_ref()
+            && ast
+                .iter_functions()
+                .any(|func| func.name == REQUIRES_WASUPDATE_FN)
+        {
+            let required = call_fn::<Dynamic>(
+                &engine,
+                &ast,
+                &deadline,
+                &options.limits,
+                REQUIRES_WASUPDATE_FN,
+                (),
+            )?
+            .into_string()
+            .map_err(|t| {
+                format!("Function '{REQUIRES_WASUPDATE_FN}' should return a string, found: {t}")
+            })?;
+            let requirement = VersionReq::parse(&required).map_err(|e| {
+                format!(
+                    "Function '{REQUIRES_WASUPDATE_FN}' should return a requirement such as '>=0.4', found '{required}': {e}"
+                )
+            })?;
+            if !requirement.matches(running) {
+                let unsupported = UnsupportedWasupdate {
+                    required: required.trim().to_string(),
+                    running: running.to_string(),
+                };
+                return Err(
+                    EvalAltResult::ErrorSystem(String::new(), Box::new(unsupported)).into(),
+                );
+            }
+        }
+
+        let mut defined = vec![];
         for func in ast.iter_functions() {
-            match func.name {
-                LATEST_VERSION_FN => {
-                    if !func.params.is_empty() {
-                        let error_msg = format!(
-                            "Function '{LATEST_VERSION_FN}' should not have any parameters, found: {}",
-                            func.params.len()
-                        );
-                        return Err(error_msg.into());
-                    }
-                    if func.access.is_private() {
-                        let error_msg =
-                            format!("Function '{LATEST_VERSION_FN}' should not be private");
-                        return Err(error_msg.into());
-                    }
-                    has_latest_version = true
-                }
-                CURRENT_VERSION_FN => {
-                    if !func.params.is_empty() {
-                        let error_msg = format!(
-                            "Function '{CURRENT_VERSION_FN}' should not have any parameters, found: {}",
-                            func.params.len()
-                        );
-                        return Err(error_msg.into());
-                    }
-                    if func.access.is_private() {
-                        let error_msg =
-                            format!("Function '{CURRENT_VERSION_FN}' should not be private");
-                        return Err(error_msg.into());
-                    }
-                    has_current_version = true
-                }
-                INSTALL_VERSION_FN => {
-                    // Check if the function has exactly one parameter
-                    if func.params.len() != 1 {
-                        let error_msg = format!(
-                            "Function '{INSTALL_VERSION_FN}' should have exactly one parameter, found: {}",
-                            func.params.len()
-                        );
-                        return Err(error_msg.into());
-                    }
-                    // Check if the parameter is a string
-                    if func.params[0] != "version" {
-                        let error_msg = format!(
-                            "Function '{INSTALL_VERSION_FN}' should have a string parameter named 'version'"
-                        );
-                        return Err(error_msg.into());
-                    }
-                    // Check if the function is public
-                    if func.access.is_private() {
-                        let error_msg =
-                            format!("Function '{INSTALL_VERSION_FN}' should not be private");
-                        return Err(error_msg.into());
-                    }
-                    has_install_version = true
-                }
-                POST_INSTALL_FN => {
-                    // Check if the function is public
-                    if func.access.is_private() {
-                        continue; // Skip private post_install functions
-                    }
-                    if func.params.len() != 1 {
-                        continue; // Skip post_install functions with incorrect parameters
-                    }
-                    // Check if the parameter is a string
-                    if func.params[0] != "version" {
-                        continue; // Skip post_install functions with incorrect parameters
-                    }
-                    // Check if the function is public
-                    has_post_install = true;
-                }
-                _ => {}
+            if let Some(hook) = check_hook(&func)? {
+                defined.push((hook, func.params.len()));
             }
         }
+        let has = |name: &str| defined.iter().any(|(defined, _)| *defined == name);
+        let has_latest_version = defined.contains(&(LATEST_VERSION_FN, 0));
+        let has_latest_version_channel = defined.contains(&(LATEST_VERSION_FN, 1));
 
-        if !has_latest_version {
+        if !has_latest_version && !has_latest_version_channel {
             return Err(format!("Function '{LATEST_VERSION_FN}' is required but not found").into());
         }
-        if !has_current_version {
+        if !has(CURRENT_VERSION_FN) {
             return Err(
                 format!("Function '{CURRENT_VERSION_FN}' is required but not found").into(),
             );
         }
-        if !has_install_version {
+        if !has(INSTALL_VERSION_FN) {
             return Err(
                 format!("Function '{INSTALL_VERSION_FN}' is required but not found").into(),
             );
         }
 
-        Ok(Self {
+        let mut wasaup = Self {
             engine,
             ast,
-            has_post_install,
-        })
+            options,
+            deadline,
+            script,
+            script_stem,
+            has_latest_version_channel,
+            has_latest_version,
+            has_pre_install: has(PRE_INSTALL_FN),
+            has_post_install: has(POST_INSTALL_FN),
+            has_checksum: has(CHECKSUM_FN),
+            has_verify: has(VERIFY_FN),
+            has_changelog: has(CHANGELOG_FN),
+            has_should_update: has(SHOULD_UPDATE_FN),
+            has_all_versions: has(ALL_VERSIONS_FN),
+            has_version_scheme: has(VERSION_SCHEME_FN),
+            has_allow_prerelease: has(ALLOW_PRERELEASE_FN),
+            has_name: has(NAME_FN),
+            has_description: has(DESCRIPTION_FN),
+            scheme: VersionScheme::default(),
+        };
+        wasaup.scheme = wasaup.resolve_version_scheme()?;
+        Ok(wasaup)
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::install::{InstallOptions, LayoutKind};
+    use crate::test_server::{Response, TestServer};
 
     const TEST_LATEST_VERSION: &str = r#"
         fn latest_version() {
@@ -279,32 +1508,1032 @@ mod tests {
         let install_path = engine
             .install_version("1.0.0")
             .expect("Failed to install version");
-        assert_eq!(install_path, "path/to/archive-1.0.0.tar.gz");
+        assert_eq!(install_path.location, "path/to/archive-1.0.0.tar.gz");
     }
 
     #[test]
-    fn test_new_engine_from_file() {
-        let script_path = PathBuf::from("test_script.rhai");
-        std::fs::write(
-            &script_path,
-            format!(
-                "{}\n{}\n{}",
-                TEST_CURRENT_VERSION, TEST_LATEST_VERSION, TEST_INSTALL_VERSION
-            ),
-        )
-        .expect("Failed to write test script file");
+    fn test_new_engine_parameter_names() {
+        let inline_script = format!(
+            r#"{TEST_CURRENT_VERSION}
+            {TEST_LATEST_VERSION}
+            fn install_version(v) {{ return "archive-" + v + ".tar.gz"; }}
+            fn pre_install(ver) {{}}
+            fn post_install(ver) {{}}
+            fn checksum(tag) {{ return "sha256:" + tag; }}
+            fn verify(v, dir) {{ return true; }}
+            fn changelog(v) {{ return "notes for " + v; }}"#
+        );
+        let engine = WasaupEngine::new(Script::Inline(inline_script)).unwrap();
+        assert_eq!(
+            engine.install_version("1.0.0").unwrap().location,
+            "archive-1.0.0.tar.gz"
+        );
+        assert_eq!(
+            engine.checksum("1.0.0").unwrap().as_deref(),
+            Some("sha256:1.0.0")
+        );
+        assert_eq!(
+            engine.changelog("1.0.0").unwrap().as_deref(),
+            Some("notes for 1.0.0")
+        );
 
-        let script = Script::File(script_path);
-        let engine = WasaupEngine::new(script).expect("Failed to create WasaupEngine from file");
+        // the number of parameters still matters
+        let inline_script = format!(
+            "{TEST_CURRENT_VERSION}\n{TEST_LATEST_VERSION}\nfn install_version(v, os) {{ v }}"
+        );
+        let engine_error = WasaupEngine::new(Script::Inline(inline_script))
+            .err()
+            .unwrap();
+        assert_eq!(
+            engine_error.to_string(),
+            format!(
+                "Runtime error: Function '{INSTALL_VERSION_FN}' should have exactly one parameter, found: 2"
+            )
+        );
+    }
 
-        // Test current version
-        let current_version = engine
-            .current_version()
-            .expect("Failed to get current version");
-        assert_eq!(current_version.to_string(), "0.9.0");
+    #[test]
+    fn test_new_engine_checksum() {
+        let base_script = format!(
+            "{}\n{}\n{}",
+            TEST_CURRENT_VERSION, TEST_LATEST_VERSION, TEST_INSTALL_VERSION
+        );
+        let engine = WasaupEngine::new(Script::Inline(base_script.clone()))
+            .expect("Failed to create WasaupEngine");
+        assert_eq!(engine.checksum("1.0.0").unwrap(), None);
 
-        // Test latest version
-        let latest_version = engine
+        let inline_script = format!(
+            "{}\nfn checksum(version) {{ return \"sha256:abc-\" + version; }}",
+            base_script
+        );
+        let engine = WasaupEngine::new(Script::Inline(inline_script))
+            .expect("Failed to create WasaupEngine");
+        assert_eq!(
+            engine.checksum("1.0.0").unwrap().as_deref(),
+            Some("sha256:abc-1.0.0")
+        );
+
+        let inline_script = format!("{}\nfn checksum() {{ return \"\"; }}", base_script);
+        let engine_error = WasaupEngine::new(Script::Inline(inline_script))
+            .err()
+            .unwrap();
+        assert_eq!(
+            engine_error.to_string(),
+            format!(
+                "Runtime error: Function '{CHECKSUM_FN}' should have exactly one parameter, found: 0"
+            )
+        );
+    }
+
+    #[test]
+    fn test_pre_install() {
+        let base_script = format!(
+            "{}\n{}\n{}",
+            TEST_CURRENT_VERSION, TEST_LATEST_VERSION, TEST_INSTALL_VERSION
+        );
+        let engine = WasaupEngine::new(Script::Inline(base_script.clone()))
+            .expect("Failed to create WasaupEngine");
+        engine.pre_install("1.0.0").unwrap();
+
+        let inline_script = format!(
+            "{}\nfn pre_install(version) {{ if version != \"1.0.0\" {{ throw \"service still running\"; }} }}",
+            base_script
+        );
+        let engine = WasaupEngine::new(Script::Inline(inline_script))
+            .expect("Failed to create WasaupEngine");
+        engine.pre_install("1.0.0").unwrap();
+        let e = engine.pre_install("2.0.0").unwrap_err();
+        assert!(e.to_string().contains("service still running"), "{e}");
+
+        let inline_script = format!("{}\nprivate fn pre_install(version) {{}}", base_script);
+        let engine_error = WasaupEngine::new(Script::Inline(inline_script))
+            .err()
+            .unwrap();
+        assert_eq!(
+            engine_error.to_string(),
+            format!("Runtime error: Function '{PRE_INSTALL_FN}' should not be private")
+        );
+        let inline_script = format!("{}\nfn pre_install(v, dir) {{}}", base_script);
+        assert!(WasaupEngine::new(Script::Inline(inline_script)).is_err());
+    }
+
+    #[test]
+    fn test_post_install() {
+        let base_script = format!(
+            "{}\n{}\n{}",
+            TEST_CURRENT_VERSION, TEST_LATEST_VERSION, TEST_INSTALL_VERSION
+        );
+        let inline_script = format!(
+            "{}\nfn post_install(version) {{ if version == \"2.0.0\" {{ throw \"migration failed\"; }} \"done\" }}",
+            base_script
+        );
+        let engine = WasaupEngine::new(Script::Inline(inline_script))
+            .expect("Failed to create WasaupEngine");
+        engine.post_install("1.0.0").unwrap();
+        let e = engine.post_install("2.0.0").unwrap_err();
+        assert!(e.to_string().contains("migration failed"), "{e}");
+
+        let inline_script = format!("{}\nfn post_install() {{}}", base_script);
+        let engine_error = WasaupEngine::new(Script::Inline(inline_script))
+            .err()
+            .unwrap();
+        assert_eq!(
+            engine_error.to_string(),
+            format!(
+                "Runtime error: Function '{POST_INSTALL_FN}' should have exactly one parameter, found: 0"
+            )
+        );
+    }
+
+    #[test]
+    fn test_verify() {
+        let base_script = format!(
+            "{}\n{}\n{}",
+            TEST_CURRENT_VERSION, TEST_LATEST_VERSION, TEST_INSTALL_VERSION
+        );
+        let engine = WasaupEngine::new(Script::Inline(base_script.clone())).unwrap();
+        assert!(!engine.has_verify());
+        engine.verify("1.0.0", Path::new("app.tar.gz")).unwrap();
+
+        let inline_script = format!(
+            r#"{}
+            fn verify(version, archive_path) {{
+                if archive_path.ends_with(".exe") {{ throw "unexpected installer"; }}
+                return archive_path.contains(version);
+            }}"#,
+            base_script
+        );
+        let engine = WasaupEngine::new(Script::Inline(inline_script)).unwrap();
+        assert!(engine.has_verify());
+        engine
+            .verify("1.0.0", Path::new("/tmp/app-1.0.0.tar.gz"))
+            .unwrap();
+        let e = engine
+            .verify("1.0.0", Path::new("/tmp/app-0.9.0.tar.gz"))
+            .unwrap_err();
+        assert!(e.to_string().contains("rejected the archive"), "{e}");
+        let e = engine
+            .verify("1.0.0", Path::new("/tmp/setup-1.0.0.exe"))
+            .unwrap_err();
+        assert!(e.to_string().contains("unexpected installer"), "{e}");
+
+        let inline_script = format!("{}\nfn verify(version) {{ true }}", base_script);
+        let engine_error = WasaupEngine::new(Script::Inline(inline_script))
+            .err()
+            .unwrap();
+        assert_eq!(
+            engine_error.to_string(),
+            format!(
+                "Runtime error: Function '{VERIFY_FN}' should have exactly two parameters, found: 1"
+            )
+        );
+    }
+
+    #[test]
+    fn test_changelog() {
+        let base_script = format!(
+            "{}\n{}\n{}",
+            TEST_CURRENT_VERSION, TEST_LATEST_VERSION, TEST_INSTALL_VERSION
+        );
+        let engine = WasaupEngine::new(Script::Inline(base_script.clone())).unwrap();
+        assert_eq!(engine.changelog("1.0.0").unwrap(), None);
+
+        let inline_script = format!(
+            "{}\nfn changelog(version) {{ if version == \"2.0.0\" {{ return 2; }} return `## ${{version}}\n- Faster startup`; }}",
+            base_script
+        );
+        let engine = WasaupEngine::new(Script::Inline(inline_script)).unwrap();
+        assert_eq!(
+            engine.changelog("1.0.0").unwrap().as_deref(),
+            Some("## 1.0.0\n- Faster startup")
+        );
+        let e = engine.changelog("2.0.0").unwrap_err();
+        assert!(e.to_string().contains("should return a string"), "{e}");
+    }
+
+    #[test]
+    fn test_should_update() {
+        let base_script = format!(
+            "{}\n{}\n{}",
+            TEST_CURRENT_VERSION, TEST_LATEST_VERSION, TEST_INSTALL_VERSION
+        );
+        let version = |v: &str| ScriptVersion::parse(v, VersionScheme::Semver, true).unwrap();
+        let engine = WasaupEngine::new(Script::Inline(base_script.clone())).unwrap();
+        assert_eq!(
+            engine
+                .should_update(&version("1.0.0"), &version("1.0.1"))
+                .unwrap(),
+            None
+        );
+
+        let inline_script = format!(
+            r#"{}
+            fn should_update(current, latest) {{
+                let c = semver_parse(current);
+                let l = semver_parse(latest);
+                return c.major == l.major && c.minor == l.minor && semver_gt(latest, current);
+            }}"#,
+            base_script
+        );
+        let engine = WasaupEngine::new(Script::Inline(inline_script)).unwrap();
+        for (current, latest, expected) in [
+            ("1.2.3", "1.2.4", true),
+            ("1.2.3", "1.3.0", false),
+            ("1.2.3", "2.0.0", false),
+            ("1.2.4", "1.2.3", false),
+        ] {
+            assert_eq!(
+                engine
+                    .should_update(&version(current), &version(latest))
+                    .unwrap(),
+                Some(expected),
+                "{current} -> {latest}"
+            );
+        }
+
+        let inline_script = format!(
+            "{}\nfn should_update(current, latest) {{ \"yes\" }}",
+            base_script
+        );
+        let engine = WasaupEngine::new(Script::Inline(inline_script)).unwrap();
+        let e = engine
+            .should_update(&version("1.0.0"), &version("1.0.1"))
+            .unwrap_err();
+        assert!(e.to_string().contains("should return a bool"), "{e}");
+
+        let inline_script = format!("{}\nfn should_update(latest) {{ true }}", base_script);
+        assert_eq!(
+            WasaupEngine::new(Script::Inline(inline_script))
+                .err()
+                .unwrap()
+                .to_string(),
+            format!(
+                "Runtime error: Function '{SHOULD_UPDATE_FN}' should have exactly two parameters, found: 1"
+            )
+        );
+    }
+
+    #[test]
+    fn test_all_versions() {
+        let base_script = format!(
+            "{}\n{}\n{}",
+            TEST_CURRENT_VERSION, TEST_LATEST_VERSION, TEST_INSTALL_VERSION
+        );
+        let engine = WasaupEngine::new(Script::Inline(base_script.clone())).unwrap();
+        assert_eq!(engine.all_versions().unwrap(), None);
+
+        let inline_script = format!(
+            r#"{}
+            fn all_versions() {{ ["v1.10.0", "1.2.0", "1.9.3", "v1.2.0"] }}"#,
+            base_script
+        );
+        let engine = WasaupEngine::new(Script::Inline(inline_script)).unwrap();
+        let versions: Vec<_> = engine
+            .all_versions()
+            .unwrap()
+            .unwrap()
+            .iter()
+            .map(|v| v.spec.to_string())
+            .collect();
+        assert_eq!(versions, ["1.2.0", "1.9.3", "1.10.0"]);
+
+        let inline_script = format!(
+            r#"{}
+            fn all_versions() {{ ["1.2.0", "nightly", 3] }}"#,
+            base_script
+        );
+        let engine = WasaupEngine::new(Script::Inline(inline_script)).unwrap();
+        let e = engine.all_versions().unwrap_err();
+        assert!(e.to_string().contains("'nightly', '3'"), "{e}");
+
+        let inline_script = format!("{}\nfn all_versions() {{ \"1.2.0\" }}", base_script);
+        let engine = WasaupEngine::new(Script::Inline(inline_script)).unwrap();
+        let e = engine.all_versions().unwrap_err();
+        assert!(e.to_string().contains("should return an array"), "{e}");
+    }
+
+    #[test]
+    fn test_lenient_versions() {
+        let script = |current: &str, latest: &str| {
+            Script::Inline(format!(
+                "fn current_version() {{ \"{current}\" }}\nfn latest_version() {{ \"{latest}\" }}\n{TEST_INSTALL_VERSION}"
+            ))
+        };
+        let engine = WasaupEngine::new(script("v1.2.3", "1.4")).unwrap();
+        let current = engine.current_version().unwrap();
+        let latest = engine.latest_version().unwrap();
+        assert_eq!(current.raw, "v1.2.3");
+        assert_eq!(current.spec.to_string(), "1.2.3");
+        assert_eq!(latest.raw, "1.4");
+        assert_eq!(latest.spec.to_string(), "1.4.0");
+        assert!(latest > current);
+        assert_eq!(
+            engine
+                .install_version(&current.to_string())
+                .unwrap()
+                .location,
+            "path/to/archive-v1.2.3.tar.gz"
+        );
+
+        let engine = WasaupEngine::new(script("1", "nightly")).unwrap();
+        assert_eq!(engine.current_version().unwrap().spec.to_string(), "1.0.0");
+        let e = engine.latest_version().unwrap_err();
+        assert!(
+            e.to_string()
+                .contains("Failed to parse 'nightly' as latest version"),
+            "{e}"
+        );
+
+        let options = EngineOptions {
+            strict_versions: true,
+            ..Default::default()
+        };
+        let engine = WasaupEngine::with_options(script("1.2", "v1.4.0"), options).unwrap();
+        assert!(engine.current_version().is_err());
+        assert_eq!(engine.latest_version().unwrap().raw, "v1.4.0");
+    }
+
+    #[test]
+    fn test_version_scheme() {
+        let inline_script = format!(
+            r#"
+            fn version_scheme() {{ "numeric" }}
+            fn current_version() {{ "999" }}
+            fn latest_version() {{ "10452" }}
+            fn all_versions() {{ ["10452", "999", "1000"] }}
+            {TEST_INSTALL_VERSION}"#
+        );
+        let engine = WasaupEngine::new(Script::Inline(inline_script.clone())).unwrap();
+        assert_eq!(engine.version_scheme(), VersionScheme::Numeric);
+        let current = engine.current_version().unwrap();
+        let latest = engine.latest_version().unwrap();
+        assert!(latest > current);
+        let versions: Vec<_> = engine
+            .all_versions()
+            .unwrap()
+            .unwrap()
+            .iter()
+            .map(ScriptVersion::to_string)
+            .collect();
+        assert_eq!(versions, ["999", "1000", "10452"]);
+
+        // in string order "10452" comes before "999"
+        let options = EngineOptions {
+            scheme: Some(VersionScheme::Lexicographic),
+            ..Default::default()
+        };
+        let engine = WasaupEngine::with_options(Script::Inline(inline_script), options).unwrap();
+        assert!(engine.latest_version().unwrap() < engine.current_version().unwrap());
+
+        let inline_script = format!(
+            "{}\n{}\n{}\nfn version_scheme() {{ \"dates\" }}",
+            TEST_CURRENT_VERSION, TEST_LATEST_VERSION, TEST_INSTALL_VERSION
+        );
+        let e = WasaupEngine::new(Script::Inline(inline_script))
+            .err()
+            .unwrap();
+        assert!(
+            e.to_string().contains("Unknown version scheme 'dates'"),
+            "{e}"
+        );
+    }
+
+    #[test]
+    fn test_latest_allowed() {
+        let version = |v: &str| ScriptVersion::parse(v, VersionScheme::Semver, true).unwrap();
+        let base_script = format!(
+            "{}\n{}\n{}",
+            TEST_CURRENT_VERSION, TEST_LATEST_VERSION, TEST_INSTALL_VERSION
+        );
+        let engine = WasaupEngine::new(Script::Inline(base_script.clone())).unwrap();
+        let allowed = |engine: &WasaupEngine, current: &str, latest: &str| {
+            engine
+                .latest_allowed(&version(current), &version(latest))
+                .unwrap()
+                .to_string()
+        };
+        assert_eq!(allowed(&engine, "1.0.0", "1.1.0"), "1.1.0");
+        assert_eq!(allowed(&engine, "1.0.0", "2.0.0-rc.1"), "1.0.0");
+        assert_eq!(allowed(&engine, "2.0.0-beta.1", "2.0.0-rc.1"), "2.0.0-rc.1");
+
+        let listed = format!(
+            "{}\nfn all_versions() {{ [\"1.0.0\", \"1.2.0\", \"1.3.0-rc.1\", \"2.0.0-rc.1\"] }}",
+            base_script
+        );
+        let engine = WasaupEngine::new(Script::Inline(listed.clone())).unwrap();
+        assert_eq!(allowed(&engine, "1.0.0", "2.0.0-rc.1"), "1.2.0");
+        assert_eq!(allowed(&engine, "1.2.0", "2.0.0-rc.1"), "1.2.0");
+
+        let options = EngineOptions {
+            allow_prerelease: true,
+            ..Default::default()
+        };
+        let engine = WasaupEngine::with_options(Script::Inline(listed), options).unwrap();
+        assert_eq!(allowed(&engine, "1.0.0", "2.0.0-rc.1"), "2.0.0-rc.1");
+
+        let opted_in = format!("{}\nfn allow_prerelease() {{ true }}", base_script);
+        let engine = WasaupEngine::new(Script::Inline(opted_in)).unwrap();
+        assert!(engine.allow_prerelease().unwrap());
+        assert_eq!(allowed(&engine, "1.0.0", "2.0.0-rc.1"), "2.0.0-rc.1");
+    }
+
+    #[test]
+    fn test_script_limits() {
+        let engine = |limits: ScriptLimits| {
+            let script = format!(
+                r#"{TEST_CURRENT_VERSION}
+                {TEST_INSTALL_VERSION}
+                fn spin() {{ loop {{}} }}
+                fn deep(n) {{ return deep(n + 1); }}
+                fn latest_version(channel) {{
+                    switch channel {{
+                        "spin" => spin(),
+                        "deep" => deep(0),
+                        "grow" => {{ let s = "x"; loop {{ s += s; }} }},
+                        _ => "1.0.0",
+                    }}
+                }}"#
+            );
+            let options = EngineOptions {
+                limits,
+                ..Default::default()
+            };
+            WasaupEngine::with_options(Script::Inline(script), options).unwrap()
+        };
+        let latest_error = |engine: &WasaupEngine, channel: &str| {
+            engine
+                .latest_version_in(Some(channel))
+                .unwrap_err()
+                .to_string()
+        };
+
+        let started = Instant::now();
+        let timed = engine(ScriptLimits {
+            max_operations: 0,
+            timeout: Some(Duration::from_millis(200)),
+            ..Default::default()
+        });
+        let e = latest_error(&timed, "spin");
+        assert!(
+            e.contains("Function 'latest_version' exceeded the timeout of 200ms"),
+            "{e}"
+        );
+        assert!(started.elapsed() < Duration::from_secs(5));
+        // the deadline is per call
+        assert_eq!(timed.latest_version().unwrap().to_string(), "1.0.0");
+
+        let counted = engine(ScriptLimits {
+            max_operations: 10_000,
+            max_call_levels: 4,
+            max_string_size: 1024,
+            timeout: None,
+            ..Default::default()
+        });
+        let e = latest_error(&counted, "spin");
+        assert!(
+            e.contains("Function 'latest_version' exceeded the limit of 10000 operations"),
+            "{e}"
+        );
+        let e = latest_error(&counted, "deep");
+        assert!(e.contains("exceeded the limit of 4 nested calls"), "{e}");
+        let e = latest_error(&counted, "grow");
+        assert!(e.contains("exceeded a size limit"), "{e}");
+        assert!(e.contains("Length of string too large"), "{e}");
+        assert!(started.elapsed() < Duration::from_secs(5));
+    }
+
+    #[test]
+    fn test_sandbox_levels() {
+        let written =
+            std::env::temp_dir().join(format!("wasupdate-sandbox-{}", std::process::id()));
+        let server = TestServer::start(|_| Response::ok("{}"));
+        let url = server.url("/");
+        let script = format!(
+            r#"{TEST_CURRENT_VERSION}
+            {TEST_INSTALL_VERSION}
+            fn latest_version(channel) {{
+                switch channel {{
+                    "run" => run("echo hi"),
+                    "run_with_env" => run_with_env(["echo", "hi"], #{{}}),
+                    "write" => write_file(get_var("written"), "x"),
+                    "fetch" => fetch("{url}"),
+                    "http_post" => http_post("{url}", "", #{{}}),
+                    "env" => env("HOME"),
+                    "env_or" => env_or("HOME", ""),
+                    "import" => {{
+                        import "common" as common;
+                        common::tag();
+                    }}
+                    _ => (),
+                }}
+                return "1.0.0";
+            }}"#
+        );
+        let denied = |level: SandboxLevel, action: &str| {
+            let options = EngineOptions {
+                sandbox: level,
+                vars: HashMap::from([("written".to_string(), written.display().to_string())]),
+                ..Default::default()
+            };
+            let engine =
+                WasaupEngine::with_options(Script::Inline(script.clone()), options).unwrap();
+            match engine.latest_version_in(Some(action)) {
+                Err(e) => e.to_string().contains("disabled by sandbox policy"),
+                Ok(_) => false,
+            }
+        };
+        let actions = [
+            "run",
+            "run_with_env",
+            "write",
+            "fetch",
+            "http_post",
+            "env",
+            "env_or",
+            "import",
+        ];
+        let matrix = [
+            (
+                SandboxLevel::Full,
+                [false, false, false, false, false, false, false, false],
+            ),
+            (
+                SandboxLevel::Network,
+                [true, true, true, false, false, false, false, false],
+            ),
+            (
+                SandboxLevel::Pure,
+                [true, true, true, true, true, true, true, true],
+            ),
+        ];
+        for (level, expected) in matrix {
+            for (action, expected) in actions.iter().zip(expected) {
+                assert_eq!(denied(level, action), expected, "{action} in {level}");
+            }
+        }
+        let _ = std::fs::remove_file(&written);
+
+        let options = EngineOptions {
+            sandbox: SandboxLevel::Network,
+            ..Default::default()
+        };
+        let engine = WasaupEngine::with_options(Script::Inline(script), options).unwrap();
+        let e = engine.latest_version_in(Some("run")).unwrap_err();
+        assert!(
+            e.to_string()
+                .contains("Function 'run' is disabled by sandbox policy 'network'"),
+            "{e}"
+        );
+        assert_eq!("Pure".parse(), Ok(SandboxLevel::Pure));
+        assert!("none".parse::<SandboxLevel>().is_err());
+    }
+
+    #[test]
+    fn test_error_positions() {
+        let dir =
+            std::env::temp_dir().join(format!("wasupdate-rhai-errors-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(
+            dir.join("common.rhai"),
+            "fn tag() {\n\tthrow \"no tag\";\n}\n",
+        )
+        .unwrap();
+        let path = dir.join("broken.rhai");
+        std::fs::write(
+            &path,
+            r#"import "common" as common;
+fn current_version() { helper() }
+fn helper() {
+    let x = 1;
+    x + missing
+}
+fn latest_version() { jq("a", "b", 3) }
+fn install_version(version) { common::tag() }
+"#,
+        )
+        .unwrap();
+        let script = Script::File(path.clone());
+        let file = Some(path.display().to_string());
+        let engine = WasaupEngine::new(script.clone()).unwrap();
+
+        let e = engine.error(&engine.current_version().unwrap_err());
+        assert_eq!(
+            e.position,
+            Some(ScriptPosition {
+                file: file.clone(),
+                line: 5,
+                column: 9,
+            })
+        );
+        assert_eq!(e.snippet.as_deref(), Some("    x + missing"));
+        assert!(
+            e.to_string().ends_with(&format!(
+                "\n  --> {}:5:9\n  |\n5 |     x + missing\n  |         ^",
+                path.display()
+            )),
+            "{e}"
+        );
+
+        let e = engine.error(&engine.latest_version().unwrap_err());
+        assert_eq!(
+            e.position.as_ref().map(|p| (p.line, p.column)),
+            Some((7, 23))
+        );
+        assert!(e.message.starts_with("Function not found: jq"), "{e}");
+
+        // located in the imported module, tabs kept for the caret
+        let e = engine.error(&engine.install_version("1.0.0").unwrap_err());
+        let common = dir.join("common.rhai").display().to_string();
+        assert_eq!(
+            e.position,
+            Some(ScriptPosition {
+                file: Some(common),
+                line: 2,
+                column: 2,
+            })
+        );
+        assert!(
+            e.to_string().ends_with("2 | \tthrow \"no tag\";\n  | \t^"),
+            "{e}"
+        );
+
+        let inline = Script::Inline("fn current_version() {\n  return (;\n}".to_string());
+        let e = WasaupEngine::new(inline.clone()).err().unwrap();
+        let e = ScriptError::from_rhai(&e, &inline);
+        assert_eq!(
+            e.position,
+            Some(ScriptPosition {
+                file: None,
+                line: 2,
+                column: 11,
+            })
+        );
+        assert!(e.to_string().contains("  --> <inline script>:2:11"), "{e}");
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_vars() {
+        let script = format!(
+            r#"{TEST_CURRENT_VERSION}
+            {TEST_INSTALL_VERSION}
+            fn latest_version() {{
+                if get_var("channel", "stable") != "beta" {{ throw "expected the beta channel"; }}
+                return get_var("pinned");
+            }}"#
+        );
+        let options = EngineOptions {
+            vars: HashMap::from([
+                ("pinned".to_string(), "1.2.0".to_string()),
+                ("channel".to_string(), "beta".to_string()),
+            ]),
+            ..Default::default()
+        };
+        let engine = WasaupEngine::with_options(Script::Inline(script.clone()), options).unwrap();
+        assert_eq!(engine.latest_version().unwrap().to_string(), "1.2.0");
+
+        let options = EngineOptions {
+            vars: HashMap::from([("channel".to_string(), "beta".to_string())]),
+            ..Default::default()
+        };
+        let engine = WasaupEngine::with_options(Script::Inline(script), options).unwrap();
+        let e = engine.latest_version().unwrap_err();
+        assert!(
+            e.to_string().contains("Variable 'pinned' is not set"),
+            "{e}"
+        );
+
+        assert_eq!(
+            parse_var("root=/opt/tool=x"),
+            Ok(("root".to_string(), "/opt/tool=x".to_string()))
+        );
+        assert!(parse_var("root").is_err());
+        assert!(parse_var("=x").is_err());
+    }
+
+    #[test]
+    fn test_metadata() {
+        let base_script = format!(
+            "{}\n{}\n{}",
+            TEST_CURRENT_VERSION, TEST_LATEST_VERSION, TEST_INSTALL_VERSION
+        );
+        let engine = WasaupEngine::new(Script::Inline(base_script.clone())).unwrap();
+        assert_eq!(engine.name().unwrap(), None);
+
+        let inline_script = format!(
+            r#"{}
+            fn name() {{ " ripgrep " }}
+            fn description() {{ "Line-oriented search tool" }}"#,
+            base_script
+        );
+        let engine = WasaupEngine::new(Script::Inline(inline_script)).unwrap();
+        assert_eq!(engine.name().unwrap().as_deref(), Some("ripgrep"));
+        assert_eq!(
+            engine.description().unwrap().as_deref(),
+            Some("Line-oriented search tool")
+        );
+
+        let inline_script = format!("{}\nfn name() {{ 42 }}", base_script);
+        let engine = WasaupEngine::new(Script::Inline(inline_script)).unwrap();
+        let e = engine.name().unwrap_err();
+        assert!(e.to_string().contains("should return a string"), "{e}");
+
+        let inline_script = format!("{}\nfn description(app) {{ app }}", base_script);
+        assert_eq!(
+            WasaupEngine::new(Script::Inline(inline_script))
+                .err()
+                .unwrap()
+                .to_string(),
+            format!(
+                "Runtime error: Function '{DESCRIPTION_FN}' should not have any parameters, found: 1"
+            )
+        );
+    }
+
+    #[test]
+    fn test_requires_wasupdate() {
+        let script = |requirement: &str| {
+            Script::Inline(format!(
+                "{}\n{}\n{}\nfn requires_wasupdate() {{ \"{requirement}\" }}",
+                TEST_CURRENT_VERSION, TEST_LATEST_VERSION, TEST_INSTALL_VERSION
+            ))
+        };
+        let running = |version: &str| EngineOptions {
+            wasupdate_version: Some(Version::parse(version).unwrap()),
+            ..Default::default()
+        };
+        assert!(WasaupEngine::with_options(script(">=0.4"), running("0.4.0")).is_ok());
+        // without the running version there is nothing to check against
+        assert!(WasaupEngine::new(script(">=0.4")).is_ok());
+
+        let e = WasaupEngine::with_options(script(">=0.4"), running("0.3.1"))
+            .err()
+            .unwrap();
+        assert_eq!(
+            UnsupportedWasupdate::from_rhai(&e),
+            Some(&UnsupportedWasupdate {
+                required: ">=0.4".to_string(),
+                running: "0.3.1".to_string(),
+            })
+        );
+        assert_eq!(
+            e.to_string(),
+            "this script requires wasupdate >=0.4, you are running 0.3.1"
+        );
+
+        let e = WasaupEngine::with_options(script("0.4 or later"), running("0.4.0"))
+            .err()
+            .unwrap();
+        assert!(UnsupportedWasupdate::from_rhai(&e).is_none());
+        assert!(e.to_string().contains("should return a requirement"), "{e}");
+
+        // hooks this version does not know the shape of are not reported over it
+        let newer = Script::Inline(format!(
+            "{}\n{}\nfn install_version(version, target) {{ \"\" }}\nfn requires_wasupdate() {{ \">=9\" }}",
+            TEST_CURRENT_VERSION, TEST_LATEST_VERSION
+        ));
+        let e = WasaupEngine::with_options(newer, running("0.4.0"))
+            .err()
+            .unwrap();
+        assert!(UnsupportedWasupdate::from_rhai(&e).is_some(), "{e}");
+    }
+
+    #[test]
+    fn test_latest_version_channel() {
+        let base_script = format!("{}\n{}", TEST_CURRENT_VERSION, TEST_INSTALL_VERSION);
+        let engine = WasaupEngine::new(Script::Inline(format!(
+            "{}\n{}",
+            base_script, TEST_LATEST_VERSION
+        )))
+        .unwrap();
+        assert_eq!(engine.latest_version_in(None).unwrap().to_string(), "1.0.0");
+        let e = engine.latest_version_in(Some("beta")).unwrap_err();
+        assert!(
+            e.to_string()
+                .contains("has no 'channel' parameter, cannot check channel 'beta'"),
+            "{e}"
+        );
+
+        let inline_script = format!(
+            r#"{}
+            fn latest_version(channel) {{
+                switch channel {{
+                    "stable" => "1.4.0",
+                    "beta" => "1.5.0-beta.2",
+                    _ => throw `unknown channel ${{channel}}`,
+                }}
+            }}"#,
+            base_script
+        );
+        let engine = WasaupEngine::new(Script::Inline(inline_script.clone())).unwrap();
+        assert_eq!(engine.latest_version().unwrap().to_string(), "1.4.0");
+        assert_eq!(
+            engine.latest_version_in(Some("beta")).unwrap().to_string(),
+            "1.5.0-beta.2"
+        );
+        assert!(engine.latest_version_in(Some("nightly")).is_err());
+
+        let both = format!("{}\nfn latest_version() {{ \"1.3.0\" }}", inline_script);
+        let engine = WasaupEngine::new(Script::Inline(both)).unwrap();
+        assert_eq!(engine.latest_version().unwrap().to_string(), "1.3.0");
+        assert_eq!(
+            engine
+                .latest_version_in(Some("stable"))
+                .unwrap()
+                .to_string(),
+            "1.4.0"
+        );
+
+        // parameters are passed by position, their names are up to the script
+        let inline_script = format!(
+            "{}\nfn latest_version(track) {{ \"1.0.0-\" + track }}",
+            base_script
+        );
+        let engine = WasaupEngine::new(Script::Inline(inline_script)).unwrap();
+        assert_eq!(
+            engine.latest_version_in(Some("beta")).unwrap().to_string(),
+            "1.0.0-beta"
+        );
+        let inline_script = format!("{}\nfn latest_version(a, b) {{ \"1.0.0\" }}", base_script);
+        assert!(WasaupEngine::new(Script::Inline(inline_script)).is_err());
+    }
+
+    #[test]
+    fn test_install_version_map() {
+        let base_script = format!("{}\n{}", TEST_CURRENT_VERSION, TEST_LATEST_VERSION);
+        let inline_script = format!(
+            "{}\nfn install_version(version) {{ return #{{ url: \"app-\" + version + \".tar.gz\", strip_components: 1, exclude: [\"docs/**\"], extract: \"mytool\", layout: \"versioned\", keep_versions: 2, headers: #{{ Authorization: \"Bearer token\" }} }}; }}",
+            base_script
+        );
+        let engine = WasaupEngine::new(Script::Inline(inline_script))
+            .expect("Failed to create WasaupEngine");
+        assert_eq!(
+            engine.install_version("1.0.0").unwrap(),
+            Artifact {
+                location: "app-1.0.0.tar.gz".to_string(),
+                mirrors: vec![],
+                strip_components: Some(1),
+                include: None,
+                exclude: Some(vec!["docs/**".to_string()]),
+                extract: Some("mytool".to_string()),
+                layout: Some(LayoutKind::Versioned),
+                keep_versions: Some(2),
+                patch: None,
+                headers: Some(vec![(
+                    "Authorization".to_string(),
+                    "Bearer token".to_string()
+                )]),
+                checksum: None,
+            }
+        );
+
+        let inline_script = format!(
+            "{}\nfn install_version(version) {{ return #{{ url: \"app\", patch: #{{ url: \"app.patch\", base_version: \"0.9.0\", file: \"bin/app\", checksum: \"abc\" }} }}; }}",
+            base_script
+        );
+        let engine = WasaupEngine::new(Script::Inline(inline_script))
+            .expect("Failed to create WasaupEngine");
+        assert_eq!(
+            engine.install_version("1.0.0").unwrap().patch,
+            Some(Patch {
+                location: "app.patch".to_string(),
+                base_version: "0.9.0".to_string(),
+                file: "bin/app".to_string(),
+                checksum: "abc".to_string(),
+            })
+        );
+
+        let inline_script = format!(
+            "{}\nfn install_version(version) {{ return #{{ url: \"app\", patch: #{{ url: \"app.patch\" }} }}; }}",
+            base_script
+        );
+        let engine = WasaupEngine::new(Script::Inline(inline_script))
+            .expect("Failed to create WasaupEngine");
+        assert!(engine.install_version("1.0.0").is_err());
+
+        let inline_script = format!(
+            "{}\nfn install_version(version) {{ return #{{ strip_components: -1, url: \"app\" }}; }}",
+            base_script
+        );
+        let engine = WasaupEngine::new(Script::Inline(inline_script))
+            .expect("Failed to create WasaupEngine");
+        assert!(engine.install_version("1.0.0").is_err());
+    }
+
+    #[test]
+    fn test_install_version_forms() {
+        let base_script = format!("{}\n{}", TEST_CURRENT_VERSION, TEST_LATEST_VERSION);
+        let install = |body: &str| {
+            let inline_script = format!("{base_script}\nfn install_version(version) {{ {body} }}");
+            WasaupEngine::new(Script::Inline(inline_script))
+                .expect("Failed to create WasaupEngine")
+                .install_version("1.0.0")
+        };
+        let hex = "ab".repeat(32);
+
+        // the plain string is the location and nothing else
+        assert_eq!(
+            install("return \"https://host/app-\" + version + \".tar.gz\";").unwrap(),
+            Artifact {
+                location: "https://host/app-1.0.0.tar.gz".to_string(),
+                ..Default::default()
+            }
+        );
+
+        let artifact = install(&format!(
+            "return #{{ url: \"https://host/app.tar.gz\", sha256: \"{hex}\", strip_components: 1, extract: \"bin/mytool\", comment: \"ignored\" }};"
+        ))
+        .unwrap();
+        assert_eq!(
+            artifact,
+            Artifact {
+                location: "https://host/app.tar.gz".to_string(),
+                strip_components: Some(1),
+                extract: Some("bin/mytool".to_string()),
+                checksum: Some(format!("sha256:{hex}")),
+                ..Default::default()
+            }
+        );
+        let mut opts = InstallOptions {
+            checksum: Some("sha256:from-the-checksum-function".to_string()),
+            ..Default::default()
+        };
+        artifact.apply(&mut opts);
+        assert_eq!(opts.checksum, Some(format!("sha256:{hex}")));
+        assert_eq!(opts.strip_components, Some(1));
+        assert_eq!(opts.extract.as_deref(), Some("bin/mytool"));
+
+        let artifact = install(&format!(
+            "return #{{ url: \"app\", checksum: \"sha256:{hex}\" }};"
+        ))
+        .unwrap();
+        assert_eq!(artifact.checksum, Some(format!("sha256:{hex}")));
+
+        let e = install("return #{ sha256: \"abc\" };").unwrap_err();
+        assert!(e.to_string().contains("missing 'url' or 'urls'"), "{e}");
+        let e = install("return #{ url: \"app\", sha256: \"abc\" };").unwrap_err();
+        assert!(e.to_string().contains("not a valid checksum"), "{e}");
+        let e = install(&format!(
+            "return #{{ url: \"app\", checksum: \"{hex}\", sha256: \"{hex}\" }};"
+        ))
+        .unwrap_err();
+        assert!(e.to_string().contains("not both"), "{e}");
+    }
+
+    #[test]
+    fn test_install_version_mirrors() {
+        let base_script = format!("{}\n{}", TEST_CURRENT_VERSION, TEST_LATEST_VERSION);
+        let install = |body: &str| {
+            let inline_script = format!("{base_script}\nfn install_version(version) {{ {body} }}");
+            WasaupEngine::new(Script::Inline(inline_script))
+                .expect("Failed to create WasaupEngine")
+                .install_version("1.0.0")
+        };
+
+        let artifact = install("return [\"https://a/app\", \"https://b/app\"];").unwrap();
+        assert_eq!(artifact.location, "https://a/app");
+        assert_eq!(artifact.mirrors, vec!["https://b/app"]);
+
+        let artifact = install(
+            "return #{ url: \"https://a/app\", urls: [\"https://b/app\", \"https://c/app\"] };",
+        )
+        .unwrap();
+        assert_eq!(artifact.location, "https://a/app");
+        assert_eq!(artifact.mirrors, vec!["https://b/app", "https://c/app"]);
+
+        let artifact =
+            install("return #{ urls: [\"https://b/app\"], strip_components: 1 };").unwrap();
+        assert_eq!(artifact.location, "https://b/app");
+        assert!(artifact.mirrors.is_empty());
+
+        assert!(install("return [];").is_err());
+        assert!(install("return [1, 2];").is_err());
+    }
+
+    #[test]
+    fn test_new_engine_from_file() {
+        let script_path = PathBuf::from("test_script.rhai");
+        std::fs::write(
+            &script_path,
+            format!(
+                "{}\n{}\n{}",
+                TEST_CURRENT_VERSION, TEST_LATEST_VERSION, TEST_INSTALL_VERSION
+            ),
+        )
+        .expect("Failed to write test script file");
+
+        let script = Script::File(script_path);
+        let engine = WasaupEngine::new(script).expect("Failed to create WasaupEngine from file");
+
+        // Test current version
+        let current_version = engine
+            .current_version()
+            .expect("Failed to get current version");
+        assert_eq!(current_version.to_string(), "0.9.0");
+
+        // Test latest version
+        let latest_version = engine
             .latest_version()
             .expect("Failed to get latest version");
         assert_eq!(latest_version.to_string(), "1.0.0");
@@ -313,9 +2542,513 @@ mod tests {
         let install_path = engine
             .install_version("1.0.0")
             .expect("Failed to install version");
-        assert_eq!(install_path, "path/to/archive-1.0.0.tar.gz");
+        assert_eq!(install_path.location, "path/to/archive-1.0.0.tar.gz");
+
+        // Without a name function the script is named after its file
+        assert_eq!(engine.name().unwrap().as_deref(), Some("test_script"));
+        assert_eq!(engine.description().unwrap(), None);
 
         // Clean up the test script file
         std::fs::remove_file("test_script.rhai").expect("Failed to remove test script file");
     }
+
+    #[test]
+    fn test_jq_on_fetched_json() {
+        let server = TestServer::start(|_| {
+            Response::ok(r#"{"tag_name": "v1.4.2", "assets": [{"size": 12}]}"#)
+        });
+        let inline_script = format!(
+            r#"
+            fn current_version() {{ return "1.0.0"; }}
+            fn latest_version() {{ return jq(fetch("{}"), "tag_name"); }}
+            fn install_version(version) {{
+                let release = #{{ tag: "v" + version, size: 3 }};
+                return jq(release, "tag") + "-" + jq(release, "size");
+            }}"#,
+            server.url("/release")
+        );
+        let engine = WasaupEngine::new(Script::Inline(inline_script)).unwrap();
+        assert_eq!(engine.latest_version().unwrap().spec.to_string(), "1.4.2");
+        assert_eq!(
+            engine.install_version("2.0.0").unwrap().location,
+            "v2.0.0-3"
+        );
+        assert_eq!(
+            utilities::jq(r#"{"assets": [{"size": 12}]}"#, "assets[0]").unwrap(),
+            r#"{"size":12}"#
+        );
+    }
+
+    #[test]
+    fn test_fetch_json_indexing() {
+        let server = TestServer::start(|req| match req.path.as_str() {
+            "/release" => {
+                assert_eq!(req.header("accept"), Some("application/json"));
+                Response::ok(
+                    r#"{"tag_name": "v1.4.2", "draft": false, "notes": null,
+                        "assets": [{"name": "tool.tar.gz", "size": 12, "ratio": 0.5}]}"#,
+                )
+            }
+            _ => Response::ok("<html>maintenance</html>"),
+        });
+        let inline_script = format!(
+            r#"
+            fn current_version() {{ return "1.0.0"; }}
+            fn latest_version() {{
+                let release = fetch_json("{}");
+                if release.draft || release.notes != () || release.assets[0].size != 12 {{
+                    throw "unexpected release";
+                }}
+                return release.tag_name;
+            }}
+            fn install_version(version) {{
+                let parsed = parse_json(`{{"url": "https://host/` + version + `"}}`);
+                return parsed.url;
+            }}
+            fn checksum(version) {{ return fetch_json("{}"); }}"#,
+            server.url("/release"),
+            server.url("/status")
+        );
+        let engine = WasaupEngine::new(Script::Inline(inline_script)).unwrap();
+        assert_eq!(engine.latest_version().unwrap().spec.to_string(), "1.4.2");
+        assert_eq!(
+            engine.install_version("2.0.0").unwrap().location,
+            "https://host/2.0.0"
+        );
+        let e = engine.checksum("2.0.0").unwrap_err();
+        assert!(e.to_string().contains("<html>maintenance</html>"), "{e}");
+    }
+
+    #[test]
+    fn test_env_in_script() {
+        let inline_script = r#"
+            fn current_version() { return env_or("WASUPDATE_TEST_UNSET_VARIABLE", "0.1.0"); }
+            fn latest_version() {
+                if env("WASUPDATE_TEST_UNSET_VARIABLE") != ""
+                    || env("PATH") == ""
+                    || env_or("PATH", "") != env("PATH") {
+                    throw "unexpected environment";
+                }
+                return "1.2.0";
+            }
+            fn install_version(version) { return version; }"#;
+        let engine = WasaupEngine::new(Script::Inline(inline_script.to_string())).unwrap();
+        assert_eq!(engine.current_version().unwrap().to_string(), "0.1.0");
+        assert_eq!(engine.latest_version().unwrap().to_string(), "1.2.0");
+    }
+
+    #[test]
+    fn test_files_relative_to_script() {
+        let dir = std::env::temp_dir().join(format!("wasupdate-rhai-files-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("VERSION"), "1.4.0\n").unwrap();
+        let script = dir.join("update.rhai");
+        std::fs::write(
+            &script,
+            r#"
+            fn current_version() { return read_file("VERSION"); }
+            fn latest_version() {
+                write_file("last-checked", read_file_raw("VERSION"));
+                if sha256_file("VERSION") != sha256("1.4.0\n") { throw "sha256 mismatch"; }
+                if md5_file("VERSION") != "0d7533cf3234e05ef2727e78ddcae4fe" { throw "md5 mismatch"; }
+                return "2.0.0";
+            }
+            fn install_version(version) { return version; }"#,
+        )
+        .unwrap();
+        let engine = WasaupEngine::new(Script::File(script)).unwrap();
+        assert_eq!(engine.current_version().unwrap().to_string(), "1.4.0");
+        assert_eq!(engine.latest_version().unwrap().to_string(), "2.0.0");
+        assert_eq!(
+            std::fs::read_to_string(dir.join("last-checked")).unwrap(),
+            "1.4.0\n"
+        );
+
+        let engine = WasaupEngine::with_options(
+            Script::File(dir.join("update.rhai")),
+            EngineOptions {
+                read_only: true,
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        let e = engine.latest_version().unwrap_err();
+        assert!(e.to_string().contains("this run makes no changes"), "{e}");
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_import_modules() {
+        let dir =
+            std::env::temp_dir().join(format!("wasupdate-rhai-modules-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(dir.join("scripts")).unwrap();
+        std::fs::create_dir_all(dir.join("shared")).unwrap();
+        std::fs::write(
+            dir.join("scripts/common.rhai"),
+            r#"
+            fn latest(repo) { return "2.1.0"; }
+            // not a hook of the importing script, so not validated
+            fn latest_version(a, b, c) { return a; }
+            fn broken() {
+                let x = 1;
+                return x + missing;
+            }"#,
+        )
+        .unwrap();
+        std::fs::write(dir.join("shared/extra.rhai"), "fn current() { \"1.0.0\" }").unwrap();
+        std::fs::write(
+            dir.join("shared/bad.rhai"),
+            "let x = 1;\nthrow \"bad module\";",
+        )
+        .unwrap();
+        let script = dir.join("scripts/update.rhai");
+        std::fs::write(
+            &script,
+            r#"
+            import "common" as c;
+            import "extra" as e;
+            fn current_version() { return e::current(); }
+            fn latest_version() { return c::latest("owner/tool"); }
+            fn install_version(version) { return c::broken(); }
+            fn changelog(version) {
+                import "bad" as b;
+                return "";
+            }"#,
+        )
+        .unwrap();
+
+        // relative to the script file, not the working directory
+        let options = EngineOptions {
+            module_paths: vec![dir.join("shared")],
+            ..Default::default()
+        };
+        let engine = WasaupEngine::with_options(Script::File(script.clone()), options).unwrap();
+        assert_eq!(engine.current_version().unwrap().to_string(), "1.0.0");
+        assert_eq!(engine.latest_version().unwrap().to_string(), "2.1.0");
+
+        let common = dir.join("scripts/common.rhai").display().to_string();
+        let e = engine.install_version("2.1.0").unwrap_err().to_string();
+        assert!(e.contains(&common), "{e}");
+        assert!(e.contains("(line 7, position 28)"), "{e}");
+        let bad = dir.join("shared/bad.rhai").display().to_string();
+        let e = engine.changelog("2.1.0").unwrap_err().to_string();
+        assert!(e.contains(&format!("in module '{bad}'")), "{e}");
+        assert!(e.contains("bad module (line 2"), "{e}");
+
+        // without the extra module path `extra` is not found
+        let engine = WasaupEngine::new(Script::File(script)).unwrap();
+        let e = engine.current_version().unwrap_err().to_string();
+        assert!(e.contains("Module not found: extra"), "{e}");
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_file_predicates_in_script() {
+        let dir = std::env::temp_dir().join(format!("wasupdate-rhai-stat-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(dir.join("cache")).unwrap();
+        std::fs::write(dir.join("LICENSE"), "MIT\n").unwrap();
+        let script = dir.join("update.rhai");
+        std::fs::write(
+            &script,
+            r#"
+            fn current_version() {
+                if !file_exists("LICENSE") || file_exists("cache") { throw "file_exists"; }
+                if !dir_exists("cache") || dir_exists("LICENSE") { throw "dir_exists"; }
+                if file_size("LICENSE") != 4 { throw "file_size"; }
+                if !file_exists("app/bin/tool") { return "0.0.0"; }
+                return "1.0.0";
+            }
+            fn latest_version() {
+                if file_size("missing") != 0 || file_mtime_secs("missing") != 0 { throw "defaults"; }
+                return file_mtime_secs("LICENSE").to_string() + ".0.0";
+            }
+            fn install_version(version) { return version; }"#,
+        )
+        .unwrap();
+        let engine = WasaupEngine::new(Script::File(script)).unwrap();
+        assert_eq!(engine.current_version().unwrap().to_string(), "0.0.0");
+        let now = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
+        let mtime = engine.latest_version().unwrap().semver().unwrap().major;
+        assert!(now.abs_diff(mtime) < 60, "{mtime} is not close to {now}");
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_discover_release_files() {
+        let dir = std::env::temp_dir().join(format!("wasupdate-rhai-glob-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(dir.join("drop")).unwrap();
+        for name in [
+            "tool 1.9.0.zip",
+            "tool 1.10.0.zip",
+            "tool 1.2.3.zip",
+            "notes.txt",
+        ] {
+            std::fs::write(dir.join("drop").join(name), "").unwrap();
+        }
+        let script = dir.join("update.rhai");
+        std::fs::write(
+            &script,
+            r#"
+            fn current_version() {
+                let names = list_dir("drop");
+                if names.len() != 4 || names[0] != "notes.txt" { throw `listed ${names}`; }
+                return "1.0.0";
+            }
+            fn latest_version() {
+                let files = glob("drop/tool *.zip");
+                return semver_max(files.map(|file| extract_semver(file)));
+            }
+            fn install_version(version) { return list_dir("missing"); }"#,
+        )
+        .unwrap();
+        let engine = WasaupEngine::new(Script::File(script)).unwrap();
+        assert_eq!(engine.current_version().unwrap().to_string(), "1.0.0");
+        assert_eq!(engine.latest_version().unwrap().to_string(), "1.10.0");
+        let e = engine.install_version("1.10.0").unwrap_err();
+        assert!(e.to_string().contains("Failed to list directory"), "{e}");
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_semver_helpers_in_script() {
+        let inline_script = r#"
+            fn current_version() {
+                let v = semver_parse("v1.9.3-beta.2");
+                return `${v.major}.${v.minor + 1}.${v.patch}`;
+            }
+            fn latest_version() {
+                let tags = ["v1.9.0", "v1.10.0", "v2.0.0", "v1.2.11", "v1.10.0-rc.1"];
+                let below_two = tags.filter(|tag| semver_satisfies(tag, "<2"));
+                if !semver_gt("1.10.0", "1.9.0") { throw "compared as strings"; }
+                return semver_max(below_two);
+            }
+            fn install_version(version) { return semver_max(["1.0.0", "x.y"]); }"#;
+        let engine = WasaupEngine::new(Script::Inline(inline_script.to_string())).unwrap();
+        assert_eq!(engine.current_version().unwrap().to_string(), "1.10.3");
+        assert_eq!(engine.latest_version().unwrap().spec.to_string(), "1.10.0");
+        let e = engine.install_version("1.10.0").unwrap_err();
+        assert!(e.to_string().contains("Invalid version 'x.y'"), "{e}");
+        assert_eq!(
+            utilities::semver_max(vec!["v1.9.0".into(), "v1.10.0".into()]).unwrap(),
+            "v1.10.0"
+        );
+    }
+
+    #[test]
+    fn test_fetch_is_memoized_per_engine() {
+        use std::sync::{
+            Arc,
+            atomic::{AtomicU32, Ordering},
+        };
+
+        let hits = Arc::new(AtomicU32::new(0));
+        let count = hits.clone();
+        let server = TestServer::start(move |_| {
+            count.fetch_add(1, Ordering::SeqCst);
+            Response::ok(r#"{"tag_name": "v1.3.0"}"#)
+        });
+        let inline_script = format!(
+            r#"
+            fn url() {{ return "{}"; }}
+            fn current_version() {{ return "1.0.0"; }}
+            fn latest_version() {{ return parse_json(fetch(url())).tag_name; }}
+            fn install_version(version) {{
+                if fetch(url()) != fetch_with_headers(url(), #{{}}) {{ throw "differs"; }}
+                return fetch_nocache(url());
+            }}"#,
+            server.url("/releases/latest")
+        );
+        let engine = WasaupEngine::new(Script::Inline(inline_script.clone())).unwrap();
+        assert_eq!(engine.latest_version().unwrap().spec.to_string(), "1.3.0");
+        assert_eq!(engine.latest_version().unwrap().spec.to_string(), "1.3.0");
+        assert_eq!(hits.load(Ordering::SeqCst), 1);
+        engine.install_version("1.3.0").unwrap();
+        assert_eq!(hits.load(Ordering::SeqCst), 2);
+
+        let engine = WasaupEngine::new(Script::Inline(inline_script)).unwrap();
+        engine.latest_version().unwrap();
+        assert_eq!(hits.load(Ordering::SeqCst), 3);
+    }
+
+    #[test]
+    fn test_parse_yaml_in_script() {
+        let server = TestServer::start(|_| {
+            Response::ok(
+                "version: 1.4.2\n\
+                 files:\n  - url: App-Setup-1.4.2.exe\n    sha512: abc\n    size: 81234\n\
+                 path: App-Setup-1.4.2.exe\n\
+                 releaseDate: '2025-01-07T10:00:00.000Z'\n",
+            )
+        });
+        let inline_script = format!(
+            r#"
+            fn current_version() {{ return "1.0.0"; }}
+            fn latest_version() {{
+                let latest = parse_yaml(fetch("{}"));
+                if latest.files[0].size != 81234 {{ throw "size is not a number"; }}
+                if latest.path != "App-Setup-1.4.2.exe" {{ throw "wrong path"; }}
+                return latest.version;
+            }}
+            fn install_version(version) {{ return parse_yaml("version: [1.0"); }}"#,
+            server.url("/latest.yml")
+        );
+        let engine = WasaupEngine::new(Script::Inline(inline_script)).unwrap();
+        assert_eq!(engine.latest_version().unwrap().to_string(), "1.4.2");
+        let e = engine.install_version("1.4.2").unwrap_err();
+        assert!(e.to_string().contains("Invalid YAML"), "{e}");
+        assert!(e.to_string().contains("line 1"), "{e}");
+    }
+
+    #[test]
+    fn test_parse_toml_in_script() {
+        let inline_script = r#"
+            fn app_toml() {
+                return `
+                [package]
+                name = "app"
+                version = "2.3.1"
+                released = 2025-03-01T12:00:00Z
+
+                [channel]
+                stable = true
+                rollout = 0.25
+                mirrors = ["a", "b"]
+                `;
+            }
+            fn current_version() {
+                let app = parse_toml(app_toml());
+                if app.channel.stable != true { throw "bool is not a bool"; }
+                if app.channel.rollout != 0.25 { throw "float is not a float"; }
+                if app.channel.mirrors.len() != 2 { throw "array is not an array"; }
+                if app["package"].released != "2025-03-01T12:00:00Z" { throw "datetime is not a string"; }
+                return app["package"].version;
+            }
+            fn latest_version() { return parse_toml("version = ").version; }
+            fn install_version(version) { return version; }"#;
+        let engine = WasaupEngine::new(Script::Inline(inline_script.to_string())).unwrap();
+        assert_eq!(engine.current_version().unwrap().to_string(), "2.3.1");
+        let e = engine.latest_version().unwrap_err();
+        assert!(e.to_string().contains("Invalid TOML"), "{e}");
+        assert!(e.to_string().contains("line 1"), "{e}");
+    }
+
+    #[test]
+    fn test_html_scraping_in_script() {
+        let server = TestServer::start(|_| {
+            Response::ok(
+                r#"<html><body>
+                <h1 class="title"> Downloads </h1>
+                <ul id="releases">
+                  <li><a href="files/tool-1.9.0.zip">tool 1.9.0</a></li>
+                  <li><a href="files/tool-1.10.2.zip">tool 1.10.2</a></li>
+                  <li><a href="/archive/tool-0.9.zip">tool 0.9</a></li>
+                  <li><a>no link</a></li>
+                </ul>
+                </body></html>"#,
+            )
+        });
+        let inline_script = format!(
+            r##"
+            fn page() {{ return "{}"; }}
+            fn current_version() {{ return "1.0.0"; }}
+            fn latest_version() {{
+                let title = html_select(fetch(page()), "h1.title");
+                if title != ["Downloads"] {{ throw `unexpected title ${{title}}`; }}
+                let links = html_select_attr(fetch(page()), "#releases a", "href");
+                let versions = links.map(|href| extract_semver(href)).filter(|v| v != "");
+                let newest = semver_max(versions);
+                let href = links.filter(|href| href.contains(newest))[0];
+                let url = url_join(page(), href);
+                if !url.ends_with("/tool/files/tool-1.10.2.zip") {{ throw `wrong link ${{url}}`; }}
+                return newest;
+            }}
+            fn install_version(version) {{ return html_select("", "li >"); }}"##,
+            server.url("/tool/downloads.html")
+        );
+        let engine = WasaupEngine::new(Script::Inline(inline_script)).unwrap();
+        assert_eq!(engine.latest_version().unwrap().to_string(), "1.10.2");
+        let e = engine.install_version("1.0.0").unwrap_err();
+        assert!(e.to_string().contains("Invalid CSS selector"), "{e}");
+        assert_eq!(
+            utilities::url_join("https://host/a/b.html", "/c.zip").unwrap(),
+            "https://host/c.zip"
+        );
+    }
+
+    #[test]
+    fn test_retry_in_script() {
+        let inline_script = r#"
+            fn current_version() {
+                let calls = 0;
+                let version = retry(5, 1, || {
+                    calls += 1;
+                    if calls < 3 { throw "flapping"; }
+                    "1.2.0"
+                });
+                if calls != 3 { throw `called ${calls} times`; }
+                return version;
+            }
+            fn latest_version() {
+                let calls = 0;
+                return retry(2, 0, || { calls += 1; throw `still down after ${calls}`; });
+            }
+            fn install_version(version) { sleep_ms(-1); }"#;
+        let engine = WasaupEngine::new(Script::Inline(inline_script.to_string())).unwrap();
+        assert_eq!(engine.current_version().unwrap().to_string(), "1.2.0");
+        let e = engine.latest_version().unwrap_err();
+        assert!(e.to_string().contains("still down after 2"), "{e}");
+        let e = engine.install_version("1.2.0").unwrap_err();
+        assert!(e.to_string().contains("Cannot sleep for -1ms"), "{e}");
+
+        utilities::set_max_sleep(Some(std::time::Duration::from_millis(10)));
+        let started = std::time::Instant::now();
+        utilities::sleep_ms(60_000).unwrap();
+        utilities::set_max_sleep(Some(utilities::DEFAULT_MAX_SLEEP));
+        assert!(started.elapsed() < std::time::Duration::from_secs(5));
+    }
+
+    #[test]
+    fn test_script_logging() {
+        let inline_script = r#"
+            fn current_version() {
+                log("reading installed version");
+                info("installed");
+                warn("no license file");
+                print("stray print");
+                debug("stray debug");
+                return "1.0.0";
+            }
+            fn latest_version() { return "1.0.0"; }
+            fn install_version(version) { return version; }"#;
+        let engine = WasaupEngine::new(Script::Inline(inline_script.to_string())).unwrap();
+        assert_eq!(engine.current_version().unwrap().to_string(), "1.0.0");
+    }
+
+    #[test]
+    fn test_platform_in_script() {
+        let inline_script = r#"
+            fn current_version() { return "1.0.0"; }
+            fn latest_version() { return "1.1.0"; }
+            fn install_version(version) {
+                return `tool-${version}-${platform()}-${os_family()}-${target_triple()}.tar.gz`;
+            }"#;
+        let engine = WasaupEngine::new(Script::Inline(inline_script.to_string())).unwrap();
+        assert_eq!(
+            engine.install_version("1.1.0").unwrap().location,
+            format!(
+                "tool-1.1.0-{}-{}-{}-{}.tar.gz",
+                std::env::consts::OS,
+                utilities::arch(),
+                std::env::consts::FAMILY,
+                env!("WASUPDATE_TARGET")
+            )
+        );
+    }
 }
@@ -0,0 +1,219 @@
+use std::{
+    fs,
+    io::{self, Error},
+    path::Path,
+};
+
+use crate::http::{default_client, request_error, with_headers};
+
+const ARMORED_KEY_HEADER: &str = "-----BEGIN PGP PUBLIC KEY BLOCK-----";
+const SIGNATURE_EXTENSION: &str = "asc";
+
+/// Returns armored key material given either inline or as a path to an armored file.
+pub fn read_key(key: &str) -> io::Result<String> {
+    if key.trim_start().starts_with(ARMORED_KEY_HEADER) {
+        return Ok(key.to_string());
+    }
+    fs::read_to_string(key)
+        .map_err(|e| Error::new(e.kind(), format!("Failed to read signing key '{key}': {e}")))
+}
+
+/// Fetches the detached signature published next to `url` as `<url>.asc`, sending
+/// the `headers` for its URL like the download itself so private hosts accept it.
+pub fn fetch_signature(
+    url: &str,
+    headers: impl Fn(&str) -> io::Result<Vec<(String, String)>>,
+) -> io::Result<String> {
+    let signature_url = format!("{url}.{SIGNATURE_EXTENSION}");
+    let response = with_headers(
+        default_client()?.get(&signature_url),
+        &headers(&signature_url)?,
+    )?
+    .send()
+    .map_err(request_error)?;
+    if !response.status().is_success() {
+        let status = response.status();
+        return Err(Error::other(format!(
+            "Failed to fetch signature '{signature_url}' with status: {status}"
+        )));
+    }
+    response.text().map_err(request_error)
+}
+
+/// Reads the detached signature stored next to a local archive as `<path>.asc`.
+pub fn read_signature(path: &Path) -> io::Result<String> {
+    let mut signature_path = path.as_os_str().to_owned();
+    signature_path.push(format!(".{SIGNATURE_EXTENSION}"));
+    fs::read_to_string(&signature_path).map_err(|e| {
+        Error::new(
+            e.kind(),
+            format!(
+                "Failed to read signature '{}': {e}",
+                Path::new(&signature_path).display()
+            ),
+        )
+    })
+}
+
+#[cfg(feature = "pgp")]
+pub fn verify_signature(path: &Path, signature: &str, key: &str) -> io::Result<()> {
+    use std::time::{SystemTime, UNIX_EPOCH};
+
+    use pgp::{Deserializable, SignedPublicKey, StandaloneSignature};
+
+    let key = read_key(key)?;
+    let (key, _) = SignedPublicKey::from_armor_single(key.as_bytes()).map_err(|e| {
+        Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("Failed to parse signing key: {e}"),
+        )
+    })?;
+    key.verify().map_err(|e| {
+        Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("Signing key has invalid self-signatures: {e}"),
+        )
+    })?;
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_err(Error::other)?
+        .as_secs() as i64;
+    if let Some(expires_at) = key.expires_at()
+        && expires_at.timestamp() < now
+    {
+        return Err(Error::new(
+            io::ErrorKind::PermissionDenied,
+            format!(
+                "The signing key expired on {}; fetch the publisher's refreshed key and pass it with --signing-key",
+                expires_at.format("%Y-%m-%d")
+            ),
+        ));
+    }
+
+    let (signature, _) =
+        StandaloneSignature::from_armor_single(signature.as_bytes()).map_err(|e| {
+            Error::new(
+                io::ErrorKind::InvalidData,
+                format!("Failed to parse signature: {e}"),
+            )
+        })?;
+    let content = fs::read(path)?;
+    let verified = signature.verify(&key, &content).is_ok()
+        || key
+            .public_subkeys
+            .iter()
+            .any(|subkey| signature.verify(subkey, &content).is_ok());
+    if !verified {
+        return Err(Error::new(
+            io::ErrorKind::InvalidData,
+            format!(
+                "OpenPGP signature verification failed for '{}'",
+                path.display()
+            ),
+        ));
+    }
+    Ok(())
+}
+
+#[cfg(not(feature = "pgp"))]
+pub fn verify_signature(_path: &Path, _signature: &str, _key: &str) -> io::Result<()> {
+    Err(Error::new(
+        io::ErrorKind::Unsupported,
+        "OpenPGP signature verification requires wasupdate to be built with the 'pgp' feature",
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TEST_PUBLIC_KEY: &str = r#"-----BEGIN PGP PUBLIC KEY BLOCK-----
+
+mDMEatJEDhYJKwYBBAHaRw8BAQdAIP3ZFoslTydZkA+MAtEMdFi2rHVFt8oUtvip
+y2c1l5a0IXdhc3VwZGF0ZSB0ZXN0IDx0ZXN0QGV4YW1wbGUuY29tPoiQBBMWCAA4
+FiEE7jsGOCr1L3E1XGWwk5A+VAQG2/YFAmrSRA4CGwMFCwkIBwIGFQoJCAsCBBYC
+AwECHgECF4AACgkQk5A+VAQG2/aTDQEAkzE3gDDF802p95hM5EdllXcCvrhBQpYk
+slDoPwntEvYA/1UtomkYBgmozD0kvO1Su1qwHjm4hisBOsuWWohSbFEM
+=a1ZE
+-----END PGP PUBLIC KEY BLOCK-----"#;
+
+    #[test]
+    fn test_read_key_inline_and_path() {
+        assert_eq!(read_key(TEST_PUBLIC_KEY).unwrap(), TEST_PUBLIC_KEY);
+
+        let path =
+            std::env::temp_dir().join(format!("wasupdate-signature-{}.asc", std::process::id()));
+        fs::write(&path, TEST_PUBLIC_KEY).unwrap();
+        assert_eq!(read_key(path.to_str().unwrap()).unwrap(), TEST_PUBLIC_KEY);
+        fs::remove_file(&path).unwrap();
+
+        let err = read_key(path.to_str().unwrap()).err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::NotFound);
+    }
+
+    #[cfg(feature = "pgp")]
+    mod pgp {
+        use super::*;
+
+        const TEST_ARTIFACT: &str = "wasupdate signed artifact\n";
+        const TEST_SIGNATURE: &str = r#"-----BEGIN PGP SIGNATURE-----
+
+iHUEABYIAB0WIQTuOwY4KvUvcTVcZbCTkD5UBAbb9gUCatJEDgAKCRCTkD5UBAbb
+9t/QAP4y8Si6NfmubefWR9//Hn+MMX7oJG2CXq9yA6HHnnTFQgD7BY+VCenHf03v
+CX7N1DrZPIGbUv9IxZDVpBnoqm7YzwY=
+=qjKe
+-----END PGP SIGNATURE-----"#;
+        const TEST_EXPIRED_KEY: &str = r#"-----BEGIN PGP PUBLIC KEY BLOCK-----
+
+mDMEXgvhABYJKwYBBAHaRw8BAQdA5TRF5loJ/swhhx5ENr+2AHc9y1UYKQfHevX5
+kVeJLtm0J3dhc3VwZGF0ZSBleHBpcmVkIDxleHBpcmVkQGV4YW1wbGUuY29tPoiW
+BBMWCAA+FiEEUwAfBAZjsXvaDuIFduPmcSh7qtMFAl4L4QACGwMFCQABUYAFCwkI
+BwIGFQoJCAsCBBYCAwECHgECF4AACgkQduPmcSh7qtPpngD+M/uWMYD0apaNCEOo
+VsxPQB1e/+JkYbWFcB/OKxB3w1gBALTKJb9NNoJz559f7Ml13D4Z0cRT/kkv47GI
+DJeQT5QL
+=GpQj
+-----END PGP PUBLIC KEY BLOCK-----"#;
+        const TEST_EXPIRED_SIGNATURE: &str = r#"-----BEGIN PGP SIGNATURE-----
+
+iIoEABYIADIWIQRTAB8EBmOxe9oO4gV24+ZxKHuq0wUCXgvvEBQcZXhwaXJlZEBl
+eGFtcGxlLmNvbQAKCRB24+ZxKHuq09DlAQDIlR4SngncWWbCsintAdmtp4N2+AyV
+KxU7A6OU2hJQgAD/YQ2GCyq5zhhSTg15Qn5XjZF7Sje0WQDG3pBkwtOhYAE=
+=0K/T
+-----END PGP SIGNATURE-----"#;
+
+        fn artifact(name: &str, content: &str) -> std::path::PathBuf {
+            let path = std::env::temp_dir()
+                .join(format!("wasupdate-signature-{}-{name}", std::process::id()));
+            fs::write(&path, content).unwrap();
+            path
+        }
+
+        #[test]
+        fn test_verify_signature() {
+            let path = artifact("valid", TEST_ARTIFACT);
+            verify_signature(&path, TEST_SIGNATURE, TEST_PUBLIC_KEY).unwrap();
+            fs::remove_file(path).unwrap();
+        }
+
+        #[test]
+        fn test_verify_signature_tampered() {
+            let path = artifact("tampered", "tampered artifact\n");
+            let err = verify_signature(&path, TEST_SIGNATURE, TEST_PUBLIC_KEY)
+                .err()
+                .unwrap();
+            assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+            fs::remove_file(path).unwrap();
+        }
+
+        #[test]
+        fn test_verify_signature_expired_key() {
+            let path = artifact("expired", TEST_ARTIFACT);
+            let err = verify_signature(&path, TEST_EXPIRED_SIGNATURE, TEST_EXPIRED_KEY)
+                .err()
+                .unwrap();
+            assert_eq!(err.kind(), io::ErrorKind::PermissionDenied);
+            assert!(err.to_string().contains("expired on 2020-01-02"));
+            fs::remove_file(path).unwrap();
+        }
+    }
+}
@@ -0,0 +1,176 @@
+use std::{
+    collections::hash_map::RandomState,
+    env, fs,
+    hash::{BuildHasher, Hasher},
+    io,
+    path::{Path, PathBuf},
+    time::{Duration, SystemTime},
+};
+
+use crate::files::remove_entry;
+
+const PREFIX: &str = "wasupdate-";
+/// Overrides the system temp directory for downloads and staging.
+pub const TMPDIR_ENV: &str = "WASUPDATE_TMPDIR";
+
+/// Directory the per-run directories and partial downloads are created in, taken from
+/// `WASUPDATE_TMPDIR` when set.
+pub fn temp_root() -> PathBuf {
+    match env::var_os(TMPDIR_ENV) {
+        Some(dir) if !dir.is_empty() => PathBuf::from(dir),
+        _ => env::temp_dir(),
+    }
+}
+
+/// A uniquely named `wasupdate-<pid>-<random>` directory for the downloads and staging
+/// of one run, removed when dropped, including while unwinding from a panic.
+#[derive(Debug)]
+pub struct RunDir {
+    path: PathBuf,
+}
+
+impl RunDir {
+    /// Creates the directory in `root`, creating `root` as well when missing.
+    pub fn create(root: &Path) -> io::Result<RunDir> {
+        let random = RandomState::new().build_hasher().finish();
+        let path = root.join(format!(
+            "{PREFIX}{}-{:08x}",
+            std::process::id(),
+            random as u32
+        ));
+        fs::create_dir_all(&path).map_err(|e| {
+            io::Error::new(
+                e.kind(),
+                format!(
+                    "Cannot create a temporary directory in '{}': {e}",
+                    root.display()
+                ),
+            )
+        })?;
+        Ok(RunDir { path })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+impl Drop for RunDir {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.path);
+    }
+}
+
+/// Whether the process that created the run directory named `name` is still running.
+/// Partial downloads carry no process id and are judged by their age alone.
+fn owner_running(name: &str) -> bool {
+    name.strip_prefix(PREFIX)
+        .and_then(|rest| rest.split_once('-'))
+        .and_then(|(pid, _)| pid.parse().ok())
+        .is_some_and(process_running)
+}
+
+#[cfg(unix)]
+fn process_running(pid: u32) -> bool {
+    let Ok(pid) = libc::pid_t::try_from(pid) else {
+        return false;
+    };
+    // Signal 0 only checks that the process exists and may be signalled
+    let signalled = unsafe { libc::kill(pid, 0) } == 0;
+    signalled || io::Error::last_os_error().raw_os_error() == Some(libc::EPERM)
+}
+
+#[cfg(windows)]
+fn process_running(pid: u32) -> bool {
+    use windows_sys::Win32::{
+        Foundation::{CloseHandle, ERROR_ACCESS_DENIED, GetLastError, STILL_ACTIVE},
+        System::Threading::{GetExitCodeProcess, OpenProcess, PROCESS_QUERY_LIMITED_INFORMATION},
+    };
+
+    let handle = unsafe { OpenProcess(PROCESS_QUERY_LIMITED_INFORMATION, 0, pid) };
+    if handle.is_null() {
+        return unsafe { GetLastError() } == ERROR_ACCESS_DENIED;
+    }
+    let mut code = 0;
+    let queried = unsafe { GetExitCodeProcess(handle, &mut code) } != 0;
+    unsafe { CloseHandle(handle) };
+    queried && code == STILL_ACTIVE as u32
+}
+
+/// Removes run directories and partial downloads in `root` that were last modified
+/// more than `max_age` ago, left behind by runs that were killed. Run directories of
+/// processes that are still running are kept however old they are. Returns what was
+/// removed.
+pub fn cleanup_stale(root: &Path, max_age: Duration) -> io::Result<Vec<PathBuf>> {
+    let mut removed = vec![];
+    let Ok(entries) = fs::read_dir(root) else {
+        return Ok(removed);
+    };
+    for entry in entries.flatten() {
+        let name = entry.file_name().to_string_lossy().to_string();
+        if !name.starts_with(PREFIX) || owner_running(&name) {
+            continue;
+        }
+        let age = entry
+            .metadata()
+            .and_then(|m| m.modified())
+            .ok()
+            .and_then(|modified| SystemTime::now().duration_since(modified).ok());
+        if age.is_some_and(|age| age > max_age) && remove_entry(&entry.path()).is_ok() {
+            removed.push(entry.path());
+        }
+    }
+    Ok(removed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_run_dir_is_unique_and_removed() {
+        let root = temp_root().join(format!("wasupdate-temp-test-{}", std::process::id()));
+        let first = RunDir::create(&root).unwrap();
+        let second = RunDir::create(&root).unwrap();
+        assert_ne!(first.path(), second.path());
+        let path = first.path().to_path_buf();
+        fs::write(path.join("release.tar.gz"), "first").unwrap();
+        drop(first);
+        assert!(!path.exists());
+        assert!(second.path().exists());
+
+        // A process that has exited leaves its directory behind
+        let mut child = std::process::Command::new(std::env::current_exe().unwrap())
+            .arg("--list")
+            .stdout(std::process::Stdio::null())
+            .spawn()
+            .unwrap();
+        child.wait().unwrap();
+        let abandoned = root.join(format!("{PREFIX}{}-0badc0de", child.id()));
+        fs::create_dir_all(&abandoned).unwrap();
+
+        assert!(
+            cleanup_stale(&root, Duration::from_secs(3600))
+                .unwrap()
+                .is_empty()
+        );
+        std::thread::sleep(Duration::from_millis(20));
+        let removed = cleanup_stale(&root, Duration::from_millis(10)).unwrap();
+        assert_eq!(removed, vec![abandoned]);
+        assert!(second.path().exists());
+        drop(second);
+        fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn test_run_dir_in_unwritable_root() {
+        let file = temp_root().join(format!("wasupdate-temp-file-{}", std::process::id()));
+        fs::write(&file, "not a directory").unwrap();
+        let e = RunDir::create(&file.join("downloads")).unwrap_err();
+        assert!(
+            e.to_string()
+                .contains("Cannot create a temporary directory")
+        );
+        fs::remove_file(file).unwrap();
+    }
+}
@@ -1,25 +1,1034 @@
+use std::{
+    collections::HashMap,
+    io::{self, Error, Read},
+    path::{Path, PathBuf},
+    process::{Child, Command, Output, Stdio},
+    sync::{Arc, Mutex, RwLock},
+    thread,
+    time::{Duration, Instant},
+};
+
+use base64::{
+    Engine as _, alphabet,
+    engine::{DecodePaddingMode, GeneralPurpose, GeneralPurposeConfig, general_purpose::STANDARD},
+};
 use jmespath::{Variable, compile};
-use rhai::EvalAltResult;
-use serde_json::json;
+use regex::Regex;
+use reqwest::{Method, StatusCode, Url};
+use rhai::{Array, Dynamic, EvalAltResult, FnPtr, Map, NativeCallContext};
+use semver::{Version, VersionReq};
+use serde::Deserialize;
+use serde_json::Value;
+
+use crate::{
+    checksum::{self, Algorithm, Hasher},
+    http::{
+        DEFAULT_USER_AGENT, HttpSettings, default_client, manual_redirect_client, percent_encode,
+        request_error, settings, with_headers,
+    },
+    install::{InstallOptions, build_globset, download_archive},
+    retry::{Failure, check_response, is_transient_status, policy, retry},
+    temp::RunDir,
+};
+
+const CRATES_API: &str = "https://crates.io/api/v1/crates";
+const CRATES_DOWNLOAD: &str = "https://static.crates.io/crates";
+const GITHUB_API: &str = "https://api.github.com";
+const GITHUB_TOKEN_ENV: &str = "GITHUB_TOKEN";
+const GITLAB_PACKAGE_PAGE_SIZE: usize = 100;
+/// How much of a body that is not JSON to show in the error.
+const BODY_EXCERPT_CHARS: usize = 200;
+/// URL-safe base64 without padding, decoding input with or without it.
+const URL_SAFE: GeneralPurpose = GeneralPurpose::new(
+    &alphabet::URL_SAFE,
+    GeneralPurposeConfig::new()
+        .with_encode_padding(false)
+        .with_decode_padding_mode(DecodePaddingMode::Indifferent),
+);
+
+/// Options for [`download`], set by the caller before running the script.
+static DOWNLOAD_OPTIONS: RwLock<Option<InstallOptions>> = RwLock::new(None);
+/// The run directory holding the script's downloads and how many it made.
+static DOWNLOADS: Mutex<Option<(RunDir, usize)>> = Mutex::new(None);
+/// Time commands started by the script get before they are killed.
+pub const DEFAULT_RUN_TIMEOUT: Duration = Duration::from_secs(600);
+/// Longest a single [`sleep_ms`] waits unless changed with [`set_max_sleep`].
+pub const DEFAULT_MAX_SLEEP: Duration = Duration::from_secs(60);
+static MAX_SLEEP: RwLock<Option<Duration>> = RwLock::new(Some(DEFAULT_MAX_SLEEP));
+
+/// GETs `url` with the shared client and retry policy, returning the body.
+fn get_text(url: &str, headers: &[(String, String)]) -> io::Result<String> {
+    retry(&policy(), &format!("Fetching {url}"), || {
+        let request = with_headers(default_client()?.get(url), headers)?;
+        let response = check_response(request.send())?;
+        response
+            .text()
+            .map_err(|e| Failure::Transient(request_error(e)))
+    })
+}
+
+/// URL and sorted headers of a GET.
+type RequestKey = (String, Vec<(String, String)>);
+
+/// Bodies of successful GETs, keyed by URL and headers, so a script asking for the same
+/// document twice makes one request. Clones share the responses.
+#[derive(Debug, Clone, Default)]
+pub struct FetchCache {
+    responses: Arc<Mutex<HashMap<RequestKey, String>>>,
+}
+
+impl FetchCache {
+    /// [`fetch`], answered from the cache when the URL was fetched before.
+    pub fn fetch(&self, url: &str) -> Result<String, Box<EvalAltResult>> {
+        fetch_text(Some(self), url, Vec::new())
+    }
+
+    /// [`fetch_with_headers`], answered from the cache for the same URL and headers.
+    pub fn fetch_with_headers(
+        &self,
+        url: &str,
+        headers: Map,
+    ) -> Result<String, Box<EvalAltResult>> {
+        fetch_text(Some(self), url, header_list(headers)?)
+    }
+
+    /// [`fetch_json`], answered from the cache when the URL was fetched before.
+    pub fn fetch_json(&self, url: &str) -> Result<Dynamic, Box<EvalAltResult>> {
+        fetch_json_from(Some(self), url)
+    }
 
+    fn get_text(&self, url: &str, mut headers: Vec<(String, String)>) -> io::Result<String> {
+        headers.sort();
+        let key = (url.to_string(), headers);
+        if let Some(body) = self.responses.lock().unwrap().get(&key) {
+            return Ok(body.clone());
+        }
+        let body = get_text(url, &key.1)?;
+        self.responses.lock().unwrap().insert(key, body.clone());
+        Ok(body)
+    }
+}
+
+fn fetch_text(
+    cache: Option<&FetchCache>,
+    url: &str,
+    headers: Vec<(String, String)>,
+) -> Result<String, Box<EvalAltResult>> {
+    match cache {
+        Some(cache) => cache.get_text(url, headers),
+        None => get_text(url, &headers),
+    }
+    .map_err(|e| format!("Failed to fetch URL: {url}: {e}").into())
+}
+
+/// GETs `url` and returns the body, without looking at earlier responses.
 pub fn fetch(url: &str) -> Result<String, Box<EvalAltResult>> {
-    let Ok(response) = reqwest::blocking::get(url) else {
-        let error_msg = format!("Failed to fetch URL: {url}");
-        return Err(error_msg.into());
+    fetch_text(None, url, Vec::new())
+}
+
+/// Like [`fetch`], sending the header names and values in `headers`. Values are kept out
+/// of errors, as they usually hold credentials.
+pub fn fetch_with_headers(url: &str, headers: Map) -> Result<String, Box<EvalAltResult>> {
+    fetch_text(None, url, header_list(headers)?)
+}
+
+fn header_list(headers: Map) -> Result<Vec<(String, String)>, Box<EvalAltResult>> {
+    headers
+        .into_iter()
+        .map(|(name, value)| {
+            let value = value
+                .into_string()
+                .map_err(|t| format!("Value of header '{name}' should be a string, found: {t}"))?;
+            Ok((name.to_string(), value))
+        })
+        .collect()
+}
+
+/// Sets the retry, HTTP and download directory options [`download`] uses. The retry
+/// policy and HTTP settings shared with [`fetch`] are used until this is called.
+pub fn set_download_options(opts: InstallOptions) {
+    *DOWNLOAD_OPTIONS.write().unwrap() = Some(opts);
+}
+
+/// Downloads `url` into this run's temp directory like the update itself, returning the
+/// path of the file. The file stays until [`cleanup_downloads`], so `install_version`
+/// can hand it back as the artifact.
+pub fn download(url: &str) -> Result<String, Box<EvalAltResult>> {
+    let opts = DOWNLOAD_OPTIONS
+        .read()
+        .unwrap()
+        .clone()
+        .unwrap_or_else(|| InstallOptions {
+            retry: policy(),
+            http: settings(),
+            ..Default::default()
+        });
+    let error = |e: Error| format!("Failed to download {url}: {e}");
+    let work_dir = {
+        let mut downloads = DOWNLOADS.lock().unwrap();
+        if downloads.is_none() {
+            *downloads = Some((RunDir::create(&opts.download_root()).map_err(error)?, 0));
+        }
+        let (run_dir, count) = downloads.as_mut().unwrap();
+        *count += 1;
+        // Each download gets its own directory, so equally named files do not collide
+        run_dir.path().join(count.to_string())
     };
-    if response.status().is_success() {
-        let Ok(body) = response.text() else {
-            let error_msg = format!("Failed to read response body from URL: {url}");
-            return Err(error_msg.into());
+    std::fs::create_dir_all(&work_dir).map_err(error)?;
+    let path = download_archive(
+        url,
+        &InstallOptions {
+            work_dir: Some(work_dir),
+            ..opts
+        },
+    )
+    .map_err(error)?;
+    Ok(path.display().to_string())
+}
+
+/// Removes the files downloaded by [`download`].
+pub fn cleanup_downloads() {
+    DOWNLOADS.lock().unwrap().take();
+}
+
+/// POSTs `body` to `url`, see [`http_request`].
+pub fn http_post(url: &str, body: &str, headers: Map) -> Result<Map, Box<EvalAltResult>> {
+    http_request("POST", url, body, headers)
+}
+
+/// Sends a `method` request with `body` and `headers` to `url`, returning a map with the
+/// `status`, the `headers` with lowercase names and the `body` of the response, whatever
+/// the status. Redirects are followed unless they would carry the body, the headers or
+/// the default headers of the [`settings`] to another origin, where they could leak
+/// credentials.
+pub fn http_request(
+    method: &str,
+    url: &str,
+    body: &str,
+    headers: Map,
+) -> Result<Map, Box<EvalAltResult>> {
+    http_request_with(&settings(), method, url, body, headers)
+}
+
+fn http_request_with(
+    settings: &HttpSettings,
+    method: &str,
+    url: &str,
+    body: &str,
+    headers: Map,
+) -> Result<Map, Box<EvalAltResult>> {
+    let mut method = Method::from_bytes(method.to_ascii_uppercase().as_bytes())
+        .map_err(|_| format!("Invalid HTTP method '{method}'"))?;
+    let headers = header_list(headers)?;
+    let mut body = Some(body.to_string()).filter(|body| !body.is_empty());
+    let mut url = Url::parse(url).map_err(|e| format!("Invalid URL '{url}': {e}"))?;
+    let max_redirects = settings.max_redirects;
+    for _ in 0..=max_redirects {
+        let response = send(settings, &method, &url, body.as_deref(), &headers)
+            .map_err(|e| format!("Failed to send {method} request to {url}: {e}"))?;
+        let status = response.status();
+        let location = response
+            .headers()
+            .get(reqwest::header::LOCATION)
+            .and_then(|location| location.to_str().ok())
+            .and_then(|location| url.join(location).ok());
+        let Some(next) = location.filter(|_| status.is_redirection()) else {
+            return response_map(response);
         };
-        Ok(body)
+        // 303 always, and 301/302 in practice, turn into a GET without body
+        if status == StatusCode::SEE_OTHER
+            || (method == Method::POST && matches!(status.as_u16(), 301 | 302))
+        {
+            method = Method::GET;
+            body = None;
+        }
+        let sends_secrets =
+            body.is_some() || !headers.is_empty() || !settings.default_headers.is_empty();
+        if next.origin() != url.origin() && sends_secrets {
+            return Err(format!(
+                "Refusing to follow the redirect from {} to {}, it would send the request body or headers to another origin",
+                url.origin().ascii_serialization(),
+                next.origin().ascii_serialization()
+            )
+            .into());
+        }
+        url = next;
+    }
+    Err(format!("Stopped after {max_redirects} redirects at {url}").into())
+}
+
+fn send(
+    settings: &HttpSettings,
+    method: &Method,
+    url: &Url,
+    body: Option<&str>,
+    headers: &[(String, String)],
+) -> io::Result<reqwest::blocking::Response> {
+    let policy = policy();
+    let mut attempts = 0;
+    retry(
+        &policy,
+        &format!("Sending {method} request to {url}"),
+        || {
+            attempts += 1;
+            let mut request =
+                manual_redirect_client(settings)?.request(method.clone(), url.clone());
+            if let Some(body) = body {
+                request = request.body(body.to_string());
+            }
+            let response = with_headers(request, headers)?.send().map_err(|e| {
+                if e.is_builder() {
+                    Failure::Permanent(Error::other(e))
+                } else {
+                    Failure::Transient(request_error(e))
+                }
+            })?;
+            // The last answer goes back to the script, which can branch on the status
+            if is_transient_status(response.status()) && attempts < policy.attempts {
+                return Err(Failure::Transient(Error::other(format!(
+                    "{url} responded with {}",
+                    response.status()
+                ))));
+            }
+            Ok(response)
+        },
+    )
+}
+
+fn response_map(response: reqwest::blocking::Response) -> Result<Map, Box<EvalAltResult>> {
+    let mut map = Map::new();
+    map.insert(
+        "status".into(),
+        i64::from(response.status().as_u16()).into(),
+    );
+    let mut headers = Map::new();
+    for (name, value) in response.headers() {
+        let value = String::from_utf8_lossy(value.as_bytes()).to_string();
+        match headers.get_mut(name.as_str()) {
+            Some(existing) => {
+                *existing = format!("{existing}, {value}").into();
+            }
+            None => {
+                headers.insert(name.as_str().into(), value.into());
+            }
+        }
+    }
+    map.insert("headers".into(), headers.into());
+    let url = response.url().to_string();
+    let body = response
+        .text()
+        .map_err(|e| format!("Failed to read the response of {url}: {}", request_error(e)))?;
+    map.insert("body".into(), body.into());
+    Ok(map)
+}
+
+/// GETs the JSON document at `url` as rhai maps, arrays and scalars.
+pub fn fetch_json(url: &str) -> Result<Dynamic, Box<EvalAltResult>> {
+    fetch_json_from(None, url)
+}
+
+fn fetch_json_from(cache: Option<&FetchCache>, url: &str) -> Result<Dynamic, Box<EvalAltResult>> {
+    let headers = vec![("Accept".to_string(), "application/json".to_string())];
+    let body = fetch_text(cache, url, headers)?;
+    parse_json(&body).map_err(|e| format!("Response of {url} is not JSON: {e}").into())
+}
+
+/// Parses `text` as JSON into rhai maps, arrays and scalars, with `()` for null.
+pub fn parse_json(text: &str) -> Result<Dynamic, Box<EvalAltResult>> {
+    match serde_json::from_str(text) {
+        Ok(value) => Ok(json_to_dynamic(value)),
+        Err(e) => {
+            let excerpt = text.chars().take(BODY_EXCERPT_CHARS).collect::<String>();
+            let ellipsis = if excerpt.len() < text.len() {
+                "..."
+            } else {
+                ""
+            };
+            Err(format!("Invalid JSON: {e}, body: '{excerpt}{ellipsis}'").into())
+        }
+    }
+}
+
+fn json_to_dynamic(value: Value) -> Dynamic {
+    match value {
+        Value::Null => Dynamic::UNIT,
+        Value::Bool(b) => b.into(),
+        Value::Number(n) => match n.as_i64() {
+            Some(i) => i.into(),
+            None => n.as_f64().unwrap_or(f64::NAN).into(),
+        },
+        Value::String(s) => s.into(),
+        Value::Array(items) => items
+            .into_iter()
+            .map(json_to_dynamic)
+            .collect::<Array>()
+            .into(),
+        Value::Object(fields) => fields
+            .into_iter()
+            .map(|(key, value)| (key.into(), json_to_dynamic(value)))
+            .collect::<Map>()
+            .into(),
+    }
+}
+
+/// Parses `text` as YAML like [`parse_json`], with anchors and merge keys resolved. A stream
+/// of several documents gives an array with one entry per document.
+pub fn parse_yaml(text: &str) -> Result<Dynamic, Box<EvalAltResult>> {
+    let mut documents = serde_yaml::Deserializer::from_str(text)
+        .map(|document| {
+            let mut value = serde_yaml::Value::deserialize(document)?;
+            value.apply_merge()?;
+            Ok(yaml_to_dynamic(value))
+        })
+        .collect::<Result<Array, serde_yaml::Error>>()
+        .map_err(|e| format!("Invalid YAML: {e}"))?;
+    Ok(match documents.len() {
+        0 => Dynamic::UNIT,
+        1 => documents.remove(0),
+        _ => documents.into(),
+    })
+}
+
+fn yaml_to_dynamic(value: serde_yaml::Value) -> Dynamic {
+    use serde_yaml::Value as Yaml;
+    match value {
+        Yaml::Null => Dynamic::UNIT,
+        Yaml::Bool(b) => b.into(),
+        Yaml::Number(n) => match n.as_i64() {
+            Some(i) => i.into(),
+            None => n.as_f64().unwrap_or(f64::NAN).into(),
+        },
+        Yaml::String(s) => s.into(),
+        Yaml::Sequence(items) => items
+            .into_iter()
+            .map(yaml_to_dynamic)
+            .collect::<Array>()
+            .into(),
+        Yaml::Mapping(fields) => fields
+            .into_iter()
+            .map(|(key, value)| (yaml_key(key).into(), yaml_to_dynamic(value)))
+            .collect::<Map>()
+            .into(),
+        Yaml::Tagged(tagged) => yaml_to_dynamic(tagged.value),
+    }
+}
+
+/// Map keys are strings in rhai, so scalar keys such as `1` or `true` keep their YAML text.
+fn yaml_key(key: serde_yaml::Value) -> String {
+    match key {
+        serde_yaml::Value::String(s) => s,
+        other => serde_yaml::to_string(&other)
+            .unwrap_or_default()
+            .trim_end()
+            .to_string(),
+    }
+}
+
+/// Parses `text` as a TOML document into nested rhai maps, with datetimes as strings.
+pub fn parse_toml(text: &str) -> Result<Map, Box<EvalAltResult>> {
+    let table = text
+        .parse::<toml::Table>()
+        .map_err(|e| format!("Invalid TOML: {e}"))?;
+    Ok(toml_table(table))
+}
+
+fn toml_table(table: toml::Table) -> Map {
+    table
+        .into_iter()
+        .map(|(key, value)| (key.into(), toml_to_dynamic(value)))
+        .collect()
+}
+
+fn toml_to_dynamic(value: toml::Value) -> Dynamic {
+    match value {
+        toml::Value::String(s) => s.into(),
+        toml::Value::Integer(i) => i.into(),
+        toml::Value::Float(f) => f.into(),
+        toml::Value::Boolean(b) => b.into(),
+        toml::Value::Datetime(datetime) => datetime.to_string().into(),
+        toml::Value::Array(items) => items
+            .into_iter()
+            .map(toml_to_dynamic)
+            .collect::<Array>()
+            .into(),
+        toml::Value::Table(table) => toml_table(table).into(),
+    }
+}
+
+/// Text of every element of `html` matching the CSS `selector`, with surrounding
+/// whitespace trimmed.
+pub fn html_select(html: &str, selector: &str) -> Result<Array, Box<EvalAltResult>> {
+    let selector = parse_selector(selector)?;
+    Ok(scraper::Html::parse_document(html)
+        .select(&selector)
+        .map(|element| element.text().collect::<String>().trim().to_string().into())
+        .collect())
+}
+
+/// Value of `attr` on every element of `html` matching the CSS `selector`. Elements
+/// without the attribute are left out.
+pub fn html_select_attr(
+    html: &str,
+    selector: &str,
+    attr: &str,
+) -> Result<Array, Box<EvalAltResult>> {
+    let selector = parse_selector(selector)?;
+    Ok(scraper::Html::parse_document(html)
+        .select(&selector)
+        .filter_map(|element| element.value().attr(attr))
+        .map(|value| value.into())
+        .collect())
+}
+
+fn parse_selector(selector: &str) -> Result<scraper::Selector, Box<EvalAltResult>> {
+    scraper::Selector::parse(selector)
+        .map_err(|e| format!("Invalid CSS selector '{selector}': {e}").into())
+}
+
+/// Resolves `relative` against `base` the way a browser resolves a link on the page.
+pub fn url_join(base: &str, relative: &str) -> Result<String, Box<EvalAltResult>> {
+    let base = Url::parse(base).map_err(|e| format!("Invalid URL '{base}': {e}"))?;
+    base.join(relative)
+        .map(String::from)
+        .map_err(|e| format!("Cannot join '{relative}' to '{base}': {e}").into())
+}
+
+/// GETs the JSON document at `url`, or `None` when the server answers 404.
+fn get_json(url: &str, headers: &[(String, String)]) -> io::Result<Option<Value>> {
+    retry(&policy(), &format!("Fetching {url}"), || {
+        let request = with_headers(default_client()?.get(url), headers)?;
+        let result = request.send();
+        if let Ok(response) = &result {
+            if response.status() == StatusCode::NOT_FOUND {
+                return Ok(None);
+            }
+            let exhausted = response
+                .headers()
+                .get("x-ratelimit-remaining")
+                .is_some_and(|remaining| remaining == "0");
+            if exhausted && matches!(response.status().as_u16(), 403 | 429) {
+                return Err(Failure::Permanent(Error::new(
+                    io::ErrorKind::PermissionDenied,
+                    format!(
+                        "{url} responded with {}: API rate limit exceeded",
+                        response.status()
+                    ),
+                )));
+            }
+        }
+        let body = check_response(result)?
+            .text()
+            .map_err(|e| Failure::Transient(request_error(e)))?;
+        serde_json::from_str(&body).map(Some).map_err(|e| {
+            Failure::Permanent(Error::new(
+                io::ErrorKind::InvalidData,
+                format!("Invalid JSON from {url}: {e}"),
+            ))
+        })
+    })
+}
+
+/// Headers identifying wasupdate to public APIs that insist on a descriptive User-Agent.
+fn api_headers() -> Vec<(String, String)> {
+    match settings().user_agent {
+        Some(_) => vec![],
+        None => vec![(
+            "User-Agent".to_string(),
+            format!("{DEFAULT_USER_AGENT} (+https://github.com/DanielHauge/wasupdate)"),
+        )],
+    }
+}
+
+/// The API token in the environment variable `name`, if set and not empty.
+fn env_token(name: &str) -> Option<String> {
+    std::env::var(name).ok().filter(|token| !token.is_empty())
+}
+
+/// A release of a forge like GitHub, reduced to what scripts need.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Release {
+    tag: String,
+    draft: bool,
+    prerelease: bool,
+    /// Asset names and their download URLs.
+    assets: Vec<(String, String)>,
+}
+
+impl Release {
+    /// Reads a release in the shape GitHub, Gitea and Forgejo share.
+    fn from_github(json: &Value) -> Option<Release> {
+        Some(Release {
+            tag: json["tag_name"].as_str()?.to_string(),
+            draft: json["draft"].as_bool().unwrap_or(false),
+            prerelease: json["prerelease"].as_bool().unwrap_or(false),
+            assets: json["assets"]
+                .as_array()
+                .into_iter()
+                .flatten()
+                .filter_map(|asset| {
+                    Some((
+                        asset["name"].as_str()?.to_string(),
+                        asset["browser_download_url"].as_str()?.to_string(),
+                    ))
+                })
+                .collect(),
+        })
+    }
+
+    /// The tag without a leading `v`, as scripts compare versions without it.
+    fn version(&self) -> &str {
+        strip_v(&self.tag)
+    }
+
+    /// Reads a GitLab release, whose assets are the links attached to it.
+    fn from_gitlab(json: &Value) -> Option<Release> {
+        Some(Release {
+            tag: json["tag_name"].as_str()?.to_string(),
+            // Releases scheduled for a later date are not published yet
+            draft: json["upcoming_release"].as_bool().unwrap_or(false),
+            prerelease: false,
+            assets: json["assets"]["links"]
+                .as_array()
+                .into_iter()
+                .flatten()
+                .filter_map(|link| {
+                    Some((
+                        link["name"].as_str()?.to_string(),
+                        link["url"].as_str()?.to_string(),
+                    ))
+                })
+                .collect(),
+        })
+    }
+
+    /// Download URL of the first asset whose name matches the glob `pattern`, if any.
+    fn find_asset(&self, pattern: &str) -> Result<Option<String>, Box<EvalAltResult>> {
+        let matcher = build_globset(&[pattern.to_string()]).map_err(|e| e.to_string())?;
+        Ok(self
+            .assets
+            .iter()
+            .find(|(name, _)| matcher.is_match(name))
+            .map(|(_, url)| url.clone()))
+    }
+
+    /// Like [`Release::find_asset`], failing with the available names when nothing matches.
+    fn asset(&self, pattern: &str) -> Result<String, Box<EvalAltResult>> {
+        if let Some(url) = self.find_asset(pattern)? {
+            return Ok(url);
+        }
+        let names = self
+            .assets
+            .iter()
+            .map(|(name, _)| name.as_str())
+            .collect::<Vec<_>>();
+        Err(if names.is_empty() {
+            format!("Release {} has no assets", self.tag)
+        } else {
+            format!(
+                "No asset of release {} matches '{pattern}', it has: {}",
+                self.tag,
+                names.join(", ")
+            )
+        }
+        .into())
+    }
+}
+
+fn strip_v(tag: &str) -> &str {
+    tag.strip_prefix('v')
+        .filter(|rest| rest.starts_with(|c: char| c.is_ascii_digit()))
+        .unwrap_or(tag)
+}
+
+/// The newest release that is not a draft, nor a prerelease unless `prereleases` is set.
+/// Forges list releases newest first.
+fn newest(releases: &Value, prereleases: bool) -> Option<Release> {
+    releases
+        .as_array()?
+        .iter()
+        .filter_map(Release::from_github)
+        .find(|release| !release.draft && (prereleases || !release.prerelease))
+}
+
+/// Tags a release of `version` is likely published under, as given and with the `v`
+/// prefix toggled.
+fn tag_candidates(version: &str) -> Vec<String> {
+    let other = match version.strip_prefix('v') {
+        Some(stripped) => stripped.to_string(),
+        None => format!("v{version}"),
+    };
+    vec![version.to_string(), other]
+}
+
+fn check_repo(repo: &str) -> Result<(&str, &str), Box<EvalAltResult>> {
+    let valid = |part: &str| {
+        !part.is_empty()
+            && part != "."
+            && part != ".."
+            && part
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.'))
+    };
+    match repo.split_once('/') {
+        Some((owner, name)) if valid(owner) && valid(name) => Ok((owner, name)),
+        _ => Err(format!("Invalid repository '{repo}', expected 'owner/repo'").into()),
+    }
+}
+
+/// Version of the newest published, non-prerelease GitHub release of `repo`.
+pub fn github_latest_release(repo: &str) -> Result<String, Box<EvalAltResult>> {
+    github_latest_release_from(GITHUB_API, repo, false)
+}
+
+/// Like [`github_latest_release`], considering prereleases when `prereleases` is set.
+pub fn github_latest_release_with(
+    repo: &str,
+    prereleases: bool,
+) -> Result<String, Box<EvalAltResult>> {
+    github_latest_release_from(GITHUB_API, repo, prereleases)
+}
+
+fn github_latest_release_from(
+    api: &str,
+    repo: &str,
+    prereleases: bool,
+) -> Result<String, Box<EvalAltResult>> {
+    let (owner, name) = check_repo(repo)?;
+    let url = format!("{api}/repos/{owner}/{name}/releases?per_page=100");
+    let releases = get_json(&url, &github_headers())
+        .map_err(|e| github_error(repo, e))?
+        .ok_or_else(|| format!("GitHub repository '{repo}' not found"))?;
+    newest(&releases, prereleases)
+        .map(|release| release.version().to_string())
+        .ok_or_else(|| format!("GitHub repository '{repo}' has no published releases").into())
+}
+
+/// Download URL of the first asset of the `version` release of `repo` whose name
+/// matches the glob `pattern`.
+pub fn github_release_asset(
+    repo: &str,
+    version: &str,
+    pattern: &str,
+) -> Result<String, Box<EvalAltResult>> {
+    github_release_asset_from(GITHUB_API, repo, version, pattern)
+}
+
+fn github_release_asset_from(
+    api: &str,
+    repo: &str,
+    version: &str,
+    pattern: &str,
+) -> Result<String, Box<EvalAltResult>> {
+    let (owner, name) = check_repo(repo)?;
+    for tag in tag_candidates(version) {
+        let url = format!(
+            "{api}/repos/{owner}/{name}/releases/tags/{}",
+            percent_encode(&tag)
+        );
+        let found = get_json(&url, &github_headers()).map_err(|e| github_error(repo, e))?;
+        if let Some(release) = found.as_ref().and_then(Release::from_github) {
+            return release.asset(pattern);
+        }
+    }
+    Err(format!("GitHub repository '{repo}' has no release for version {version}").into())
+}
+
+fn github_headers() -> Vec<(String, String)> {
+    let mut headers = api_headers();
+    headers.push((
+        "Accept".to_string(),
+        "application/vnd.github+json".to_string(),
+    ));
+    if let Some(token) = env_token(GITHUB_TOKEN_ENV) {
+        headers.push(("Authorization".to_string(), format!("Bearer {token}")));
+    }
+    headers
+}
+
+fn github_error(repo: &str, e: Error) -> String {
+    if e.kind() == io::ErrorKind::PermissionDenied && std::env::var(GITHUB_TOKEN_ENV).is_err() {
+        format!(
+            "Failed to query GitHub releases of '{repo}': {e}, set {GITHUB_TOKEN_ENV} to raise the limit"
+        )
     } else {
-        let response_status = response.status();
-        let error_msg = format!("Failed to fetch URL: {url} with status: {response_status}");
-        Err(error_msg.into())
+        format!("Failed to query GitHub releases of '{repo}': {e}")
     }
 }
 
+/// Tag of the newest published release of the GitLab `project`, given by its ID or full
+/// path like `group/sub/project`, on the instance at `base_url`. The token in the
+/// environment variable `token_env` is sent when set, for private projects.
+pub fn gitlab_latest_release(
+    base_url: &str,
+    project: &str,
+    token_env: &str,
+) -> Result<String, Box<EvalAltResult>> {
+    let url = format!("{}/releases", gitlab_project_url(base_url, project));
+    let releases = get_json(&url, &gitlab_headers(Some(token_env)))
+        .map_err(|e| format!("Failed to query GitLab releases of '{project}': {e}"))?
+        .ok_or_else(|| format!("GitLab project '{project}' not found"))?;
+    releases
+        .as_array()
+        .into_iter()
+        .flatten()
+        .filter_map(Release::from_gitlab)
+        .find(|release| !release.draft)
+        .map(|release| release.tag)
+        .ok_or_else(|| format!("GitLab project '{project}' has no published releases").into())
+}
+
+/// URL of the first asset of the `version` release of the GitLab `project` whose name
+/// matches the glob `pattern`. Release links are searched first, then the files of the
+/// generic packages published with that version.
+pub fn gitlab_release_asset(
+    base_url: &str,
+    project: &str,
+    version: &str,
+    pattern: &str,
+) -> Result<String, Box<EvalAltResult>> {
+    gitlab_release_asset_from(base_url, project, version, pattern, None)
+}
+
+/// Like [`gitlab_release_asset`], sending the token in the environment variable `token_env`.
+pub fn gitlab_release_asset_with_token(
+    base_url: &str,
+    project: &str,
+    version: &str,
+    pattern: &str,
+    token_env: &str,
+) -> Result<String, Box<EvalAltResult>> {
+    gitlab_release_asset_from(base_url, project, version, pattern, Some(token_env))
+}
+
+fn gitlab_release_asset_from(
+    base_url: &str,
+    project: &str,
+    version: &str,
+    pattern: &str,
+    token_env: Option<&str>,
+) -> Result<String, Box<EvalAltResult>> {
+    let project_url = gitlab_project_url(base_url, project);
+    let headers = gitlab_headers(token_env);
+    let error = |e: Error| format!("Failed to query GitLab releases of '{project}': {e}");
+    for tag in tag_candidates(version) {
+        let url = format!("{project_url}/releases/{}", percent_encode(&tag));
+        let Some(mut release) = get_json(&url, &headers)
+            .map_err(error)?
+            .as_ref()
+            .and_then(Release::from_gitlab)
+        else {
+            continue;
+        };
+        if let Some(url) = release.find_asset(pattern)? {
+            return Ok(url);
+        }
+        // Packages are published under the tag or the bare version
+        let mut versions = vec![tag.as_str(), strip_v(&tag)];
+        versions.dedup();
+        for version in versions {
+            release
+                .assets
+                .extend(gitlab_package_files(&project_url, version, &headers).map_err(error)?);
+            if let Some(url) = release.find_asset(pattern)? {
+                return Ok(url);
+            }
+        }
+        return release.asset(pattern);
+    }
+    Err(format!("GitLab project '{project}' has no release for version {version}").into())
+}
+
+/// Names and download URLs of the files in the generic packages of `project_url` that
+/// were published as `version`.
+fn gitlab_package_files(
+    project_url: &str,
+    version: &str,
+    headers: &[(String, String)],
+) -> io::Result<Vec<(String, String)>> {
+    let url = format!(
+        "{project_url}/packages?package_type=generic&package_version={}&per_page={GITLAB_PACKAGE_PAGE_SIZE}",
+        percent_encode(version)
+    );
+    let mut files = vec![];
+    let packages = get_json(&url, headers)?.unwrap_or_default();
+    for package in packages.as_array().into_iter().flatten() {
+        let (Some(id), Some(name)) = (package["id"].as_u64(), package["name"].as_str()) else {
+            continue;
+        };
+        let url = format!(
+            "{project_url}/packages/{id}/package_files?per_page={GITLAB_PACKAGE_PAGE_SIZE}"
+        );
+        let listed = get_json(&url, headers)?.unwrap_or_default();
+        for file in listed.as_array().into_iter().flatten() {
+            if let Some(file_name) = file["file_name"].as_str() {
+                files.push((
+                    file_name.to_string(),
+                    format!(
+                        "{project_url}/packages/generic/{}/{}/{}",
+                        percent_encode(name),
+                        percent_encode(version),
+                        percent_encode(file_name)
+                    ),
+                ));
+            }
+        }
+    }
+    Ok(files)
+}
+
+/// API URL of `project`, whose path has to be escaped into a single segment.
+fn gitlab_project_url(base_url: &str, project: &str) -> String {
+    format!(
+        "{}/api/v4/projects/{}",
+        base_url.trim_end_matches('/'),
+        percent_encode(project.trim_matches('/'))
+    )
+}
+
+fn gitlab_headers(token_env: Option<&str>) -> Vec<(String, String)> {
+    let mut headers = api_headers();
+    if let Some(token) = token_env.and_then(env_token) {
+        headers.push(("PRIVATE-TOKEN".to_string(), token));
+    }
+    headers
+}
+
+/// Version of the newest published, non-prerelease release of `repo` on the Gitea or
+/// Forgejo instance at `base_url`.
+pub fn gitea_latest_release(base_url: &str, repo: &str) -> Result<String, Box<EvalAltResult>> {
+    gitea_latest_release_from(base_url, repo, None)
+}
+
+/// Like [`gitea_latest_release`], sending the token in the environment variable `token_env`.
+pub fn gitea_latest_release_with_token(
+    base_url: &str,
+    repo: &str,
+    token_env: &str,
+) -> Result<String, Box<EvalAltResult>> {
+    gitea_latest_release_from(base_url, repo, Some(token_env))
+}
+
+fn gitea_latest_release_from(
+    base_url: &str,
+    repo: &str,
+    token_env: Option<&str>,
+) -> Result<String, Box<EvalAltResult>> {
+    let url = format!("{}/releases?limit=50", gitea_repo_url(base_url, repo)?);
+    let releases = get_json(&url, &gitea_headers(token_env))
+        .map_err(|e| format!("Failed to query releases of '{repo}': {e}"))?
+        .ok_or_else(|| format!("Repository '{repo}' not found on {base_url}"))?;
+    newest(&releases, false)
+        .map(|release| release.version().to_string())
+        .ok_or_else(|| format!("Repository '{repo}' has no published releases").into())
+}
+
+/// Download URL of the first asset of the `version` release of `repo` on the Gitea or
+/// Forgejo instance at `base_url` whose name matches the glob `pattern`.
+pub fn gitea_release_asset(
+    base_url: &str,
+    repo: &str,
+    version: &str,
+    pattern: &str,
+) -> Result<String, Box<EvalAltResult>> {
+    gitea_release_asset_from(base_url, repo, version, pattern, None)
+}
+
+/// Like [`gitea_release_asset`], sending the token in the environment variable `token_env`.
+pub fn gitea_release_asset_with_token(
+    base_url: &str,
+    repo: &str,
+    version: &str,
+    pattern: &str,
+    token_env: &str,
+) -> Result<String, Box<EvalAltResult>> {
+    gitea_release_asset_from(base_url, repo, version, pattern, Some(token_env))
+}
+
+fn gitea_release_asset_from(
+    base_url: &str,
+    repo: &str,
+    version: &str,
+    pattern: &str,
+    token_env: Option<&str>,
+) -> Result<String, Box<EvalAltResult>> {
+    let repo_url = gitea_repo_url(base_url, repo)?;
+    let headers = gitea_headers(token_env);
+    let error = |e: Error| format!("Failed to query releases of '{repo}': {e}");
+    for tag in tag_candidates(version) {
+        let url = format!("{repo_url}/releases/tags/{}", percent_encode(&tag));
+        let found = get_json(&url, &headers).map_err(error)?;
+        if let Some(release) = found.as_ref().and_then(Release::from_github) {
+            return release.asset(pattern);
+        }
+    }
+    // Missing tags and missing repositories both answer 404
+    if get_json(&repo_url, &headers).map_err(error)?.is_none() {
+        return Err(format!("Repository '{repo}' not found on {base_url}").into());
+    }
+    Err(format!("Repository '{repo}' has no release for version {version}").into())
+}
+
+fn gitea_repo_url(base_url: &str, repo: &str) -> Result<String, Box<EvalAltResult>> {
+    let (owner, name) = check_repo(repo)?;
+    Ok(format!(
+        "{}/api/v1/repos/{owner}/{name}",
+        base_url.trim_end_matches('/')
+    ))
+}
+
+fn gitea_headers(token_env: Option<&str>) -> Vec<(String, String)> {
+    let mut headers = api_headers();
+    if let Some(token) = token_env.and_then(env_token) {
+        headers.push(("Authorization".to_string(), format!("token {token}")));
+    }
+    headers
+}
+
+fn check_crate_name(name: &str) -> Result<(), Box<EvalAltResult>> {
+    if name.is_empty()
+        || !name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+    {
+        return Err(format!("Invalid crate name '{name}'").into());
+    }
+    Ok(())
+}
+
+/// Highest stable version of the crate `name` on crates.io, skipping yanked versions.
+pub fn crates_latest(name: &str) -> Result<String, Box<EvalAltResult>> {
+    crates_latest_from(CRATES_API, name)
+}
+
+fn crates_latest_from(api: &str, name: &str) -> Result<String, Box<EvalAltResult>> {
+    check_crate_name(name)?;
+    // crates.io rejects requests without a User-Agent that identifies the client
+    let body = get_text(&format!("{api}/{name}"), &api_headers())
+        .map_err(|e| format!("Failed to look up crate '{name}' on crates.io: {e}"))?;
+    let json: serde_json::Value = serde_json::from_str(&body)
+        .map_err(|e| format!("Invalid crates.io response for '{name}': {e}"))?;
+    json["versions"]
+        .as_array()
+        .into_iter()
+        .flatten()
+        .filter(|version| !version["yanked"].as_bool().unwrap_or(false))
+        .filter_map(|version| Version::parse(version["num"].as_str()?).ok())
+        .filter(|version| version.pre.is_empty())
+        .max()
+        .map(|version| version.to_string())
+        .ok_or_else(|| format!("Crate '{name}' has no stable versions on crates.io").into())
+}
+
+/// URL of the `.crate` tarball of `name` at `version` on static.crates.io.
+pub fn crates_download_url(name: &str, version: &str) -> Result<String, Box<EvalAltResult>> {
+    check_crate_name(name)?;
+    let version = Version::parse(version)
+        .map_err(|e| format!("Invalid version '{version}' for crate '{name}': {e}"))?;
+    Ok(format!("{CRATES_DOWNLOAD}/{name}/{name}-{version}.crate"))
+}
+
+/// Runs the JMESPath `query` on `json_str`. Strings are returned without their JSON
+/// quotes so they can be used as versions or URLs directly, other results as JSON.
 pub fn jq(json_str: &str, query: &str) -> Result<String, Box<EvalAltResult>> {
     let expr = match compile(query) {
         Ok(k) => k,
@@ -37,38 +1046,462 @@ pub fn jq(json_str: &str, query: &str) -> Result<String, Box<EvalAltResult>> {
             return Err(error_msg.into());
         }
     };
-    Ok(result.to_string())
+    match result.as_string() {
+        Some(text) => Ok(text.clone()),
+        None => Ok(result.to_string()),
+    }
+}
+
+/// Like [`jq`], on a map the script already holds.
+pub fn jq_map(map: Map, query: &str) -> Result<String, Box<EvalAltResult>> {
+    jq(&rhai::format_map_as_json(&map), query)
+}
+
+/// Sets the longest a single [`sleep_ms`] waits, `None` removing the cap.
+/// [`DEFAULT_MAX_SLEEP`] until set.
+pub fn set_max_sleep(max: Option<Duration>) {
+    *MAX_SLEEP.write().unwrap() = max;
+}
+
+/// Pauses the script for `ms` milliseconds, cut short at the maximum set with
+/// [`set_max_sleep`].
+pub fn sleep_ms(ms: i64) -> Result<(), Box<EvalAltResult>> {
+    let ms = u64::try_from(ms).map_err(|_| format!("Cannot sleep for {ms}ms"))?;
+    let mut duration = Duration::from_millis(ms);
+    if let Some(max) = *MAX_SLEEP.read().unwrap() {
+        duration = duration.min(max);
+    }
+    thread::sleep(duration);
+    Ok(())
+}
+
+/// Calls `callback` until it returns without an error, at most `times` times with
+/// [`sleep_ms`] of `delay_ms` in between. Fails with the error of the last attempt.
+pub fn retry_call(
+    context: NativeCallContext,
+    times: i64,
+    delay_ms: i64,
+    callback: FnPtr,
+) -> Result<Dynamic, Box<EvalAltResult>> {
+    if times < 1 {
+        return Err(format!("retry needs at least 1 attempt, got {times}").into());
+    }
+    let mut attempt = 1;
+    loop {
+        match callback.call_within_context(&context, ()) {
+            Ok(value) => return Ok(value),
+            Err(e) if attempt >= times => return Err(e),
+            Err(_) => {
+                attempt += 1;
+                sleep_ms(delay_ms)?;
+            }
+        }
+    }
+}
+
+/// Runs `cmd`, split into the program and its arguments like a shell would, and returns
+/// its stdout. Quotes group words with spaces, see [`split_command`]. The command and
+/// everything it started are killed once `timeout` passes, `None` letting it run forever.
+pub fn run(timeout: Option<Duration>, cmd: &str) -> Result<String, Box<EvalAltResult>> {
+    run_split(cmd, timeout)
+}
+
+/// Like [`run`], killing the command and everything it started after `seconds`.
+pub fn run_timeout(cmd: &str, seconds: i64) -> Result<String, Box<EvalAltResult>> {
+    let seconds = u64::try_from(seconds)
+        .ok()
+        .filter(|seconds| *seconds > 0)
+        .ok_or_else(|| format!("Timeout of '{cmd}' should be a positive number of seconds"))?;
+    run_split(cmd, Some(Duration::from_secs(seconds)))
+}
+
+fn run_split(cmd: &str, timeout: Option<Duration>) -> Result<String, Box<EvalAltResult>> {
+    let mut parts = split_command(cmd)?.into_iter();
+    let program = parts
+        .next()
+        .ok_or_else(|| "Cannot run an empty command".to_string())?;
+    run_program(&program, &parts.collect::<Vec<_>>(), cmd, timeout, |_| {})
+}
+
+/// Runs `program` with `args` as given, without any splitting or quoting.
+pub fn run_args(
+    timeout: Option<Duration>,
+    program: &str,
+    args: Array,
+) -> Result<String, Box<EvalAltResult>> {
+    let args = args
+        .into_iter()
+        .map(|arg| {
+            arg.into_string()
+                .map_err(|t| format!("Arguments of '{program}' should be strings, found: {t}"))
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+    let display = std::iter::once(program.to_string())
+        .chain(args.iter().cloned())
+        .collect::<Vec<_>>()
+        .join(" ");
+    run_program(program, &args, &display, timeout, |_| {})
+}
+
+/// Like [`run`], with the variables in `env` added to the environment of the command.
+pub fn run_with_env(
+    timeout: Option<Duration>,
+    cmd: &str,
+    env: Map,
+) -> Result<String, Box<EvalAltResult>> {
+    run_with_env_from(timeout, split_command(cmd)?, env, false)
+}
+
+/// Like [`run_with_env`], starting from an empty environment when `clean` is set.
+pub fn run_with_env_clean(
+    timeout: Option<Duration>,
+    cmd: &str,
+    env: Map,
+    clean: bool,
+) -> Result<String, Box<EvalAltResult>> {
+    run_with_env_from(timeout, split_command(cmd)?, env, clean)
+}
+
+/// Like [`run_with_env`], with the program and its arguments given as an array.
+pub fn run_args_with_env(
+    timeout: Option<Duration>,
+    command: Array,
+    env: Map,
+) -> Result<String, Box<EvalAltResult>> {
+    run_args_with_env_clean(timeout, command, env, false)
+}
+
+/// Like [`run_with_env_clean`], with the program and its arguments given as an array.
+pub fn run_args_with_env_clean(
+    timeout: Option<Duration>,
+    command: Array,
+    env: Map,
+    clean: bool,
+) -> Result<String, Box<EvalAltResult>> {
+    let command = command
+        .into_iter()
+        .map(|arg| {
+            arg.into_string()
+                .map_err(|t| format!("Command parts should be strings, found: {t}"))
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+    run_with_env_from(timeout, command, env, clean)
+}
+
+fn run_with_env_from(
+    timeout: Option<Duration>,
+    command: Vec<String>,
+    env: Map,
+    clean: bool,
+) -> Result<String, Box<EvalAltResult>> {
+    let mut parts = command.into_iter();
+    let program = parts
+        .next()
+        .ok_or_else(|| "Cannot run an empty command".to_string())?;
+    let args = parts.collect::<Vec<_>>();
+    let vars = env
+        .into_iter()
+        .map(|(name, value)| {
+            let value = value.into_string().map_err(|t| {
+                format!("Value of environment variable '{name}' should be a string, found: {t}")
+            })?;
+            Ok((name.to_string(), value))
+        })
+        .collect::<Result<Vec<_>, Box<EvalAltResult>>>()?;
+    let display = vars
+        .iter()
+        .map(|(name, value)| format!("{name}={}", redact_env_value(name, value)))
+        .chain(std::iter::once(program.clone()))
+        .chain(args.iter().cloned())
+        .collect::<Vec<_>>()
+        .join(" ");
+    run_program(&program, &args, &display, timeout, |command| {
+        if clean {
+            command.env_clear();
+        }
+        command.envs(vars.iter().map(|(name, value)| (name, value)));
+    })
+}
+
+/// Waits for `child` and collects its output, or kills it with everything it started and
+/// returns `None` once `timeout` passes.
+fn wait_with_timeout(mut child: Child, timeout: Option<Duration>) -> io::Result<Option<Output>> {
+    let Some(timeout) = timeout else {
+        return child.wait_with_output().map(Some);
+    };
+    // Read both pipes while waiting, so a chatty command does not block on a full one
+    let collect = |pipe: Option<Box<dyn Read + Send>>| {
+        thread::spawn(move || {
+            let mut bytes = vec![];
+            if let Some(mut pipe) = pipe {
+                let _ = pipe.read_to_end(&mut bytes);
+            }
+            bytes
+        })
+    };
+    let stdout = collect(child.stdout.take().map(|pipe| Box::new(pipe) as _));
+    let stderr = collect(child.stderr.take().map(|pipe| Box::new(pipe) as _));
+    let started = Instant::now();
+    loop {
+        if let Some(status) = child.try_wait()? {
+            return Ok(Some(Output {
+                status,
+                stdout: stdout.join().unwrap_or_default(),
+                stderr: stderr.join().unwrap_or_default(),
+            }));
+        }
+        if started.elapsed() >= timeout {
+            kill_tree(&mut child);
+            let _ = child.wait();
+            return Ok(None);
+        }
+        thread::sleep(Duration::from_millis(20));
+    }
+}
+
+fn kill_tree(child: &mut Child) {
+    #[cfg(unix)]
+    if let Ok(pid) = i32::try_from(child.id()) {
+        // The command leads its own process group, see `run_program`
+        unsafe { libc::kill(-pid, libc::SIGKILL) };
+    }
+    #[cfg(windows)]
+    let _ = Command::new("taskkill")
+        .args(["/T", "/F", "/PID", &child.id().to_string()])
+        .output();
+    let _ = child.kill();
 }
 
-pub fn run(cmd: &str) -> Result<String, Box<EvalAltResult>> {
-    let command_parts: Vec<&str> = cmd.split_whitespace().collect();
-    let command = command_parts[0];
-    let args = &command_parts[1..];
-    let output = match std::process::Command::new(command).args(args).output() {
-        Ok(o) => o,
+/// `value` for errors, hidden when the name of the variable suggests a secret.
+fn redact_env_value<'a>(name: &str, value: &'a str) -> &'a str {
+    let name = name.to_ascii_uppercase();
+    if ["TOKEN", "SECRET", "PASSWORD"]
+        .iter()
+        .any(|word| name.contains(word))
+    {
+        "***"
+    } else {
+        value
+    }
+}
+
+/// Splits `cmd` into words at whitespace. Single quotes keep everything up to the next
+/// one as is, double quotes group words and take `\"` and `\\` escapes, and outside of
+/// quotes a backslash only escapes quotes, backslashes and whitespace, so Windows paths
+/// keep theirs.
+pub fn split_command(cmd: &str) -> Result<Vec<String>, Box<EvalAltResult>> {
+    let mut words = vec![];
+    let mut word: Option<String> = None;
+    let mut chars = cmd.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            c if c.is_whitespace() => words.extend(word.take()),
+            '\'' => {
+                let word = word.get_or_insert_with(String::new);
+                loop {
+                    match chars.next() {
+                        Some('\'') => break,
+                        Some(c) => word.push(c),
+                        None => return Err(format!("Unclosed ' in command '{cmd}'").into()),
+                    }
+                }
+            }
+            '"' => {
+                let word = word.get_or_insert_with(String::new);
+                loop {
+                    match chars.next() {
+                        Some('"') => break,
+                        Some('\\') if matches!(chars.peek(), Some('"' | '\\')) => {
+                            word.extend(chars.next());
+                        }
+                        Some(c) => word.push(c),
+                        None => return Err(format!("Unclosed \" in command '{cmd}'").into()),
+                    }
+                }
+            }
+            '\\' if chars
+                .peek()
+                .is_some_and(|next| matches!(next, '"' | '\'' | '\\') || next.is_whitespace()) =>
+            {
+                word.get_or_insert_with(String::new).extend(chars.next());
+            }
+            c => word.get_or_insert_with(String::new).push(c),
+        }
+    }
+    words.extend(word);
+    Ok(words)
+}
+
+/// Runs `program` like [`program_output`] and returns its stdout, failing with its
+/// stderr when it exits unsuccessfully.
+fn run_program(
+    program: &str,
+    args: &[String],
+    display: &str,
+    timeout: Option<Duration>,
+    setup: impl Fn(&mut Command),
+) -> Result<String, Box<EvalAltResult>> {
+    let output = program_output(program, args, display, timeout, setup)?;
+    if output.status.success() {
+        let output_str = String::from_utf8_lossy(&output.stdout).to_string();
+        Ok(output_str)
+    } else {
+        let output_status = output.status;
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        let error_msg = match stderr.trim() {
+            "" => format!("Command '{display}' failed with status: {output_status}"),
+            stderr => format!("Command '{display}' failed with status: {output_status}: {stderr}"),
+        };
+        Err(error_msg.into())
+    }
+}
+
+/// Runs `cmd` like [`run`], returning a map with its exit `code`, `stdout`, `stderr` and
+/// whether it exited with `success`, whatever the status. `code` is `()` when the
+/// command was ended by a signal.
+pub fn run_full(timeout: Option<Duration>, cmd: &str) -> Result<Map, Box<EvalAltResult>> {
+    let mut parts = split_command(cmd)?.into_iter();
+    let program = parts
+        .next()
+        .ok_or_else(|| "Cannot run an empty command".to_string())?;
+    let args = parts.collect::<Vec<_>>();
+    let output = program_output(&program, &args, cmd, timeout, |_| {})?;
+    let mut map = Map::new();
+    map.insert(
+        "code".into(),
+        output
+            .status
+            .code()
+            .map_or(Dynamic::UNIT, |code| i64::from(code).into()),
+    );
+    map.insert(
+        "stdout".into(),
+        String::from_utf8_lossy(&output.stdout).to_string().into(),
+    );
+    map.insert(
+        "stderr".into(),
+        String::from_utf8_lossy(&output.stderr).to_string().into(),
+    );
+    map.insert("success".into(), output.status.success().into());
+    Ok(map)
+}
+
+/// Runs `program`, falling back to the one next to the current executable when it is
+/// not found, and collects its output. `setup` configures the command before it starts,
+/// `display` names it in errors. The command is killed, with everything it started,
+/// once `timeout` passes.
+fn program_output(
+    program: &str,
+    args: &[String],
+    display: &str,
+    timeout: Option<Duration>,
+    setup: impl Fn(&mut Command),
+) -> Result<Output, Box<EvalAltResult>> {
+    let spawn = |program: &Path| {
+        let mut command = Command::new(program);
+        command
+            .args(args)
+            .stdin(Stdio::null())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped());
+        // A group of its own lets a timeout kill the grandchildren too
+        #[cfg(unix)]
+        std::os::unix::process::CommandExt::process_group(&mut command, 0);
+        setup(&mut command);
+        command.spawn()
+    };
+    let child = match spawn(Path::new(program)) {
+        Ok(child) => child,
         Err(_) => {
             let current_exe = std::env::current_exe().map_err(|e| e.to_string())?;
             let current_dir = current_exe
                 .parent()
                 .ok_or("Current executable has no parent directory")?;
-            let cmd_path = current_dir.join(command);
-            let output = std::process::Command::new(cmd_path).args(args).output();
-            match output {
-                Ok(o) => o,
+            match spawn(&current_dir.join(program)) {
+                Ok(child) => child,
                 Err(e) => {
-                    let error_msg = format!("Failed to execute command '{cmd}': {e}");
+                    let error_msg = format!("Failed to execute command '{display}': {e}");
                     return Err(error_msg.into());
                 }
             }
         }
     };
-    if output.status.success() {
-        let output_str = String::from_utf8_lossy(&output.stdout).to_string();
-        Ok(output_str)
-    } else {
-        let output_status = output.status;
-        let error_msg = format!("Command '{cmd}' failed with status: {output_status}");
-        Err(error_msg.into())
+    wait_with_timeout(child, timeout)
+        .map_err(|e| format!("Failed to execute command '{display}': {e}"))?
+        .ok_or_else(|| {
+            format!(
+                "Command '{display}' timed out after {:.1}s and was killed",
+                timeout.unwrap_or_default().as_secs_f64()
+            )
+            .into()
+        })
+}
+
+/// Absolute path of the executable `name` on `PATH` or next to the current executable,
+/// where [`run`] also looks, empty when there is none. On Windows the extensions in
+/// `PATHEXT` are tried as well.
+pub fn which(name: &str) -> String {
+    let exe_dir = std::env::current_exe()
+        .ok()
+        .and_then(|exe| exe.parent().map(Path::to_path_buf));
+    which_in(
+        name,
+        std::env::var_os("PATH").as_deref(),
+        exe_dir.as_deref(),
+    )
+    .map(|path| path.display().to_string())
+    .unwrap_or_default()
+}
+
+fn which_in(
+    name: &str,
+    path_var: Option<&std::ffi::OsStr>,
+    exe_dir: Option<&Path>,
+) -> Option<PathBuf> {
+    if name.is_empty() {
+        return None;
+    }
+    let candidates = |dir: &Path| -> Vec<PathBuf> {
+        let mut names = vec![dir.join(name)];
+        if cfg!(windows) && Path::new(name).extension().is_none() {
+            let pathext = std::env::var("PATHEXT").unwrap_or(".COM;.EXE;.BAT;.CMD".into());
+            names.extend(
+                pathext
+                    .split(';')
+                    .filter(|ext| !ext.is_empty())
+                    .map(|ext| dir.join(format!("{name}{}", ext.to_ascii_lowercase()))),
+            );
+        }
+        names
+    };
+    // Names with a directory part are not looked up
+    if Path::new(name).components().count() > 1 {
+        let dir = std::env::current_dir().ok()?;
+        return candidates(&dir)
+            .into_iter()
+            .find(|path| is_executable(path));
+    }
+    path_var
+        .map(std::env::split_paths)
+        .into_iter()
+        .flatten()
+        .filter(|dir| !dir.as_os_str().is_empty())
+        .chain(exe_dir.map(Path::to_path_buf))
+        .flat_map(|dir| candidates(&dir))
+        .find(|path| is_executable(path))
+        .and_then(|path| std::path::absolute(path).ok())
+}
+
+fn is_executable(path: &Path) -> bool {
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        path.metadata()
+            .is_ok_and(|meta| meta.is_file() && meta.permissions().mode() & 0o111 != 0)
+    }
+    #[cfg(not(unix))]
+    {
+        path.is_file()
     }
 }
 
@@ -77,23 +1510,392 @@ pub fn exists(path: &str) -> bool {
     path.exists()
 }
 
+/// The value of the environment variable `key`, empty when it is not set.
 pub fn env(key: &str) -> Result<String, Box<EvalAltResult>> {
-    std::env::var(key)
-        .map_err(|e| format!("Failed to get environment variable '{key}': {e}").into())
+    env_or(key, "")
+}
+
+/// The value of the environment variable `key`, or `default` when it is not set.
+pub fn env_or(key: &str, default: &str) -> Result<String, Box<EvalAltResult>> {
+    match std::env::var(key) {
+        Ok(value) => Ok(value),
+        Err(std::env::VarError::NotPresent) => Ok(default.to_string()),
+        Err(e) => Err(format!("Failed to get environment variable '{key}': {e}").into()),
+    }
 }
 
 pub fn read(path: &str) -> Result<String, Box<EvalAltResult>> {
     std::fs::read_to_string(path).map_err(|e| format!("Failed to read file '{path}': {e}").into())
 }
 
-pub fn write(path: &str, content: &str) -> Result<(), Box<EvalAltResult>> {
+pub fn write(read_only: bool, path: &str, content: &str) -> Result<(), Box<EvalAltResult>> {
+    check_writable(read_only, path)?;
     std::fs::write(path, content)
         .map_err(|e| format!("Failed to write to file '{path}': {e}").into())
 }
 
+fn compile_regex(pattern: &str) -> Result<Regex, Box<EvalAltResult>> {
+    // The error shows where in the pattern it went wrong
+    Regex::new(pattern).map_err(|e| format!("Invalid regex '{pattern}':\n{e}").into())
+}
+
+/// The first match of `pattern` in `text`, empty when there is none.
+pub fn regex_find(text: &str, pattern: &str) -> Result<String, Box<EvalAltResult>> {
+    let regex = compile_regex(pattern)?;
+    Ok(regex
+        .find(text)
+        .map(|m| m.as_str().to_string())
+        .unwrap_or_default())
+}
+
+/// Capture group `group` of the first match of `pattern` in `text`, empty when there is
+/// no match or the group did not take part in it.
+pub fn regex_capture(text: &str, pattern: &str, group: i64) -> Result<String, Box<EvalAltResult>> {
+    let regex = compile_regex(pattern)?;
+    let index = usize::try_from(group)
+        .ok()
+        .filter(|index| *index < regex.captures_len())
+        .ok_or_else(|| format!("Regex '{pattern}' has no capture group {group}"))?;
+    Ok(regex
+        .captures(text)
+        .and_then(|captures| captures.get(index))
+        .map(|m| m.as_str().to_string())
+        .unwrap_or_default())
+}
+
+/// Like [`regex_capture`], with the group given by name.
+pub fn regex_capture_named(
+    text: &str,
+    pattern: &str,
+    group: &str,
+) -> Result<String, Box<EvalAltResult>> {
+    let regex = compile_regex(pattern)?;
+    if !regex.capture_names().flatten().any(|name| name == group) {
+        return Err(format!("Regex '{pattern}' has no capture group named '{group}'").into());
+    }
+    Ok(regex
+        .captures(text)
+        .and_then(|captures| captures.name(group))
+        .map(|m| m.as_str().to_string())
+        .unwrap_or_default())
+}
+
+/// All non-overlapping matches of `pattern` in `text`.
+pub fn regex_matches(text: &str, pattern: &str) -> Result<Array, Box<EvalAltResult>> {
+    let regex = compile_regex(pattern)?;
+    Ok(regex
+        .find_iter(text)
+        .map(|m| m.as_str().to_string().into())
+        .collect())
+}
+
+/// The first version in `text` that parses as semver, without a leading `v`. Empty when
+/// there is none.
+pub fn extract_semver(text: &str) -> String {
+    let candidates =
+        Regex::new(r"\b[vV]?(\d+\.\d+\.\d+(?:-[0-9A-Za-z.-]+)?(?:\+[0-9A-Za-z.-]+)?)").unwrap();
+    candidates
+        .captures_iter(text)
+        .filter_map(|captures| Version::parse(&captures[1]).ok())
+        .map(|version| version.to_string())
+        .next()
+        .unwrap_or_default()
+}
+
+/// Parses `version` as semver, allowing a leading `v` like tags usually have.
+fn parse_semver(version: &str) -> Result<Version, Box<EvalAltResult>> {
+    let trimmed = version.trim();
+    let bare = trimmed.strip_prefix(['v', 'V']).unwrap_or(trimmed);
+    Version::parse(bare).map_err(|e| format!("Invalid version '{version}': {e}").into())
+}
+
+/// Whether version `a` is newer than `b`.
+pub fn semver_gt(a: &str, b: &str) -> Result<bool, Box<EvalAltResult>> {
+    Ok(parse_semver(a)? > parse_semver(b)?)
+}
+
+/// The newest of `versions`, returned as given.
+pub fn semver_max(versions: Array) -> Result<String, Box<EvalAltResult>> {
+    let mut newest: Option<(Version, String)> = None;
+    for version in versions {
+        let text = version
+            .into_string()
+            .map_err(|t| format!("Versions should be strings, found: {t}"))?;
+        let parsed = parse_semver(&text)?;
+        if newest.as_ref().is_none_or(|(max, _)| parsed > *max) {
+            newest = Some((parsed, text));
+        }
+    }
+    newest
+        .map(|(_, text)| text)
+        .ok_or_else(|| "Cannot take the newest of no versions".into())
+}
+
+/// Whether `version` meets the requirement `req`, like `>=1.2, <2`.
+pub fn semver_satisfies(version: &str, req: &str) -> Result<bool, Box<EvalAltResult>> {
+    let req =
+        VersionReq::parse(req).map_err(|e| format!("Invalid version requirement '{req}': {e}"))?;
+    Ok(req.matches(&parse_semver(version)?))
+}
+
+/// `version` split into `major`, `minor`, `patch`, `pre` and `build`.
+pub fn semver_parse(version: &str) -> Result<Map, Box<EvalAltResult>> {
+    let parsed = parse_semver(version)?;
+    let number = |n: u64| Dynamic::from(i64::try_from(n).unwrap_or(i64::MAX));
+    let mut map = Map::new();
+    map.insert("major".into(), number(parsed.major));
+    map.insert("minor".into(), number(parsed.minor));
+    map.insert("patch".into(), number(parsed.patch));
+    map.insert("pre".into(), parsed.pre.to_string().into());
+    map.insert("build".into(), parsed.build.to_string().into());
+    Ok(map)
+}
+
+/// The operating system wasupdate was built for: `linux`, `macos`, `windows` and so on.
+pub fn os() -> String {
+    std::env::consts::OS.to_string()
+}
+
+/// `unix` or `windows`.
+pub fn os_family() -> String {
+    std::env::consts::FAMILY.to_string()
+}
+
+/// The CPU architecture wasupdate was built for, like `x86_64` or `aarch64`. 32-bit ARM
+/// is named as in the target triple, like `armv7`, since artifacts are built per variant.
+pub fn arch() -> String {
+    match std::env::consts::ARCH {
+        "arm" => target_triple()
+            .split('-')
+            .next()
+            .unwrap_or("arm")
+            .to_string(),
+        arch => arch.to_string(),
+    }
+}
+
+/// The target triple wasupdate was built for, like `x86_64-unknown-linux-gnu`.
+pub fn target_triple() -> String {
+    env!("WASUPDATE_TARGET").to_string()
+}
+
+/// `<os>-<arch>`, like `linux-x86_64`.
+pub fn platform() -> String {
+    format!("{}-{}", os(), arch())
+}
+
+/// Fails for `read_only` engines, whose runs promise no side effects.
+fn check_writable(read_only: bool, path: &str) -> Result<(), Box<EvalAltResult>> {
+    if read_only {
+        return Err(format!("Cannot write to file '{path}', this run makes no changes").into());
+    }
+    Ok(())
+}
+
+/// `path` relative to the script directory `base`, or to the working directory for
+/// inline scripts.
+fn resolve(base: Option<&Path>, path: &str) -> PathBuf {
+    match base {
+        Some(base) => base.join(path),
+        None => PathBuf::from(path),
+    }
+}
+
+/// Contents of the file at `path` without surrounding whitespace, like the trailing
+/// newline of a `VERSION` file.
+pub fn read_file(base: Option<&Path>, path: &str) -> Result<String, Box<EvalAltResult>> {
+    Ok(read_file_raw(base, path)?.trim().to_string())
+}
+
+/// Contents of the file at `path`, exactly as stored.
+pub fn read_file_raw(base: Option<&Path>, path: &str) -> Result<String, Box<EvalAltResult>> {
+    let resolved = resolve(base, path);
+    std::fs::read_to_string(&resolved)
+        .map_err(|e| format!("Failed to read file '{}': {e}", resolved.display()).into())
+}
+
+/// Standard base64 of `text`, with padding.
+pub fn base64_encode(text: &str) -> String {
+    STANDARD.encode(text)
+}
+
+/// Decodes standard base64. Fails on invalid input and on content that is not UTF-8 text.
+pub fn base64_decode(text: &str) -> Result<String, Box<EvalAltResult>> {
+    decode_base64(&STANDARD, text)
+}
+
+/// URL-safe base64 of `text`, without padding.
+pub fn base64url_encode(text: &str) -> String {
+    URL_SAFE.encode(text)
+}
+
+/// Decodes URL-safe base64, padded or not. Fails like [`base64_decode`].
+pub fn base64url_decode(text: &str) -> Result<String, Box<EvalAltResult>> {
+    decode_base64(&URL_SAFE, text)
+}
+
+fn decode_base64(engine: &GeneralPurpose, text: &str) -> Result<String, Box<EvalAltResult>> {
+    let bytes = engine
+        .decode(text.trim())
+        .map_err(|e| format!("Invalid base64: {e}"))?;
+    String::from_utf8(bytes).map_err(|_| "Decoded base64 is not UTF-8 text".into())
+}
+
+/// SHA-256 of `text` in lowercase hex.
+pub fn sha256(text: &str) -> String {
+    let mut hasher = Hasher::new(Algorithm::Sha256);
+    hasher.update(text.as_bytes());
+    hasher.finalize().hex
+}
+
+/// SHA-256 of the file at `path` in lowercase hex, read in chunks.
+pub fn sha256_file(base: Option<&Path>, path: &str) -> Result<String, Box<EvalAltResult>> {
+    let resolved = resolve(base, path);
+    checksum::sha256_file(&resolved)
+        .map_err(|e| format!("Failed to hash file '{}': {e}", resolved.display()).into())
+}
+
+/// MD5 of the file at `path` in lowercase hex, for comparing against legacy manifests.
+pub fn md5_file(base: Option<&Path>, path: &str) -> Result<String, Box<EvalAltResult>> {
+    let resolved = resolve(base, path);
+    checksum::md5_file(&resolved)
+        .map_err(|e| format!("Failed to hash file '{}': {e}", resolved.display()).into())
+}
+
+/// Reads `value` of the registry `key` under `hive` (`HKLM`, `HKCU`, `HKCR` or `HKU`).
+/// String values are returned as stored and DWORD and QWORD values in decimal.
+#[cfg(windows)]
+pub fn reg_read(hive: &str, key: &str, value: &str) -> Result<String, Box<EvalAltResult>> {
+    use winreg::{
+        RegKey,
+        enums::{
+            HKEY_CLASSES_ROOT, HKEY_CURRENT_USER, HKEY_LOCAL_MACHINE, HKEY_USERS, KEY_READ, RegType,
+        },
+    };
+
+    let predef = match hive.to_ascii_uppercase().as_str() {
+        "HKLM" | "HKEY_LOCAL_MACHINE" => HKEY_LOCAL_MACHINE,
+        "HKCU" | "HKEY_CURRENT_USER" => HKEY_CURRENT_USER,
+        "HKCR" | "HKEY_CLASSES_ROOT" => HKEY_CLASSES_ROOT,
+        "HKU" | "HKEY_USERS" => HKEY_USERS,
+        _ => return Err(format!("Unknown registry hive '{hive}'").into()),
+    };
+    let location = format!("{hive}\\{key}");
+    let not_found = |e: io::Error| -> Box<EvalAltResult> {
+        if e.kind() == io::ErrorKind::NotFound {
+            format!("Registry value '{value}' not found in {location}").into()
+        } else {
+            format!("Failed to read registry value '{value}' in {location}: {e}").into()
+        }
+    };
+    let key = RegKey::predef(predef)
+        .open_subkey_with_flags(key, KEY_READ)
+        .map_err(&not_found)?;
+    let raw = key.get_raw_value(value).map_err(&not_found)?;
+    match raw.vtype {
+        RegType::REG_SZ | RegType::REG_EXPAND_SZ => key.get_value::<String, _>(value),
+        RegType::REG_DWORD => key.get_value::<u32, _>(value).map(|n| n.to_string()),
+        RegType::REG_QWORD => key.get_value::<u64, _>(value).map(|n| n.to_string()),
+        other => {
+            return Err(format!(
+                "Registry value '{value}' in {location} has unsupported type {other:?}"
+            )
+            .into());
+        }
+    }
+    .map_err(not_found)
+}
+
+/// Registry reads exist only on Windows, this always fails.
+#[cfg(not(windows))]
+pub fn reg_read(_hive: &str, _key: &str, value: &str) -> Result<String, Box<EvalAltResult>> {
+    Err(
+        format!("Cannot read registry value '{value}', reg_read is only available on Windows")
+            .into(),
+    )
+}
+
+/// Whether `path` is an existing file.
+pub fn file_exists(base: Option<&Path>, path: &str) -> bool {
+    resolve(base, path).is_file()
+}
+
+/// Whether `path` is an existing directory.
+pub fn dir_exists(base: Option<&Path>, path: &str) -> bool {
+    resolve(base, path).is_dir()
+}
+
+/// Size in bytes of the file at `path`, 0 when it does not exist.
+pub fn file_size(base: Option<&Path>, path: &str) -> i64 {
+    std::fs::metadata(resolve(base, path)).map_or(0, |metadata| metadata.len() as i64)
+}
+
+/// Last modification of the file at `path` in seconds since the Unix epoch, 0 when it
+/// does not exist.
+pub fn file_mtime_secs(base: Option<&Path>, path: &str) -> i64 {
+    std::fs::metadata(resolve(base, path))
+        .and_then(|metadata| metadata.modified())
+        .ok()
+        .and_then(|modified| modified.duration_since(std::time::UNIX_EPOCH).ok())
+        .map_or(0, |age| age.as_secs() as i64)
+}
+
+/// Names of the entries of the directory at `path`, sorted.
+pub fn list_dir(base: Option<&Path>, path: &str) -> Result<Array, Box<EvalAltResult>> {
+    let resolved = resolve(base, path);
+    let mut names = std::fs::read_dir(&resolved)
+        .and_then(|entries| {
+            entries
+                .map(|entry| Ok(entry?.file_name().to_string_lossy().to_string()))
+                .collect::<io::Result<Vec<_>>>()
+        })
+        .map_err(|e| format!("Failed to list directory '{}': {e}", resolved.display()))?;
+    names.sort();
+    Ok(names.into_iter().map(Dynamic::from).collect())
+}
+
+/// Paths matching the glob `pattern`, sorted. A relative pattern is matched from the
+/// script directory and gives paths starting with it.
+pub fn glob(base: Option<&Path>, pattern: &str) -> Result<Array, Box<EvalAltResult>> {
+    let resolved = resolve(base, pattern);
+    let resolved = resolved.to_string_lossy();
+    glob::glob(&resolved)
+        .map_err(|e| format!("Invalid glob pattern '{pattern}': {e}"))?
+        .map(|path| match path {
+            Ok(path) => Ok(path.to_string_lossy().to_string().into()),
+            Err(e) => Err(format!("Failed to read '{}': {}", e.path().display(), e.error()).into()),
+        })
+        .collect()
+}
+
+/// Writes `content` to the file at `path`, replacing it.
+pub fn write_file(
+    base: Option<&Path>,
+    read_only: bool,
+    path: &str,
+    content: &str,
+) -> Result<(), Box<EvalAltResult>> {
+    let resolved = resolve(base, path);
+    check_writable(read_only, &resolved.display().to_string())?;
+    std::fs::write(&resolved, content)
+        .map_err(|e| format!("Failed to write to file '{}': {e}", resolved.display()).into())
+}
+
 #[cfg(test)]
 mod tests {
+    use std::{
+        sync::{
+            Arc,
+            atomic::{AtomicU32, Ordering},
+        },
+        time::Duration,
+    };
+
     use super::*;
+    use crate::{
+        retry::{RetryPolicy, set_policy},
+        test_server::{Response, TestServer},
+    };
 
     #[test]
     fn test_fetch() {
@@ -106,8 +1908,675 @@ mod tests {
     #[test]
     fn test_run() {
         let cmd = "echo Hello, World!";
-        let result = run(cmd);
+        let result = run(Some(DEFAULT_RUN_TIMEOUT), cmd);
         assert!(result.is_ok());
         assert_eq!(result.unwrap().trim(), "Hello, World!");
     }
+
+    #[test]
+    fn test_fetch_retries_server_errors() {
+        set_policy(RetryPolicy {
+            base_delay: Duration::from_millis(1),
+            ..Default::default()
+        });
+        let requests = Arc::new(AtomicU32::new(0));
+        let count = requests.clone();
+        let server = TestServer::start(move |_| match count.fetch_add(1, Ordering::SeqCst) {
+            0 | 1 => Response::status(502),
+            _ => Response::ok("1.2.3"),
+        });
+        assert_eq!(fetch(&server.url("/latest")).unwrap(), "1.2.3");
+        assert_eq!(requests.load(Ordering::SeqCst), 3);
+    }
+
+    #[test]
+    fn test_crates_latest_skips_yanked_and_prereleases() {
+        let server = TestServer::start(|req| {
+            assert!(req.header("user-agent").unwrap().starts_with("wasupdate/"));
+            match req.path.as_str() {
+                "/api/v1/crates/ripgrep" => Response::ok(
+                    r#"{"crate": {"name": "ripgrep"}, "versions": [
+                        {"num": "15.0.0-beta.1", "yanked": false},
+                        {"num": "14.2.0", "yanked": true},
+                        {"num": "14.1.1", "yanked": false},
+                        {"num": "13.0.0", "yanked": false}
+                    ]}"#,
+                ),
+                _ => Response::status(404),
+            }
+        });
+        let api = server.url("/api/v1/crates");
+        assert_eq!(crates_latest_from(&api, "ripgrep").unwrap(), "14.1.1");
+        let e = crates_latest_from(&api, "no-such-crate").unwrap_err();
+        assert!(e.to_string().contains("404"), "{e}");
+        assert!(crates_latest_from(&api, "../etc").is_err());
+        assert_eq!(
+            crates_download_url("ripgrep", "14.1.1").unwrap(),
+            "https://static.crates.io/crates/ripgrep/ripgrep-14.1.1.crate"
+        );
+        assert!(crates_download_url("ripgrep", "latest").is_err());
+    }
+
+    fn release_json(tag: &str, draft: bool, prerelease: bool, assets: &[&str]) -> String {
+        let assets = assets
+            .iter()
+            .map(|name| {
+                format!(
+                    r#"{{"name": "{name}", "browser_download_url": "https://dl/{tag}/{name}"}}"#
+                )
+            })
+            .collect::<Vec<_>>()
+            .join(",");
+        format!(
+            r#"{{"tag_name": "{tag}", "draft": {draft}, "prerelease": {prerelease}, "assets": [{assets}]}}"#
+        )
+    }
+
+    #[test]
+    fn test_github_releases() {
+        let server = TestServer::start(|req| {
+            assert_eq!(req.header("accept").unwrap(), "application/vnd.github+json");
+            match req.path.as_str() {
+                "/repos/owner/tool/releases?per_page=100" => Response::ok(format!(
+                    "[{},{},{}]",
+                    release_json("v2.0.0", true, false, &[]),
+                    release_json("v1.3.0-rc.1", false, true, &[]),
+                    release_json("v1.2.0", false, false, &[]),
+                )),
+                "/repos/owner/tool/releases/tags/v1.2.0" => Response::ok(release_json(
+                    "v1.2.0",
+                    false,
+                    false,
+                    &["tool-windows.zip", "tool-linux-x86_64.tar.gz"],
+                )),
+                "/repos/owner/empty/releases?per_page=100" => Response::ok("[]"),
+                _ => Response::status(404),
+            }
+        });
+        let api = &server.url("");
+        assert_eq!(
+            github_latest_release_from(api, "owner/tool", false).unwrap(),
+            "1.2.0"
+        );
+        assert_eq!(
+            github_latest_release_from(api, "owner/tool", true).unwrap(),
+            "1.3.0-rc.1"
+        );
+        let e = github_latest_release_from(api, "owner/empty", false).unwrap_err();
+        assert!(e.to_string().contains("no published releases"), "{e}");
+        let e = github_latest_release_from(api, "owner/missing", false).unwrap_err();
+        assert!(e.to_string().contains("not found"), "{e}");
+        assert!(github_latest_release_from(api, "owner/../x", false).is_err());
+
+        assert_eq!(
+            github_release_asset_from(api, "owner/tool", "1.2.0", "*linux-x86_64.tar.gz").unwrap(),
+            "https://dl/v1.2.0/tool-linux-x86_64.tar.gz"
+        );
+        let e = github_release_asset_from(api, "owner/tool", "1.2.0", "*.deb").unwrap_err();
+        assert!(
+            e.to_string()
+                .contains("it has: tool-windows.zip, tool-linux-x86_64.tar.gz"),
+            "{e}"
+        );
+        let e = github_release_asset_from(api, "owner/tool", "9.9.9", "*").unwrap_err();
+        assert!(
+            e.to_string().contains("no release for version 9.9.9"),
+            "{e}"
+        );
+    }
+
+    #[test]
+    fn test_github_rate_limit_is_reported() {
+        let server = TestServer::start(|_| {
+            let mut response = Response::status(403);
+            response
+                .headers
+                .push(("x-ratelimit-remaining".to_string(), "0".to_string()));
+            response
+        });
+        let e = github_latest_release_from(&server.url(""), "owner/tool", false).unwrap_err();
+        assert!(e.to_string().contains("rate limit exceeded"), "{e}");
+    }
+
+    #[test]
+    fn test_gitlab_releases() {
+        const TOKEN_ENV: &str = "WASUPDATE_TEST_GITLAB_TOKEN";
+        // Only this test reads the variable
+        unsafe { std::env::set_var(TOKEN_ENV, "glpat-test") };
+        let server = TestServer::start(|req| {
+            let project = "/api/v4/projects/group%2Fsub%2Ftool";
+            let Some(path) = req.path.strip_prefix(project) else {
+                return Response::status(404);
+            };
+            match path {
+                "/releases" => {
+                    assert_eq!(req.header("private-token"), Some("glpat-test"));
+                    Response::ok(
+                        r#"[{"tag_name": "v2.0.0", "upcoming_release": true},
+                            {"tag_name": "v1.2.0", "upcoming_release": false}]"#,
+                    )
+                }
+                "/releases/v1.2.0" => Response::ok(
+                    r#"{"tag_name": "v1.2.0", "assets": {"links": [
+                        {"name": "tool.msi", "url": "https://dl/tool.msi"}
+                    ]}}"#,
+                ),
+                "/packages?package_type=generic&package_version=v1.2.0&per_page=100" => {
+                    Response::ok("[]")
+                }
+                "/packages?package_type=generic&package_version=1.2.0&per_page=100" => {
+                    Response::ok(r#"[{"id": 7, "name": "tool"}]"#)
+                }
+                "/packages/7/package_files?per_page=100" => {
+                    Response::ok(r#"[{"file_name": "tool-linux.tar.gz"}]"#)
+                }
+                _ => Response::status(404),
+            }
+        });
+        let base = &server.url("/");
+        assert_eq!(
+            gitlab_latest_release(base, "group/sub/tool", TOKEN_ENV).unwrap(),
+            "v1.2.0"
+        );
+        let e = gitlab_latest_release(base, "group/missing", "UNSET").unwrap_err();
+        assert!(e.to_string().contains("not found"), "{e}");
+
+        assert_eq!(
+            gitlab_release_asset(base, "group/sub/tool", "1.2.0", "*.msi").unwrap(),
+            "https://dl/tool.msi"
+        );
+        assert_eq!(
+            gitlab_release_asset(base, "group/sub/tool", "1.2.0", "*linux*").unwrap(),
+            format!(
+                "{base}api/v4/projects/group%2Fsub%2Ftool/packages/generic/tool/1.2.0/tool-linux.tar.gz"
+            )
+        );
+        let e = gitlab_release_asset(base, "group/sub/tool", "1.2.0", "*.deb").unwrap_err();
+        assert!(
+            e.to_string()
+                .contains("it has: tool.msi, tool-linux.tar.gz"),
+            "{e}"
+        );
+        let e = gitlab_release_asset(base, "group/sub/tool", "3.0.0", "*").unwrap_err();
+        assert!(
+            e.to_string().contains("no release for version 3.0.0"),
+            "{e}"
+        );
+    }
+
+    #[test]
+    fn test_gitea_releases() {
+        const TOKEN_ENV: &str = "WASUPDATE_TEST_GITEA_TOKEN";
+        // Only this test reads the variable
+        unsafe { std::env::set_var(TOKEN_ENV, "gitea-test") };
+        let server = TestServer::start(|req| match req.path.as_str() {
+            "/api/v1/repos/team/tool/releases?limit=50" => {
+                assert_eq!(req.header("authorization"), Some("token gitea-test"));
+                Response::ok(format!(
+                    "[{},{}]",
+                    release_json("v1.3.0", false, true, &[]),
+                    release_json("v1.2.0", false, false, &[]),
+                ))
+            }
+            "/api/v1/repos/team/empty/releases?limit=50" => Response::ok("[]"),
+            "/api/v1/repos/team/tool" => Response::ok("{}"),
+            "/api/v1/repos/team/tool/releases/tags/v1.2.0" => {
+                Response::ok(release_json("v1.2.0", false, false, &["tool-linux-amd64"]))
+            }
+            _ => Response::status(404),
+        });
+        let base = &server.url("");
+        assert_eq!(
+            gitea_latest_release_with_token(base, "team/tool", TOKEN_ENV).unwrap(),
+            "1.2.0"
+        );
+        let e = gitea_latest_release(base, "team/empty").unwrap_err();
+        assert!(e.to_string().contains("has no published releases"), "{e}");
+        let e = gitea_latest_release(base, "team/missing").unwrap_err();
+        assert!(e.to_string().contains("not found"), "{e}");
+
+        assert_eq!(
+            gitea_release_asset(base, "team/tool", "1.2.0", "*-linux-amd64").unwrap(),
+            "https://dl/v1.2.0/tool-linux-amd64"
+        );
+        let e = gitea_release_asset(base, "team/tool", "1.2.0", "*.exe").unwrap_err();
+        assert!(e.to_string().contains("No asset of release v1.2.0"), "{e}");
+        let e = gitea_release_asset(base, "team/tool", "9.0.0", "*").unwrap_err();
+        assert!(
+            e.to_string().contains("no release for version 9.0.0"),
+            "{e}"
+        );
+        let e = gitea_release_asset(base, "team/missing", "1.2.0", "*").unwrap_err();
+        assert!(e.to_string().contains("not found"), "{e}");
+    }
+
+    #[test]
+    fn test_fetch_with_headers() {
+        let server =
+            TestServer::start(
+                |req| match (req.header("authorization"), req.header("x-tenant")) {
+                    (Some("Bearer s3cret"), Some("acme")) => Response::ok("2.1.0"),
+                    _ => Response::status(401),
+                },
+            );
+        let mut headers = Map::new();
+        headers.insert("Authorization".into(), "Bearer s3cret".into());
+        headers.insert("X-Tenant".into(), "acme".into());
+        assert_eq!(
+            fetch_with_headers(&server.url("/latest"), headers).unwrap(),
+            "2.1.0"
+        );
+
+        let mut headers = Map::new();
+        headers.insert("Authorization".into(), "Bearer wrong".into());
+        let e = fetch_with_headers(&server.url("/latest"), headers).unwrap_err();
+        assert!(e.to_string().contains("401"), "{e}");
+        assert!(!e.to_string().contains("wrong"), "{e}");
+
+        // Rejected before anything is sent, so no server is needed
+        let mut headers = Map::new();
+        headers.insert("Bad Header".into(), "s3cret".into());
+        let e = fetch_with_headers("http://127.0.0.1:1/latest", headers).unwrap_err();
+        assert!(
+            e.to_string().contains("Invalid header name 'Bad Header'"),
+            "{e}"
+        );
+        assert!(!e.to_string().contains("s3cret"), "{e}");
+    }
+
+    #[test]
+    fn test_http_request_keeps_default_headers_on_origin() {
+        let hits = Arc::new(Mutex::new(vec![]));
+        let seen = hits.clone();
+        let other = TestServer::start(move |req| {
+            seen.lock()
+                .unwrap()
+                .push(req.header("x-api-key").map(str::to_string));
+            Response::ok("other")
+        });
+        let elsewhere = other.url("/latest");
+        let server = TestServer::start(move |_| Response {
+            status: 302,
+            headers: vec![("Location".to_string(), elsewhere.clone())],
+            body: vec![],
+        });
+
+        let response = http_request_with(
+            &HttpSettings::default(),
+            "GET",
+            &server.url("/"),
+            "",
+            Map::new(),
+        )
+        .unwrap();
+        assert_eq!(response["body"].clone().into_string().unwrap(), "other");
+        assert_eq!(*hits.lock().unwrap(), vec![None]);
+
+        let settings = HttpSettings {
+            default_headers: vec![("X-Api-Key".to_string(), "secret".to_string())],
+            ..Default::default()
+        };
+        let e = http_request_with(&settings, "GET", &server.url("/"), "", Map::new()).unwrap_err();
+        assert!(
+            e.to_string().contains("Refusing to follow the redirect"),
+            "{e}"
+        );
+        assert_eq!(hits.lock().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn test_http_post() {
+        set_policy(RetryPolicy {
+            base_delay: Duration::from_millis(1),
+            ..Default::default()
+        });
+        let other = TestServer::start(|_| Response::ok("leaked"));
+        let elsewhere = other.url("/graphql");
+        let server = TestServer::start(move |req| {
+            let redirect = |location: &str| Response {
+                status: 307,
+                headers: vec![("Location".to_string(), location.to_string())],
+                body: vec![],
+            };
+            match req.path.as_str() {
+                "/graphql" => {
+                    assert_eq!(req.method, "POST");
+                    assert_eq!(req.header("x-tenant"), Some("acme"));
+                    assert_eq!(req.body, br#"{"query": "{ latest }"}"#);
+                    let mut response = Response::ok(r#"{"data": {"latest": "3.1.0"}}"#);
+                    response
+                        .headers
+                        .push(("Content-Type".to_string(), "application/json".to_string()));
+                    response
+                }
+                "/moved" => redirect("/graphql"),
+                "/elsewhere" => redirect(&elsewhere),
+                _ => Response::status(404),
+            }
+        });
+        let headers = || {
+            let mut headers = Map::new();
+            headers.insert("X-Tenant".into(), "acme".into());
+            headers
+        };
+        let body = r#"{"query": "{ latest }"}"#;
+
+        let response = http_post(&server.url("/moved"), body, headers()).unwrap();
+        assert_eq!(response["status"].as_int().unwrap(), 200);
+        assert_eq!(
+            response["body"].clone().into_string().unwrap(),
+            r#"{"data": {"latest": "3.1.0"}}"#
+        );
+        let response_headers = response["headers"].read_lock::<Map>().unwrap();
+        assert_eq!(
+            response_headers["content-type"]
+                .clone()
+                .into_string()
+                .unwrap(),
+            "application/json"
+        );
+
+        let response = http_request("get", &server.url("/missing"), "", Map::new()).unwrap();
+        assert_eq!(response["status"].as_int().unwrap(), 404);
+
+        let e = http_post(&server.url("/elsewhere"), body, headers()).unwrap_err();
+        assert!(
+            e.to_string().contains("Refusing to follow the redirect"),
+            "{e}"
+        );
+
+        let e = http_post("http://127.0.0.1:1/graphql", body, headers()).unwrap_err();
+        assert!(
+            e.to_string()
+                .contains("Failed to send POST request to http://127.0.0.1:1/graphql"),
+            "{e}"
+        );
+    }
+
+    #[test]
+    fn test_download_keeps_files_until_cleanup() {
+        let server = TestServer::start(|req| match req.path.as_str() {
+            "/a/meta.bin" => Response::ok(vec![0u8, 159, 146, 150]),
+            _ => Response::ok("second"),
+        });
+        let first = download(&server.url("/a/meta.bin")).unwrap();
+        let second = download(&server.url("/b/meta.bin")).unwrap();
+        assert_ne!(first, second);
+        assert_eq!(std::fs::read(&first).unwrap(), [0u8, 159, 146, 150]);
+        assert_eq!(std::fs::read_to_string(&second).unwrap(), "second");
+        cleanup_downloads();
+        assert!(!std::path::Path::new(&first).exists());
+        assert!(!std::path::Path::new(&second).exists());
+    }
+
+    #[test]
+    fn test_regex_helpers() {
+        let page = r#"<a href="/dl/tool-1.9.0.tgz">old</a> <a href="/dl/tool-1.10.2.tgz">new</a>"#;
+        assert_eq!(regex_find(page, r"tool-[\d.]+\d").unwrap(), "tool-1.9.0");
+        assert_eq!(regex_find(page, r"nothing").unwrap(), "");
+        assert_eq!(regex_capture(page, r"tool-(\d+)\.(\d+)", 2).unwrap(), "9");
+        assert_eq!(
+            regex_capture_named(page, r"new.*|tool-(?<v>[\d.]+\d)", "v").unwrap(),
+            "1.9.0"
+        );
+        let all = regex_matches(page, r"\d+\.\d+\.\d+").unwrap();
+        let all: Vec<String> = all.into_iter().map(|m| m.into_string().unwrap()).collect();
+        assert_eq!(all, ["1.9.0", "1.10.2"]);
+        assert!(regex_capture(page, r"tool", 1).is_err());
+        let e = regex_find(page, r"tool-(\d+").unwrap_err();
+        assert!(e.to_string().contains("unclosed group"), "{e}");
+
+        assert_eq!(
+            extract_semver("tool version v2.3.4-rc.1 (abc)"),
+            "2.3.4-rc.1"
+        );
+        assert_eq!(extract_semver("git version 2.43.0\n"), "2.43.0");
+        assert_eq!(extract_semver("no version here 1.2"), "");
+    }
+
+    #[test]
+    fn test_split_command() {
+        let split = |cmd: &str| split_command(cmd).unwrap();
+        assert_eq!(
+            split(r#"git log -1 "--format=%(describe) x" 'a "b"' ''"#),
+            ["git", "log", "-1", "--format=%(describe) x", r#"a "b""#, ""]
+        );
+        assert_eq!(
+            split(r#"C:\tools\app.exe --name=a\ b "q\"uote""#),
+            [r"C:\tools\app.exe", "--name=a b", r#"q"uote"#]
+        );
+        assert_eq!(split("  tool   ünïcødé  "), ["tool", "ünïcødé"]);
+        assert!(split_command("echo 'open").is_err());
+        assert!(split("").is_empty());
+        assert!(run(Some(DEFAULT_RUN_TIMEOUT), "   ").is_err());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_run_keeps_arguments_whole() {
+        assert_eq!(
+            run(
+                Some(DEFAULT_RUN_TIMEOUT),
+                r#"printf "%s|" "with space" 'ünïcødé' "quo\"te""#
+            )
+            .unwrap(),
+            r#"with space|ünïcødé|quo"te|"#
+        );
+        let args = vec!["%s|".into(), "a b".into(), "'c'".into(), "".into()];
+        assert_eq!(
+            run_args(Some(DEFAULT_RUN_TIMEOUT), "printf", args).unwrap(),
+            "a b|'c'||"
+        );
+    }
+
+    #[test]
+    fn test_run_with_env() {
+        let list_env = || -> Array {
+            if cfg!(windows) {
+                vec!["cmd".into(), "/c".into(), "set".into()]
+            } else {
+                vec!["env".into()]
+            }
+        };
+        let mut env = Map::new();
+        env.insert("APP_CONFIG".into(), "/etc/app/config.toml".into());
+        let output = run_args_with_env(Some(DEFAULT_RUN_TIMEOUT), list_env(), env.clone()).unwrap();
+        assert!(
+            output.contains("APP_CONFIG=/etc/app/config.toml"),
+            "{output}"
+        );
+        assert!(output.contains("PATH="), "{output}");
+
+        let output =
+            run_args_with_env_clean(Some(DEFAULT_RUN_TIMEOUT), list_env(), env.clone(), true)
+                .unwrap();
+        assert!(
+            output.contains("APP_CONFIG=/etc/app/config.toml"),
+            "{output}"
+        );
+        assert!(!output.contains("HOME="), "{output}");
+
+        env.insert("API_TOKEN".into(), "s3cret".into());
+        let e = run_with_env(
+            Some(DEFAULT_RUN_TIMEOUT),
+            "wasupdate-no-such-command --version",
+            env,
+        )
+        .unwrap_err();
+        assert!(e.to_string().contains("API_TOKEN=***"), "{e}");
+        assert!(!e.to_string().contains("s3cret"), "{e}");
+        assert!(
+            e.to_string().contains("APP_CONFIG=/etc/app/config.toml"),
+            "{e}"
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_run_timeout_kills_the_process_group() {
+        let started = Instant::now();
+        let e = run_timeout("sh -c 'sleep 30 & sleep 30'", 1).unwrap_err();
+        assert!(started.elapsed() < Duration::from_secs(10));
+        assert!(
+            e.to_string()
+                .contains("Command 'sh -c 'sleep 30 & sleep 30'' timed out after 1.0s"),
+            "{e}"
+        );
+        assert!(run_timeout("echo fast", 5).unwrap().contains("fast"));
+        assert!(run_timeout("echo fast", 0).is_err());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_run_full_reports_failures() {
+        let cmd = "sh -c 'echo app 1.2.0; echo deprecated >&2; exit 1'";
+        let result = run_full(Some(DEFAULT_RUN_TIMEOUT), cmd).unwrap();
+        assert_eq!(result["code"].as_int().unwrap(), 1);
+        assert!(!result["success"].as_bool().unwrap());
+        assert_eq!(
+            result["stdout"].clone().into_string().unwrap(),
+            "app 1.2.0\n"
+        );
+        assert_eq!(
+            result["stderr"].clone().into_string().unwrap(),
+            "deprecated\n"
+        );
+
+        let e = run(Some(DEFAULT_RUN_TIMEOUT), cmd).unwrap_err();
+        assert!(
+            e.to_string()
+                .contains("failed with status: exit status: 1: deprecated"),
+            "{e}"
+        );
+        assert!(
+            run_full(Some(DEFAULT_RUN_TIMEOUT), "echo ok").unwrap()["success"]
+                .as_bool()
+                .unwrap()
+        );
+    }
+
+    #[test]
+    fn test_which_in() {
+        let root = std::env::temp_dir().join(format!("wasupdate-which-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&root);
+        let (on_path, exe_dir) = (root.join("bin"), root.join("app"));
+        std::fs::create_dir_all(&on_path).unwrap();
+        std::fs::create_dir_all(&exe_dir).unwrap();
+        let executable = |path: PathBuf| {
+            let path = if cfg!(windows) {
+                path.with_extension("exe")
+            } else {
+                path
+            };
+            std::fs::write(&path, "").unwrap();
+            #[cfg(unix)]
+            {
+                use std::os::unix::fs::PermissionsExt;
+                std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o755)).unwrap();
+            }
+            path
+        };
+        let tool = executable(on_path.join("tool"));
+        let helper = executable(exe_dir.join("helper"));
+        std::fs::write(on_path.join("notes"), "").unwrap();
+        let path_var = std::env::join_paths([&on_path]).unwrap();
+
+        let which = |name: &str| which_in(name, Some(&path_var), Some(&exe_dir));
+        assert_eq!(which("tool"), Some(tool));
+        assert_eq!(which("helper"), Some(helper));
+        assert_eq!(which("missing"), None);
+        #[cfg(unix)]
+        assert_eq!(which("notes"), None);
+        assert_eq!(which(""), None);
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn test_sha256() {
+        assert_eq!(
+            sha256("abc"),
+            "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+        );
+        let e = sha256_file(None, "wasupdate-no-such-file").unwrap_err();
+        assert!(e.to_string().contains("Failed to hash file"), "{e}");
+    }
+
+    #[test]
+    fn test_base64() {
+        assert_eq!(base64_encode("user:pass"), "dXNlcjpwYXNz");
+        assert_eq!(base64_decode("dXNlcjpwYXNz").unwrap(), "user:pass");
+        assert_eq!(base64_encode("v1.2?>"), "djEuMj8+");
+        assert_eq!(base64url_encode("v1.2?>"), "djEuMj8-");
+        assert_eq!(base64url_decode("djEuMj8-").unwrap(), "v1.2?>");
+        assert_eq!(base64url_decode("dXNlcg").unwrap(), "user");
+        assert_eq!(base64url_decode("dXNlcg==").unwrap(), "user");
+
+        let e = base64_decode("not base64!").unwrap_err();
+        assert!(e.to_string().contains("Invalid base64"), "{e}");
+        let e = base64_decode("/w==").unwrap_err();
+        assert!(e.to_string().contains("not UTF-8"), "{e}");
+    }
+
+    #[test]
+    fn test_parse_yaml() {
+        let value = parse_yaml(
+            "defaults: &defaults\n  arch: x86_64\n  stable: true\n\
+             entries:\n  - <<: *defaults\n    version: 1.2.0\n  - *defaults\n",
+        )
+        .unwrap();
+        let entries = value.cast::<Map>()["entries"].clone().cast::<Array>();
+        let first = entries[0].clone().cast::<Map>();
+        assert_eq!(first["arch"].to_string(), "x86_64");
+        assert_eq!(first["version"].to_string(), "1.2.0");
+        assert!(first["stable"].as_bool().unwrap());
+        assert_eq!(
+            entries[1].clone().cast::<Map>()["arch"].to_string(),
+            "x86_64"
+        );
+
+        let documents = parse_yaml("version: 1\n---\nversion: 2\n")
+            .unwrap()
+            .cast::<Array>();
+        assert_eq!(documents.len(), 2);
+        assert_eq!(
+            documents[1].clone().cast::<Map>()["version"].as_int(),
+            Ok(2)
+        );
+
+        let e = parse_yaml("a: 1\nb: [2\n").unwrap_err();
+        assert!(e.to_string().contains("line"), "{e}");
+        assert!(e.to_string().contains("column"), "{e}");
+    }
+
+    #[cfg(windows)]
+    #[test]
+    fn test_reg_read() {
+        use winreg::{RegKey, enums::HKEY_CURRENT_USER};
+
+        let path = format!("Software\\wasupdate-test-{}", std::process::id());
+        let (key, _) = RegKey::predef(HKEY_CURRENT_USER)
+            .create_subkey(&path)
+            .unwrap();
+        key.set_value("Version", &"1.4.2").unwrap();
+        key.set_value("Build", &1042u32).unwrap();
+        assert_eq!(reg_read("HKCU", &path, "Version").unwrap(), "1.4.2");
+        assert_eq!(
+            reg_read("HKEY_CURRENT_USER", &path, "Build").unwrap(),
+            "1042"
+        );
+        let e = reg_read("HKCU", &path, "Missing").unwrap_err();
+        assert!(e.to_string().contains("not found"), "{e}");
+        let e = reg_read("HKXX", &path, "Version").unwrap_err();
+        assert!(e.to_string().contains("Unknown registry hive"), "{e}");
+        RegKey::predef(HKEY_CURRENT_USER)
+            .delete_subkey_all(&path)
+            .unwrap();
+    }
+
+    #[cfg(not(windows))]
+    #[test]
+    fn test_reg_read() {
+        let e = reg_read("HKLM", "SOFTWARE\\Vendor\\App", "Version").unwrap_err();
+        assert!(e.to_string().contains("only available on Windows"), "{e}");
+    }
 }
@@ -0,0 +1,325 @@
+use std::{cmp::Ordering, fmt, str::FromStr};
+
+use regex::Regex;
+use semver::Version;
+use serde::{Deserialize, Serialize};
+
+/// How the versions of an update script are read and ordered.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum VersionScheme {
+    /// `1.4.2`, with prereleases before their release.
+    #[default]
+    Semver,
+    /// Build numbers such as `10452`.
+    Numeric,
+    /// Dates such as `2024.07.15`, compared component by component.
+    CalVer,
+    /// Any text, in string order.
+    Lexicographic,
+}
+
+impl VersionScheme {
+    pub const NAMES: [&str; 4] = ["semver", "numeric", "calver", "lexicographic"];
+}
+
+impl FromStr for VersionScheme {
+    type Err = String;
+
+    fn from_str(name: &str) -> Result<Self, Self::Err> {
+        match name.trim().to_ascii_lowercase().as_str() {
+            "semver" => Ok(VersionScheme::Semver),
+            "numeric" => Ok(VersionScheme::Numeric),
+            "calver" => Ok(VersionScheme::CalVer),
+            "lexicographic" => Ok(VersionScheme::Lexicographic),
+            _ => Err(format!(
+                "Unknown version scheme '{name}', expected one of: {}",
+                VersionScheme::NAMES.join(", ")
+            )),
+        }
+    }
+}
+
+impl fmt::Display for VersionScheme {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            VersionScheme::Semver => "semver",
+            VersionScheme::Numeric => "numeric",
+            VersionScheme::CalVer => "calver",
+            VersionScheme::Lexicographic => "lexicographic",
+        };
+        f.write_str(name)
+    }
+}
+
+/// A version read with a [`VersionScheme`]. Versions of different schemes order by scheme.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub enum VersionSpec {
+    Semver(Version),
+    Numeric(u64),
+    CalVer(Vec<u64>),
+    Lexicographic(String),
+}
+
+impl fmt::Display for VersionSpec {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            VersionSpec::Semver(version) => write!(f, "{version}"),
+            VersionSpec::Numeric(build) => write!(f, "{build}"),
+            VersionSpec::CalVer(components) => {
+                let components: Vec<_> = components.iter().map(u64::to_string).collect();
+                f.write_str(&components.join("."))
+            }
+            VersionSpec::Lexicographic(text) => f.write_str(text),
+        }
+    }
+}
+
+/// A version reported by an update script. Compares by `spec`, ignoring semver build
+/// metadata like semver precedence does, and displays as `raw`, the string the script
+/// gave, which is also what is handed back to the script.
+#[derive(Debug, Clone)]
+pub struct ScriptVersion {
+    pub raw: String,
+    pub spec: VersionSpec,
+}
+
+impl ScriptVersion {
+    /// Parses `text` with `scheme`. Semver versions are picked out of longer text such as
+    /// `tool v1.2.3 (linux)`, and unless `strict`, missing minor and patch components count
+    /// as zero. Unless `strict`, other schemes drop a leading `v`.
+    pub fn parse(text: &str, scheme: VersionScheme, strict: bool) -> Result<ScriptVersion, String> {
+        let text = text.trim();
+        let unprefixed = match strict {
+            true => text,
+            false => text.strip_prefix(['v', 'V']).unwrap_or(text),
+        };
+        let spec = match scheme {
+            VersionScheme::Semver => return parse_semver(text, strict),
+            VersionScheme::Numeric => VersionSpec::Numeric(
+                unprefixed
+                    .parse()
+                    .map_err(|_| "not a build number".to_string())?,
+            ),
+            VersionScheme::CalVer => VersionSpec::CalVer(
+                unprefixed
+                    .split(['.', '-'])
+                    .map(|component| component.parse().ok())
+                    .collect::<Option<_>>()
+                    .ok_or_else(|| "not a dot-separated date".to_string())?,
+            ),
+            VersionScheme::Lexicographic if text.is_empty() => {
+                return Err("empty version".to_string());
+            }
+            VersionScheme::Lexicographic => VersionSpec::Lexicographic(text.to_string()),
+        };
+        Ok(ScriptVersion {
+            raw: text.to_string(),
+            spec,
+        })
+    }
+
+    /// Whether this is a semver prerelease such as `2.0.0-rc.1`. Other schemes have none.
+    pub fn is_prerelease(&self) -> bool {
+        self.semver().is_some_and(|version| !version.pre.is_empty())
+    }
+
+    /// Orders like [`Ord`], except that semver build metadata breaks ties.
+    pub fn cmp_including_build(&self, other: &Self) -> Ordering {
+        self.spec.cmp(&other.spec)
+    }
+
+    /// Whether both are semver versions that only differ in their build metadata, such as
+    /// `1.4.2+build.2930` and `1.4.2+build.2931`.
+    pub fn differs_only_in_build(&self, other: &Self) -> bool {
+        match (self.semver(), other.semver()) {
+            (Some(a), Some(b)) => a.cmp_precedence(b).is_eq() && a.build != b.build,
+            _ => false,
+        }
+    }
+
+    /// The semver version, `None` for other schemes.
+    pub fn semver(&self) -> Option<&Version> {
+        match &self.spec {
+            VersionSpec::Semver(version) => Some(version),
+            _ => None,
+        }
+    }
+}
+
+fn parse_semver(text: &str, strict: bool) -> Result<ScriptVersion, String> {
+    if !strict && let Some(semver) = parse_lenient(text) {
+        return Ok(ScriptVersion {
+            raw: text.to_string(),
+            spec: VersionSpec::Semver(semver),
+        });
+    }
+    let re =
+        Regex::new(r"\b[vV]?\d+\.\d+\.\d+(?:-[0-9A-Za-z.-]+)?(?:\+[0-9A-Za-z.-]+)?\b").unwrap();
+    re.find(text)
+        .and_then(|found| {
+            let semver = parse_lenient(found.as_str())?;
+            Some(ScriptVersion {
+                raw: found.as_str().to_string(),
+                spec: VersionSpec::Semver(semver),
+            })
+        })
+        .ok_or_else(|| "no version found".to_string())
+}
+
+/// `text` as semver after dropping a leading `v` and padding `1` or `1.4` with zeros.
+fn parse_lenient(text: &str) -> Option<Version> {
+    let text = text.strip_prefix(['v', 'V']).unwrap_or(text);
+    if let Ok(version) = Version::parse(text) {
+        return Some(version);
+    }
+    let split = text.find(['-', '+']).unwrap_or(text.len());
+    let (core, suffix) = text.split_at(split);
+    let components = core.split('.').count();
+    if components > 2 || !core.split('.').all(|c| c.parse::<u64>().is_ok()) {
+        return None;
+    }
+    let padding = ".0".repeat(3 - components);
+    Version::parse(&format!("{core}{padding}{suffix}")).ok()
+}
+
+impl fmt::Display for ScriptVersion {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.raw)
+    }
+}
+
+impl PartialEq for ScriptVersion {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other).is_eq()
+    }
+}
+
+impl Eq for ScriptVersion {}
+
+impl PartialOrd for ScriptVersion {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for ScriptVersion {
+    fn cmp(&self, other: &Self) -> Ordering {
+        match (&self.spec, &other.spec) {
+            (VersionSpec::Semver(a), VersionSpec::Semver(b)) => a.cmp_precedence(b),
+            (a, b) => a.cmp(b),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(text: &str, scheme: VersionScheme) -> ScriptVersion {
+        ScriptVersion::parse(text, scheme, false).unwrap()
+    }
+
+    fn sorted(scheme: VersionScheme, versions: &[&str]) -> Vec<String> {
+        let mut versions: Vec<_> = versions.iter().map(|v| parse(v, scheme)).collect();
+        versions.sort();
+        versions.iter().map(ScriptVersion::to_string).collect()
+    }
+
+    #[test]
+    fn test_parse_lenient() {
+        let semver = |text: &str| parse(text, VersionScheme::Semver);
+        let version = semver(" v1.2.3\n");
+        assert_eq!(version.semver(), Some(&Version::new(1, 2, 3)));
+        assert_eq!(version.to_string(), "v1.2.3");
+        assert_eq!(semver("1.4").spec.to_string(), "1.4.0");
+        assert_eq!(semver("V1").spec.to_string(), "1.0.0");
+        assert_eq!(semver("1.4-rc.1").spec.to_string(), "1.4.0-rc.1");
+        assert_eq!(semver("2.0.0-beta.2").spec.to_string(), "2.0.0-beta.2");
+        let version = semver("tool v1.2.3 (linux)");
+        assert_eq!(version.raw, "v1.2.3");
+        assert_eq!(version.spec.to_string(), "1.2.3");
+        assert_eq!(semver("v1.4"), semver("1.4.0"));
+        assert!(semver("1.10") > semver("1.9.9"));
+
+        for invalid in ["", "latest", "1.x", "v", "1..2", "version one"] {
+            assert!(
+                ScriptVersion::parse(invalid, VersionScheme::Semver, false).is_err(),
+                "{invalid}"
+            );
+        }
+    }
+
+    #[test]
+    fn test_parse_strict() {
+        assert_eq!(
+            ScriptVersion::parse(" 1.2.3 ", VersionScheme::Semver, true)
+                .unwrap()
+                .spec,
+            VersionSpec::Semver(Version::new(1, 2, 3))
+        );
+        let strict = |text: &str| ScriptVersion::parse(text, VersionScheme::Semver, true);
+        assert_eq!(strict("v1.2.3").unwrap().raw, "v1.2.3");
+        assert_eq!(strict("tool 1.2.3 (linux)").unwrap().raw, "1.2.3");
+        for invalid in ["1.4", "1", "v1.4", "tool 1.4"] {
+            assert!(
+                ScriptVersion::parse(invalid, VersionScheme::Semver, true).is_err(),
+                "{invalid}"
+            );
+        }
+        assert!(ScriptVersion::parse("v10452", VersionScheme::Numeric, true).is_err());
+    }
+
+    #[test]
+    fn test_build_metadata() {
+        let semver = |text: &str| parse(text, VersionScheme::Semver);
+        let (current, latest) = (semver("1.4.2+build.2930"), semver("1.4.2+build.2931"));
+        assert_eq!(current, latest);
+        assert!(current.differs_only_in_build(&latest));
+        assert_eq!(current.cmp_including_build(&latest), Ordering::Less);
+        assert!(semver("1.4.3+build.1") > latest);
+        assert!(!semver("1.4.3").differs_only_in_build(&latest));
+        assert!(!semver("1.4.2").differs_only_in_build(&semver("v1.4.2")));
+        assert_eq!(
+            sorted(
+                VersionScheme::Semver,
+                &["1.4.2+b", "1.4.2-rc.1+a", "1.4.1+z"]
+            ),
+            ["1.4.1+z", "1.4.2-rc.1+a", "1.4.2+b"]
+        );
+    }
+
+    #[test]
+    fn test_scheme_ordering() {
+        assert_eq!(
+            sorted(
+                VersionScheme::Semver,
+                &["1.10.0", "1.2.0", "1.10.0-rc.1", "1.9.3"]
+            ),
+            ["1.2.0", "1.9.3", "1.10.0-rc.1", "1.10.0"]
+        );
+        assert_eq!(
+            sorted(VersionScheme::Numeric, &["10452", "999", "v10460"]),
+            ["999", "10452", "v10460"]
+        );
+        assert_eq!(
+            sorted(
+                VersionScheme::CalVer,
+                &["2024.10.01", "2024.07.15", "2023.12.31", "2024.07.15.1"]
+            ),
+            ["2023.12.31", "2024.07.15", "2024.07.15.1", "2024.10.01"]
+        );
+        assert_eq!(
+            parse("2024.07.15", VersionScheme::CalVer).spec.to_string(),
+            "2024.7.15"
+        );
+        assert_eq!(
+            sorted(VersionScheme::Lexicographic, &["beta", "alpha", "alpha-2"]),
+            ["alpha", "alpha-2", "beta"]
+        );
+        assert!(ScriptVersion::parse("1.2.3", VersionScheme::Numeric, false).is_err());
+        assert!(ScriptVersion::parse("2024.07.xx", VersionScheme::CalVer, false).is_err());
+        assert_eq!("CalVer".parse(), Ok(VersionScheme::CalVer));
+        assert!("dates".parse::<VersionScheme>().is_err());
+    }
+}